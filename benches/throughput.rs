@@ -0,0 +1,102 @@
+// Copyright (c) 2019 Rafael Alcaraz Mercado. All rights reserved.
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// All files in the project carrying such notice may not be copied, modified, or distributed
+// except according to those terms.
+// THE SOURCE CODE IS AVAILABLE UNDER THE ABOVE CHOSEN LICENSE "AS IS", WITH NO WARRANTIES.
+
+//! Throughput benchmarks of the mount, format, expand and compact workflows against
+//! temporary VHDX files. Setup of every iteration is excluded from the measurements.
+//! These need to run elevated.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use std::time::Duration;
+use virtdisk_rs::perf;
+use virtdisk_rs::testutils::*;
+use virtdisk_rs::vhdutilities::*;
+
+fn time_to_first_volume(c: &mut Criterion) {
+    c.bench_function("time_to_first_volume", |b| {
+        b.iter_custom(|iterations| {
+            let mut total = Duration::from_secs(0);
+
+            for _ in 0..iterations {
+                let mut temp_vhd = TempVhd::new_formatted(1, 1, "NTFS").unwrap();
+                temp_vhd.close().unwrap();
+
+                let (virtual_disk, elapsed) = perf::time_to_first_volume(
+                    temp_vhd.path(),
+                    &MountOptions::temporary_for_setup(),
+                )
+                .unwrap();
+
+                dismount_vhd(&virtual_disk).unwrap();
+                total += elapsed;
+            }
+
+            total
+        })
+    });
+}
+
+fn format(c: &mut Criterion) {
+    c.bench_function("format", |b| {
+        b.iter_custom(|iterations| {
+            let mut total = Duration::from_secs(0);
+
+            for _ in 0..iterations {
+                let temp_vhd = TempVhd::new_mounted(1, 1).unwrap();
+                let (_, elapsed) = perf::format_duration(temp_vhd.disk().unwrap(), "NTFS").unwrap();
+                total += elapsed;
+            }
+
+            total
+        })
+    });
+}
+
+fn expand_volume(c: &mut Criterion) {
+    c.bench_function("expand_volume", |b| {
+        b.iter_custom(|iterations| {
+            let mut total = Duration::from_secs(0);
+
+            for _ in 0..iterations {
+                let mut temp_vhd = TempVhd::new_formatted(1, 1, "NTFS").unwrap();
+                temp_vhd.dismount().unwrap();
+                expand_vhd(temp_vhd.virtual_disk(), SizeSpec::gib(2)).unwrap();
+                temp_vhd.mount().unwrap();
+
+                let (_, elapsed) = perf::expand_volume_duration(temp_vhd.disk().unwrap()).unwrap();
+                total += elapsed;
+            }
+
+            total
+        })
+    });
+}
+
+fn compaction(c: &mut Criterion) {
+    c.bench_function("compaction", |b| {
+        b.iter_custom(|iterations| {
+            let mut total = Duration::from_secs(0);
+
+            for _ in 0..iterations {
+                let mut temp_vhd = TempVhd::new_formatted(1, 1, "NTFS").unwrap();
+                temp_vhd.dismount().unwrap();
+
+                let measurement = perf::compaction_throughput(temp_vhd.virtual_disk()).unwrap();
+                total += measurement.elapsed;
+            }
+
+            total
+        })
+    });
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = time_to_first_volume, format, expand_volume, compaction
+}
+criterion_main!(benches);
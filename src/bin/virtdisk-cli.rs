@@ -0,0 +1,373 @@
+// Copyright (c) 2019 Rafael Alcaraz Mercado. All rights reserved.
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// All files in the project carrying such notice may not be copied, modified, or distributed
+// except according to those terms.
+// THE SOURCE CODE IS AVAILABLE UNDER THE ABOVE CHOSEN LICENSE "AS IS", WITH NO WARRANTIES.
+
+//! Command line tool to create, attach and maintain virtual disks,
+//! built on top of the workflows exposed by this crate.
+
+use virtdisk_rs::prelude::*;
+use virtdisk_rs::vhdset::*;
+use virtdisk_rs::vhdutilities::*;
+use virtdisk_rs::winutilities::*;
+
+const USAGE: &str = "Usage: virtdisk-cli <command> [arguments]
+
+Commands:
+    create <path> <size_gb> [--block-size-mb <mb>]
+    attach <path> [--read-only]
+    detach <path>
+    info <path> [--json]
+    list-attached [--json]
+    chain <leaf_path> [--json]
+    compact <path>
+    merge <path>
+    snapshot take <vhds_path> <snapshot_id>
+    snapshot list <vhds_path>
+    convert <source_path> <path> [--block-size-mb <mb>] [--verify]";
+
+type CliResult<T> = Result<T, String>;
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    if let Err(message) = run(&args) {
+        eprintln!("{}", message);
+        std::process::exit(1);
+    }
+}
+
+fn run(args: &[String]) -> CliResult<()> {
+    let command = match args.first() {
+        Some(command) => command.as_str(),
+        None => return Err(String::from(USAGE)),
+    };
+
+    let arguments = Arguments::parse(&args[1..]);
+
+    match command {
+        "create" => create(&arguments),
+        "attach" => attach(&arguments),
+        "detach" => detach(&arguments),
+        "info" => info(&arguments),
+        "list-attached" => list_attached(&arguments),
+        "chain" => chain(&arguments),
+        "compact" => compact(&arguments),
+        "merge" => merge(&arguments),
+        "snapshot" => snapshot(&arguments),
+        "convert" => convert(&arguments),
+        "help" | "--help" | "-h" => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        command => Err(format!("Unknown command {}\n\n{}", command, USAGE)),
+    }
+}
+
+/// Positional arguments and `--name [value]` options of a command.
+struct Arguments {
+    positional: Vec<String>,
+    options: Vec<(String, Option<String>)>,
+}
+
+impl Arguments {
+    /// Options that take a value.
+    const VALUED_OPTIONS: &'static [&'static str] = &["--block-size-mb"];
+
+    fn parse(args: &[String]) -> Arguments {
+        let mut positional: Vec<String> = Vec::new();
+        let mut options: Vec<(String, Option<String>)> = Vec::new();
+        let mut args = args.iter();
+
+        while let Some(arg) = args.next() {
+            if arg.starts_with("--") {
+                let value = match Arguments::VALUED_OPTIONS.contains(&arg.as_str()) {
+                    true => args.next().cloned(),
+                    false => None,
+                };
+                options.push((arg.clone(), value));
+            } else {
+                positional.push(arg.clone());
+            }
+        }
+
+        Arguments {
+            positional,
+            options,
+        }
+    }
+
+    fn positional(&self, index: usize, name: &str) -> CliResult<&str> {
+        match self.positional.get(index) {
+            Some(value) => Ok(value),
+            None => Err(format!("Missing argument <{}>\n\n{}", name, USAGE)),
+        }
+    }
+
+    fn flag(&self, name: &str) -> bool {
+        self.options.iter().any(|(option, _)| option == name)
+    }
+
+    fn value<T: std::str::FromStr>(&self, name: &str, default: T) -> CliResult<T> {
+        match self.options.iter().find(|(option, _)| option == name) {
+            Some((_, Some(value))) => value
+                .parse::<T>()
+                .map_err(|_| format!("Invalid value {} for {}", value, name)),
+            Some((_, None)) => Err(format!("Missing value for {}", name)),
+            None => Ok(default),
+        }
+    }
+}
+
+fn win_error<T>(context: &str, result: WinResult<T>) -> CliResult<T> {
+    result.map_err(|error| format!("{} failed: {:?}", context, error))
+}
+
+fn print_json<T: serde::Serialize>(value: &T) -> CliResult<()> {
+    let json = serde_json::to_string_pretty(value)
+        .map_err(|error| format!("Failed to serialize output: {}", error))?;
+    println!("{}", json);
+    Ok(())
+}
+
+fn print_progress(current: u64, total: u64) {
+    if total != 0 {
+        eprint!("\r{:3}%", current * 100 / total);
+    }
+}
+
+fn create(arguments: &Arguments) -> CliResult<()> {
+    let path = arguments.positional(0, "path")?;
+    let size_gb: u64 = arguments
+        .positional(1, "size_gb")?
+        .parse()
+        .map_err(|_| String::from("Invalid <size_gb>"))?;
+    let block_size_mb: u32 = arguments.value("--block-size-mb", 0)?;
+
+    win_error(
+        "Create",
+        create_vhd(path, SizeSpec::gib(size_gb), block_size_mb),
+    )?;
+    println!("Created {}", path);
+    Ok(())
+}
+
+fn attach(arguments: &Arguments) -> CliResult<()> {
+    let path = arguments.positional(0, "path")?;
+    let mut options = MountOptions::permanently_for_use();
+    options.read_only = arguments.flag("--read-only");
+
+    let virtual_disk = win_error("Attach", mount_vhd_with_options(path, &options))?;
+    let physical_path = win_error("Get physical path", virtual_disk.get_physical_path())?;
+    println!("Attached {} as {}", path, physical_path);
+    Ok(())
+}
+
+fn detach(arguments: &Arguments) -> CliResult<()> {
+    let path = arguments.positional(0, "path")?;
+    let virtual_disk = win_error("Open", open_vhd(path, true))?;
+    win_error("Detach", dismount_vhd(&virtual_disk))?;
+    println!("Detached {}", path);
+    Ok(())
+}
+
+/// Output of the info command.
+#[derive(serde::Serialize)]
+struct InfoOutput {
+    path: String,
+    kind: DiskKind,
+    virtual_size: u64,
+    physical_size: u64,
+    block_size: u32,
+    sector_size: u32,
+    fragmentation_percent: u32,
+    physical_path: Option<String>,
+}
+
+fn info(arguments: &Arguments) -> CliResult<()> {
+    let path = arguments.positional(0, "path")?;
+    let virtual_disk = win_error(
+        "Open",
+        VirtualDisk::open_options().get_info_only(true).open(path),
+    )?;
+
+    let size_info = win_error(
+        "Get size",
+        virtual_disk.get_information(get_virtual_disk::InfoVersion::Size),
+    )?;
+    let size = unsafe { size_info.info().version_details.size };
+
+    let output = InfoOutput {
+        path: String::from(path),
+        kind: win_error("Get kind", virtual_disk.disk_kind())?,
+        virtual_size: size.virtual_size,
+        physical_size: size.physical_size,
+        block_size: size.block_size,
+        sector_size: size.sector_size,
+        fragmentation_percent: win_error(
+            "Get fragmentation",
+            virtual_disk.fragmentation_percent(),
+        )?,
+        physical_path: virtual_disk.get_physical_path().ok(),
+    };
+
+    if arguments.flag("--json") {
+        return print_json(&output);
+    }
+
+    println!("Path:            {}", output.path);
+    println!("Kind:            {:?}", output.kind);
+    println!("Virtual size:    {}", output.virtual_size);
+    println!("Physical size:   {}", output.physical_size);
+    println!("Block size:      {}", output.block_size);
+    println!("Sector size:     {}", output.sector_size);
+    println!("Fragmentation:   {}%", output.fragmentation_percent);
+
+    match output.physical_path {
+        Some(physical_path) => println!("Attached as:     {}", physical_path),
+        None => println!("Attached as:     (not attached)"),
+    }
+
+    Ok(())
+}
+
+fn list_attached(arguments: &Arguments) -> CliResult<()> {
+    let paths = win_error(
+        "List attached",
+        VirtualDisk::get_all_attached_physical_paths(),
+    )?;
+
+    if arguments.flag("--json") {
+        return print_json(&paths);
+    }
+
+    for path in paths {
+        println!("{}", path);
+    }
+
+    Ok(())
+}
+
+fn chain(arguments: &Arguments) -> CliResult<()> {
+    let leaf_path = arguments.positional(0, "leaf_path")?;
+    let chain = win_error("Walk chain", differencing_chain(leaf_path))?;
+
+    if arguments.flag("--json") {
+        return print_json(&chain);
+    }
+
+    for (depth, path) in chain.iter().enumerate() {
+        println!("{:width$}{}", "", path, width = depth * 2);
+    }
+
+    Ok(())
+}
+
+fn compact(arguments: &Arguments) -> CliResult<()> {
+    let path = arguments.positional(0, "path")?;
+    let virtual_disk = win_error("Open", open_vhd(path, false))?;
+    let overlapped_event = win_error("Create event", OverlappedEvent::new())?;
+
+    let parameters = compact_virtual_disk::Parameters {
+        version: compact_virtual_disk::Version::Version1,
+        version_details: compact_virtual_disk::VersionDetails {
+            version1: compact_virtual_disk::Version1 { reserved: 0 },
+        },
+    };
+
+    match virtual_disk.compact(
+        compact_virtual_disk::Flag::None as u32,
+        &parameters,
+        Some(overlapped_event.as_overlapped()),
+    ) {
+        Ok(()) | Err(WinResultCode::ErrorIoPending) => {}
+        Err(error) => return Err(format!("Compact failed: {:?}", error)),
+    }
+
+    win_error(
+        "Compact",
+        wait_for_vhd_operation_with_progress(
+            &virtual_disk,
+            overlapped_event.as_overlapped(),
+            Some(&mut print_progress),
+        ),
+    )?;
+
+    eprintln!();
+    println!("Compacted {}", path);
+    Ok(())
+}
+
+fn merge(arguments: &Arguments) -> CliResult<()> {
+    let path = arguments.positional(0, "path")?;
+    let virtual_disk = win_error("Open", open_vhd(path, false))?;
+    win_error("Merge", merge_diff_vhd(&virtual_disk))?;
+    println!("Merged {} into its parent", path);
+    Ok(())
+}
+
+fn snapshot(arguments: &Arguments) -> CliResult<()> {
+    match arguments.positional(0, "take|list")? {
+        "take" => {
+            let vhds_path = arguments.positional(1, "vhds_path")?;
+            let snapshot_id = arguments.positional(2, "snapshot_id")?;
+            let snapshot_id = guid_from_str(snapshot_id)
+                .ok_or_else(|| format!("Invalid snapshot ID {}", snapshot_id))?;
+
+            let parameters = take_snapshot_vhdset::Parameters {
+                version: take_snapshot_vhdset::Version::Version1,
+                version_details: take_snapshot_vhdset::VersionDetails {
+                    version1: take_snapshot_vhdset::Version1 { snapshot_id },
+                },
+            };
+
+            let virtual_disk = win_error("Open", open_vhd(vhds_path, false))?;
+            win_error(
+                "Take snapshot",
+                virtual_disk
+                    .take_snapshot_vhdset(&parameters, take_snapshot_vhdset::Flag::None as u32),
+            )?;
+            println!("Took snapshot {}", guid_to_string(&snapshot_id));
+            Ok(())
+        }
+        "list" => {
+            let vhds_path = arguments.positional(1, "vhds_path")?;
+
+            for snapshot in win_error("List snapshots", list_snapshots(vhds_path))? {
+                println!(
+                    "{} {} {}",
+                    guid_to_string(&snapshot.snapshot_id),
+                    guid_to_string(&snapshot.parent_snapshot_id),
+                    snapshot.file_path
+                );
+            }
+
+            Ok(())
+        }
+        command => Err(format!("Unknown snapshot command {}\n\n{}", command, USAGE)),
+    }
+}
+
+fn convert(arguments: &Arguments) -> CliResult<()> {
+    let source_path = arguments.positional(0, "source_path")?;
+    let path = arguments.positional(1, "path")?;
+    let block_size_mb: u32 = arguments.value("--block-size-mb", 0)?;
+
+    win_error(
+        "Convert",
+        create_vhd_from_source(
+            path,
+            source_path,
+            block_size_mb,
+            Some(&mut print_progress),
+            arguments.flag("--verify"),
+        ),
+    )?;
+
+    eprintln!();
+    println!("Converted {} into {}", source_path, path);
+    Ok(())
+}
@@ -0,0 +1,283 @@
+// Copyright (c) 2019 Rafael Alcaraz Mercado. All rights reserved.
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// All files in the project carrying such notice may not be copied, modified, or distributed
+// except according to those terms.
+// THE SOURCE CODE IS AVAILABLE UNDER THE ABOVE CHOSEN LICENSE "AS IS", WITH NO WARRANTIES.
+
+//! Makes the Windows installation on a VHD bootable, the last step of the native boot workflow
+//! after `vhdutilities::create_gen2_boot_vhdx` and `imaging::apply_image`.
+//!
+//! Boot entries and boot files are laid down by the inbox bcdboot.exe, which is always
+//! invoked from System32 and whose output is returned as a `BcdbootReport`.
+
+use crate::diskutilities::{add_mount_point, remove_mount_point, resolve_volume};
+use crate::vhdutilities::volume_path_for_vhd_file;
+use crate::winutilities::io_error_to_winresult_code;
+use winutils_rs::errorcodes::{error_code_to_winresult_code, WinResult, WinResultCode};
+
+/// Firmware types to lay down boot files for, see the `/f` option of bcdboot.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Firmware {
+    Uefi,
+    Bios,
+    All,
+}
+
+impl Firmware {
+    fn as_argument(self) -> &'static str {
+        match self {
+            Firmware::Uefi => "UEFI",
+            Firmware::Bios => "BIOS",
+            Firmware::All => "ALL",
+        }
+    }
+}
+
+/// Outcome of a bcdboot invocation.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BcdbootReport {
+    /// Full command line bcdboot was invoked with.
+    pub command_line: String,
+
+    /// Exit code of bcdboot, -1 if it was terminated.
+    pub exit_code: i32,
+
+    /// Non empty lines written by bcdboot to its standard output and error, in order.
+    pub messages: Vec<String>,
+
+    /// True if bcdboot exited successfully.
+    pub succeeded: bool,
+}
+
+/// Error returned by `make_vhd_bootable` and `make_boot_partition`.
+#[derive(Debug, Clone)]
+pub struct BcdbootError {
+    /// Error the operation failed with, ErrorGenFailure if bcdboot itself failed.
+    pub error: WinResultCode,
+
+    /// Outcome of bcdboot, if it got to run.
+    pub report: Option<BcdbootReport>,
+}
+
+impl From<WinResultCode> for BcdbootError {
+    fn from(error: WinResultCode) -> BcdbootError {
+        BcdbootError {
+            error,
+            report: None,
+        }
+    }
+}
+
+impl From<BcdbootError> for WinResultCode {
+    fn from(error: BcdbootError) -> WinResultCode {
+        error.error
+    }
+}
+
+impl std::fmt::Display for BcdbootError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.report {
+            Some(report) => {
+                write!(
+                    f,
+                    "bcdboot failed with exit code {}: {}",
+                    report.exit_code, report.command_line
+                )?;
+
+                for message in &report.messages {
+                    write!(f, "; {}", message)?;
+                }
+
+                Ok(())
+            }
+            None => write!(f, "failed to run bcdboot: {:?}", self.error),
+        }
+    }
+}
+
+impl std::error::Error for BcdbootError {}
+
+/// Returns the report of a bcdboot run, or an error carrying it if bcdboot failed.
+fn succeeded(report: BcdbootReport) -> Result<BcdbootReport, BcdbootError> {
+    match report.succeeded {
+        true => Ok(report),
+        false => Err(BcdbootError {
+            error: WinResultCode::ErrorGenFailure,
+            report: Some(report),
+        }),
+    }
+}
+
+/// Returns the path to bcdboot.exe in System32, so that a bcdboot found earlier
+/// in the PATH is never run elevated by mistake.
+fn bcdboot_path() -> WinResult<std::path::PathBuf> {
+    let system_root = std::env::var_os("SystemRoot").unwrap_or_else(|| "C:\\Windows".into());
+    let path = std::path::Path::new(&system_root)
+        .join("System32")
+        .join("bcdboot.exe");
+
+    match path.is_file() {
+        true => Ok(path),
+        false => Err(error_code_to_winresult_code(
+            winapi::shared::winerror::ERROR_FILE_NOT_FOUND,
+        )),
+    }
+}
+
+fn run_bcdboot(arguments: &[&str]) -> WinResult<BcdbootReport> {
+    let path = bcdboot_path()?;
+    let output = std::process::Command::new(&path)
+        .args(arguments)
+        .output()
+        .map_err(|error| io_error_to_winresult_code(&error))?;
+
+    let messages = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .chain(String::from_utf8_lossy(&output.stderr).lines())
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect();
+
+    Ok(BcdbootReport {
+        command_line: format!("{} {}", path.display(), arguments.join(" ")),
+        exit_code: output.status.code().unwrap_or(-1),
+        messages,
+        succeeded: output.status.success(),
+    })
+}
+
+/// Returns the Windows directory under the given volume root, or ERROR_PATH_NOT_FOUND
+/// if the volume doesn't hold a Windows installation.
+fn windows_directory(windows_volume_root: &str) -> WinResult<String> {
+    let windows_directory = format!("{}\\Windows", windows_volume_root.trim_end_matches('\\'));
+
+    match std::path::Path::new(&windows_directory).is_dir() {
+        true => Ok(windows_directory),
+        false => Err(error_code_to_winresult_code(
+            winapi::shared::winerror::ERROR_PATH_NOT_FOUND,
+        )),
+    }
+}
+
+/// Returns the first drive letter not in use, e.g. `S:`.
+fn free_drive_letter() -> WinResult<String> {
+    let drives = unsafe { winapi::um::fileapi::GetLogicalDrives() };
+
+    // Skip A: through C:, which tools still assume to be floppy and system drives.
+    (3..26)
+        .find(|letter| drives & (1 << letter) == 0)
+        .map(|letter| format!("{}:", (b'A' + letter as u8) as char))
+        .ok_or_else(|| error_code_to_winresult_code(winapi::shared::winerror::ERROR_NO_MORE_ITEMS))
+}
+
+/// Drive letter assigned to a volume that is removed at the end of its lifetime.
+struct TemporaryDriveLetter {
+    drive_letter: String,
+}
+
+impl std::ops::Drop for TemporaryDriveLetter {
+    fn drop(&mut self) {
+        #[allow(unused_must_use)]
+        {
+            remove_mount_point(&self.drive_letter);
+        }
+    }
+}
+
+/// Adds a boot entry to the boot configuration of the host for the Windows installation
+/// on the given volume of the VHD, so that the host can natively boot from the VHD.
+/// The volume can be given by its volume GUID path or any of its mount points,
+/// e.g. `Gen2BootVhdx::windows_volume_path`.
+/// The entry is added last, so the default boot entry of the host is preserved.
+///
+/// Returns ERROR_PATH_NOT_FOUND if there's no Windows directory on the volume,
+/// ErrorInvalidArgument if the volume isn't backed by the VHD, and ErrorGenFailure
+/// along with the bcdboot report if bcdboot fails.
+pub fn make_vhd_bootable(
+    vhd_path: &str,
+    windows_volume_root: &str,
+) -> Result<BcdbootReport, BcdbootError> {
+    let volume = resolve_volume(windows_volume_root)?;
+    let is_vhd_volume = volume_path_for_vhd_file(vhd_path)?
+        .iter()
+        .any(|vhd_volume| {
+            vhd_volume
+                .trim_end_matches('\\')
+                .eq_ignore_ascii_case(&volume.volume_name)
+        });
+
+    if !is_vhd_volume {
+        return Err(BcdbootError::from(WinResultCode::ErrorInvalidArgument));
+    }
+
+    // Prefer a drive letter, bcdboot doesn't reliably accept volume GUID paths as its source.
+    let _temporary_drive_letter;
+    let source_root = match volume
+        .mount_points
+        .iter()
+        .find(|mount_point| mount_point.len() == 3)
+    {
+        Some(drive_letter) => drive_letter.clone(),
+        None => {
+            let drive_letter = free_drive_letter()?;
+            add_mount_point(&drive_letter, &volume.volume_name)?;
+            _temporary_drive_letter = TemporaryDriveLetter {
+                drive_letter: drive_letter.clone(),
+            };
+            drive_letter
+        }
+    };
+
+    succeeded(run_bcdboot_for(&source_root, &["/d", "/addlast"])?)
+}
+
+/// Lays down the boot files of the Windows installation on the given volume onto
+/// a system partition, typically `Gen2BootVhdx::esp_volume_path`, so that the VHD
+/// itself boots (e.g. as a generation 2 virtual machine) without touching the host boot configuration.
+/// The system partition gets a drive letter while bcdboot runs, as bcdboot requires one.
+///
+/// Returns ErrorGenFailure along with the bcdboot report if bcdboot fails.
+pub fn make_boot_partition(
+    windows_volume_root: &str,
+    system_volume_path: &str,
+    firmware: Firmware,
+) -> Result<BcdbootReport, BcdbootError> {
+    let system_volume = resolve_volume(system_volume_path)?;
+
+    let _temporary_drive_letter;
+    let system_drive_letter = match system_volume
+        .mount_points
+        .iter()
+        .find(|mount_point| mount_point.len() == 3)
+    {
+        Some(drive_letter) => String::from(drive_letter.trim_end_matches('\\')),
+        None => {
+            let drive_letter = free_drive_letter()?;
+            add_mount_point(&drive_letter, &system_volume.volume_name)?;
+            _temporary_drive_letter = TemporaryDriveLetter {
+                drive_letter: drive_letter.clone(),
+            };
+            drive_letter
+        }
+    };
+
+    succeeded(run_bcdboot_for(
+        windows_volume_root,
+        &["/s", &system_drive_letter, "/f", firmware.as_argument()],
+    )?)
+}
+
+/// Runs bcdboot for the Windows installation on the given volume with the given extra arguments,
+/// returning its report whether it succeeded or not.
+/// Returns ERROR_PATH_NOT_FOUND if there's no Windows directory on the volume,
+/// and ERROR_FILE_NOT_FOUND if bcdboot.exe isn't present in System32.
+pub fn run_bcdboot_for(windows_volume_root: &str, arguments: &[&str]) -> WinResult<BcdbootReport> {
+    let windows_directory = windows_directory(windows_volume_root)?;
+    let mut bcdboot_arguments = vec![windows_directory.as_str()];
+    bcdboot_arguments.extend_from_slice(arguments);
+    run_bcdboot(&bcdboot_arguments)
+}
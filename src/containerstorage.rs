@@ -0,0 +1,151 @@
+// Copyright (c) 2019 Rafael Alcaraz Mercado. All rights reserved.
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// All files in the project carrying such notice may not be copied, modified, or distributed
+// except according to those terms.
+// THE SOURCE CODE IS AVAILABLE UNDER THE ABOVE CHOSEN LICENSE "AS IS", WITH NO WARRANTIES.
+
+//! End to end workflows that prepare the storage of Windows containers.
+
+use crate::vhdutilities::*;
+use winutils_rs::errorcodes::{error_code_to_winresult_code, WinResult};
+use winutils_rs::windefs::*;
+
+/// Well known SID of the NT VIRTUAL MACHINE\Virtual Machines group,
+/// which every utility VM worker process is a member of.
+const VIRTUAL_MACHINES_SID: &str = "S-1-5-83-0";
+
+/// Memory allocated by Windows APIs that must be released with LocalFree.
+struct LocalAllocation {
+    pointer: PVoid,
+}
+
+impl std::ops::Drop for LocalAllocation {
+    fn drop(&mut self) {
+        if self.pointer != std::ptr::null_mut() {
+            unsafe {
+                winapi::um::winbase::LocalFree(self.pointer);
+            }
+        }
+    }
+}
+
+/// Prepares the sandbox (scratch) layer of a container on top of its base layer:
+/// creates a differencing VHDX of the base layer, expands it and its volume to the requested size,
+/// and grants the utility VM worker processes access to the file.
+/// If any step fails, the sandbox VHDX is deleted.
+pub fn prepare_sandbox_layer(base_vhdx: &str, sandbox_vhdx: &str, size_gb: u64) -> WinResult<()> {
+    // A block size of 0 inherits the block size of the base layer.
+    create_diff_vhd(sandbox_vhdx, base_vhdx, 0)?;
+
+    match configure_sandbox_layer(sandbox_vhdx, size_gb) {
+        Ok(()) => Ok(()),
+        Err(error) => {
+            #[allow(unused_must_use)]
+            {
+                std::fs::remove_file(sandbox_vhdx);
+            }
+
+            Err(error)
+        }
+    }
+}
+
+/// Expands the sandbox layer if needed and sets its security for use by containers.
+fn configure_sandbox_layer(sandbox_vhdx: &str, size_gb: u64) -> WinResult<()> {
+    {
+        let virtual_disk = open_vhd(sandbox_vhdx, false)?;
+
+        if expand_vhd(&virtual_disk, SizeSpec::gib(size_gb))? {
+            mount_vhd_temporarily_for_setup(&virtual_disk)?;
+
+            let expand_result =
+                open_vhd_backed_disk(&virtual_disk).and_then(|disk| disk.expand_volume());
+            let dismount_result = dismount_vhd(&virtual_disk);
+
+            expand_result?;
+            dismount_result?;
+        }
+    }
+
+    grant_vm_group_access(sandbox_vhdx)
+}
+
+/// Grants read and write access to a file to the NT VIRTUAL MACHINE\Virtual Machines group,
+/// so that utility VMs hosting containers can open the layer files attached to them.
+pub fn grant_vm_group_access(filename: &str) -> WinResult<()> {
+    use winapi::shared::{sddl, winerror};
+    use winapi::um::{accctrl, aclapi, winnt};
+
+    let filename_wstr = widestring::WideCString::from_str(filename).unwrap();
+    let sid_wstr = widestring::WideCString::from_str(VIRTUAL_MACHINES_SID).unwrap();
+
+    unsafe {
+        let mut old_dacl: winnt::PACL = std::ptr::null_mut();
+        let mut security_descriptor: winnt::PSECURITY_DESCRIPTOR = std::ptr::null_mut();
+
+        let result = aclapi::GetNamedSecurityInfoW(
+            filename_wstr.as_ptr(),
+            accctrl::SE_FILE_OBJECT,
+            winnt::DACL_SECURITY_INFORMATION,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            &mut old_dacl,
+            std::ptr::null_mut(),
+            &mut security_descriptor,
+        );
+
+        if result != winerror::ERROR_SUCCESS {
+            return Err(error_code_to_winresult_code(result));
+        }
+
+        let _security_descriptor = LocalAllocation {
+            pointer: security_descriptor,
+        };
+
+        let mut sid: winnt::PSID = std::ptr::null_mut();
+
+        if sddl::ConvertStringSidToSidW(sid_wstr.as_ptr(), &mut sid) == 0 {
+            return Err(error_code_to_winresult_code(
+                winapi::um::errhandlingapi::GetLastError(),
+            ));
+        }
+
+        let _sid = LocalAllocation { pointer: sid };
+
+        let mut explicit_access = std::mem::zeroed::<accctrl::EXPLICIT_ACCESS_W>();
+        explicit_access.grfAccessPermissions = winnt::GENERIC_READ | winnt::GENERIC_WRITE;
+        explicit_access.grfAccessMode = accctrl::GRANT_ACCESS;
+        explicit_access.grfInheritance = accctrl::NO_INHERITANCE;
+        explicit_access.Trustee.TrusteeForm = accctrl::TRUSTEE_IS_SID;
+        explicit_access.Trustee.TrusteeType = accctrl::TRUSTEE_IS_WELL_KNOWN_GROUP;
+        explicit_access.Trustee.ptstrName = sid as *mut WChar;
+
+        let mut new_dacl: winnt::PACL = std::ptr::null_mut();
+        let result = aclapi::SetEntriesInAclW(1, &mut explicit_access, old_dacl, &mut new_dacl);
+
+        if result != winerror::ERROR_SUCCESS {
+            return Err(error_code_to_winresult_code(result));
+        }
+
+        let _new_dacl = LocalAllocation {
+            pointer: new_dacl as PVoid,
+        };
+
+        let result = aclapi::SetNamedSecurityInfoW(
+            filename_wstr.as_ptr() as *mut WChar,
+            accctrl::SE_FILE_OBJECT,
+            winnt::DACL_SECURITY_INFORMATION,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            new_dacl,
+            std::ptr::null_mut(),
+        );
+
+        match result {
+            winerror::ERROR_SUCCESS => Ok(()),
+            error => Err(error_code_to_winresult_code(error)),
+        }
+    }
+}
@@ -8,33 +8,245 @@
 
 //! Wrappers around basic disk functions used to setup container storage.
 
+use crate::gpt::*;
+use crate::guid::WinGuid;
+use crate::winutilities::*;
+use std::os::windows::io::{AsRawHandle, IntoRawHandle, RawHandle};
 use winutils_rs::diskformat::*;
-use winutils_rs::errorcodes::{error_code_to_winresult_code, WinResult, WinResultCode};
+use winutils_rs::errorcodes::{
+    error_code_to_winresult_code, winresult_code_to_error_code, WinResult, WinResultCode,
+};
 use winutils_rs::utilities::*;
 use winutils_rs::windefs::*;
 
-#[allow(dead_code)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PartitionInfo {
     volume_path: String,
+    #[cfg_attr(feature = "serde", serde(with = "crate::winutilities::serde_guid"))]
     disk_id: Guid,
+    #[cfg_attr(feature = "serde", serde(with = "crate::winutilities::serde_guid"))]
     partition_id: Guid,
 }
 
-const PARTITION_MSFT_RESERVED_GUID: Guid = Guid {
-    Data1: 0xE3C9E316,
-    Data2: 0x0B5C,
-    Data3: 0x4DB8,
-    Data4: [0x81, 0x7D, 0xF9, 0x2D, 0xF0, 0x02, 0x15, 0xAE],
-};
+impl PartitionInfo {
+    /// Path to the volume of the partition.
+    pub fn volume_path(&self) -> &str {
+        &self.volume_path
+    }
 
-const PARTITION_BASIC_DATA_GUID: Guid = Guid {
-    Data1: 0xEBD0A0A2,
-    Data2: 0xB9E5,
-    Data3: 0x4433,
-    Data4: [0x87, 0xC0, 0x68, 0xB6, 0xB7, 0x26, 0x99, 0xC7],
-};
+    /// GPT disk ID of the disk holding the partition.
+    pub fn disk_id(&self) -> WinGuid {
+        WinGuid::from(self.disk_id)
+    }
+
+    /// GPT partition ID of the partition.
+    pub fn partition_id(&self) -> WinGuid {
+        WinGuid::from(self.partition_id)
+    }
+
+    /// Returns the information of the same partition surfaced under another volume path,
+    /// e.g. after its disk got detached and attached again.
+    pub(crate) fn with_volume_path(self, volume_path: String) -> PartitionInfo {
+        PartitionInfo {
+            volume_path,
+            ..self
+        }
+    }
+}
+
+/// GPT partition attributes that can be toggled through `Disk::set_partition_attributes`.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct GptAttributes {
+    /// The partition is required for the platform to function (GPT_ATTRIBUTE_PLATFORM_REQUIRED).
+    pub required: bool,
+
+    /// The volume of the partition is read-only (GPT_BASIC_DATA_ATTRIBUTE_READ_ONLY).
+    pub read_only: bool,
+
+    /// The volume of the partition is hidden from the mount manager (GPT_BASIC_DATA_ATTRIBUTE_HIDDEN).
+    pub hidden: bool,
+
+    /// The volume of the partition is not assigned a drive letter (GPT_BASIC_DATA_ATTRIBUTE_NO_DRIVE_LETTER).
+    pub no_drive_letter: bool,
+}
+
+impl GptAttributes {
+    /// Mask of all the attribute bits represented by this type.
+    const MASK: u64 = GPT_ATTRIBUTE_PLATFORM_REQUIRED
+        | GPT_BASIC_DATA_ATTRIBUTE_READ_ONLY
+        | GPT_BASIC_DATA_ATTRIBUTE_HIDDEN
+        | GPT_BASIC_DATA_ATTRIBUTE_NO_DRIVE_LETTER;
+
+    /// Decodes the attributes from the raw GPT partition attribute bits.
+    pub fn from_bits(bits: u64) -> GptAttributes {
+        GptAttributes {
+            required: bits & GPT_ATTRIBUTE_PLATFORM_REQUIRED != 0,
+            read_only: bits & GPT_BASIC_DATA_ATTRIBUTE_READ_ONLY != 0,
+            hidden: bits & GPT_BASIC_DATA_ATTRIBUTE_HIDDEN != 0,
+            no_drive_letter: bits & GPT_BASIC_DATA_ATTRIBUTE_NO_DRIVE_LETTER != 0,
+        }
+    }
+
+    /// Encodes the attributes into raw GPT partition attribute bits.
+    pub fn bits(&self) -> u64 {
+        let mut bits: u64 = 0;
+
+        if self.required {
+            bits |= GPT_ATTRIBUTE_PLATFORM_REQUIRED;
+        }
+
+        if self.read_only {
+            bits |= GPT_BASIC_DATA_ATTRIBUTE_READ_ONLY;
+        }
+
+        if self.hidden {
+            bits |= GPT_BASIC_DATA_ATTRIBUTE_HIDDEN;
+        }
+
+        if self.no_drive_letter {
+            bits |= GPT_BASIC_DATA_ATTRIBUTE_NO_DRIVE_LETTER;
+        }
+
+        bits
+    }
+}
+
+/// Partitioning scheme of a disk.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum PartitionStyle {
+    Mbr,
+    Gpt,
+
+    /// The disk isn't partitioned.
+    Raw,
+}
+
+/// Identifier written to the partition table of a disk, see `Disk::identity`.
+/// Windows refuses to bring online a disk whose identifier collides with the one of a disk
+/// already online, so clones of a disk need a new identifier, see `Disk::set_gpt_disk_id`.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum DiskIdentity {
+    /// Disk GUID of a GPT disk.
+    Gpt(WinGuid),
+
+    /// 32 bit signature of an MBR disk.
+    Mbr(u32),
+
+    /// The disk isn't partitioned.
+    Raw,
+}
+
+/// How `Disk::clean` wipes a disk.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WipeMode {
+    /// Replaces the partition table with an empty one of the given style.
+    /// With `PartitionStyle::Raw` the disk is left without a partition table.
+    Layout(PartitionStyle),
+
+    /// Like `Layout`, but also zeroes the first and last MiB of the disk first,
+    /// wiping boot code and the file system signatures that survive a layout change.
+    ZeroEdges(PartitionStyle),
+}
+
+/// Partition to lay out with `Disk::create_gpt_partitions`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GptPartitionSpec {
+    /// GPT partition type, see the `gpt` module.
+    pub partition_type: WinGuid,
+
+    /// Length of the partition in bytes, rounded up to a MiB.
+    /// None makes the partition span the rest of the disk, which is only valid for the last one.
+    pub length: Option<u64>,
+
+    /// Raw GPT attribute bits, see the `gpt` module.
+    pub attributes: u64,
+
+    /// GPT partition name, at most 36 UTF-16 characters.
+    pub name: String,
+}
+
+/// Partition of a disk, see `Disk::partitions`.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Partition {
+    pub style: PartitionStyle,
+
+    /// Number of the partition, 1-based.
+    pub number: u32,
+
+    /// Offset of the partition from the start of the disk, in bytes.
+    pub offset: u64,
+
+    /// Length of the partition in bytes.
+    pub length: u64,
+
+    /// GPT partition type, see the `gpt` module. Null for MBR partitions.
+    pub partition_type: WinGuid,
+
+    /// Unique ID of the partition. MBR partitions only have one on recent versions of Windows.
+    pub partition_id: WinGuid,
+
+    /// Raw GPT attribute bits, see the `gpt` module. Zero for MBR partitions.
+    pub attributes: u64,
+
+    /// GPT partition name. Empty for MBR partitions.
+    pub name: String,
+
+    /// MBR partition type byte. Zero for GPT partitions.
+    pub mbr_type: u8,
+
+    /// True if this is the active MBR partition.
+    pub bootable: bool,
+}
+
+impl Partition {
+    fn from_entry(entry: &winapi::um::winioctl::PARTITION_INFORMATION_EX) -> Option<Partition> {
+        use winapi::um::winioctl;
+
+        let mut partition = Partition {
+            style: PartitionStyle::Raw,
+            number: entry.PartitionNumber,
+            offset: unsafe { *entry.StartingOffset.QuadPart() } as u64,
+            length: unsafe { *entry.PartitionLength.QuadPart() } as u64,
+            partition_type: WinGuid::NULL,
+            partition_id: WinGuid::NULL,
+            attributes: 0,
+            name: String::new(),
+            mbr_type: 0,
+            bootable: false,
+        };
+
+        match entry.PartitionStyle {
+            winioctl::PARTITION_STYLE_GPT => {
+                let gpt = unsafe { entry.u.Gpt() };
+                partition.style = PartitionStyle::Gpt;
+                partition.partition_type = WinGuid::from(gpt.PartitionType);
+                partition.partition_id = WinGuid::from(gpt.PartitionId);
+                partition.attributes = gpt.Attributes;
+                partition.name = wide_to_string(&gpt.Name).unwrap_or_default();
+            }
+            winioctl::PARTITION_STYLE_MBR => {
+                let mbr = unsafe { entry.u.Mbr() };
+
+                // The MBR layout always reports its 4 primary slots, even the unused ones.
+                if mbr.PartitionType == 0 {
+                    return None;
+                }
+
+                partition.style = PartitionStyle::Mbr;
+                partition.partition_id = WinGuid::from(mbr.PartitionId);
+                partition.mbr_type = mbr.PartitionType;
+                partition.bootable = mbr.BootIndicator != 0;
+            }
+            _ => return None,
+        }
 
-const GPT_BASIC_DATA_ATTRIBUTE_NO_DRIVE_LETTER: u64 = 0x8000000000000000;
+        Some(partition)
+    }
+}
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
@@ -61,14 +273,157 @@ pub(crate) struct SetDiskAttributes {
 const DISK_ATTRIBUTE_OFFLINE: u64 = 0x0000000000000001;
 const DISK_ATTRIBUTE_READ_ONLY: u64 = 0x0000000000000002;
 
+/// Heap allocated drive layout of a disk, holding a variable number of partition entries.
+/// The buffer is backed by u64 values so that the layout structures are properly aligned.
+struct DriveLayout {
+    buffer: Vec<u64>,
+}
+
+impl DriveLayout {
+    /// Queries the drive layout of the given disk, growing the buffer until all partition entries fit.
+    fn query(handle: Handle) -> WinResult<DriveLayout> {
+        use winapi::um::{ioapiset, winioctl};
+
+        let mut partition_count: usize = 4;
+
+        loop {
+            let size = std::mem::size_of::<winioctl::DRIVE_LAYOUT_INFORMATION_EX>()
+                + partition_count * std::mem::size_of::<winioctl::PARTITION_INFORMATION_EX>();
+            let mut buffer: Vec<u64> = vec![0; (size + 7) / 8];
+            let mut bytes: DWord = 0;
+
+            unsafe {
+                if ioapiset::DeviceIoControl(
+                    handle,
+                    winioctl::IOCTL_DISK_GET_DRIVE_LAYOUT_EX,
+                    std::ptr::null_mut(),
+                    0,
+                    buffer.as_mut_ptr() as PVoid,
+                    (buffer.len() * std::mem::size_of::<u64>()) as DWord,
+                    &mut bytes,
+                    std::ptr::null_mut(),
+                ) != 0
+                {
+                    return Ok(DriveLayout { buffer });
+                }
+
+                let error = winapi::um::errhandlingapi::GetLastError();
+
+                if error != winapi::shared::winerror::ERROR_INSUFFICIENT_BUFFER {
+                    return Err(error_code_to_winresult_code(error));
+                }
+            }
+
+            partition_count *= 2;
+        }
+    }
+
+    /// Writes this drive layout to the given disk.
+    fn apply(&mut self, handle: Handle) -> WinResult<()> {
+        use winapi::um::{ioapiset, winioctl};
+
+        let mut bytes: DWord = 0;
+
+        unsafe {
+            match ioapiset::DeviceIoControl(
+                handle,
+                winioctl::IOCTL_DISK_SET_DRIVE_LAYOUT_EX,
+                self.buffer.as_mut_ptr() as PVoid,
+                (self.buffer.len() * std::mem::size_of::<u64>()) as DWord,
+                std::ptr::null_mut(),
+                0,
+                &mut bytes,
+                std::ptr::null_mut(),
+            ) {
+                0 => Err(error_code_to_winresult_code(
+                    winapi::um::errhandlingapi::GetLastError(),
+                )),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    fn info(&self) -> &winapi::um::winioctl::DRIVE_LAYOUT_INFORMATION_EX {
+        unsafe {
+            &*(self.buffer.as_ptr() as *const winapi::um::winioctl::DRIVE_LAYOUT_INFORMATION_EX)
+        }
+    }
+
+    fn info_mut(&mut self) -> &mut winapi::um::winioctl::DRIVE_LAYOUT_INFORMATION_EX {
+        unsafe {
+            &mut *(self.buffer.as_mut_ptr()
+                as *mut winapi::um::winioctl::DRIVE_LAYOUT_INFORMATION_EX)
+        }
+    }
+
+    fn partition_entries(&self) -> &[winapi::um::winioctl::PARTITION_INFORMATION_EX] {
+        let info = self.info();
+        unsafe {
+            std::slice::from_raw_parts(info.PartitionEntry.as_ptr(), info.PartitionCount as usize)
+        }
+    }
+
+    fn partition_entries_mut(&mut self) -> &mut [winapi::um::winioctl::PARTITION_INFORMATION_EX] {
+        let info = self.info_mut();
+        unsafe {
+            std::slice::from_raw_parts_mut(
+                info.PartitionEntry.as_mut_ptr(),
+                info.PartitionCount as usize,
+            )
+        }
+    }
+
+    /// Returns the index of the GPT partition entry with the given partition ID.
+    fn partition_index_by_id(&self, partition_id: &Guid) -> WinResult<usize> {
+        use winapi::um::winioctl;
+
+        if self.info().PartitionStyle != winioctl::PARTITION_STYLE_GPT {
+            return Err(WinResultCode::ErrorInvalidArgument);
+        }
+
+        match self
+            .partition_entries()
+            .iter()
+            .position(|entry| unsafe { guid_are_equal(&entry.u.Gpt().PartitionId, partition_id) })
+        {
+            Some(index) => Ok(index),
+            None => Err(error_code_to_winresult_code(
+                winapi::shared::winerror::ERROR_NOT_FOUND,
+            )),
+        }
+    }
+
+    /// Returns the index of the last GPT partition entry of the given partition type.
+    fn last_partition_of_type(&self, partition_type: &Guid) -> Option<usize> {
+        self.partition_entries().iter().rposition(|entry| unsafe {
+            guid_are_equal(&entry.u.Gpt().PartitionType, partition_type)
+        })
+    }
+}
+
 /// Safe abstraction to a disk handle.
+/// Disks are Send and Sync, IOCTLs can be issued on the same handle from any thread.
 pub struct Disk {
-    handle: Handle,
+    handle: OwnedHandle,
 }
 
-impl std::ops::Drop for Disk {
-    fn drop(&mut self) {
-        close_handle(&mut self.handle);
+impl AsRawHandle for Disk {
+    fn as_raw_handle(&self) -> RawHandle {
+        self.handle.as_raw_handle()
+    }
+}
+
+impl IntoRawHandle for Disk {
+    fn into_raw_handle(self) -> RawHandle {
+        self.handle.into_raw_handle()
+    }
+}
+
+impl From<std::os::windows::io::OwnedHandle> for Disk {
+    fn from(handle: std::os::windows::io::OwnedHandle) -> Disk {
+        Disk {
+            handle: OwnedHandle::from(handle),
+        }
     }
 }
 
@@ -76,10 +431,9 @@ impl Disk {
     /// Wraps the supplied disk handle, providing a safe drop implementation that will close the handle
     /// on the end of its lifetime.
     pub fn wrap_handle(handle: Handle) -> WinResult<Disk> {
-        match handle {
-            handle if handle == std::ptr::null_mut() => Err(WinResultCode::ErrorInvalidArgument),
-            handle => Ok(Disk { handle }),
-        }
+        Ok(Disk {
+            handle: OwnedHandle::wrap(handle)?,
+        })
     }
 
     /// Releases the wrapped handle to ensure that at the end of the lifetime of this Disk instance
@@ -88,10 +442,10 @@ impl Disk {
     /// # Unsafe
     ///
     /// Marked as unsafe because of the possibility of leaking a handle.
+    /// Prefer `IntoRawHandle::into_raw_handle` or converting into
+    /// `std::os::windows::io::OwnedHandle`, which hand over ownership of the handle.
     pub unsafe fn release_handle(&mut self) -> Handle {
-        let handle = self.handle;
-        self.handle = std::ptr::null_mut();
-        handle
+        self.handle.release()
     }
 
     /// Returns a cloned value of the internally stored handle to the disk.
@@ -99,7 +453,7 @@ impl Disk {
     /// Be careful and do not close the handle returned here because the code will panic at the
     /// end of the lifetime of this Disk instance if CloseHandle fails.
     pub fn get_handle(&self) -> Handle {
-        self.handle.clone()
+        self.handle.get()
     }
 
     /// Opens a disk by path. Path can be
@@ -139,7 +493,7 @@ impl Disk {
             file_flags,
             None,
         ) {
-            Ok(handle) => Ok(Disk { handle }),
+            Ok(handle) => Disk::wrap_handle(handle),
             Err(error) => Err(error),
         }
     }
@@ -159,7 +513,7 @@ impl Disk {
 
         unsafe {
             match winapi::um::ioapiset::DeviceIoControl(
-                self.handle,
+                self.handle.get(),
                 winapi::um::winioctl::IOCTL_DISK_SET_DISK_ATTRIBUTES,
                 &mut params as *mut _ as LPVoid,
                 SET_DISK_ATTRIBUTES_SIZE,
@@ -176,9 +530,176 @@ impl Disk {
         }
     }
 
+    /// Trims the given byte ranges of the disk, so that the storage backing them
+    /// (for VHD-backed disks, the blocks of the VHD file) can be returned to the host.
+    ///
+    /// The ranges are trimmed with `IOCTL_STORAGE_MANAGE_DATA_SET_ATTRIBUTES`. Disks that don't
+    /// support trim get the ranges overwritten with zeros instead, which a later compaction
+    /// of the VHD reclaims. The contents of trimmed ranges are undefined afterwards,
+    /// so only pass ranges whose contents are no longer needed.
+    /// Ranges must be aligned to the logical sector size of the disk, and must not overlap
+    /// mounted volumes since Windows refuses raw writes over them.
+    pub fn trim_ranges(
+        &self,
+        ranges: &[crate::virtdiskdefs::query_changes_virtual_disk::Range],
+    ) -> WinResult<()> {
+        use winapi::shared::winerror;
+
+        #[repr(C)]
+        struct DeviceManageDataSetAttributes {
+            size: DWord,
+            action: DWord,
+            flags: DWord,
+            parameter_block_offset: DWord,
+            parameter_block_length: DWord,
+            data_set_ranges_offset: DWord,
+            data_set_ranges_length: DWord,
+        }
+
+        #[repr(C)]
+        struct DeviceDataSetRange {
+            starting_offset: LongLong,
+            length_in_bytes: DWordLong,
+        }
+
+        const DEVICE_DSM_ACTION_TRIM: DWord = 1;
+        const ZERO_CHUNK_SIZE: u64 = 1024 * 1024;
+
+        if ranges.is_empty() {
+            return Ok(());
+        }
+
+        // The ranges follow the header, which is padded so they stay 8 byte aligned.
+        let ranges_offset = (std::mem::size_of::<DeviceManageDataSetAttributes>() + 7) & !7;
+        let ranges_length = ranges.len() * std::mem::size_of::<DeviceDataSetRange>();
+        let mut buffer: Vec<u64> = vec![0; (ranges_offset + ranges_length + 7) / 8];
+
+        unsafe {
+            let attributes = buffer.as_mut_ptr() as *mut DeviceManageDataSetAttributes;
+            (*attributes).size = std::mem::size_of::<DeviceManageDataSetAttributes>() as DWord;
+            (*attributes).action = DEVICE_DSM_ACTION_TRIM;
+            (*attributes).data_set_ranges_offset = ranges_offset as DWord;
+            (*attributes).data_set_ranges_length = ranges_length as DWord;
+
+            let data_set_ranges =
+                (buffer.as_mut_ptr() as *mut u8).add(ranges_offset) as *mut DeviceDataSetRange;
+
+            for (index, range) in ranges.iter().enumerate() {
+                let data_set_range = &mut *data_set_ranges.add(index);
+                data_set_range.starting_offset = range.byte_offset as LongLong;
+                data_set_range.length_in_bytes = range.byte_length;
+            }
+        }
+
+        let mut bytes: DWord = 0;
+        let trimmed = unsafe {
+            winapi::um::ioapiset::DeviceIoControl(
+                self.handle.get(),
+                winapi::um::winioctl::IOCTL_STORAGE_MANAGE_DATA_SET_ATTRIBUTES,
+                buffer.as_mut_ptr() as PVoid,
+                (ranges_offset + ranges_length) as DWord,
+                std::ptr::null_mut(),
+                0,
+                &mut bytes,
+                std::ptr::null_mut(),
+            )
+        };
+
+        if trimmed != 0 {
+            return Ok(());
+        }
+
+        match unsafe { winapi::um::errhandlingapi::GetLastError() } {
+            winerror::ERROR_INVALID_FUNCTION | winerror::ERROR_NOT_SUPPORTED => {}
+            error => return Err(error_code_to_winresult_code(error)),
+        }
+
+        let zeros: Vec<Byte> = vec![0; ZERO_CHUNK_SIZE as usize];
+
+        for range in ranges {
+            let mut byte_offset = range.byte_offset;
+            let range_end = range.byte_offset + range.byte_length;
+
+            while byte_offset < range_end {
+                let length = std::cmp::min(ZERO_CHUNK_SIZE, range_end - byte_offset);
+                write_handle_at(self.handle.get(), byte_offset, &zeros[..length as usize])?;
+                byte_offset += length;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns false if the disk is write protected, e.g. a VHD attached read-only.
+    pub fn is_writable(&self) -> WinResult<bool> {
+        let mut bytes: DWord = 0;
+
+        unsafe {
+            match winapi::um::ioapiset::DeviceIoControl(
+                self.handle.get(),
+                winapi::um::winioctl::IOCTL_DISK_IS_WRITABLE,
+                std::ptr::null_mut(),
+                0,
+                std::ptr::null_mut(),
+                0,
+                &mut bytes,
+                std::ptr::null_mut(),
+            ) {
+                0 => match winapi::um::errhandlingapi::GetLastError() {
+                    winapi::shared::winerror::ERROR_WRITE_PROTECT => Ok(false),
+                    error => Err(error_code_to_winresult_code(error)),
+                },
+                _ => Ok(true),
+            }
+        }
+    }
+
+    /// Makes the disk pick up changes in its size and partition layout,
+    /// so that stale geometry isn't reported after the disk or its layout changed.
+    pub fn refresh(&self) -> WinResult<()> {
+        let mut bytes: DWord = 0;
+
+        unsafe {
+            match winapi::um::ioapiset::DeviceIoControl(
+                self.handle.get(),
+                winapi::um::winioctl::IOCTL_DISK_UPDATE_PROPERTIES,
+                std::ptr::null_mut(),
+                0,
+                std::ptr::null_mut(),
+                0,
+                &mut bytes,
+                std::ptr::null_mut(),
+            ) {
+                0 => Err(error_code_to_winresult_code(
+                    winapi::um::errhandlingapi::GetLastError(),
+                )),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    /// Returns the number of the disk, as in \\.\PhysicalDrive<number>.
+    pub fn number(&self) -> WinResult<DWord> {
+        device_number(self.handle.get())
+    }
+
     /// Retrieves the path to the first volume on a disk, waiting for the volumes to arrive
     /// if the have not yet.
     pub fn volume_path(&self) -> WinResult<String> {
+        self.volume_path_until_cancelled(None)
+    }
+
+    /// Like `volume_path`, but the wait for the volumes to arrive is aborted as soon as
+    /// the given token is cancelled, returning ErrorOperationAborted.
+    /// The volume arrival notification is unregistered before returning.
+    pub fn volume_path_cancellable(&self, cancellation: &CancellationToken) -> WinResult<String> {
+        self.volume_path_until_cancelled(Some(cancellation))
+    }
+
+    fn volume_path_until_cancelled(
+        &self,
+        cancellation: Option<&CancellationToken>,
+    ) -> WinResult<String> {
         use winapi::um::{cfgmgr32, winioctl};
 
         let mut filter = unsafe { std::mem::zeroed::<cfgmgr32::CM_NOTIFY_FILTER>() };
@@ -188,26 +709,22 @@ impl Disk {
             filter.u.DeviceInterface_mut().ClassGuid = winioctl::GUID_DEVINTERFACE_VOLUME;
         }
 
-        let mut event = WinEvent::create(false, false, None, None).unwrap();
-        let mut path_result = Ok(String::new());
+        // Declared before the notification so that it outlives the registration,
+        // the callback only borrows the state.
+        let state = std::sync::Arc::new(VolumeArrivalState {
+            disk_handle: self.handle.get(),
+            event: WinEvent::create(false, false, None, None)?,
+            arrived: std::sync::atomic::AtomicBool::new(false),
+            path_result: std::sync::Mutex::new(Ok(String::new())),
+        });
 
-        let mut context = VolumeArrivalCallbackContext {
-            event: &mut event,
-            path_result: &mut path_result,
-            disk_handle: self.handle,
-        };
-
-        let cm_notification = CmNotification::register(
+        let _cm_notification = CmNotification::register(
             &mut filter,
-            &mut context as *mut _ as PVoid,
+            std::sync::Arc::as_ptr(&state) as PVoid,
             Some(volume_arrival_callback),
-        );
-
-        if let Err(error) = cm_notification {
-            return Err(error);
-        }
+        )?;
 
-        let mut volume_path = try_get_disk_volume_path(self.handle)?;
+        let mut volume_path = try_get_disk_volume_path(self.handle.get())?;
 
         if volume_path.is_empty() {
             pub const VOLUME_ARRIVAL_DEFAULT_FORCE_ONLINE_INTERVAL_MS: DWord = 10000; // 10 seconds
@@ -216,6 +733,12 @@ impl Disk {
             let volume_arrival_timeout = VOLUME_ARRIVAL_DEFAULT_TIMEOUT_MS;
             let mut time_waited: DWord = 0;
 
+            let mut wait_events: Vec<&WinEvent> = vec![&state.event];
+
+            if let Some(cancellation) = cancellation {
+                wait_events.push(cancellation.event());
+            }
+
             //
             // wait for a volume to arrive
             //
@@ -238,13 +761,16 @@ impl Disk {
             // 4. Keep doing this until the volume comes online, or until we reach the timeout.
             //
             loop {
-                force_online_disk(self.handle)?;
+                self.force_online()?;
 
-                if context.event.wait(force_online_interval) == WinEventResult::WaitObject0 {
-                    volume_path = match *context.path_result {
-                        Ok(ref path) => String::from(path.as_str()),
-                        Err(error) => return Err(error),
-                    };
+                let signaled = wait_multiple(&wait_events, false, force_online_interval)?;
+
+                if signaled == Some(1) {
+                    return Err(WinResultCode::ErrorOperationAborted);
+                }
+
+                if signaled == Some(0) || state.arrived.load(std::sync::atomic::Ordering::Acquire) {
+                    volume_path = state.path_result()?;
 
                     if volume_path.is_empty() {
                         return Ok(String::new());
@@ -253,7 +779,7 @@ impl Disk {
                     break;
                 }
 
-                time_waited += volume_arrival_timeout;
+                time_waited += force_online_interval;
 
                 if time_waited >= volume_arrival_timeout {
                     break;
@@ -269,16 +795,66 @@ impl Disk {
         Ok(volume_path)
     }
 
+    /// Retrieves the paths of all the volumes currently surfaced on the disk,
+    /// without waiting for any volume to arrive.
+    pub fn volume_paths(&self) -> WinResult<Vec<String>> {
+        let mut volume_paths: Vec<String> = Vec::new();
+
+        for_each_disk_volume(self.handle.get(), |volume_name, _| {
+            volume_paths.push(volume_name);
+            false
+        })?;
+
+        Ok(volume_paths)
+    }
+
     /// Initializes, partitions, and formats the given disk into a single volume.
+    /// The volume is formatted through FormatEx2, or FormatEx where FormatEx2 isn't available.
     pub fn format(&self, file_system: &str) -> WinResult<PartitionInfo> {
+        self.format_until(file_system, 0, None)
+    }
+
+    /// Initializes, partitions, and formats the given disk into a single volume whose partition
+    /// starts at a multiple of the given alignment in bytes, zero meaning the default of 1 MB.
+    pub fn format_aligned(
+        &self,
+        file_system: &str,
+        alignment_in_bytes: u64,
+    ) -> WinResult<PartitionInfo> {
+        self.format_until(file_system, alignment_in_bytes, None)
+    }
+
+    /// Initializes, partitions, and formats the given disk into a single volume,
+    /// failing with ERROR_TIMEOUT if the format doesn't complete within the given timeout.
+    /// FormatEx can't be interrupted, so on timeout the format keeps running in the background
+    /// and the volume may still end up formatted. Until it completes, later formats of the
+    /// process wait for it: `format_volume` and the other calls without a timeout block,
+    /// and calls with a timeout fail with ERROR_TIMEOUT if it doesn't complete in time.
+    pub fn format_with_timeout(
+        &self,
+        file_system: &str,
+        timeout: std::time::Duration,
+    ) -> WinResult<PartitionInfo> {
+        self.format_until(file_system, 0, Some(std::time::Instant::now() + timeout))
+    }
+
+    fn format_until(
+        &self,
+        file_system: &str,
+        alignment_in_bytes: u64,
+        deadline: Option<std::time::Instant>,
+    ) -> WinResult<PartitionInfo> {
         use winapi::um::{ioapiset, winioctl};
 
-        let format_module = WinLibrary::load(
-            "fmifs.dll",
-            winapi::um::libloaderapi::LOAD_LIBRARY_SEARCH_SYSTEM32,
-        )?;
-        let format_ex2_farproc = format_module.proc_address("FormatEx2")?;
-        let format_ex2: FormatEx2Routine = unsafe { std::mem::transmute(format_ex2_farproc) };
+        const DEFAULT_ALIGNMENT: i64 = 1024 * 1024;
+
+        let alignment = match alignment_in_bytes {
+            0 => DEFAULT_ALIGNMENT,
+            alignment_in_bytes => alignment_in_bytes as i64,
+        };
+
+        // Fail before partitioning the disk if the volume can't be formatted.
+        format_routines()?;
 
         // Partition the disk
         unsafe {
@@ -287,7 +863,7 @@ impl Disk {
             let mut bytes: DWord = 0;
 
             if ioapiset::DeviceIoControl(
-                self.handle,
+                self.handle.get(),
                 winioctl::IOCTL_DISK_CREATE_DISK,
                 &mut create_disk as *mut _ as PVoid,
                 std::mem::size_of::<winioctl::CREATE_DISK>() as DWord,
@@ -314,7 +890,7 @@ impl Disk {
             let layout: &mut Layout = std::mem::transmute(layout_buffer.as_mut_ptr());
 
             if ioapiset::DeviceIoControl(
-                self.handle,
+                self.handle.get(),
                 winioctl::IOCTL_DISK_GET_DRIVE_LAYOUT_EX,
                 std::ptr::null_mut(),
                 0,
@@ -335,7 +911,7 @@ impl Disk {
             let mut partition_info = PartitionInfo {
                 volume_path: String::new(),
                 disk_id: layout_mut_ref.info.u.Gpt().DiskId,
-                partition_id: create_guid()?,
+                partition_id: new_guid()?,
             };
 
             layout_mut_ref.info.PartitionCount = 2;
@@ -348,8 +924,9 @@ impl Disk {
                 partition_1.PartitionNumber = 0;
                 partition_1.RewritePartition = 1;
                 partition_1.u.Gpt_mut().PartitionType = PARTITION_MSFT_RESERVED_GUID;
-                let start: i64 =
+                let msr_end: i64 =
                     partition_1.StartingOffset.QuadPart() + partition_1.PartitionLength.QuadPart();
+                let start = (msr_end + alignment - 1) / alignment * alignment;
 
                 let mut partition_2 = std::mem::zeroed::<winioctl::PARTITION_INFORMATION_EX>();
                 partition_2.PartitionStyle = winioctl::PARTITION_STYLE_GPT;
@@ -374,7 +951,7 @@ impl Disk {
             *part_info = partition_entries.1;
 
             if ioapiset::DeviceIoControl(
-                self.handle,
+                self.handle.get(),
                 winioctl::IOCTL_DISK_SET_DRIVE_LAYOUT_EX,
                 layout_buffer.as_mut_ptr() as *mut _ as PVoid,
                 LAYOUT_BUFFER_SIZE as u32,
@@ -389,116 +966,104 @@ impl Disk {
                 ));
             }
 
+            self.refresh()?;
+
             // Get the mounted volume path
-            partition_info.volume_path = volume_path_disk(self.handle)?;
+            partition_info.volume_path = self.volume_path()?;
+            format_volume_until(&partition_info.volume_path, file_system, "", deadline)?;
+            Ok(partition_info)
+        }
+    }
 
-            // Store a string that lives longer than the loop below.
-            let label_string = widestring::WideCString::from_str("").unwrap();
-            let label_string_ptr = label_string.into_raw();
+    /// Expands the last basic partition and its file system to occupy any available space left on disk.
+    /// Returns true if the file system was expanded, false if there is no more space left for further expansion.
+    pub fn expand_volume(&self) -> WinResult<bool> {
+        self.expand_partition(None)
+    }
 
-            // This uses a static initialized context since FormatEx2 does not provide a context
-            // pointer in its callback routine.
-            let _lock = FORMAT_CONTEXT_LOCK
-                .get_or_insert(std::sync::Mutex::new(0))
-                .lock()
-                .unwrap();
-
-            FORMAT_CONTEXT = Some(FormatContext {
-                event: WinEvent::create(true, false, None, None).unwrap(),
-                result: WinResultCode::ErrorSuccess,
-            });
-
-            // Unfortunately, FormatEx2 can fail if another thread is accessing the volume, perhaps
-            // because it is responding to the arrival notification. We will retry the format
-            // three times before finally giving up.
-            for _retry in 0..3 {
-                // Format the volume without TxF or short name support.
-                let mut format_param = std::mem::zeroed::<FmIfsFormatEx2Param>();
-                format_param.major = 2;
-                format_param.label_string = label_string_ptr;
-                format_param.flags = FMIFS_FORMAT_QUICK
-                    | FMIFS_FORMAT_TXF_DISABLE
-                    | FMIFS_FORMAT_SHORT_NAMES_DISABLE
-                    | FMIFS_FORMAT_FORCE;
-
-                let mut volume_path_wstr =
-                    widestring::WideString::from_str(&partition_info.volume_path).into_vec();
-                volume_path_wstr.push(0);
-                let mut file_system_wstr = widestring::WideString::from_str(file_system).into_vec();
-                file_system_wstr.push(0);
-
-                format_ex2(
-                    volume_path_wstr.as_mut_ptr(),
-                    FmIfsMediaType::FmMediaFixed,
-                    file_system_wstr.as_mut_ptr(),
-                    &mut format_param,
-                    format_ex2_callback,
-                );
+    /// Expands a basic partition and its file system to occupy the free space that follows it,
+    /// up to the next partition or the end of the usable space on disk.
+    /// If no partition number is supplied, the last basic partition of the disk is expanded.
+    /// Returns true if the file system was expanded, false if there is no more space left for further expansion.
+    pub fn expand_partition(&self, partition_number: Option<DWord>) -> WinResult<bool> {
+        use winapi::um::{ioapiset, winioctl};
 
-                if let Some(ref context) = FORMAT_CONTEXT {
-                    context.event.wait(winapi::um::winbase::INFINITE);
-                    match context.result {
-                        WinResultCode::ErrorSuccess => {
-                            return Ok(partition_info);
-                        }
-                        _ => {
-                            std::thread::sleep(std::time::Duration::from_millis(1000));
-                        }
-                    };
-                }
-            }
+        // Make sure the usable space of the layout reflects the current disk size
+        self.refresh()?;
+
+        // Query the current partition layout
+        let layout = DriveLayout::query(self.handle.get())?;
 
-            Err(WinResultCode::ErrorGenFailure)
+        if layout.info().PartitionStyle != winioctl::PARTITION_STYLE_GPT {
+            return Err(WinResultCode::ErrorInvalidArgument);
         }
-    }
 
-    /// Expands the last basic partition and its file system to occupy any available space left on disk.
-    /// Returns true if the file system was expanded, false if there is no more space left for further expansion.
-    pub fn expand_volume(&self) -> WinResult<bool> {
-        #[allow(unused_assignments, dead_code)]
-        unsafe {
-            use winapi::um::{errhandlingapi, ioapiset, winioctl};
-            let mut result: bool = false;
+        let partition_index = match partition_number {
+            Some(partition_number) => layout.partition_entries().iter().position(|entry| {
+                entry.PartitionNumber == partition_number
+                    && unsafe {
+                        guid_are_equal(&entry.u.Gpt().PartitionType, &PARTITION_BASIC_DATA_GUID)
+                    }
+            }),
+            None => layout.last_partition_of_type(&PARTITION_BASIC_DATA_GUID),
+        };
 
-            // Query the current partition layout
-            let mut bytes_returned: DWord = 0;
-            let mut drive_layout: winioctl::PDRIVE_LAYOUT_INFORMATION_EX = std::ptr::null_mut();
-            let mut buffer: Vec<Byte> = Vec::new();
+        let partition_info = match partition_index {
+            Some(index) => &layout.partition_entries()[index],
+            None => return Err(WinResultCode::ErrorInvalidArgument),
+        };
 
-            #[repr(C)]
-            struct ExpectedLayout {
-                info: winioctl::DRIVE_LAYOUT_INFORMATION_EX,
-                partitions: [winioctl::PARTITION_INFORMATION_EX; 1],
-            }
+        // Determine the new partition end: the start of the next partition on disk,
+        // or the end of the usable space if this is the last one.
+        let (partition_start, current_partition_end, new_partition_end) = unsafe {
+            let partition_start = *partition_info.StartingOffset.QuadPart();
+            let current_partition_end =
+                partition_start + *partition_info.PartitionLength.QuadPart();
+            let usable_end = layout.info().u.Gpt().StartingUsableOffset.QuadPart()
+                + layout.info().u.Gpt().UsableLength.QuadPart();
+
+            let new_partition_end = layout
+                .partition_entries()
+                .iter()
+                .filter(|entry| *entry.PartitionLength.QuadPart() != 0)
+                .map(|entry| *entry.StartingOffset.QuadPart())
+                .filter(|start| *start >= current_partition_end)
+                .fold(usable_end, std::cmp::min);
+
+            (partition_start, current_partition_end, new_partition_end)
+        };
 
-            let mut expected_layout = std::mem::zeroed::<ExpectedLayout>();
+        if current_partition_end > new_partition_end {
+            return Err(WinResultCode::ErrorInvalidArgument);
+        }
 
-            if ioapiset::DeviceIoControl(
-                self.handle,
-                winioctl::IOCTL_DISK_GET_DRIVE_LAYOUT_EX,
-                std::ptr::null_mut(),
-                0,
-                &mut expected_layout as *mut _ as PVoid,
-                std::mem::size_of::<ExpectedLayout>() as DWord,
-                &mut bytes_returned,
-                std::ptr::null_mut(),
-            ) == 0
-            {
-                let error = errhandlingapi::GetLastError();
+        let mut new_partition_size: LongLong = current_partition_end - partition_start;
+        let mut bytes_returned: DWord = 0;
 
-                if winapi::shared::winerror::ERROR_INSUFFICIENT_BUFFER != error {
-                    return Err(error_code_to_winresult_code(error));
-                }
+        if current_partition_end < new_partition_end {
+            #[repr(C)]
+            struct DiskGrowPartition {
+                partition_number: DWord,
+                bytes_to_grow: winapi::shared::ntdef::LARGE_INTEGER,
+            }
 
-                buffer.reserve(4096);
+            let mut grow_partition = unsafe { std::mem::zeroed::<DiskGrowPartition>() };
+            grow_partition.partition_number = partition_info.PartitionNumber;
+            unsafe {
+                *grow_partition.bytes_to_grow.QuadPart_mut() =
+                    new_partition_end - current_partition_end;
+            }
 
+            new_partition_size = new_partition_end - partition_start;
+
+            unsafe {
                 if ioapiset::DeviceIoControl(
-                    self.handle,
-                    winioctl::IOCTL_DISK_GET_DRIVE_LAYOUT_EX,
+                    self.handle.get(),
+                    winioctl::IOCTL_DISK_GROW_PARTITION,
+                    &mut grow_partition as *mut _ as PVoid,
+                    std::mem::size_of::<DiskGrowPartition>() as DWord,
                     std::ptr::null_mut(),
                     0,
-                    buffer.as_mut_ptr() as PVoid,
-                    buffer.len() as DWord,
                     &mut bytes_returned,
                     std::ptr::null_mut(),
                 ) == 0
@@ -507,219 +1072,1582 @@ impl Disk {
                         winapi::um::errhandlingapi::GetLastError(),
                     ));
                 }
-
-                drive_layout = std::mem::transmute(buffer.as_ptr());
-            } else {
-                drive_layout = &mut expected_layout.info;
             }
+        }
 
-            // Find the last basic partition
-            if (*drive_layout).PartitionStyle != winioctl::PARTITION_STYLE_GPT {
-                return Err(WinResultCode::ErrorInvalidArgument);
-            }
+        let volume_path = self.volume_path_at(partition_start)?;
 
-            let mut partition_info: winioctl::PPARTITION_INFORMATION_EX = std::ptr::null_mut();
-            let mut drive_layout_ptr =
-                &mut (*drive_layout).PartitionEntry[0] as winioctl::PPARTITION_INFORMATION_EX;
+        // Query the current file system size.
+        let ntfsinfo = get_ntfsinfo(&volume_path)?;
 
-            for _i in 0..(*drive_layout).PartitionCount {
-                if guid_are_equal(
-                    &(*drive_layout_ptr).u.Gpt().PartitionType,
-                    &PARTITION_BASIC_DATA_GUID,
-                ) {
-                    partition_info = drive_layout_ptr;
-                    break;
-                }
-                drive_layout_ptr = drive_layout_ptr.offset(1);
-            }
+        // Compute the new number of clusters (rounding down) and extend the file system.
+        let new_number_of_clusters = new_partition_size / ntfsinfo.bytes_per_cluster as LongLong;
+        let old_number_of_clusters = ntfsinfo.total_clusters - ntfsinfo.free_clusters;
+
+        // NTFS may extend the volume by one sector less than requested (NtfsChangeVolumeSize),
+        // so increase the current size by one to check if there's any space left.
+        if old_number_of_clusters + 1 >= new_number_of_clusters as u64 {
+            return Ok(false);
+        }
 
-            if partition_info == std::ptr::null_mut() {
-                return Err(WinResultCode::ErrorInvalidArgument);
+        let sectors_in_cluster = ntfsinfo.bytes_per_cluster / ntfsinfo.bytes_per_sector;
+        let mut new_number_of_sectors = new_number_of_clusters * sectors_in_cluster as LongLong;
+        let volume = Volume::open(&volume_path, None)?;
+
+        unsafe {
+            if ioapiset::DeviceIoControl(
+                volume.handle.get(),
+                winioctl::FSCTL_EXTEND_VOLUME,
+                &mut new_number_of_sectors as *mut _ as PVoid,
+                std::mem::size_of::<LongLong>() as DWord,
+                std::ptr::null_mut(),
+                0,
+                &mut bytes_returned,
+                std::ptr::null_mut(),
+            ) == 0
+            {
+                return Err(error_code_to_winresult_code(
+                    winapi::um::errhandlingapi::GetLastError(),
+                ));
             }
+        }
 
-            // Determine the new partition size and extend the partition
-            let current_partition_end: LongLong = (*partition_info).StartingOffset.QuadPart()
-                + (*partition_info).PartitionLength.QuadPart();
-            let new_partition_end: LongLong =
-                (*drive_layout).u.Gpt().StartingUsableOffset.QuadPart()
-                    + (*drive_layout).u.Gpt().UsableLength.QuadPart();
-
-            assert!(current_partition_end <= new_partition_end);
-            let mut new_partition_size: LongLong = *(*partition_info).PartitionLength.QuadPart();
-
-            if current_partition_end < new_partition_end {
-                #[repr(C)]
-                struct DiskGrowPartition {
-                    partition_number: DWord,
-                    bytes_to_grow: winapi::shared::ntdef::LARGE_INTEGER,
-                }
+        self.refresh()?;
+        Ok(true)
+    }
 
-                let mut grow_partition = std::mem::zeroed::<DiskGrowPartition>();
-                grow_partition.partition_number = (*partition_info).PartitionNumber;
-                *grow_partition.bytes_to_grow.QuadPart_mut() =
-                    new_partition_end - current_partition_end;
+    /// Returns the partitions of the disk, ordered as in its partition table.
+    /// Unused MBR slots are skipped.
+    pub fn partitions(&self) -> WinResult<Vec<Partition>> {
+        let layout = DriveLayout::query(self.handle.get())?;
+        Ok(layout
+            .partition_entries()
+            .iter()
+            .filter_map(Partition::from_entry)
+            .collect())
+    }
 
-                new_partition_size += *grow_partition.bytes_to_grow.QuadPart();
+    /// Returns the partitioning scheme of the disk.
+    pub fn partition_style(&self) -> WinResult<PartitionStyle> {
+        use winapi::um::winioctl;
 
-                if ioapiset::DeviceIoControl(
-                    self.handle,
-                    winioctl::IOCTL_DISK_GROW_PARTITION,
-                    &mut grow_partition as *mut _ as PVoid,
-                    std::mem::size_of::<DiskGrowPartition>() as DWord,
-                    std::ptr::null_mut(),
-                    0,
-                    &mut bytes_returned,
-                    std::ptr::null_mut(),
-                ) == 0
-                {
-                    return Err(error_code_to_winresult_code(
-                        winapi::um::errhandlingapi::GetLastError(),
-                    ));
+        match DriveLayout::query(self.handle.get())?.info().PartitionStyle {
+            winioctl::PARTITION_STYLE_GPT => Ok(PartitionStyle::Gpt),
+            winioctl::PARTITION_STYLE_MBR => Ok(PartitionStyle::Mbr),
+            _ => Ok(PartitionStyle::Raw),
+        }
+    }
+
+    /// Returns the identifier written to the partition table of the disk.
+    pub fn identity(&self) -> WinResult<DiskIdentity> {
+        use winapi::um::winioctl;
+
+        let layout = DriveLayout::query(self.handle.get())?;
+        let info = layout.info();
+
+        unsafe {
+            match info.PartitionStyle {
+                winioctl::PARTITION_STYLE_GPT => {
+                    Ok(DiskIdentity::Gpt(WinGuid::from(info.u.Gpt().DiskId)))
                 }
+                winioctl::PARTITION_STYLE_MBR => Ok(DiskIdentity::Mbr(info.u.Mbr().Signature)),
+                _ => Ok(DiskIdentity::Raw),
             }
+        }
+    }
 
-            // Query the current file system size.
-            let volume_path = volume_path_disk(self.handle)?;
-            let ntfsinfo = get_ntfsinfo(&volume_path).unwrap();
+    /// Writes a new disk GUID to the partition table of a GPT disk, keeping its partitions,
+    /// e.g. to re-randomize the identity of a disk cloned from an image.
+    /// Partition IDs are left as-is.
+    /// Returns ErrorInvalidArgument if the disk isn't a GPT disk or the GUID is null.
+    pub fn set_gpt_disk_id(&self, disk_id: Guid) -> WinResult<()> {
+        use winapi::um::winioctl;
 
-            // Compute the new number of clusters (rounding down) and extend the file system.
-            let new_number_of_clusters =
-                new_partition_size / ntfsinfo.bytes_per_cluster as LongLong;
-            let old_number_of_clusters = ntfsinfo.total_clusters - ntfsinfo.free_clusters;
+        if WinGuid::from(disk_id).is_null() {
+            return Err(WinResultCode::ErrorInvalidArgument);
+        }
 
-            // NTFS may extend the volume by one sector less than requested (NtfsChangeVolumeSize),
-            // so increase the current size by one to check if there's any space left.
-            if old_number_of_clusters + 1 < new_number_of_clusters as u64 {
-                let sectors_in_cluster = ntfsinfo.bytes_per_cluster / ntfsinfo.bytes_per_sector;
-                let mut new_number_of_sectors =
-                    new_number_of_clusters * sectors_in_cluster as LongLong;
-                let volume = Volume::open(&volume_path, None)?;
+        let mut layout = DriveLayout::query(self.handle.get())?;
 
-                if ioapiset::DeviceIoControl(
-                    volume.handle,
-                    winioctl::FSCTL_EXTEND_VOLUME,
-                    &mut new_number_of_sectors as *mut _ as PVoid,
-                    std::mem::size_of::<LongLong>() as DWord,
-                    std::ptr::null_mut(),
-                    0,
-                    &mut bytes_returned,
-                    std::ptr::null_mut(),
-                ) == 0
-                {
-                    return Err(error_code_to_winresult_code(
-                        winapi::um::errhandlingapi::GetLastError(),
-                    ));
-                }
+        if layout.info().PartitionStyle != winioctl::PARTITION_STYLE_GPT {
+            return Err(WinResultCode::ErrorInvalidArgument);
+        }
 
-                result = true;
-            }
+        unsafe {
+            layout.info_mut().u.Gpt_mut().DiskId = disk_id;
+        }
 
-            Ok(result)
+        for partition in layout.partition_entries_mut() {
+            partition.RewritePartition = 1;
         }
+
+        layout.apply(self.handle.get())?;
+        self.refresh()
     }
-}
 
-/// Forces the disk to be brought online and surface its volumes.
-pub fn force_online_disk(handle: Handle) -> WinResult<()> {
-    let mut disk = Disk { handle };
-    let result = disk.force_online();
-    unsafe {
-        disk.release_handle();
+    /// Returns the size of the disk in bytes.
+    pub fn size_in_bytes(&self) -> WinResult<u64> {
+        let mut length_info =
+            unsafe { std::mem::zeroed::<winapi::um::winioctl::GET_LENGTH_INFORMATION>() };
+        let mut bytes: DWord = 0;
+
+        unsafe {
+            match winapi::um::ioapiset::DeviceIoControl(
+                self.handle.get(),
+                winapi::um::winioctl::IOCTL_DISK_GET_LENGTH_INFO,
+                std::ptr::null_mut(),
+                0,
+                &mut length_info as *mut _ as PVoid,
+                std::mem::size_of::<winapi::um::winioctl::GET_LENGTH_INFORMATION>() as DWord,
+                &mut bytes,
+                std::ptr::null_mut(),
+            ) {
+                0 => Err(error_code_to_winresult_code(
+                    winapi::um::errhandlingapi::GetLastError(),
+                )),
+                _ => Ok(*length_info.Length.QuadPart() as u64),
+            }
+        }
     }
-    result
-}
 
-/// Retrieves the volume disk path.
-pub fn volume_path_disk(handle: Handle) -> WinResult<String> {
-    let mut disk = Disk { handle };
-    let result = disk.volume_path();
-    unsafe {
-        disk.release_handle();
+    /// Returns the usable byte range a GPT partition table would leave on this disk,
+    /// whatever its current partition style. The table takes two sectors plus 16 KiB of
+    /// partition entries at the start of the disk, and one sector plus the entries backup
+    /// at the end.
+    fn gpt_usable_range(&self) -> WinResult<(i64, i64)> {
+        use winapi::um::winioctl;
+
+        const PARTITION_ENTRIES_SIZE: i64 = 128 * 128;
+
+        let mut geometry = unsafe { std::mem::zeroed::<winioctl::DISK_GEOMETRY_EX>() };
+        let mut bytes: DWord = 0;
+
+        unsafe {
+            if winapi::um::ioapiset::DeviceIoControl(
+                self.handle.get(),
+                winioctl::IOCTL_DISK_GET_DRIVE_GEOMETRY_EX,
+                std::ptr::null_mut(),
+                0,
+                &mut geometry as *mut _ as PVoid,
+                std::mem::size_of::<winioctl::DISK_GEOMETRY_EX>() as DWord,
+                &mut bytes,
+                std::ptr::null_mut(),
+            ) == 0
+            {
+                return Err(error_code_to_winresult_code(
+                    winapi::um::errhandlingapi::GetLastError(),
+                ));
+            }
+        }
+
+        let sector_size = geometry.Geometry.BytesPerSector as i64;
+        let disk_size = unsafe { *geometry.DiskSize.QuadPart() };
+
+        Ok((
+            2 * sector_size + PARTITION_ENTRIES_SIZE,
+            disk_size - sector_size - PARTITION_ENTRIES_SIZE,
+        ))
     }
-    result
-}
 
-struct Volume {
-    handle: Handle,
-}
+    /// Deletes the partition with the given number from the partition table of the disk.
+    /// The contents of the partition are left in place.
+    /// Returns ERROR_NOT_FOUND if the disk has no partition with that number.
+    pub fn delete_partition(&self, partition_number: DWord) -> WinResult<()> {
+        use winapi::um::winioctl;
+
+        let mut layout = DriveLayout::query(self.handle.get())?;
+        let index = match layout
+            .partition_entries()
+            .iter()
+            .position(|entry| entry.PartitionNumber == partition_number)
+        {
+            Some(index) => index,
+            None => {
+                return Err(error_code_to_winresult_code(
+                    winapi::shared::winerror::ERROR_NOT_FOUND,
+                ))
+            }
+        };
 
-impl std::ops::Drop for Volume {
-    fn drop(&mut self) {
-        close_handle(&mut self.handle);
+        if layout.info().PartitionStyle == winioctl::PARTITION_STYLE_GPT {
+            // GPT layouts only hold the partitions in use, so the entry is removed.
+            let entries = layout.partition_entries_mut();
+            entries[index..].rotate_left(1);
+            layout.info_mut().PartitionCount -= 1;
+        } else {
+            // MBR layouts keep their slots, which are marked as unused instead.
+            unsafe {
+                layout.partition_entries_mut()[index]
+                    .u
+                    .Mbr_mut()
+                    .PartitionType = 0;
+            }
+        }
+
+        for entry in layout.partition_entries_mut() {
+            entry.RewritePartition = 1;
+        }
+
+        layout.apply(self.handle.get())?;
+        self.refresh()
     }
-}
 
-impl Volume {
-    pub fn open(path: &str, access_mask: Option<DWord>) -> WinResult<Volume> {
-        use winapi::um::{fileapi, winnt};
+    /// Resets the disk to an empty partition table, so that a previously used disk can be reused
+    /// without going through `format`. The disk gets new disk IDs (GPT) or signatures (MBR).
+    /// The disk must not have volumes in use, take them offline or dismount them first.
+    pub fn clean(&self, mode: WipeMode) -> WinResult<()> {
+        use winapi::um::{ioapiset, winioctl};
 
-        let access_mask_flags = match access_mask {
-            Some(flags) => flags,
-            None => winnt::GENERIC_READ | winnt::GENERIC_WRITE,
+        const EDGE_SIZE: u64 = 1024 * 1024;
+
+        let mut bytes: DWord = 0;
+
+        unsafe {
+            if ioapiset::DeviceIoControl(
+                self.handle.get(),
+                winioctl::IOCTL_DISK_DELETE_DRIVE_LAYOUT,
+                std::ptr::null_mut(),
+                0,
+                std::ptr::null_mut(),
+                0,
+                &mut bytes,
+                std::ptr::null_mut(),
+            ) == 0
+            {
+                return Err(error_code_to_winresult_code(
+                    winapi::um::errhandlingapi::GetLastError(),
+                ));
+            }
+        }
+
+        let style = match mode {
+            WipeMode::Layout(style) => style,
+            WipeMode::ZeroEdges(style) => {
+                let size = self.size_in_bytes()?;
+                let edge_size = std::cmp::min(EDGE_SIZE, size);
+                let zeros: Vec<Byte> = vec![0; edge_size as usize];
+
+                write_handle_at(self.handle.get(), 0, &zeros)?;
+                write_handle_at(self.handle.get(), size - edge_size, &zeros)?;
+                style
+            }
         };
 
-        match create_file(
-            path,
-            access_mask_flags,
-            winnt::FILE_SHARE_READ | winnt::FILE_SHARE_WRITE,
-            None,
-            fileapi::OPEN_EXISTING,
-            winnt::FILE_ATTRIBUTE_NORMAL,
-            None,
-        ) {
-            Ok(handle) => Ok(Volume { handle }),
-            Err(error) => Err(error),
+        self.initialize(style)
+    }
+
+    /// Writes an empty partition table of the given style, with a new disk ID (GPT)
+    /// or signature (MBR).
+    fn initialize(&self, style: PartitionStyle) -> WinResult<()> {
+        use winapi::um::{ioapiset, winioctl};
+
+        let mut bytes: DWord = 0;
+        let mut create_disk = unsafe { std::mem::zeroed::<winioctl::CREATE_DISK>() };
+
+        match style {
+            PartitionStyle::Raw => return self.refresh(),
+            PartitionStyle::Gpt => unsafe {
+                create_disk.PartitionStyle = winioctl::PARTITION_STYLE_GPT;
+                create_disk.u.Gpt_mut().DiskId = new_guid()?;
+            },
+            PartitionStyle::Mbr => unsafe {
+                create_disk.PartitionStyle = winioctl::PARTITION_STYLE_MBR;
+                create_disk.u.Mbr_mut().Signature = new_guid()?.Data1;
+            },
+        }
+
+        unsafe {
+            if ioapiset::DeviceIoControl(
+                self.handle.get(),
+                winioctl::IOCTL_DISK_CREATE_DISK,
+                &mut create_disk as *mut _ as PVoid,
+                std::mem::size_of::<winioctl::CREATE_DISK>() as DWord,
+                std::ptr::null_mut(),
+                0,
+                &mut bytes,
+                std::ptr::null_mut(),
+            ) == 0
+            {
+                return Err(error_code_to_winresult_code(
+                    winapi::um::errhandlingapi::GetLastError(),
+                ));
+            }
         }
+
+        self.refresh()
     }
-}
 
-/// Force a volume to be brought online (ie: mounted by a filesystem).
-/// This is needed when automount has been disabled (mountvol /N).
-pub fn force_online_volume(volume_name: &str) -> WinResult<()> {
-    use winapi::um::{ioapiset, winioctl};
+    /// Replaces the partition table of the disk with a new GPT one holding the given partitions,
+    /// in order, each aligned to a MiB. Returns the new partitions.
+    /// Partitions are left unformatted, see `Disk::partition_volume_path` and `format_volume`.
+    /// Returns ErrorInvalidArgument if a partition other than the last one has no length,
+    /// a name is longer than 36 UTF-16 characters or there are more than 128 partitions,
+    /// and ERROR_DISK_FULL if the partitions don't fit in the disk. Both are checked before
+    /// the current partition table is touched.
+    pub fn create_gpt_partitions(&self, specs: &[GptPartitionSpec]) -> WinResult<Vec<Partition>> {
+        use winapi::um::winioctl;
+
+        // Reject layouts that can't fit before initialize wipes the current partition table.
+        let (usable_start, usable_end) = self.gpt_usable_range()?;
+        plan_gpt_partitions(specs, usable_start, usable_end)?;
+
+        self.initialize(PartitionStyle::Gpt)?;
+
+        let current = DriveLayout::query(self.handle.get())?;
+        let header_size = std::mem::size_of::<winioctl::DRIVE_LAYOUT_INFORMATION_EX>();
+        let size =
+            header_size + specs.len() * std::mem::size_of::<winioctl::PARTITION_INFORMATION_EX>();
+        let mut layout = DriveLayout {
+            buffer: vec![0; (size + 7) / 8],
+        };
 
-    match Volume::open(volume_name, None) {
-        Ok(volume) => {
-            let mut bytes: DWord = 0;
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                current.buffer.as_ptr() as *const u8,
+                layout.buffer.as_mut_ptr() as *mut u8,
+                header_size,
+            );
+        }
+
+        let (usable_start, usable_end) = unsafe {
+            let gpt = current.info().u.Gpt();
+            (
+                *gpt.StartingUsableOffset.QuadPart(),
+                *gpt.StartingUsableOffset.QuadPart() + *gpt.UsableLength.QuadPart(),
+            )
+        };
+        let extents = plan_gpt_partitions(specs, usable_start, usable_end)?;
+
+        layout.info_mut().PartitionCount = specs.len() as DWord;
 
+        for ((entry, spec), (offset, length)) in layout
+            .partition_entries_mut()
+            .iter_mut()
+            .zip(specs)
+            .zip(extents)
+        {
             unsafe {
-                if ioapiset::DeviceIoControl(
-                    volume.handle,
-                    winioctl::IOCTL_VOLUME_OFFLINE,
-                    std::ptr::null_mut(),
-                    0,
-                    std::ptr::null_mut(),
-                    0,
-                    &mut bytes,
-                    std::ptr::null_mut(),
-                ) == 0
-                {
-                    return Err(error_code_to_winresult_code(
-                        winapi::um::errhandlingapi::GetLastError(),
-                    ));
+                *entry = std::mem::zeroed::<winioctl::PARTITION_INFORMATION_EX>();
+                entry.PartitionStyle = winioctl::PARTITION_STYLE_GPT;
+                *entry.StartingOffset.QuadPart_mut() = offset;
+                *entry.PartitionLength.QuadPart_mut() = length;
+                entry.RewritePartition = 1;
+
+                let gpt = entry.u.Gpt_mut();
+                gpt.PartitionType = *spec.partition_type;
+                gpt.PartitionId = new_guid()?;
+                gpt.Attributes = spec.attributes;
+
+                for (name_char, spec_char) in gpt.Name.iter_mut().zip(spec.name.encode_utf16()) {
+                    *name_char = spec_char;
                 }
+            }
+        }
 
-                if ioapiset::DeviceIoControl(
-                    volume.handle,
-                    winioctl::IOCTL_VOLUME_ONLINE,
-                    std::ptr::null_mut(),
-                    0,
+        layout.apply(self.handle.get())?;
+        self.refresh()?;
+        self.partitions()
+    }
+
+    /// Waits for the volume living on the given partition of this disk to arrive,
+    /// and returns its path (e.g. \\?\Volume{GUID}).
+    /// Fails with ERROR_TIMEOUT if it doesn't arrive within `DISK_ARRIVAL_DEFAULT_TIMEOUT`,
+    /// which is always the case for partitions that never get a volume, like the MSR.
+    pub fn partition_volume_path(&self, partition: &Partition) -> WinResult<String> {
+        let deadline = std::time::Instant::now() + DISK_ARRIVAL_DEFAULT_TIMEOUT;
+
+        loop {
+            let volume_path = try_get_partition_volume_path(
+                self.handle.get(),
+                Some(partition.offset as LongLong),
+            )?;
+
+            if !volume_path.is_empty() {
+                return Ok(volume_path);
+            }
+
+            if std::time::Instant::now() >= deadline {
+                return Err(timeout_error());
+            }
+
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+    }
+
+    /// Returns the attributes of the GPT partition with the given partition ID.
+    pub fn partition_attributes(&self, partition_id: &Guid) -> WinResult<GptAttributes> {
+        let layout = DriveLayout::query(self.handle.get())?;
+        let index = layout.partition_index_by_id(partition_id)?;
+        let attributes = unsafe { layout.partition_entries()[index].u.Gpt().Attributes };
+        Ok(GptAttributes::from_bits(attributes))
+    }
+
+    /// Updates the attributes of the GPT partition with the given partition ID.
+    /// Attribute bits not represented by `GptAttributes` are preserved.
+    pub fn set_partition_attributes(
+        &self,
+        partition_id: &Guid,
+        attributes: GptAttributes,
+    ) -> WinResult<()> {
+        let mut layout = DriveLayout::query(self.handle.get())?;
+        let index = layout.partition_index_by_id(partition_id)?;
+
+        {
+            let partition = &mut layout.partition_entries_mut()[index];
+            unsafe {
+                let gpt = partition.u.Gpt_mut();
+                gpt.Attributes = (gpt.Attributes & !GptAttributes::MASK) | attributes.bits();
+            }
+            partition.RewritePartition = 1;
+        }
+
+        layout.apply(self.handle.get())
+    }
+
+    /// Waits for the volumes of the disk to arrive and returns the path of the one living
+    /// in the partition starting at the given offset, or ERROR_FILE_NOT_FOUND if there is none.
+    fn volume_path_at(&self, partition_start: LongLong) -> WinResult<String> {
+        self.volume_path()?;
+        let volume_path = try_get_partition_volume_path(self.handle.get(), Some(partition_start))?;
+
+        if volume_path.is_empty() {
+            return Err(error_code_to_winresult_code(
+                winapi::shared::winerror::ERROR_FILE_NOT_FOUND,
+            ));
+        }
+
+        Ok(volume_path)
+    }
+
+    /// Returns the path of the volume living in the last basic partition of the disk,
+    /// the one `expand_volume` and `shrink_volume` work on.
+    pub(crate) fn last_basic_volume_path(&self) -> WinResult<String> {
+        let layout = DriveLayout::query(self.handle.get())?;
+
+        let partition_start = match layout.last_partition_of_type(&PARTITION_BASIC_DATA_GUID) {
+            Some(index) => unsafe { *layout.partition_entries()[index].StartingOffset.QuadPart() },
+            None => return Err(WinResultCode::ErrorInvalidArgument),
+        };
+
+        self.volume_path_at(partition_start)
+    }
+
+    /// Shrinks the last basic partition and its NTFS file system to the requested size in bytes.
+    /// Returns true if the volume was shrunk, false if the partition is already smaller than
+    /// or equal to the requested size.
+    /// Returns ErrorInvalidArgument if the partition isn't formatted with NTFS.
+    /// The space freed at the end of the disk can then be reclaimed by resizing the virtual disk.
+    pub fn shrink_volume(&self, new_size_bytes: u64) -> WinResult<bool> {
+        use winapi::um::winioctl;
+
+        let mut layout = DriveLayout::query(self.handle.get())?;
+
+        if layout.info().PartitionStyle != winioctl::PARTITION_STYLE_GPT {
+            return Err(WinResultCode::ErrorInvalidArgument);
+        }
+
+        let partition_index = match layout.last_partition_of_type(&PARTITION_BASIC_DATA_GUID) {
+            Some(index) => index,
+            None => return Err(WinResultCode::ErrorInvalidArgument),
+        };
+
+        let (partition_start, current_size) = unsafe {
+            let partition = &layout.partition_entries()[partition_index];
+            (
+                *partition.StartingOffset.QuadPart(),
+                *partition.PartitionLength.QuadPart() as u64,
+            )
+        };
+
+        if new_size_bytes >= current_size {
+            return Ok(false);
+        }
+
+        // The volume of the partition isn't the first volume of the disk when there are several.
+        let volume_path = self.volume_path_at(partition_start)?;
+
+        // fsutil only reports the geometry of NTFS volumes.
+        if !volume_file_system(&volume_path)?.eq_ignore_ascii_case("NTFS") {
+            return Err(WinResultCode::ErrorInvalidArgument);
+        }
+
+        let ntfsinfo = get_ntfsinfo(&volume_path)?;
+
+        if ntfsinfo.bytes_per_cluster == 0 || ntfsinfo.bytes_per_sector == 0 {
+            return Err(WinResultCode::ErrorGenFailure);
+        }
+
+        // Round down to whole clusters so that file system and partition end up aligned.
+        let bytes_per_cluster = ntfsinfo.bytes_per_cluster as u64;
+        let new_size_bytes = new_size_bytes - (new_size_bytes % bytes_per_cluster);
+        let new_number_of_sectors = new_size_bytes / ntfsinfo.bytes_per_sector as u64;
+
+        let volume = Volume::open(&volume_path, None)?;
+        volume.shrink_file_system(new_number_of_sectors)?;
+
+        {
+            let partition = &mut layout.partition_entries_mut()[partition_index];
+            unsafe {
+                *partition.PartitionLength.QuadPart_mut() = new_size_bytes as LongLong;
+            }
+            partition.RewritePartition = 1;
+        }
+
+        layout.apply(self.handle.get())?;
+        Ok(true)
+    }
+}
+
+/// Returns the FormatEx2 routine, or the FormatEx routine where FormatEx2 isn't exported
+/// (e.g. older Server Core builds).
+fn format_routines() -> WinResult<(Option<FormatEx2Routine>, Option<FormatExRoutine>)> {
+    let fmifs = FmIfs::get()?;
+
+    match fmifs.format() {
+        Ok(format_ex2) => Ok((Some(format_ex2), None)),
+        Err(_) => Ok((None, Some(fmifs.format_ex()?))),
+    }
+}
+
+/// Formats the given volume (e.g. \\?\Volume{GUID}) with the given file system and label.
+/// NTFS volumes are formatted without TxF or short name support.
+/// The volume is formatted through FormatEx2, or FormatEx where FormatEx2 isn't available.
+pub fn format_volume(volume_path: &str, file_system: &str, label: &str) -> WinResult<()> {
+    format_volume_until(volume_path, file_system, label, None)
+}
+
+fn format_volume_until(
+    volume_path: &str,
+    file_system: &str,
+    label: &str,
+    deadline: Option<std::time::Instant>,
+) -> WinResult<()> {
+    match time_left(deadline)? {
+        // FormatEx only returns once the format is done, so it's waited on from a worker thread.
+        Some(timeout) => {
+            let volume_path = String::from(volume_path);
+            let file_system = String::from(file_system);
+            let label = String::from(label);
+
+            with_timeout(timeout, move || {
+                format_volume_blocking(&volume_path, &file_system, &label, deadline)
+            })
+        }
+        None => format_volume_blocking(volume_path, file_system, label, None),
+    }
+}
+
+/// Formats the volume, waiting for any other format of the process to complete first.
+/// A format abandoned on timeout keeps the format context until FormatEx returns,
+/// so with a deadline the wait for the context fails with ERROR_TIMEOUT once it passes
+/// instead of starting the format after the caller gave up on it.
+fn format_volume_blocking(
+    volume_path: &str,
+    file_system: &str,
+    label: &str,
+    deadline: Option<std::time::Instant>,
+) -> WinResult<()> {
+    let (format_ex2, format_ex) = format_routines()?;
+    let is_fat = file_system.to_ascii_uppercase().starts_with("FAT");
+
+    unsafe {
+        // Store a string that lives longer than the loop below.
+        let label_string = widestring::WideCString::from_str(label)
+            .map_err(|_| WinResultCode::ErrorInvalidArgument)?;
+        let label_string_ptr = label_string.as_ptr() as *mut WChar;
+
+        // This uses a static initialized context since FormatEx2 does not provide a context
+        // pointer in its callback routine.
+        let format_context_lock = FORMAT_CONTEXT_LOCK.get_or_insert(std::sync::Mutex::new(0));
+        let _lock = match deadline {
+            Some(deadline) => loop {
+                match format_context_lock.try_lock() {
+                    Ok(lock) => break lock,
+                    Err(std::sync::TryLockError::Poisoned(error)) => break error.into_inner(),
+                    Err(std::sync::TryLockError::WouldBlock) => {
+                        time_left(Some(deadline))?;
+                        std::thread::sleep(std::time::Duration::from_millis(50));
+                    }
+                }
+            },
+            None => format_context_lock
+                .lock()
+                .unwrap_or_else(|error| error.into_inner()),
+        };
+
+        time_left(deadline)?;
+
+        FORMAT_CONTEXT = Some(FormatContext {
+            event: WinEvent::create(true, false, None, None).unwrap(),
+            result: WinResultCode::ErrorSuccess,
+        });
+
+        // Unfortunately, FormatEx2 can fail if another thread is accessing the volume, perhaps
+        // because it is responding to the arrival notification. We will retry the format
+        // three times before finally giving up.
+        for _retry in 0..3 {
+            // The event is manual reset, a previous failed attempt left it signaled.
+            if let Some(ref mut context) = FORMAT_CONTEXT {
+                context.event.reset_event()?;
+                context.result = WinResultCode::ErrorSuccess;
+            }
+
+            // Format the volume without TxF or short name support.
+            // FAT volumes always carry short names and never support TxF.
+            let mut format_param = std::mem::zeroed::<FmIfsFormatEx2Param>();
+            format_param.major = 2;
+            format_param.label_string = label_string_ptr;
+            format_param.flags = match is_fat {
+                true => FMIFS_FORMAT_QUICK | FMIFS_FORMAT_FORCE,
+                false => {
+                    FMIFS_FORMAT_QUICK
+                        | FMIFS_FORMAT_TXF_DISABLE
+                        | FMIFS_FORMAT_SHORT_NAMES_DISABLE
+                        | FMIFS_FORMAT_FORCE
+                }
+            };
+
+            let mut volume_path_wstr = widestring::WideString::from_str(volume_path).into_vec();
+            volume_path_wstr.push(0);
+            let mut file_system_wstr = widestring::WideString::from_str(file_system).into_vec();
+            file_system_wstr.push(0);
+
+            match (format_ex2, format_ex) {
+                (Some(format_ex2), _) => format_ex2(
+                    volume_path_wstr.as_mut_ptr(),
+                    FmIfsMediaType::FmMediaFixed,
+                    file_system_wstr.as_mut_ptr(),
+                    &mut format_param,
+                    format_ex2_callback,
+                ),
+                // FormatEx can't disable TxF or short names, only the quick format
+                // and the default cluster size translate.
+                (None, Some(format_ex)) => format_ex(
+                    volume_path_wstr.as_mut_ptr(),
+                    FmIfsMediaType::FmMediaFixed,
+                    file_system_wstr.as_mut_ptr(),
+                    label_string_ptr,
+                    1,
+                    0,
+                    format_ex2_callback as usize as PVoid,
+                ),
+                (None, None) => unreachable!(),
+            }
+
+            if let Some(ref context) = FORMAT_CONTEXT {
+                if context.event.wait(winapi::um::winbase::INFINITE) != WinEventResult::WaitObject0
+                {
+                    return Err(WinResultCode::ErrorGenFailure);
+                }
+
+                match context.result {
+                    WinResultCode::ErrorSuccess => {
+                        return Ok(());
+                    }
+                    _ => {
+                        std::thread::sleep(std::time::Duration::from_millis(1000));
+                    }
+                };
+            }
+        }
+
+        Err(WinResultCode::ErrorGenFailure)
+    }
+}
+
+/// Forces the disk to be brought online and surface its volumes.
+pub fn force_online_disk(disk: &Disk) -> WinResult<()> {
+    disk.force_online()
+}
+
+/// Retrieves the volume disk path.
+pub fn volume_path_disk(disk: &Disk) -> WinResult<String> {
+    disk.volume_path()
+}
+
+/// Default amount of time to wait for a disk to arrive after its virtual disk got attached.
+pub const DISK_ARRIVAL_DEFAULT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Waits for the disk identified by the given physical path (e.g. \\.\PhysicalDrive3)
+/// to arrive in the system and be openable.
+/// Some hosts take seconds after a virtual disk is attached before its physical path
+/// can be opened, so this waits on disk interface arrival notifications until the disk
+/// can be opened or the timeout expires.
+pub fn wait_for_disk(physical_path: &str, timeout: std::time::Duration) -> WinResult<()> {
+    use winapi::um::{cfgmgr32, winioctl};
+
+    let mut filter = unsafe { std::mem::zeroed::<cfgmgr32::CM_NOTIFY_FILTER>() };
+    filter.cbSize = std::mem::size_of::<cfgmgr32::CM_NOTIFY_FILTER>() as DWord;
+    filter.FilterType = cfgmgr32::CM_NOTIFY_FILTER_TYPE_DEVICEINTERFACE;
+    unsafe {
+        filter.u.DeviceInterface_mut().ClassGuid = winioctl::GUID_DEVINTERFACE_DISK;
+    }
+
+    let event = WinEvent::create(false, false, None, None)?;
+
+    // Register before the first open attempt so that no arrival can be missed.
+    let _cm_notification = CmNotification::register(
+        &mut filter,
+        event.get_handle() as PVoid,
+        Some(disk_arrival_callback),
+    )?;
+
+    let deadline = std::time::Instant::now() + timeout;
+
+    loop {
+        match Disk::open(physical_path, Some(0), None) {
+            Ok(_) => return Ok(()),
+            Err(error) => match winresult_code_to_error_code(error) {
+                winapi::shared::winerror::ERROR_FILE_NOT_FOUND
+                | winapi::shared::winerror::ERROR_PATH_NOT_FOUND => {}
+                _ => return Err(error),
+            },
+        }
+
+        let now = std::time::Instant::now();
+
+        if now >= deadline {
+            return Err(error_code_to_winresult_code(
+                winapi::shared::winerror::ERROR_TIMEOUT,
+            ));
+        }
+
+        event.wait(wait_milliseconds(deadline - now));
+    }
+}
+
+/// Asks the PnP manager to re-enumerate the whole device tree, so that disks and volumes
+/// that changed behind its back are detected again. Returns once the enumeration is done.
+pub fn rescan_all() -> WinResult<()> {
+    use winapi::um::cfgmgr32;
+
+    let mut root: cfgmgr32::DEVINST = 0;
+
+    unsafe {
+        if cfgmgr32::CM_Locate_DevNodeW(
+            &mut root,
+            std::ptr::null_mut(),
+            cfgmgr32::CM_LOCATE_DEVNODE_NORMAL,
+        ) != cfgmgr32::CR_SUCCESS
+        {
+            return Err(WinResultCode::ErrorGenFailure);
+        }
+
+        match cfgmgr32::CM_Reenumerate_DevNode(root, cfgmgr32::CM_REENUMERATE_SYNCHRONOUS) {
+            cfgmgr32::CR_SUCCESS => Ok(()),
+            _ => Err(WinResultCode::ErrorGenFailure),
+        }
+    }
+}
+
+/// The callback called when a new disk arrives in the system.
+/// Signals the event whose handle is supplied as context, so that the waiter retries opening the disk.
+unsafe extern "system" fn disk_arrival_callback(
+    _: winapi::um::cfgmgr32::HCMNOTIFICATION,
+    context: PVoid,
+    action: winapi::um::cfgmgr32::CM_NOTIFY_ACTION,
+    _: winapi::um::cfgmgr32::PCM_NOTIFY_EVENT_DATA,
+    _: DWord,
+) -> DWord {
+    if action == winapi::um::cfgmgr32::CM_NOTIFY_ACTION_DEVICEINTERFACEARRIVAL {
+        winapi::um::synchapi::SetEvent(context as Handle);
+    }
+
+    winapi::shared::winerror::ERROR_SUCCESS
+}
+
+/// Safe abstraction to a volume handle.
+/// Volumes are Send and Sync, IOCTLs can be issued on the same handle from any thread.
+pub struct Volume {
+    handle: OwnedHandle,
+}
+
+impl AsRawHandle for Volume {
+    fn as_raw_handle(&self) -> RawHandle {
+        self.handle.as_raw_handle()
+    }
+}
+
+impl IntoRawHandle for Volume {
+    fn into_raw_handle(self) -> RawHandle {
+        self.handle.into_raw_handle()
+    }
+}
+
+impl From<std::os::windows::io::OwnedHandle> for Volume {
+    fn from(handle: std::os::windows::io::OwnedHandle) -> Volume {
+        Volume {
+            handle: OwnedHandle::from(handle),
+        }
+    }
+}
+
+impl Volume {
+    /// Wraps the supplied volume handle, providing a safe drop implementation that will close the handle
+    /// on the end of its lifetime.
+    pub fn wrap_handle(handle: Handle) -> WinResult<Volume> {
+        Ok(Volume {
+            handle: OwnedHandle::wrap(handle)?,
+        })
+    }
+
+    /// Releases the wrapped handle to ensure that at the end of the lifetime of this Volume instance
+    /// the handle is not closed.
+    ///
+    /// # Unsafe
+    ///
+    /// Marked as unsafe because of the possibility of leaking a handle.
+    /// Prefer `IntoRawHandle::into_raw_handle` or converting into
+    /// `std::os::windows::io::OwnedHandle`, which hand over ownership of the handle.
+    pub unsafe fn release_handle(&mut self) -> Handle {
+        self.handle.release()
+    }
+
+    /// Returns a cloned value of the internally stored handle to the volume.
+    /// This is useful so that the volume handle can be used on other Windows APIs.
+    /// Be careful and do not close the handle returned here because the code will panic at the
+    /// end of the lifetime of this Volume instance if CloseHandle fails.
+    pub fn get_handle(&self) -> Handle {
+        self.handle.get()
+    }
+
+    /// Opens a volume by path (e.g. \\?\Volume{4c1b02c1-d990-11dc-99ae-806e6f6e6963}).
+    pub fn open(path: &str, access_mask: Option<DWord>) -> WinResult<Volume> {
+        use winapi::um::{fileapi, winnt};
+
+        let access_mask_flags = match access_mask {
+            Some(flags) => flags,
+            None => winnt::GENERIC_READ | winnt::GENERIC_WRITE,
+        };
+
+        match create_file(
+            path,
+            access_mask_flags,
+            winnt::FILE_SHARE_READ | winnt::FILE_SHARE_WRITE,
+            None,
+            fileapi::OPEN_EXISTING,
+            winnt::FILE_ATTRIBUTE_NORMAL,
+            None,
+        ) {
+            Ok(handle) => Volume::wrap_handle(handle),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Shrinks the file system of the volume to the given number of sectors,
+    /// using the prepare and commit sequence of FSCTL_SHRINK_VOLUME.
+    /// The shrink request is aborted if it can't be committed.
+    pub fn shrink_file_system(&self, new_number_of_sectors: u64) -> WinResult<()> {
+        use winapi::um::{ioapiset, winioctl};
+
+        #[repr(C)]
+        struct ShrinkVolumeInformation {
+            shrink_request_type: u32,
+            flags: DWordLong,
+            new_number_of_sectors: LongLong,
+        }
+
+        const SHRINK_PREPARE: u32 = 1;
+        const SHRINK_COMMIT: u32 = 2;
+        const SHRINK_ABORT: u32 = 3;
+
+        let shrink_request = |request_type: u32| -> WinResult<()> {
+            let mut request = ShrinkVolumeInformation {
+                shrink_request_type: request_type,
+                flags: 0,
+                new_number_of_sectors: new_number_of_sectors as LongLong,
+            };
+            let mut bytes: DWord = 0;
+
+            unsafe {
+                match ioapiset::DeviceIoControl(
+                    self.handle.get(),
+                    winioctl::FSCTL_SHRINK_VOLUME,
+                    &mut request as *mut _ as PVoid,
+                    std::mem::size_of::<ShrinkVolumeInformation>() as DWord,
                     std::ptr::null_mut(),
                     0,
                     &mut bytes,
                     std::ptr::null_mut(),
-                ) == 0
-                {
-                    return Err(error_code_to_winresult_code(
+                ) {
+                    0 => Err(error_code_to_winresult_code(
                         winapi::um::errhandlingapi::GetLastError(),
-                    ));
+                    )),
+                    _ => Ok(()),
                 }
+            }
+        };
+
+        shrink_request(SHRINK_PREPARE)?;
+
+        if let Err(error) = shrink_request(SHRINK_COMMIT) {
+            #[allow(unused_must_use)]
+            {
+                shrink_request(SHRINK_ABORT);
+            }
+
+            return Err(error);
+        }
+
+        Ok(())
+    }
+
+    /// Flushes all the buffered data of the volume to disk.
+    pub fn flush(&self) -> WinResult<()> {
+        unsafe {
+            if winapi::um::fileapi::FlushFileBuffers(self.handle.get()) == 0 {
+                return Err(error_code_to_winresult_code(
+                    winapi::um::errhandlingapi::GetLastError(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Locks the volume, which fails if there are other open handles to files on it.
+    /// The lock is released when this instance is dropped or through `unlock`.
+    pub fn lock(&self) -> WinResult<()> {
+        self.file_system_control(winapi::um::winioctl::FSCTL_LOCK_VOLUME)
+    }
+
+    /// Releases a lock taken through `lock`.
+    pub fn unlock(&self) -> WinResult<()> {
+        self.file_system_control(winapi::um::winioctl::FSCTL_UNLOCK_VOLUME)
+    }
+
+    /// Dismounts the file system of the volume. Handles to files on the volume
+    /// are invalidated if the volume is not locked first.
+    pub fn dismount(&self) -> WinResult<()> {
+        self.file_system_control(winapi::um::winioctl::FSCTL_DISMOUNT_VOLUME)
+    }
+
+    /// Issues a file system or volume control code that takes no input or output buffers.
+    fn file_system_control(&self, control_code: DWord) -> WinResult<()> {
+        let mut bytes: DWord = 0;
+
+        unsafe {
+            if winapi::um::ioapiset::DeviceIoControl(
+                self.handle.get(),
+                control_code,
+                std::ptr::null_mut(),
+                0,
+                std::ptr::null_mut(),
+                0,
+                &mut bytes,
+                std::ptr::null_mut(),
+            ) == 0
+            {
+                return Err(error_code_to_winresult_code(
+                    winapi::um::errhandlingapi::GetLastError(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns whether the volume is online, as reported by IOCTL_VOLUME_IS_OFFLINE.
+    /// Volumes are left offline when automount has been disabled (mountvol /N).
+    /// Fails with the error of the IOCTL if it doesn't report either state,
+    /// e.g. ERROR_INVALID_FUNCTION for volumes whose driver doesn't support it.
+    pub fn is_online(&self) -> WinResult<bool> {
+        // Not defined by winapi: CTL_CODE(IOCTL_VOLUME_BASE, 4, METHOD_BUFFERED, FILE_ANY_ACCESS).
+        const IOCTL_VOLUME_IS_OFFLINE: DWord = 0x0056_0010;
+
+        let mut bytes: DWord = 0;
+
+        // The IOCTL succeeds only if the volume is offline, and fails with STATUS_UNSUCCESSFUL
+        // (ERROR_GEN_FAILURE) if it's online. Any other failure means the state is unknown.
+        let is_offline = unsafe {
+            winapi::um::ioapiset::DeviceIoControl(
+                self.handle.get(),
+                IOCTL_VOLUME_IS_OFFLINE,
+                std::ptr::null_mut(),
+                0,
+                std::ptr::null_mut(),
+                0,
+                &mut bytes,
+                std::ptr::null_mut(),
+            )
+        } != 0;
+
+        if is_offline {
+            return Ok(false);
+        }
+
+        match unsafe { winapi::um::errhandlingapi::GetLastError() } {
+            winapi::shared::winerror::ERROR_GEN_FAILURE => Ok(true),
+            error => Err(error_code_to_winresult_code(error)),
+        }
+    }
+
+    /// Takes the volume offline, dismounting its file system.
+    pub fn offline(&self) -> WinResult<()> {
+        self.file_system_control(winapi::um::winioctl::IOCTL_VOLUME_OFFLINE)
+    }
+
+    /// Brings the volume online, letting a file system mount it.
+    pub fn online(&self) -> WinResult<()> {
+        self.file_system_control(winapi::um::winioctl::IOCTL_VOLUME_ONLINE)
+    }
+
+    /// Force the volume to be brought online (ie: mounted by a filesystem).
+    /// This is needed when automount has been disabled (mountvol /N).
+    /// The volume is only cycled through offline if it isn't online already,
+    /// so that calling this on a mounted volume doesn't yank it from its users.
+    /// Volumes whose state can't be queried are always cycled through offline.
+    pub fn force_online(&self) -> WinResult<()> {
+        if !self.is_online().unwrap_or(false) {
+            self.offline()?;
+        }
+
+        self.online()
+    }
+}
+
+/// Force a volume to be brought online (ie: mounted by a filesystem).
+/// This is needed when automount has been disabled (mountvol /N).
+pub fn force_online_volume(volume_name: &str) -> WinResult<()> {
+    Volume::open(volume_name, None)?.force_online()
+}
+
+/// Every name a volume can be referred to by.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VolumeIdentity {
+    /// Volume GUID path, without trailing backslash (e.g. \\?\Volume{GUID}).
+    pub volume_name: String,
+
+    /// Drive letters and mounted folders of the volume, with trailing backslash (e.g. C:\).
+    pub mount_points: Vec<String>,
+
+    /// NT device name of the volume (e.g. \Device\HarddiskVolume3).
+    pub dos_device: String,
+}
+
+/// Resolves a volume GUID path, drive letter or mounted folder into all the names of its volume,
+/// so that paths returned in different shapes by other helpers can be correlated.
+pub fn resolve_volume(name: &str) -> WinResult<VolumeIdentity> {
+    use winapi::um::{errhandlingapi, fileapi};
+
+    const MAX_PATH: usize = 260;
+
+    let mut mount_point = String::from(name);
+
+    if !mount_point.ends_with('\\') {
+        mount_point.push('\\');
+    }
+
+    let mount_point_wstr = widestring::WideCString::from_str(&mount_point).unwrap();
+    let mut volume_name_buffer: [WChar; MAX_PATH] = [0; MAX_PATH];
+
+    unsafe {
+        if fileapi::GetVolumeNameForVolumeMountPointW(
+            mount_point_wstr.as_ptr(),
+            volume_name_buffer.as_mut_ptr(),
+            MAX_PATH as DWord,
+        ) == 0
+        {
+            return Err(error_code_to_winresult_code(errhandlingapi::GetLastError()));
+        }
+    }
+
+    // Query the drive letters and mounted folders, growing the buffer as requested.
+    let mut path_names: Vec<WChar> = vec![0; MAX_PATH];
+
+    loop {
+        let mut length: DWord = 0;
+
+        unsafe {
+            if fileapi::GetVolumePathNamesForVolumeNameW(
+                volume_name_buffer.as_ptr(),
+                path_names.as_mut_ptr(),
+                path_names.len() as DWord,
+                &mut length,
+            ) != 0
+            {
+                path_names.truncate(length as usize);
+                break;
+            }
+
+            match errhandlingapi::GetLastError() {
+                winapi::shared::winerror::ERROR_MORE_DATA => {
+                    path_names.resize(length as usize, 0);
+                }
+                error => return Err(error_code_to_winresult_code(error)),
+            }
+        }
+    }
+
+    let mount_points: Vec<String> = path_names
+        .split(|wchar| *wchar == 0)
+        .filter(|path| !path.is_empty())
+        .map(wide_to_string)
+        .collect::<WinResult<Vec<String>>>()?;
+
+    let mut volume_name = wide_to_string(&volume_name_buffer)?;
+
+    if volume_name.ends_with('\\') {
+        volume_name.pop();
+    }
+
+    // QueryDosDevice expects the volume GUID name without the \\?\ prefix.
+    let device_name_wstr =
+        widestring::WideCString::from_str(volume_name.trim_start_matches("\\\\?\\")).unwrap();
+    let mut dos_device_buffer: [WChar; MAX_PATH] = [0; MAX_PATH];
+
+    unsafe {
+        if fileapi::QueryDosDeviceW(
+            device_name_wstr.as_ptr(),
+            dos_device_buffer.as_mut_ptr(),
+            MAX_PATH as DWord,
+        ) == 0
+        {
+            return Err(error_code_to_winresult_code(errhandlingapi::GetLastError()));
+        }
+    }
+
+    let dos_device = wide_to_string(&dos_device_buffer)?;
+
+    Ok(VolumeIdentity {
+        volume_name,
+        mount_points,
+        dos_device,
+    })
+}
+
+/// Lists the drive letters and mounted folders of a volume,
+/// given its volume GUID path or any of its mount points.
+pub fn list_mount_points(volume_path: &str) -> WinResult<Vec<String>> {
+    Ok(resolve_volume(volume_path)?.mount_points)
+}
+
+/// Returns the volume GUID path (e.g. \\?\Volume{GUID}) of the volume with the given
+/// NT device name (e.g. \Device\HarddiskVolume3), the reverse of `VolumeIdentity::dos_device`.
+/// Only the given device is queried, through its \\?\GLOBALROOT link.
+/// Fails, typically with ERROR_FILE_NOT_FOUND or ERROR_PATH_NOT_FOUND, if the device isn't a volume.
+pub fn volume_name_for_device(dos_device: &str) -> WinResult<String> {
+    use winapi::um::{errhandlingapi, fileapi};
+
+    const MAX_PATH: usize = 260;
+
+    let mount_point = format!("\\\\?\\GLOBALROOT{}\\", dos_device.trim_end_matches('\\'));
+    let mount_point_wstr = widestring::WideCString::from_str(&mount_point)
+        .map_err(|_| WinResultCode::ErrorInvalidArgument)?;
+    let mut volume_name_buffer: [WChar; MAX_PATH] = [0; MAX_PATH];
+
+    unsafe {
+        if fileapi::GetVolumeNameForVolumeMountPointW(
+            mount_point_wstr.as_ptr(),
+            volume_name_buffer.as_mut_ptr(),
+            MAX_PATH as DWord,
+        ) == 0
+        {
+            return Err(error_code_to_winresult_code(errhandlingapi::GetLastError()));
+        }
+    }
+
+    Ok(wide_to_string(&volume_name_buffer)?
+        .trim_end_matches('\\')
+        .to_string())
+}
+
+/// Mounts a volume, given by its volume GUID path, on a drive letter or empty folder,
+/// e.g. `S:\` or `C:\mnt\layer\`. See `remove_mount_point` to undo it.
+/// The mount point is created through the mount manager, so it's visible to every session
+/// and persists across reboots.
+pub fn add_mount_point(path: &str, volume_name: &str) -> WinResult<()> {
+    use winapi::um::{errhandlingapi, winbase};
+
+    let mut mount_point = String::from(path);
+
+    if !mount_point.ends_with('\\') {
+        mount_point.push('\\');
+    }
+
+    let mut volume_name = String::from(volume_name);
+
+    if !volume_name.ends_with('\\') {
+        volume_name.push('\\');
+    }
+
+    let mount_point_wstr = widestring::WideCString::from_str(&mount_point).unwrap();
+    let volume_name_wstr = widestring::WideCString::from_str(&volume_name).unwrap();
+
+    unsafe {
+        if winbase::SetVolumeMountPointW(mount_point_wstr.as_ptr(), volume_name_wstr.as_ptr()) == 0
+        {
+            return Err(error_code_to_winresult_code(errhandlingapi::GetLastError()));
+        }
+    }
+
+    Ok(())
+}
+
+/// Removes a drive letter or mounted folder, e.g. `C:\mnt\layer\`.
+/// The volume itself is not affected.
+pub fn remove_mount_point(path: &str) -> WinResult<()> {
+    use winapi::um::{errhandlingapi, fileapi};
+
+    let mut mount_point = String::from(path);
+
+    if !mount_point.ends_with('\\') {
+        mount_point.push('\\');
+    }
+
+    let mount_point_wstr = widestring::WideCString::from_str(&mount_point).unwrap();
+
+    unsafe {
+        if fileapi::DeleteVolumeMountPointW(mount_point_wstr.as_ptr()) == 0 {
+            return Err(error_code_to_winresult_code(errhandlingapi::GetLastError()));
+        }
+    }
+
+    Ok(())
+}
+
+const DDD_RAW_TARGET_PATH: DWord = 0x00000001;
+const DDD_REMOVE_DEFINITION: DWord = 0x00000002;
+const DDD_EXACT_MATCH_ON_REMOVE: DWord = 0x00000004;
+
+/// Assigns a drive letter (e.g. `S:`) to a volume, given by its volume GUID path or any of its
+/// mount points, in the DOS device namespace of the logon session of the caller instead of
+/// through the mount manager. The drive letter is only visible to processes of that logon session
+/// and isn't persisted, which suits services exposing a volume to a single user through
+/// impersonation. Without impersonation, services running as LocalSystem define it globally.
+/// See `remove_session_mount_point` to undo it.
+pub fn add_session_mount_point(drive_letter: &str, volume_path: &str) -> WinResult<()> {
+    define_session_dos_device(DDD_RAW_TARGET_PATH, drive_letter, volume_path)
+}
+
+/// Removes a drive letter assigned with `add_session_mount_point` to the given volume.
+pub fn remove_session_mount_point(drive_letter: &str, volume_path: &str) -> WinResult<()> {
+    define_session_dos_device(
+        DDD_RAW_TARGET_PATH | DDD_REMOVE_DEFINITION | DDD_EXACT_MATCH_ON_REMOVE,
+        drive_letter,
+        volume_path,
+    )
+}
+
+fn define_session_dos_device(flags: DWord, drive_letter: &str, volume_path: &str) -> WinResult<()> {
+    let volume = resolve_volume(volume_path)?;
+    let drive_letter_wstr =
+        widestring::WideCString::from_str(drive_letter.trim_end_matches('\\')).unwrap();
+    let dos_device_wstr = widestring::WideCString::from_str(&volume.dos_device).unwrap();
+
+    unsafe {
+        if winapi::um::fileapi::DefineDosDeviceW(
+            flags,
+            drive_letter_wstr.as_ptr(),
+            dos_device_wstr.as_ptr(),
+        ) == 0
+        {
+            return Err(error_code_to_winresult_code(
+                winapi::um::errhandlingapi::GetLastError(),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Maximum number of files of a volume `find_volume_lockers` asks the Restart Manager about.
+const MAX_LOCKER_CANDIDATES: usize = 8192;
+
+/// Returns the processes holding files of the given volume open, through
+/// the Restart Manager, so that failures to dismount, lock or format the volume can report
+/// which processes need to be closed. The volume can be given by its volume GUID path
+/// or any of its mount points.
+/// The Restart Manager only tracks the files it's asked about, so the file tree of the volume
+/// is walked breadth first, without following reparse points, and only its first 8192 files
+/// are checked; on large volumes, processes holding deeply nested files only may go unreported.
+/// Processes holding only directories or the volume itself open (e.g. \\.\X:) aren't reported.
+pub fn find_volume_lockers(volume_path: &str) -> WinResult<Vec<ProcessInfo>> {
+    use std::os::windows::fs::MetadataExt;
+
+    const FILE_ATTRIBUTE_REPARSE_POINT: u32 = 0x400;
+
+    let volume = resolve_volume(volume_path)?;
+    let mut candidates: Vec<String> = Vec::new();
+    let mut directories = std::collections::VecDeque::new();
+    directories.push_back(std::path::PathBuf::from(format!(
+        "{}\\",
+        volume.volume_name
+    )));
+
+    'walk: while let Some(directory) = directories.pop_front() {
+        let entries = match std::fs::read_dir(&directory) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+
+        for entry in entries.filter_map(Result::ok) {
+            if candidates.len() >= MAX_LOCKER_CANDIDATES {
+                break 'walk;
+            }
+
+            let metadata = match entry.metadata() {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+
+            let path = entry.path();
+
+            if !metadata.is_dir() {
+                candidates.push(path.to_string_lossy().into_owned());
+            } else if metadata.file_attributes() & FILE_ATTRIBUTE_REPARSE_POINT == 0 {
+                directories.push_back(path);
+            }
+        }
+    }
+
+    processes_using_files(&candidates)
+}
+
+/// Space usage of a volume, regardless of its file system.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct VolumeSpace {
+    /// Total size of the volume, in bytes.
+    pub total: u64,
+
+    /// Free space on the volume, in bytes.
+    pub free: u64,
+
+    /// Free space on the volume available to the calling user, in bytes.
+    /// This is lower than `free` when quotas apply.
+    pub available: u64,
+}
+
+impl VolumeSpace {
+    /// Returns the used space on the volume, in bytes.
+    pub fn used(&self) -> u64 {
+        self.total.saturating_sub(self.free)
+    }
+}
+
+/// Retrieves the space usage of a volume, given its volume GUID path or any of its mount points.
+pub fn volume_space(volume_path: &str) -> WinResult<VolumeSpace> {
+    let mut root_path = String::from(volume_path);
+
+    if !root_path.ends_with('\\') {
+        root_path.push('\\');
+    }
+
+    let root_path_wstr = widestring::WideCString::from_str(root_path).unwrap();
+
+    unsafe {
+        let mut available = std::mem::zeroed::<winapi::shared::ntdef::ULARGE_INTEGER>();
+        let mut total = std::mem::zeroed::<winapi::shared::ntdef::ULARGE_INTEGER>();
+        let mut free = std::mem::zeroed::<winapi::shared::ntdef::ULARGE_INTEGER>();
+
+        if winapi::um::fileapi::GetDiskFreeSpaceExW(
+            root_path_wstr.as_ptr(),
+            &mut available,
+            &mut total,
+            &mut free,
+        ) == 0
+        {
+            return Err(error_code_to_winresult_code(
+                winapi::um::errhandlingapi::GetLastError(),
+            ));
+        }
+
+        Ok(VolumeSpace {
+            total: *total.QuadPart(),
+            free: *free.QuadPart(),
+            available: *available.QuadPart(),
+        })
+    }
+}
+
+/// Outcome of a file system verification run through `verify_filesystem`.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChkdskReport {
+    /// The verification finished and found the file system healthy, or repaired it.
+    pub success: bool,
+
+    /// The volume couldn't be locked and the verification was scheduled for the next reboot.
+    pub check_on_reboot: bool,
+
+    /// The volume couldn't be locked, typically because there are open handles to files on it.
+    pub lock_failed: bool,
+
+    /// Access to the volume was denied.
+    pub access_denied: bool,
+
+    /// An IO error was hit while reading or writing the volume.
+    pub io_error: bool,
+
+    /// Text messages reported while verifying the file system.
+    pub messages: Vec<String>,
+}
+
+// Packet types reported to the fmifs.dll callback (FMIFS_PACKET_TYPE).
+const FMIFS_PERCENT_COMPLETED: u32 = 0;
+const FMIFS_ACCESS_DENIED: u32 = 6;
+const FMIFS_CANT_LOCK: u32 = 8;
+const FMIFS_IO_ERROR: u32 = 10;
+const FMIFS_FINISHED: u32 = 11;
+const FMIFS_CHECK_ON_REBOOT: u32 = 13;
+const FMIFS_TEXT_MESSAGE: u32 = 14;
+
+#[repr(C)]
+struct FmIfsTextMessage {
+    message_type: u32,
+    message: *const std::os::raw::c_char,
+}
+
+/// State of the verification in flight, reachable from the callback since Chkdsk
+/// doesn't provide a context pointer to it.
+struct ChkdskContext<'progress> {
+    report: ChkdskReport,
+    progress: Option<&'progress mut dyn FnMut(u32)>,
+}
+
+/// Serializes verifications, since the callback context is global.
+static CHKDSK_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+static CHKDSK_CONTEXT: std::sync::atomic::AtomicPtr<ChkdskContext<'static>> =
+    std::sync::atomic::AtomicPtr::new(std::ptr::null_mut());
+
+unsafe extern "system" fn chkdsk_callback(
+    packet_type: u32,
+    _packet_length: ULong,
+    packet_data: PVoid,
+) -> Boolean {
+    let context = CHKDSK_CONTEXT.load(std::sync::atomic::Ordering::SeqCst);
+
+    if context.is_null() {
+        return 1;
+    }
+
+    let context = &mut *context;
+
+    match packet_type {
+        FMIFS_PERCENT_COMPLETED => {
+            if let Some(progress) = context.progress.as_mut() {
+                progress(*(packet_data as *const ULong));
+            }
+        }
+        FMIFS_ACCESS_DENIED => context.report.access_denied = true,
+        FMIFS_CANT_LOCK => context.report.lock_failed = true,
+        FMIFS_IO_ERROR => context.report.io_error = true,
+        FMIFS_FINISHED => context.report.success = *(packet_data as *const Boolean) != 0,
+        FMIFS_CHECK_ON_REBOOT => context.report.check_on_reboot = true,
+        FMIFS_TEXT_MESSAGE => {
+            let text_message = &*(packet_data as *const FmIfsTextMessage);
+
+            if !text_message.message.is_null() {
+                let message = std::ffi::CStr::from_ptr(text_message.message)
+                    .to_string_lossy()
+                    .trim_end()
+                    .to_string();
+
+                if !message.is_empty() {
+                    context.report.messages.push(message);
+                }
+            }
+        }
+        _ => {}
+    }
+
+    1
+}
+
+/// Returns the name of the file system of a volume, e.g. `NTFS`.
+/// The volume can be supplied as a volume GUID path or any of its mount points.
+pub fn volume_file_system(volume_path: &str) -> WinResult<String> {
+    use winapi::um::{errhandlingapi, fileapi};
 
-                Ok(())
-            }
+    const MAX_PATH: usize = 260;
+
+    let mut root_path = String::from(volume_path);
+
+    if !root_path.ends_with('\\') {
+        root_path.push('\\');
+    }
+
+    let root_path_wstr = widestring::WideCString::from_str(&root_path)
+        .map_err(|_| WinResultCode::ErrorInvalidArgument)?;
+    let mut file_system_wstr: Vec<WChar> = vec![0; MAX_PATH + 1];
+
+    unsafe {
+        if fileapi::GetVolumeInformationW(
+            root_path_wstr.as_ptr(),
+            std::ptr::null_mut(),
+            0,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            file_system_wstr.as_mut_ptr(),
+            file_system_wstr.len() as DWord,
+        ) == 0
+        {
+            return Err(error_code_to_winresult_code(errhandlingapi::GetLastError()));
         }
-        Err(error) => Err(error),
     }
+
+    wide_to_string(&file_system_wstr)
+}
+
+/// Verifies the file system of a volume through the `Chkdsk` routine of fmifs.dll,
+/// optionally repairing any corruption found. This is useful after unclean container shutdowns.
+/// The volume can be supplied as a volume GUID path or any of its mount points.
+/// The optional progress callback receives the completion percentage.
+pub fn verify_filesystem(
+    volume_path: &str,
+    repair: bool,
+    progress: Option<&mut dyn FnMut(u32)>,
+) -> WinResult<ChkdskReport> {
+    let mut root_path = String::from(volume_path);
+
+    if !root_path.ends_with('\\') {
+        root_path.push('\\');
+    }
+
+    let mut root_path_wstr = widestring::WideString::from_str(&root_path).into_vec();
+    root_path_wstr.push(0);
+
+    // Chkdsk needs to be told the file system of the volume.
+    let mut file_system_wstr =
+        widestring::WideString::from_str(&volume_file_system(&root_path)?).into_vec();
+    file_system_wstr.push(0);
+
+    let chkdsk = FmIfs::get()?.chkdsk()?;
+
+    // The lock guards no data and the context is set afresh below, so a poisoned lock is still usable.
+    let _lock = CHKDSK_LOCK
+        .lock()
+        .unwrap_or_else(|error| error.into_inner());
+    let mut context = ChkdskContext {
+        report: ChkdskReport::default(),
+        progress,
+    };
+
+    unsafe {
+        CHKDSK_CONTEXT.store(
+            &mut context as *mut ChkdskContext as *mut ChkdskContext<'static>,
+            std::sync::atomic::Ordering::SeqCst,
+        );
+
+        chkdsk(
+            root_path_wstr.as_mut_ptr(),
+            file_system_wstr.as_mut_ptr(),
+            repair as winapi::shared::minwindef::BOOL,
+            0,
+            0,
+            0,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            chkdsk_callback,
+        );
+
+        CHKDSK_CONTEXT.store(std::ptr::null_mut(), std::sync::atomic::Ordering::SeqCst);
+    }
+
+    Ok(context.report)
 }
 
 #[repr(C)]
@@ -761,9 +2689,102 @@ impl std::ops::Drop for SafeFindVolumeHandle {
 
 /// Tries to get the volume path of the volume in a disk.
 /// Returns an empty string if the volume is not found.
+/// Lays out the given GPT partitions back to back over the usable range of a disk,
+/// aligning each to a MiB. Returns the offset and length of every partition.
+/// Returns ErrorInvalidArgument for specs a GPT table can't hold, and ERROR_DISK_FULL
+/// if the partitions don't fit in the usable range.
+fn plan_gpt_partitions(
+    specs: &[GptPartitionSpec],
+    usable_start: i64,
+    usable_end: i64,
+) -> WinResult<Vec<(i64, i64)>> {
+    const ALIGNMENT: i64 = 1024 * 1024;
+    const MAX_PARTITIONS: usize = 128;
+    const MAX_NAME_LENGTH: usize = 36;
+
+    let align_up = |value: i64| (value + ALIGNMENT - 1) / ALIGNMENT * ALIGNMENT;
+
+    if specs.is_empty()
+        || specs.len() > MAX_PARTITIONS
+        || specs[..specs.len() - 1]
+            .iter()
+            .any(|spec| spec.length.is_none())
+        || specs
+            .iter()
+            .any(|spec| spec.name.encode_utf16().count() > MAX_NAME_LENGTH)
+    {
+        return Err(WinResultCode::ErrorInvalidArgument);
+    }
+
+    let mut extents = Vec::with_capacity(specs.len());
+    let mut offset = align_up(usable_start);
+
+    for spec in specs {
+        let available = usable_end - offset;
+        let length = match spec.length {
+            Some(length) if length > available.max(0) as u64 => available + 1,
+            Some(length) => align_up(length as i64),
+            None => available / ALIGNMENT * ALIGNMENT,
+        };
+
+        if length <= 0 || length > available {
+            return Err(error_code_to_winresult_code(
+                winapi::shared::winerror::ERROR_DISK_FULL,
+            ));
+        }
+
+        extents.push((offset, length));
+        offset += length;
+    }
+
+    Ok(extents)
+}
+
 fn try_get_disk_volume_path(handle: Handle) -> WinResult<String> {
-    use winapi::um::{fileapi, ioapiset, winioctl};
+    try_get_partition_volume_path(handle, None)
+}
+
+/// Finds the volume living on the disk of the given handle.
+/// If a partition starting offset is supplied, only the volume whose first extent
+/// starts at that offset is considered.
+/// Returns an empty string if no such volume has arrived yet.
+fn try_get_partition_volume_path(
+    handle: Handle,
+    partition_start: Option<LongLong>,
+) -> WinResult<String> {
+    let mut result = String::new();
+
+    for_each_disk_volume(handle, |volume_name, starting_offset| {
+        if partition_start.map_or(true, |partition_start| starting_offset == partition_start) {
+            result = volume_name;
+            return true;
+        }
+
+        false
+    })?;
+
+    Ok(result)
+}
 
+/// Enumerates the volumes whose first extent lives on the disk of the given handle,
+/// calling the visitor with the volume path and the starting offset of its first extent.
+/// Enumeration stops as soon as the visitor returns true.
+fn for_each_disk_volume<F>(handle: Handle, mut visitor: F) -> WinResult<()>
+where
+    F: FnMut(String, LongLong) -> bool,
+{
+    let disk_number = device_number(handle)?;
+
+    for_each_volume(|volume_name| match first_disk_extent(&volume_name) {
+        Some((extent_disk_number, starting_offset)) if extent_disk_number == disk_number => {
+            visitor(volume_name, starting_offset)
+        }
+        _ => false,
+    })
+}
+
+/// Returns the device number of the disk of the given handle.
+fn device_number(handle: Handle) -> WinResult<DWord> {
     let mut dev_number = StorageDeviceNumber {
         device_type: 0,
         device_number: 0,
@@ -773,7 +2794,7 @@ fn try_get_disk_volume_path(handle: Handle) -> WinResult<String> {
     let mut bytes: DWord = 0;
 
     unsafe {
-        if ioapiset::DeviceIoControl(
+        if winapi::um::ioapiset::DeviceIoControl(
             handle,
             winapi::um::winioctl::IOCTL_STORAGE_GET_DEVICE_NUMBER,
             std::ptr::null_mut(),
@@ -788,7 +2809,20 @@ fn try_get_disk_volume_path(handle: Handle) -> WinResult<String> {
                 winapi::um::errhandlingapi::GetLastError(),
             ));
         }
+    }
+
+    Ok(dev_number.device_number)
+}
+
+/// Enumerates every volume of the system, calling the visitor with the volume path
+/// without trailing backslash. Enumeration stops as soon as the visitor returns true.
+fn for_each_volume<F>(mut visitor: F) -> WinResult<()>
+where
+    F: FnMut(String) -> bool,
+{
+    use winapi::um::fileapi;
 
+    unsafe {
         const MAX_PATH: usize = 256;
         let mut volume_name_buffer: [WChar; MAX_PATH] = [0; MAX_PATH];
         let find_volume_handle =
@@ -805,33 +2839,14 @@ fn try_get_disk_volume_path(handle: Handle) -> WinResult<String> {
         };
 
         loop {
-            let volume_name_wstr =
-                widestring::WideCString::from_ptr_str(volume_name_buffer.as_ptr());
-            let mut volume_name = volume_name_wstr.to_string_lossy();
-            volume_name.shrink_to_fit();
+            let mut volume_name = wide_to_string(&volume_name_buffer)?;
 
             if volume_name.chars().last().unwrap() == '\\' {
                 volume_name.pop();
             }
 
-            if let Ok(volume) = Volume::open(&volume_name, Some(0)) {
-                let mut extents = std::mem::zeroed::<winioctl::VOLUME_DISK_EXTENTS>();
-
-                if ioapiset::DeviceIoControl(
-                    volume.handle,
-                    winioctl::IOCTL_VOLUME_GET_VOLUME_DISK_EXTENTS,
-                    std::ptr::null_mut(),
-                    0,
-                    &mut extents as *mut _ as LPVoid,
-                    std::mem::size_of::<winioctl::VOLUME_DISK_EXTENTS>() as DWord,
-                    &mut bytes,
-                    std::ptr::null_mut(),
-                ) != 0
-                {
-                    if extents.Extents[0].DiskNumber == dev_number.device_number {
-                        return Ok(volume_name);
-                    }
-                }
+            if visitor(volume_name) {
+                return Ok(());
             }
 
             if fileapi::FindNextVolumeW(
@@ -845,18 +2860,432 @@ fn try_get_disk_volume_path(handle: Handle) -> WinResult<String> {
         }
     }
 
-    Ok(String::new())
+    Ok(())
+}
+
+/// Returns the disk number and starting offset of the first extent of the given volume,
+/// or None if the volume can't be queried.
+fn first_disk_extent(volume_name: &str) -> Option<(DWord, LongLong)> {
+    use winapi::um::{ioapiset, winioctl};
+
+    let volume = Volume::open(volume_name, Some(0)).ok()?;
+    let mut extents = unsafe { std::mem::zeroed::<winioctl::VOLUME_DISK_EXTENTS>() };
+    let mut bytes: DWord = 0;
+
+    unsafe {
+        if ioapiset::DeviceIoControl(
+            volume.handle.get(),
+            winioctl::IOCTL_VOLUME_GET_VOLUME_DISK_EXTENTS,
+            std::ptr::null_mut(),
+            0,
+            &mut extents as *mut _ as LPVoid,
+            std::mem::size_of::<winioctl::VOLUME_DISK_EXTENTS>() as DWord,
+            &mut bytes,
+            std::ptr::null_mut(),
+        ) == 0
+        {
+            return None;
+        }
+
+        let extent = &extents.Extents[0];
+        Some((extent.DiskNumber, *extent.StartingOffset.QuadPart()))
+    }
+}
+
+/// Kind of device interface change reported by a `DeviceNotifier`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum DeviceEventKind {
+    Arrival,
+    Removal,
+}
+
+/// Device interface change reported by a `DeviceNotifier`.
+#[derive(Clone)]
+pub struct DeviceEvent {
+    /// Whether the device interface arrived or was removed.
+    pub kind: DeviceEventKind,
+
+    /// Device interface class of the device (e.g. GUID_DEVINTERFACE_VOLUME).
+    pub class_guid: Guid,
+
+    /// Symbolic link of the device interface, which can be used to open the device.
+    pub symbolic_link: String,
+}
+
+/// Safe subscription to device interface arrival and removal notifications.
+/// Events are queued as they are received and can be consumed either through
+/// the `recv` family of functions or by iterating over the notifier.
+pub struct DeviceNotifier {
+    // Field order matters, the notification must be unregistered before the sender is dropped.
+    _cm_notification: CmNotification,
+    receiver: std::sync::mpsc::Receiver<DeviceEvent>,
+    _sender: Box<std::sync::Mutex<std::sync::mpsc::Sender<DeviceEvent>>>,
+}
+
+impl DeviceNotifier {
+    /// Subscribes to arrival and removal notifications of the given device interface class.
+    pub fn register(class_guid: Guid) -> WinResult<DeviceNotifier> {
+        use winapi::um::cfgmgr32;
+
+        let mut filter = unsafe { std::mem::zeroed::<cfgmgr32::CM_NOTIFY_FILTER>() };
+        filter.cbSize = std::mem::size_of::<cfgmgr32::CM_NOTIFY_FILTER>() as DWord;
+        filter.FilterType = cfgmgr32::CM_NOTIFY_FILTER_TYPE_DEVICEINTERFACE;
+        unsafe {
+            filter.u.DeviceInterface_mut().ClassGuid = class_guid;
+        }
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let sender = Box::new(std::sync::Mutex::new(sender));
+
+        let cm_notification = CmNotification::register(
+            &mut filter,
+            &*sender as *const _ as PVoid,
+            Some(device_notifier_callback),
+        )?;
+
+        Ok(DeviceNotifier {
+            _cm_notification: cm_notification,
+            receiver,
+            _sender: sender,
+        })
+    }
+
+    /// Subscribes to volume arrival and removal notifications.
+    pub fn volumes() -> WinResult<DeviceNotifier> {
+        DeviceNotifier::register(winapi::um::winioctl::GUID_DEVINTERFACE_VOLUME)
+    }
+
+    /// Subscribes to disk arrival and removal notifications.
+    pub fn disks() -> WinResult<DeviceNotifier> {
+        DeviceNotifier::register(winapi::um::winioctl::GUID_DEVINTERFACE_DISK)
+    }
+
+    /// Blocks until the next device event is received.
+    pub fn recv(&self) -> Option<DeviceEvent> {
+        self.receiver.recv().ok()
+    }
+
+    /// Blocks until the next device event is received or the timeout expires.
+    pub fn recv_timeout(&self, timeout: std::time::Duration) -> Option<DeviceEvent> {
+        self.receiver.recv_timeout(timeout).ok()
+    }
+
+    /// Returns the next queued device event, if any, without blocking.
+    pub fn try_recv(&self) -> Option<DeviceEvent> {
+        self.receiver.try_recv().ok()
+    }
+}
+
+impl Iterator for DeviceNotifier {
+    type Item = DeviceEvent;
+
+    fn next(&mut self) -> Option<DeviceEvent> {
+        self.recv()
+    }
+}
+
+/// The callback used by `DeviceNotifier` to queue device interface events.
+/// Never panics, since unwinding across the C callback boundary is undefined behavior.
+unsafe extern "system" fn device_notifier_callback(
+    _: winapi::um::cfgmgr32::HCMNOTIFICATION,
+    context: PVoid,
+    action: winapi::um::cfgmgr32::CM_NOTIFY_ACTION,
+    event_data: winapi::um::cfgmgr32::PCM_NOTIFY_EVENT_DATA,
+    _: DWord,
+) -> DWord {
+    use winapi::um::cfgmgr32;
+
+    let kind = match action {
+        cfgmgr32::CM_NOTIFY_ACTION_DEVICEINTERFACEARRIVAL => DeviceEventKind::Arrival,
+        cfgmgr32::CM_NOTIFY_ACTION_DEVICEINTERFACEREMOVAL => DeviceEventKind::Removal,
+        _ => return winapi::shared::winerror::ERROR_SUCCESS,
+    };
+
+    if context.is_null() || event_data.is_null() {
+        return winapi::shared::winerror::ERROR_SUCCESS;
+    }
+
+    let device_interface = (*event_data).u.DeviceInterface();
+    let mut symbolic_link =
+        widestring::WideCString::from_ptr_str(device_interface.SymbolicLink.as_ptr())
+            .to_string_lossy();
+    symbolic_link.shrink_to_fit();
+
+    let event = DeviceEvent {
+        kind,
+        class_guid: device_interface.ClassGuid,
+        symbolic_link,
+    };
+
+    let sender = &*(context as *const std::sync::Mutex<std::sync::mpsc::Sender<DeviceEvent>>);
+
+    if let Ok(sender) = sender.lock() {
+        // The receiving end might have gone away already, nothing to do in that case.
+        #[allow(unused_must_use)]
+        {
+            sender.send(event);
+        }
+    }
+
+    winapi::shared::winerror::ERROR_SUCCESS
+}
+
+/// How often the `VolumeWatcher` worker checks whether it must stop and retries
+/// the volumes that couldn't be resolved when they arrived.
+const VOLUME_WATCHER_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(200);
+
+/// How long the `VolumeWatcher` worker keeps retrying a volume that couldn't be resolved,
+/// the mount manager might not know about it yet when its interface arrives.
+const VOLUME_WATCHER_RESOLVE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Volumes known by a `VolumeWatcher`, keyed by the number of the disk holding their first extent.
+#[derive(Default)]
+struct WatchedVolumes {
+    by_disk: std::collections::HashMap<DWord, Vec<String>>,
+    stopped: bool,
+}
+
+impl WatchedVolumes {
+    fn insert(&mut self, volume_name: String) -> bool {
+        match first_disk_extent(&volume_name) {
+            Some((disk_number, _)) => {
+                let volumes = self.by_disk.entry(disk_number).or_insert_with(Vec::new);
+
+                if !volumes.contains(&volume_name) {
+                    volumes.push(volume_name);
+                }
+
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Shared watch of volume arrivals, serving any number of concurrent waits for the volumes
+/// of specific disks with a single notification registration and a single scan of the system
+/// volumes, instead of one of each per `Disk::volume_path` call.
+/// Useful when many VHDs are attached at once.
+pub struct VolumeWatcher {
+    shared: std::sync::Arc<(std::sync::Mutex<WatchedVolumes>, std::sync::Condvar)>,
+    worker: Option<std::thread::JoinHandle<()>>,
+}
+
+impl VolumeWatcher {
+    /// Registers for volume notifications and scans the volumes already present.
+    /// Notifications are handled by a worker thread that lives as long as the watcher.
+    pub fn start() -> WinResult<VolumeWatcher> {
+        let shared = std::sync::Arc::new((
+            std::sync::Mutex::new(WatchedVolumes::default()),
+            std::sync::Condvar::new(),
+        ));
+        let (ready_sender, ready_receiver) = std::sync::mpsc::channel::<WinResult<()>>();
+
+        let worker_shared = shared.clone();
+        let worker = std::thread::spawn(move || {
+            // Registered before the initial scan so that no arrival can be missed in between.
+            let notifier = match DeviceNotifier::volumes() {
+                Ok(notifier) => notifier,
+                Err(error) => {
+                    #[allow(unused_must_use)]
+                    {
+                        ready_sender.send(Err(error));
+                    }
+                    return;
+                }
+            };
+
+            let (watched, changed) = &*worker_shared;
+
+            let scan_result = match watched.lock() {
+                Ok(mut volumes) => for_each_volume(|volume_name| {
+                    volumes.insert(volume_name);
+                    false
+                }),
+                Err(_) => Err(WinResultCode::ErrorGenFailure),
+            };
+
+            #[allow(unused_must_use)]
+            {
+                ready_sender.send(scan_result);
+            }
+
+            let mut pending: Vec<(String, std::time::Instant)> = Vec::new();
+
+            loop {
+                match watched.lock() {
+                    Ok(ref volumes) if !volumes.stopped => {}
+                    _ => break,
+                }
+
+                let event = notifier.recv_timeout(VOLUME_WATCHER_POLL_INTERVAL);
+                let mut volumes = match watched.lock() {
+                    Ok(volumes) => volumes,
+                    Err(_) => break,
+                };
+
+                match event {
+                    Some(DeviceEvent {
+                        kind: DeviceEventKind::Arrival,
+                        symbolic_link,
+                        ..
+                    }) => pending.push((symbolic_link, std::time::Instant::now())),
+                    Some(DeviceEvent {
+                        kind: DeviceEventKind::Removal,
+                        ..
+                    }) => {
+                        // The removed interface can't be resolved anymore, drop the volumes
+                        // that can't be opened instead.
+                        for disk_volumes in volumes.by_disk.values_mut() {
+                            disk_volumes
+                                .retain(|volume_name| Volume::open(volume_name, Some(0)).is_ok());
+                        }
+                    }
+                    None => {}
+                }
+
+                let mut inserted = false;
+
+                pending.retain(|(symbolic_link, arrived_at)| {
+                    let resolved = match resolve_volume(symbolic_link) {
+                        Ok(identity) => volumes.insert(identity.volume_name),
+                        Err(_) => false,
+                    };
+
+                    inserted |= resolved;
+                    !resolved && arrived_at.elapsed() < VOLUME_WATCHER_RESOLVE_TIMEOUT
+                });
+
+                if inserted {
+                    changed.notify_all();
+                }
+            }
+        });
+
+        match ready_receiver.recv() {
+            Ok(Ok(())) => Ok(VolumeWatcher {
+                shared,
+                worker: Some(worker),
+            }),
+            Ok(Err(error)) => {
+                #[allow(unused_must_use)]
+                {
+                    worker.join();
+                }
+                Err(error)
+            }
+            Err(_) => Err(WinResultCode::ErrorGenFailure),
+        }
+    }
+
+    /// Returns the volumes currently known to live on the given disk.
+    pub fn volumes(&self, disk_number: DWord) -> Vec<String> {
+        match self.shared.0.lock() {
+            Ok(volumes) => volumes
+                .by_disk
+                .get(&disk_number)
+                .cloned()
+                .unwrap_or_default(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// Waits until a volume of the given disk is known, and returns its path.
+    /// Returns ERROR_TIMEOUT if no volume arrives within the timeout.
+    /// The disk is expected to be online already, see `Disk::force_online`.
+    pub fn wait_for_volume(
+        &self,
+        disk_number: DWord,
+        timeout: std::time::Duration,
+    ) -> WinResult<String> {
+        let (volumes, changed) = &*self.shared;
+        let deadline = std::time::Instant::now() + timeout;
+        let mut volumes = volumes.lock().map_err(|_| WinResultCode::ErrorGenFailure)?;
+
+        loop {
+            if let Some(volume_name) = volumes
+                .by_disk
+                .get(&disk_number)
+                .and_then(|disk_volumes| disk_volumes.first())
+            {
+                return Ok(volume_name.clone());
+            }
+
+            let now = std::time::Instant::now();
+
+            if now >= deadline {
+                return Err(timeout_error());
+            }
+
+            volumes = changed
+                .wait_timeout(volumes, deadline - now)
+                .map_err(|_| WinResultCode::ErrorGenFailure)?
+                .0;
+        }
+    }
+}
+
+impl std::ops::Drop for VolumeWatcher {
+    fn drop(&mut self) {
+        if let Ok(mut volumes) = self.shared.0.lock() {
+            volumes.stopped = true;
+        }
+
+        if let Some(worker) = self.worker.take() {
+            #[allow(unused_must_use)]
+            {
+                worker.join();
+            }
+        }
+    }
 }
 
-/// Context structure used for asynchronous volume arrival.
-struct VolumeArrivalCallbackContext<'event, 'result> {
-    event: &'event mut WinEvent,
-    path_result: &'result mut WinResult<String>,
+/// State shared between `Disk::volume_path` and the volume arrival callback.
+/// The callback only borrows the state, which outlives the notification registration.
+struct VolumeArrivalState {
     disk_handle: Handle,
+    event: WinEvent,
+    arrived: std::sync::atomic::AtomicBool,
+    path_result: std::sync::Mutex<WinResult<String>>,
+}
+
+impl VolumeArrivalState {
+    /// Stores the result of looking up the volume path, and wakes up the waiter
+    /// if the volume arrived or the lookup failed.
+    fn publish(&self, path_result: WinResult<String>) {
+        let arrived = match path_result {
+            Ok(ref path) => !path.is_empty(),
+            Err(_) => true,
+        };
+
+        if let Ok(mut result) = self.path_result.lock() {
+            *result = path_result;
+        }
+
+        if arrived {
+            self.arrived
+                .store(true, std::sync::atomic::Ordering::Release);
+
+            // The waiter also polls the arrived flag, so failing to signal only delays it
+            #[allow(unused_must_use)]
+            {
+                self.event.set_event();
+            }
+        }
+    }
+
+    /// Returns the last published volume path lookup result.
+    fn path_result(&self) -> WinResult<String> {
+        match self.path_result.lock() {
+            Ok(result) => result.clone(),
+            Err(_) => Err(WinResultCode::ErrorGenFailure),
+        }
+    }
 }
 
 /// The callback called when a new volume arrives in the system. Checks to see if the volume
 /// we are looking for has arrived yet (i.e. if this is the correct one) and signals the waiter if so.
+/// Panics are caught and reported as a failed lookup, since they can't unwind into the caller.
 #[no_mangle]
 unsafe extern "system" fn volume_arrival_callback(
     _: winapi::um::cfgmgr32::HCMNOTIFICATION,
@@ -866,28 +3295,21 @@ unsafe extern "system" fn volume_arrival_callback(
     _: DWord,
 ) -> DWord {
     if action == winapi::um::cfgmgr32::CM_NOTIFY_ACTION_DEVICEINTERFACEARRIVAL {
-        let callback_context: VolumeArrivalCallbackContext = std::ptr::read(context as *mut _);
-        *callback_context.path_result = try_get_disk_volume_path(callback_context.disk_handle);
+        let state = &*(context as *const VolumeArrivalState);
 
-        #[allow(unused_must_use)]
-        {
-            match callback_context.path_result {
-                Ok(ref path) => {
-                    if !path.is_empty() {
-                        callback_context.event.set();
-                    }
-                }
-                Err(_) => {
-                    callback_context.event.set();
-                }
-            };
-        }
+        let path_result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            try_get_disk_volume_path(state.disk_handle)
+        }))
+        .unwrap_or(Err(WinResultCode::ErrorGenFailure));
+
+        state.publish(path_result);
     }
 
     winapi::shared::winerror::ERROR_SUCCESS
 }
 
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct NtFileSystemInfo {
     pub ntfs_volume_serial_number: u64,
     pub ntfs_version: String,
@@ -912,6 +3334,7 @@ pub struct NtFileSystemInfo {
     pub max_device_trim_byte_count: u32,
     pub max_volume_trim_extent_count: u32,
     pub max_volume_trim_byte_count: u32,
+    #[cfg_attr(feature = "serde", serde(with = "crate::winutilities::serde_guid"))]
     pub resource_manager_identifier: Guid,
 }
 
@@ -1072,3 +3495,50 @@ pub fn get_ntfsinfo(volume_path: &str) -> WinResult<NtFileSystemInfo> {
 
     Ok(ntfsinfo)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: i64 = 1024 * 1024;
+
+    fn spec(length: Option<u64>, name: &str) -> GptPartitionSpec {
+        GptPartitionSpec {
+            partition_type: WinGuid::from(crate::gpt::PARTITION_BASIC_DATA_GUID),
+            length,
+            attributes: 0,
+            name: String::from(name),
+        }
+    }
+
+    #[test]
+    fn plans_aligned_gpt_partitions() {
+        let usable_end = 64 * MIB - 16896;
+        let extents = plan_gpt_partitions(
+            &[spec(Some(1000), "small"), spec(None, "rest")],
+            17408,
+            usable_end,
+        )
+        .unwrap();
+
+        assert_eq!(extents, vec![(MIB, MIB), (2 * MIB, 61 * MIB)]);
+    }
+
+    #[test]
+    fn rejects_gpt_partitions_that_dont_fit() {
+        assert!(
+            plan_gpt_partitions(&[spec(Some(64 * MIB as u64), "big")], 17408, 64 * MIB).is_err()
+        );
+        assert!(
+            plan_gpt_partitions(&[spec(Some(u64::max_value()), "huge")], 17408, 64 * MIB).is_err()
+        );
+        assert!(plan_gpt_partitions(
+            &[spec(None, "first"), spec(Some(1), "second")],
+            17408,
+            64 * MIB
+        )
+        .is_err());
+        assert!(plan_gpt_partitions(&[spec(None, &"x".repeat(37))], 17408, 64 * MIB).is_err());
+        assert!(plan_gpt_partitions(&[], 17408, 64 * MIB).is_err());
+    }
+}
@@ -0,0 +1,67 @@
+// Copyright (c) 2019 Rafael Alcaraz Mercado. All rights reserved.
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// All files in the project carrying such notice may not be copied, modified, or distributed
+// except according to those terms.
+// THE SOURCE CODE IS AVAILABLE UNDER THE ABOVE CHOSEN LICENSE "AS IS", WITH NO WARRANTIES.
+
+//! GPT partition type GUIDs and partition attribute bits, as defined in winioctl.h,
+//! for callers laying out partitions themselves.
+
+use crate::guid::WinGuid;
+use winutils_rs::windefs::Guid;
+
+/// Unused partition table entry.
+pub const PARTITION_ENTRY_UNUSED_GUID: Guid = WinGuid::NULL.into_guid();
+
+/// EFI System Partition (ESP).
+pub const PARTITION_SYSTEM_GUID: Guid =
+    WinGuid::from_u128(0xC12A7328_F81F_11D2_BA4B_00A0C93EC93B).into_guid();
+
+/// Microsoft Reserved Partition (MSR).
+pub const PARTITION_MSFT_RESERVED_GUID: Guid =
+    WinGuid::from_u128(0xE3C9E316_0B5C_4DB8_817D_F92DF00215AE).into_guid();
+
+/// Basic data partition.
+pub const PARTITION_BASIC_DATA_GUID: Guid =
+    WinGuid::from_u128(0xEBD0A0A2_B9E5_4433_87C0_68B6B72699C7).into_guid();
+
+/// Windows Recovery Environment (Windows RE) partition.
+pub const PARTITION_MSFT_RECOVERY_GUID: Guid =
+    WinGuid::from_u128(0xDE94BBA4_06D1_4D40_A16A_BFD50179D6AC).into_guid();
+
+/// Logical Disk Manager (LDM) metadata partition of a dynamic disk.
+pub const PARTITION_LDM_METADATA_GUID: Guid =
+    WinGuid::from_u128(0x5808C8AA_7E8F_42E0_85D2_E1E90434CFB3).into_guid();
+
+/// Logical Disk Manager (LDM) data partition of a dynamic disk.
+pub const PARTITION_LDM_DATA_GUID: Guid =
+    WinGuid::from_u128(0xAF9B60A0_1431_4F62_BC68_3311714A69AD).into_guid();
+
+/// The partition is required for the platform to function, and must not be deleted or modified.
+pub const GPT_ATTRIBUTE_PLATFORM_REQUIRED: u64 = 0x0000000000000001;
+
+/// Firmware must not expose the partition through the EFI block IO protocol.
+pub const GPT_ATTRIBUTE_NO_BLOCK_IO_PROTOCOL: u64 = 0x0000000000000002;
+
+/// The partition is bootable by legacy BIOS firmware.
+pub const GPT_ATTRIBUTE_LEGACY_BIOS_BOOTABLE: u64 = 0x0000000000000004;
+
+/// The volume of a basic data partition is surfaced with DAX (direct access) semantics.
+pub const GPT_BASIC_DATA_ATTRIBUTE_DAX: u64 = 0x0400000000000000;
+
+/// The volume of a basic data partition is offline.
+pub const GPT_BASIC_DATA_ATTRIBUTE_OFFLINE: u64 = 0x0800000000000000;
+
+/// The volume of a basic data partition is read-only.
+pub const GPT_BASIC_DATA_ATTRIBUTE_READ_ONLY: u64 = 0x1000000000000000;
+
+/// The volume of a basic data partition is a shadow copy of another volume.
+pub const GPT_BASIC_DATA_ATTRIBUTE_SHADOW_COPY: u64 = 0x2000000000000000;
+
+/// The volume of a basic data partition is hidden from the mount manager.
+pub const GPT_BASIC_DATA_ATTRIBUTE_HIDDEN: u64 = 0x4000000000000000;
+
+/// The volume of a basic data partition is not assigned a drive letter.
+pub const GPT_BASIC_DATA_ATTRIBUTE_NO_DRIVE_LETTER: u64 = 0x8000000000000000;
@@ -0,0 +1,187 @@
+// Copyright (c) 2019 Rafael Alcaraz Mercado. All rights reserved.
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// All files in the project carrying such notice may not be copied, modified, or distributed
+// except according to those terms.
+// THE SOURCE CODE IS AVAILABLE UNDER THE ABOVE CHOSEN LICENSE "AS IS", WITH NO WARRANTIES.
+
+//! Strongly typed GUID that parses, formats, compares and hashes like a value,
+//! instead of exposing the winapi field layout of `Guid`.
+//!
+//! `WinGuid` dereferences to `Guid`, so it can be passed as-is to the APIs of this crate
+//! that take `&Guid` (metadata items, partition IDs), and converts into `Guid` for the ones
+//! that take it by value (snapshot IDs). With the `uuid` feature enabled it converts
+//! from and into `uuid::Uuid`.
+
+use crate::winutilities::{guid_from_str, guid_to_string};
+use winutils_rs::errorcodes::{WinResult, WinResultCode};
+use winutils_rs::windefs::*;
+
+/// GUID value wrapping the Windows `Guid` structure.
+#[derive(Copy, Clone)]
+pub struct WinGuid(Guid);
+
+impl WinGuid {
+    /// The all zero GUID.
+    pub const NULL: WinGuid = WinGuid::from_u128(0);
+
+    /// Builds a GUID from its 128 bit value, as written in its canonical representation
+    /// (e.g. 0xEBD0A0A2_B9E5_4433_87C0_68B6B72699C7).
+    pub const fn from_u128(value: u128) -> WinGuid {
+        WinGuid(Guid {
+            Data1: (value >> 96) as u32,
+            Data2: (value >> 80) as u16,
+            Data3: (value >> 64) as u16,
+            Data4: [
+                (value >> 56) as u8,
+                (value >> 48) as u8,
+                (value >> 40) as u8,
+                (value >> 32) as u8,
+                (value >> 24) as u8,
+                (value >> 16) as u8,
+                (value >> 8) as u8,
+                value as u8,
+            ],
+        })
+    }
+
+    /// Returns the 128 bit value of the GUID, as written in its canonical representation.
+    pub fn as_u128(&self) -> u128 {
+        let mut value = (self.0.Data1 as u128) << 96
+            | (self.0.Data2 as u128) << 80
+            | (self.0.Data3 as u128) << 64;
+
+        for (index, byte) in self.0.Data4.iter().enumerate() {
+            value |= (*byte as u128) << (56 - index * 8);
+        }
+
+        value
+    }
+
+    /// Returns the wrapped Windows GUID, usable in constant expressions.
+    pub const fn into_guid(self) -> Guid {
+        self.0
+    }
+
+    /// Parses a GUID from its canonical string representation, with or without braces.
+    /// Returns ErrorInvalidArgument if the string is not a well formed GUID.
+    pub fn parse_str(string: &str) -> WinResult<WinGuid> {
+        guid_from_str(string)
+            .map(WinGuid)
+            .ok_or(WinResultCode::ErrorInvalidArgument)
+    }
+
+    /// Returns true if this is the all zero GUID.
+    pub fn is_null(&self) -> bool {
+        self.as_u128() == 0
+    }
+}
+
+impl std::ops::Deref for WinGuid {
+    type Target = Guid;
+
+    fn deref(&self) -> &Guid {
+        &self.0
+    }
+}
+
+impl From<Guid> for WinGuid {
+    fn from(guid: Guid) -> WinGuid {
+        WinGuid(guid)
+    }
+}
+
+impl From<WinGuid> for Guid {
+    fn from(guid: WinGuid) -> Guid {
+        guid.0
+    }
+}
+
+#[cfg(feature = "uuid")]
+impl From<uuid::Uuid> for WinGuid {
+    fn from(uuid: uuid::Uuid) -> WinGuid {
+        WinGuid::from_u128(uuid.as_u128())
+    }
+}
+
+#[cfg(feature = "uuid")]
+impl From<WinGuid> for uuid::Uuid {
+    fn from(guid: WinGuid) -> uuid::Uuid {
+        uuid::Uuid::from_u128(guid.as_u128())
+    }
+}
+
+impl std::str::FromStr for WinGuid {
+    type Err = WinResultCode;
+
+    fn from_str(string: &str) -> WinResult<WinGuid> {
+        WinGuid::parse_str(string)
+    }
+}
+
+/// Formats the GUID in registry format, e.g. {EBD0A0A2-B9E5-4433-87C0-68B6B72699C7}.
+impl std::fmt::Display for WinGuid {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{{{}}}", guid_to_string(&self.0).to_uppercase())
+    }
+}
+
+impl std::fmt::Debug for WinGuid {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "WinGuid({})", self)
+    }
+}
+
+impl PartialEq for WinGuid {
+    fn eq(&self, other: &WinGuid) -> bool {
+        self.as_u128() == other.as_u128()
+    }
+}
+
+impl Eq for WinGuid {}
+
+impl std::hash::Hash for WinGuid {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.as_u128().hash(state)
+    }
+}
+
+impl Default for WinGuid {
+    fn default() -> WinGuid {
+        WinGuid::NULL
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for WinGuid {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        crate::winutilities::serde_guid::serialize(&self.0, serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for WinGuid {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<WinGuid, D::Error> {
+        crate::winutilities::serde_guid::deserialize(deserializer).map(WinGuid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_through_registry_format() {
+        let guid = WinGuid::from_u128(0xEBD0A0A2_B9E5_4433_87C0_68B6B72699C7);
+        assert_eq!("{EBD0A0A2-B9E5-4433-87C0-68B6B72699C7}", guid.to_string());
+        assert_eq!(guid, WinGuid::parse_str(&guid.to_string()).unwrap());
+        assert_eq!(
+            guid,
+            "ebd0a0a2-b9e5-4433-87c0-68b6b72699c7"
+                .parse::<WinGuid>()
+                .unwrap()
+        );
+        assert!(WinGuid::parse_str("not-a-guid").is_err());
+    }
+}
@@ -0,0 +1,276 @@
+// Copyright (c) 2019 Rafael Alcaraz Mercado. All rights reserved.
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// All files in the project carrying such notice may not be copied, modified, or distributed
+// except according to those terms.
+// THE SOURCE CODE IS AVAILABLE UNDER THE ABOVE CHOSEN LICENSE "AS IS", WITH NO WARRANTIES.
+
+//! Applies Windows images (WIM) to the volumes of attached VHDs, so that an empty VHDX
+//! laid out by `vhdutilities::create_gen2_boot_vhdx` can be taken to a Windows volume
+//! with a single call. Only built with the `wimgapi` feature.
+//!
+//! Images are applied through WIMGAPI by default, see `Wimgapi`. wimgapi.dll is loaded
+//! on first use instead of being linked, so that the crate still loads where it's missing.
+//! Other imaging tools can be plugged in by implementing `ImageApplier`.
+
+use crate::vhdutilities::ProgressCallback;
+use widestring::WideCString;
+use winutils_rs::errorcodes::{error_code_to_winresult_code, WinResult, WinResultCode};
+use winutils_rs::utilities::WinLibrary;
+use winutils_rs::windefs::*;
+
+/// Applies the image with the given index of an image file to a directory,
+/// typically the root of a volume of an attached VHD.
+pub trait ImageApplier {
+    /// Applies image `index` (1-based) of `image_path` to `target_root`, a directory path
+    /// ending with a backslash (e.g. \\?\Volume{GUID}\).
+    /// The optional callback receives the completion percentage and 100.
+    fn apply(
+        &self,
+        image_path: &str,
+        index: u32,
+        target_root: &str,
+        progress: Option<ProgressCallback>,
+    ) -> WinResult<()>;
+}
+
+type WimCreateFileRoutine =
+    unsafe extern "system" fn(*const WChar, DWord, DWord, DWord, DWord, *mut DWord) -> Handle;
+type WimSetTemporaryPathRoutine = unsafe extern "system" fn(Handle, *const WChar) -> Bool;
+type WimGetImageCountRoutine = unsafe extern "system" fn(Handle) -> DWord;
+type WimLoadImageRoutine = unsafe extern "system" fn(Handle, DWord) -> Handle;
+type WimApplyImageRoutine = unsafe extern "system" fn(Handle, *const WChar, DWord) -> Bool;
+type WimCloseHandleRoutine = unsafe extern "system" fn(Handle) -> Bool;
+type WimMessageCallback = unsafe extern "system" fn(
+    DWord,
+    winapi::shared::minwindef::WPARAM,
+    winapi::shared::minwindef::LPARAM,
+    PVoid,
+) -> DWord;
+type WimRegisterMessageCallbackRoutine =
+    unsafe extern "system" fn(Handle, WimMessageCallback, PVoid) -> DWord;
+type WimUnregisterMessageCallbackRoutine =
+    unsafe extern "system" fn(Handle, WimMessageCallback) -> Bool;
+
+const WIM_GENERIC_READ: DWord = winapi::um::winnt::GENERIC_READ;
+const WIM_OPEN_EXISTING: DWord = 3;
+const WIM_MSG: DWord = 0x8000 + 0x1476; // WM_APP + 0x1476
+const WIM_MSG_PROGRESS: DWord = WIM_MSG + 1;
+const WIM_MSG_SUCCESS: DWord = 0;
+const INVALID_CALLBACK_VALUE: DWord = 0xFFFF_FFFF;
+
+/// Cached facade over wimgapi.dll. The module is loaded and its routines resolved once
+/// per process, on first successful use.
+pub struct Wimgapi {
+    _module: WinLibrary,
+    create_file: WimCreateFileRoutine,
+    set_temporary_path: WimSetTemporaryPathRoutine,
+    get_image_count: WimGetImageCountRoutine,
+    load_image: WimLoadImageRoutine,
+    apply_image: WimApplyImageRoutine,
+    close_handle: WimCloseHandleRoutine,
+    register_message_callback: WimRegisterMessageCallbackRoutine,
+    unregister_message_callback: WimUnregisterMessageCallbackRoutine,
+}
+
+// The module is never unloaded while the process is alive and the routines are free functions,
+// so the facade can be shared across threads.
+unsafe impl Send for Wimgapi {}
+unsafe impl Sync for Wimgapi {}
+
+static WIMGAPI: std::sync::OnceLock<Wimgapi> = std::sync::OnceLock::new();
+
+/// WIMGAPI handle that is closed at the end of its lifetime.
+struct WimHandle<'wimgapi> {
+    handle: Handle,
+    wimgapi: &'wimgapi Wimgapi,
+}
+
+impl<'wimgapi> std::ops::Drop for WimHandle<'wimgapi> {
+    fn drop(&mut self) {
+        unsafe {
+            (self.wimgapi.close_handle)(self.handle);
+        }
+    }
+}
+
+fn last_error() -> WinResultCode {
+    error_code_to_winresult_code(unsafe { winapi::um::errhandlingapi::GetLastError() })
+}
+
+unsafe extern "system" fn wim_message_callback(
+    message_id: DWord,
+    wparam: winapi::shared::minwindef::WPARAM,
+    _lparam: winapi::shared::minwindef::LPARAM,
+    user_data: PVoid,
+) -> DWord {
+    if message_id == WIM_MSG_PROGRESS && !user_data.is_null() {
+        let progress = &mut *(user_data as *mut Option<ProgressCallback>);
+
+        if let Some(callback) = progress.as_mut() {
+            (*callback)(wparam as u64, 100);
+        }
+    }
+
+    WIM_MSG_SUCCESS
+}
+
+impl Wimgapi {
+    /// Returns the process wide facade, loading wimgapi.dll on the first call.
+    /// Returns ERROR_MOD_NOT_FOUND if wimgapi.dll isn't available. Failed loads aren't cached,
+    /// so wimgapi.dll is picked up once installed (e.g. along with the ADK).
+    pub fn get() -> WinResult<&'static Wimgapi> {
+        if let Some(wimgapi) = WIMGAPI.get() {
+            return Ok(wimgapi);
+        }
+
+        // A thread racing this one might win, in which case the facade loaded here is dropped.
+        let wimgapi = Wimgapi::load()?;
+        Ok(WIMGAPI.get_or_init(|| wimgapi))
+    }
+
+    fn load() -> WinResult<Wimgapi> {
+        let module = WinLibrary::load(
+            "wimgapi.dll",
+            winapi::um::libloaderapi::LOAD_LIBRARY_SEARCH_SYSTEM32,
+        )?;
+
+        unsafe {
+            Ok(Wimgapi {
+                create_file: std::mem::transmute(module.proc_address("WIMCreateFile")?),
+                set_temporary_path: std::mem::transmute(
+                    module.proc_address("WIMSetTemporaryPath")?,
+                ),
+                get_image_count: std::mem::transmute(module.proc_address("WIMGetImageCount")?),
+                load_image: std::mem::transmute(module.proc_address("WIMLoadImage")?),
+                apply_image: std::mem::transmute(module.proc_address("WIMApplyImage")?),
+                close_handle: std::mem::transmute(module.proc_address("WIMCloseHandle")?),
+                register_message_callback: std::mem::transmute(
+                    module.proc_address("WIMRegisterMessageCallback")?,
+                ),
+                unregister_message_callback: std::mem::transmute(
+                    module.proc_address("WIMUnregisterMessageCallback")?,
+                ),
+                _module: module,
+            })
+        }
+    }
+
+    /// Opens the given image file for reading, with the temp directory of the current user
+    /// as its scratch directory.
+    fn open(&self, image_path: &str) -> WinResult<WimHandle> {
+        let image_path_wstr = WideCString::from_str(image_path).unwrap();
+        let mut creation_result: DWord = 0;
+
+        let handle = unsafe {
+            (self.create_file)(
+                image_path_wstr.as_ptr(),
+                WIM_GENERIC_READ,
+                WIM_OPEN_EXISTING,
+                0,
+                0,
+                &mut creation_result,
+            )
+        };
+
+        if handle.is_null() {
+            return Err(last_error());
+        }
+
+        let wim = WimHandle {
+            handle,
+            wimgapi: self,
+        };
+
+        let temp_dir_wstr =
+            WideCString::from_str(std::env::temp_dir().to_string_lossy().as_ref()).unwrap();
+
+        if unsafe { (self.set_temporary_path)(wim.handle, temp_dir_wstr.as_ptr()) } == 0 {
+            return Err(last_error());
+        }
+
+        Ok(wim)
+    }
+
+    /// Returns the number of images stored in the given image file.
+    pub fn image_count(&self, image_path: &str) -> WinResult<u32> {
+        let wim = self.open(image_path)?;
+        Ok(unsafe { (self.get_image_count)(wim.handle) })
+    }
+}
+
+impl ImageApplier for Wimgapi {
+    fn apply(
+        &self,
+        image_path: &str,
+        index: u32,
+        target_root: &str,
+        mut progress: Option<ProgressCallback>,
+    ) -> WinResult<()> {
+        let wim = self.open(image_path)?;
+
+        let image_handle = unsafe { (self.load_image)(wim.handle, index) };
+
+        if image_handle.is_null() {
+            return Err(last_error());
+        }
+
+        let image = WimHandle {
+            handle: image_handle,
+            wimgapi: self,
+        };
+
+        let user_data = &mut progress as *mut Option<ProgressCallback> as PVoid;
+
+        if unsafe { (self.register_message_callback)(wim.handle, wim_message_callback, user_data) }
+            == INVALID_CALLBACK_VALUE
+        {
+            return Err(last_error());
+        }
+
+        let target_root_wstr = WideCString::from_str(target_root).unwrap();
+        let applied = unsafe { (self.apply_image)(image.handle, target_root_wstr.as_ptr(), 0) };
+        let result = match applied {
+            0 => Err(last_error()),
+            _ => Ok(()),
+        };
+
+        unsafe {
+            (self.unregister_message_callback)(wim.handle, wim_message_callback);
+        }
+
+        result
+    }
+}
+
+/// Applies image `index` (1-based) of the WIM file to the given volume of an attached VHD,
+/// through WIMGAPI. The volume can be given by its volume GUID path or any of its mount points,
+/// e.g. `Gen2BootVhdx::windows_volume_path`.
+/// Boot files still need to be laid down on the EFI system partition afterwards (e.g. with bcdboot).
+pub fn apply_image(wim_path: &str, index: u32, mounted_volume_root: &str) -> WinResult<()> {
+    apply_image_with(Wimgapi::get()?, wim_path, index, mounted_volume_root, None)
+}
+
+/// Like `apply_image`, but through the given applier and reporting progress through
+/// the optional callback.
+/// Returns ErrorInvalidArgument for an index of 0.
+pub fn apply_image_with<A: ImageApplier + ?Sized>(
+    applier: &A,
+    wim_path: &str,
+    index: u32,
+    mounted_volume_root: &str,
+    progress: Option<ProgressCallback>,
+) -> WinResult<()> {
+    if index == 0 {
+        return Err(WinResultCode::ErrorInvalidArgument);
+    }
+
+    let mut target_root = String::from(mounted_volume_root);
+
+    if !target_root.ends_with('\\') {
+        target_root.push('\\');
+    }
+
+    applier.apply(wim_path, index, &target_root, progress)
+}
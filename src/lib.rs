@@ -31,10 +31,36 @@
 //! - C:\Program Files (x86)\Windows Kits\10\Lib\10.0.18362.0\um\x64\virtdisk.lib
 //! - C:\Windows\System32\virtdisk.dll
 //!
+//! # Optional features
+//!
+//! - `serde`: implements `Serialize` and `Deserialize` for the info and summary structs of this crate.
+//!   GUIDs are serialized as strings in their canonical representation.
+//! - `guid-v4`: generates GUIDs in Rust instead of through `CoCreateGuid`,
+//!   so that this crate doesn't link ole32. The NtosKrnl and RpcRT4 link directives of
+//!   winutils-rs are outside of this crate and still apply.
+//! - `wimgapi`: adds the `imaging` module, which applies WIM images to the volumes of attached VHDs.
+//! - `testutils`: adds the `testutils` module, with fixtures to write storage tests on top of this crate.
+//!
 
+pub mod bootutilities;
+pub mod containerstorage;
 pub mod diskutilities;
+pub mod gpt;
+pub mod guid;
+#[cfg(feature = "wimgapi")]
+pub mod imaging;
+pub mod perf;
+pub mod prelude;
+pub mod replication;
+#[cfg(any(test, feature = "testutils"))]
+pub mod testutils;
+pub mod types;
+pub mod vhderrors;
+pub mod vhdformat;
+pub mod vhdset;
 pub mod vhdutilities;
 pub mod virtdisk;
 pub mod virtdiskdefs;
+pub mod winutilities;
 
 pub(crate) mod virtdisk_bindings;
@@ -0,0 +1,113 @@
+// Copyright (c) 2019 Rafael Alcaraz Mercado. All rights reserved.
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// All files in the project carrying such notice may not be copied, modified, or distributed
+// except according to those terms.
+// THE SOURCE CODE IS AVAILABLE UNDER THE ABOVE CHOSEN LICENSE "AS IS", WITH NO WARRANTIES.
+
+//! Helpers that time the storage workflows of this crate, used by the benchmarks
+//! to make regressions in the retry and wait loops visible.
+
+use crate::diskutilities::*;
+use crate::vhdutilities::*;
+use crate::virtdisk::*;
+use crate::virtdiskdefs::*;
+use crate::winutilities::*;
+use std::time::{Duration, Instant};
+use winutils_rs::errorcodes::{WinResult, WinResultCode};
+
+/// Runs the given operation and returns its result along with how long it took.
+pub fn measure<T, F: FnOnce() -> WinResult<T>>(operation: F) -> WinResult<(T, Duration)> {
+    let start = Instant::now();
+    let result = operation()?;
+    Ok((result, start.elapsed()))
+}
+
+/// Opens and mounts the VHD specified by filename, and measures the time it takes
+/// from the attach request until the first volume of the VHD is surfaced.
+/// The VHD is expected to hold a formatted volume already.
+pub fn time_to_first_volume(
+    filename: &str,
+    options: &MountOptions,
+) -> WinResult<(VirtualDisk, Duration)> {
+    measure(|| {
+        let virtual_disk = mount_vhd_with_options(filename, options)?;
+        open_vhd_backed_disk_with_profile(&virtual_disk, options.io_profile)?.volume_path()?;
+        Ok(virtual_disk)
+    })
+}
+
+/// Measures the time it takes to partition and format a disk into a single volume.
+pub fn format_duration(disk: &Disk, file_system: &str) -> WinResult<(PartitionInfo, Duration)> {
+    measure(|| disk.format(file_system))
+}
+
+/// Measures the time it takes to expand the last volume of a disk to its available space.
+pub fn expand_volume_duration(disk: &Disk) -> WinResult<(bool, Duration)> {
+    measure(|| disk.expand_volume())
+}
+
+/// Outcome of `compaction_throughput`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CompactionMeasurement {
+    /// Time the compaction took to complete.
+    pub elapsed: Duration,
+
+    /// Physical size of the VHD before compaction, in bytes.
+    pub physical_size_before: u64,
+
+    /// Physical size of the VHD after compaction, in bytes.
+    pub physical_size_after: u64,
+}
+
+impl CompactionMeasurement {
+    /// Returns the number of bytes released from the backing file.
+    pub fn reclaimed_bytes(&self) -> u64 {
+        self.physical_size_before
+            .saturating_sub(self.physical_size_after)
+    }
+
+    /// Returns how many bytes of the backing file were processed per second.
+    pub fn bytes_per_second(&self) -> f64 {
+        self.physical_size_before as f64 / self.elapsed.as_secs_f64().max(std::f64::EPSILON)
+    }
+}
+
+/// Compacts a VHD and measures how long it takes.
+pub fn compaction_throughput(virtual_disk: &VirtualDisk) -> WinResult<CompactionMeasurement> {
+    let physical_size = || -> WinResult<u64> {
+        let info_wrapper = virtual_disk.get_information(get_virtual_disk::InfoVersion::Size)?;
+        Ok(unsafe { info_wrapper.info().version_details.size.physical_size })
+    };
+
+    let physical_size_before = physical_size()?;
+
+    let parameters = compact_virtual_disk::Parameters {
+        version: compact_virtual_disk::Version::Version1,
+        version_details: compact_virtual_disk::VersionDetails {
+            version1: compact_virtual_disk::Version1 { reserved: 0 },
+        },
+    };
+
+    let ((), elapsed) = measure(|| {
+        let overlapped_event = OverlappedEvent::new()?;
+
+        match virtual_disk.compact(
+            compact_virtual_disk::Flag::None as u32,
+            &parameters,
+            Some(overlapped_event.as_overlapped()),
+        ) {
+            Ok(()) | Err(WinResultCode::ErrorIoPending) => {}
+            Err(error) => return Err(error),
+        }
+
+        wait_for_vhd_operation(virtual_disk, overlapped_event.as_overlapped())
+    })?;
+
+    Ok(CompactionMeasurement {
+        elapsed,
+        physical_size_before,
+        physical_size_after: physical_size()?,
+    })
+}
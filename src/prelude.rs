@@ -0,0 +1,41 @@
+// Copyright (c) 2019 Rafael Alcaraz Mercado. All rights reserved.
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// All files in the project carrying such notice may not be copied, modified, or distributed
+// except according to those terms.
+// THE SOURCE CODE IS AVAILABLE UNDER THE ABOVE CHOSEN LICENSE "AS IS", WITH NO WARRANTIES.
+
+//! Re-exports of the types needed by the most common workflows of this crate,
+//! including the Windows type aliases used on its signatures, so that downstream code
+//! doesn't need a direct dependency on winutils-rs or winapi just to call into this crate.
+//!
+//! ```ignore
+//! use virtdisk_rs::prelude::*;
+//! ```
+
+pub use crate::bootutilities::{BcdbootError, BcdbootReport, Firmware};
+pub use crate::diskutilities::{
+    ChkdskReport, DeviceEvent, DeviceEventKind, DeviceNotifier, Disk, DiskIdentity, GptAttributes,
+    GptPartitionSpec, NtFileSystemInfo, Partition, PartitionInfo, PartitionStyle, Volume,
+    VolumeIdentity, VolumeSpace, VolumeWatcher, WipeMode,
+};
+pub use crate::guid::WinGuid;
+pub use crate::replication::ReplicationSummary;
+pub use crate::types::{Guid, Handle, Overlapped, SecurityDescriptor, GUID_NULL};
+pub use crate::vhderrors::{VhdErrorExt, VhdErrorKind};
+pub use crate::vhdutilities::{
+    BatchMode, BootLayout, CompactionAdvice, ContentDigest, CreateTransaction, DiskGeometry,
+    DismountOptions, ForceLevel, FormatSpec, Gen2BootVhdx, GrowResult, HashAlgo, HashScope,
+    HostedOn, IoProfile, MountOptions, MountState, MountedVolume, Operation, PermanentAttachment,
+    PrivilegeName, ProgressCallback, SealOptions, SealReport, SealStep, SharedAttachOptions,
+    SizeSpec, StorageDependencyEntry, VhdIoRates, VhdIoStats, Workload,
+};
+pub use crate::virtdisk::{
+    CreateOptions, DetachError, InfoCache, OpenFallbackError, OpenMode, OpenOptions,
+    Page83Identity, ParentLocation, ReadOnlyVirtualDisk, SharingConflict, VirtualDisk,
+    VirtualDiskInfoHandle, VirtualDiskSummary, WritableVirtualDisk,
+};
+pub use crate::virtdiskdefs::*;
+pub use crate::winutilities::{ProcessInfo, RetryPolicy};
+pub use winutils_rs::errorcodes::{WinResult, WinResultCode};
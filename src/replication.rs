@@ -0,0 +1,251 @@
+// Copyright (c) 2019 Rafael Alcaraz Mercado. All rights reserved.
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// All files in the project carrying such notice may not be copied, modified, or distributed
+// except according to those terms.
+// THE SOURCE CODE IS AVAILABLE UNDER THE ABOVE CHOSEN LICENSE "AS IS", WITH NO WARRANTIES.
+
+//! Asynchronous replication of attached VHDs across hosts, built on resilient change tracking (RCT).
+//!
+//! `send_changes` writes the ranges that changed since a change tracking ID to any `Write`
+//! (a file, a socket, a pipe) and `apply_changes` replays them on the replica.
+//! The stream uses a small framed wire format, all integers little endian:
+//!
+//! - Header: the `VDRP` magic, the format version (u32) and the virtual size of the source (u64).
+//! - Data frames: the `DATA_FRAME` tag (u8), the byte offset (u64), the length (u32)
+//!   and the bytes of the range.
+//! - End frame: the `END_FRAME` tag (u8), the byte length of the change tracking ID (u32)
+//!   and the UTF-16 change tracking ID the replica is now in sync with.
+
+use crate::diskutilities::*;
+use crate::virtdisk::*;
+use crate::virtdiskdefs::*;
+use crate::winutilities::*;
+use std::io::{Read, Write};
+use winutils_rs::errorcodes::{error_code_to_winresult_code, WinResult, WinResultCode};
+use winutils_rs::windefs::*;
+
+const STREAM_MAGIC: [u8; 4] = *b"VDRP";
+const STREAM_VERSION: u32 = 1;
+const DATA_FRAME: u8 = 1;
+const END_FRAME: u8 = 2;
+
+/// Largest range carried by a single data frame.
+/// Larger changed ranges are split, which keeps the buffers of both ends bounded.
+const MAX_FRAME_LENGTH: u64 = 1024 * 1024;
+
+/// Summary of a replication pass, returned by both ends of the stream.
+#[derive(Debug, Clone)]
+pub struct ReplicationSummary {
+    /// Number of data frames sent or applied.
+    pub frames: u64,
+
+    /// Number of bytes of disk contents sent or applied.
+    pub bytes: u64,
+
+    /// Change tracking ID of the source at the time the changes were collected.
+    /// Pass it as `since_id` to the next `send_changes` call to ship only newer changes.
+    pub change_tracking_id: String,
+}
+
+fn invalid_stream_error() -> WinResultCode {
+    error_code_to_winresult_code(winapi::shared::winerror::ERROR_INVALID_DATA)
+}
+
+fn write_all<W: Write>(writer: &mut W, buffer: &[u8]) -> WinResult<()> {
+    writer
+        .write_all(buffer)
+        .map_err(|error| io_error_to_winresult_code(&error))
+}
+
+fn read_exact<R: Read>(reader: &mut R, buffer: &mut [u8]) -> WinResult<()> {
+    reader
+        .read_exact(buffer)
+        .map_err(|error| match error.kind() {
+            std::io::ErrorKind::UnexpectedEof => invalid_stream_error(),
+            _ => io_error_to_winresult_code(&error),
+        })
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> WinResult<u32> {
+    let mut buffer: [u8; 4] = [0; 4];
+    read_exact(reader, &mut buffer)?;
+    Ok(u32::from_le_bytes(buffer))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> WinResult<u64> {
+    let mut buffer: [u8; 8] = [0; 8];
+    read_exact(reader, &mut buffer)?;
+    Ok(u64::from_le_bytes(buffer))
+}
+
+/// Opens the disk surfaced by the given attached VHD with the requested access.
+fn open_attached_disk(virtual_disk: &VirtualDisk, access: DWord) -> WinResult<Disk> {
+    let disk_path = virtual_disk.get_physical_path()?;
+    wait_for_disk(&disk_path, DISK_ARRIVAL_DEFAULT_TIMEOUT)?;
+    Disk::open(&disk_path, Some(access), None)
+}
+
+/// Streams the contents of every range of the VHD that changed since `since_id` to the writer.
+///
+/// The VHD must be attached, since the changed ranges are read from the disk it surfaces,
+/// and it must have resilient change tracking enabled.
+/// The change tracking ID is captured before the ranges are queried, so writes that race
+/// with the transfer are shipped again on the next pass instead of being lost.
+pub fn send_changes<W: Write>(
+    virtual_disk: &VirtualDisk,
+    since_id: &str,
+    mut writer: W,
+) -> WinResult<ReplicationSummary> {
+    let change_tracking_id = virtual_disk
+        .get_information(get_virtual_disk::InfoVersion::ChangeTrackingState)?
+        .most_recent_change_tracking_id()?;
+    let virtual_size = unsafe {
+        virtual_disk
+            .get_information(get_virtual_disk::InfoVersion::Size)?
+            .info()
+            .version_details
+            .size
+            .virtual_size
+    };
+    let changes = virtual_disk.query_all_changes(since_id)?;
+    let disk = open_attached_disk(virtual_disk, winapi::um::winnt::GENERIC_READ)?;
+
+    write_all(&mut writer, &STREAM_MAGIC)?;
+    write_all(&mut writer, &STREAM_VERSION.to_le_bytes())?;
+    write_all(&mut writer, &virtual_size.to_le_bytes())?;
+
+    let mut summary = ReplicationSummary {
+        frames: 0,
+        bytes: 0,
+        change_tracking_id,
+    };
+    let mut buffer: Vec<Byte> = vec![0; MAX_FRAME_LENGTH as usize];
+
+    for range in changes {
+        let mut byte_offset = range.byte_offset;
+        let range_end = range.byte_offset + range.byte_length;
+
+        while byte_offset < range_end {
+            let length = std::cmp::min(MAX_FRAME_LENGTH, range_end - byte_offset) as usize;
+            let bytes_read = read_handle_at(disk.get_handle(), byte_offset, &mut buffer[..length])?;
+
+            if bytes_read != length {
+                return Err(error_code_to_winresult_code(
+                    winapi::shared::winerror::ERROR_HANDLE_EOF,
+                ));
+            }
+
+            write_all(&mut writer, &[DATA_FRAME])?;
+            write_all(&mut writer, &byte_offset.to_le_bytes())?;
+            write_all(&mut writer, &(length as u32).to_le_bytes())?;
+            write_all(&mut writer, &buffer[..length])?;
+
+            summary.frames += 1;
+            summary.bytes += length as u64;
+            byte_offset += length as u64;
+        }
+    }
+
+    let id_bytes: Vec<u8> = summary
+        .change_tracking_id
+        .encode_utf16()
+        .flat_map(|wchar| wchar.to_le_bytes().to_vec())
+        .collect();
+
+    write_all(&mut writer, &[END_FRAME])?;
+    write_all(&mut writer, &(id_bytes.len() as u32).to_le_bytes())?;
+    write_all(&mut writer, &id_bytes)?;
+    writer
+        .flush()
+        .map_err(|error| io_error_to_winresult_code(&error))?;
+
+    Ok(summary)
+}
+
+/// Replays a stream produced by `send_changes` on the replica VHD.
+///
+/// The replica must be attached and at least as large as the source.
+/// Windows refuses raw writes over mounted volumes, so attach the replica without
+/// mounting its volumes (or with its disk offline) before applying changes.
+/// Returns ERROR_INVALID_DATA if the stream is malformed or truncated; frames applied
+/// before the error stay written, so the pass must be retried from the same `since_id`.
+pub fn apply_changes<R: Read>(
+    virtual_disk: &VirtualDisk,
+    mut reader: R,
+) -> WinResult<ReplicationSummary> {
+    let mut magic: [u8; 4] = [0; 4];
+    read_exact(&mut reader, &mut magic)?;
+
+    if magic != STREAM_MAGIC || read_u32(&mut reader)? != STREAM_VERSION {
+        return Err(invalid_stream_error());
+    }
+
+    let source_size = read_u64(&mut reader)?;
+    let replica_size = unsafe {
+        virtual_disk
+            .get_information(get_virtual_disk::InfoVersion::Size)?
+            .info()
+            .version_details
+            .size
+            .virtual_size
+    };
+
+    if source_size > replica_size {
+        return Err(WinResultCode::ErrorInvalidArgument);
+    }
+
+    let disk = open_attached_disk(
+        virtual_disk,
+        winapi::um::winnt::GENERIC_READ | winapi::um::winnt::GENERIC_WRITE,
+    )?;
+
+    let mut frames: u64 = 0;
+    let mut bytes: u64 = 0;
+    let mut buffer: Vec<Byte> = vec![0; MAX_FRAME_LENGTH as usize];
+
+    loop {
+        let mut tag: [u8; 1] = [0; 1];
+        read_exact(&mut reader, &mut tag)?;
+
+        match tag[0] {
+            DATA_FRAME => {
+                let byte_offset = read_u64(&mut reader)?;
+                let length = read_u32(&mut reader)? as u64;
+
+                if length > MAX_FRAME_LENGTH || byte_offset + length > source_size {
+                    return Err(invalid_stream_error());
+                }
+
+                read_exact(&mut reader, &mut buffer[..length as usize])?;
+                write_handle_at(disk.get_handle(), byte_offset, &buffer[..length as usize])?;
+
+                frames += 1;
+                bytes += length;
+            }
+            END_FRAME => {
+                let id_length = read_u32(&mut reader)? as usize;
+
+                if id_length % 2 != 0 || id_length > MAX_FRAME_LENGTH as usize {
+                    return Err(invalid_stream_error());
+                }
+
+                let mut id_bytes: Vec<u8> = vec![0; id_length];
+                read_exact(&mut reader, &mut id_bytes)?;
+
+                let id_wchars: Vec<WChar> = id_bytes
+                    .chunks(2)
+                    .map(|wchar| u16::from_le_bytes([wchar[0], wchar[1]]))
+                    .collect();
+
+                return Ok(ReplicationSummary {
+                    frames,
+                    bytes,
+                    change_tracking_id: wide_to_string(&id_wchars)?,
+                });
+            }
+            _ => return Err(invalid_stream_error()),
+        }
+    }
+}
@@ -0,0 +1,168 @@
+// Copyright (c) 2019 Rafael Alcaraz Mercado. All rights reserved.
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// All files in the project carrying such notice may not be copied, modified, or distributed
+// except according to those terms.
+// THE SOURCE CODE IS AVAILABLE UNDER THE ABOVE CHOSEN LICENSE "AS IS", WITH NO WARRANTIES.
+
+//! Test fixtures that make it easy to write storage tests on top of this crate.
+
+use crate::diskutilities::*;
+use crate::vhdutilities::*;
+use crate::virtdisk::*;
+use winutils_rs::errorcodes::WinResult;
+
+static TEMP_VHD_COUNTER: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+/// Temporary VHDX living in the temp directory of the current user.
+/// At the end of its lifetime the VHD is detached (if it was mounted) and its file deleted,
+/// even if the owning test panics. Failures to clean up at that point are ignored,
+/// call `TempVhd::close` beforehand to get the dismount error.
+pub struct TempVhd {
+    path: String,
+    virtual_disk: Option<VirtualDisk>,
+    disk: Option<Disk>,
+    partition: Option<PartitionInfo>,
+    mounted: bool,
+}
+
+impl std::ops::Drop for TempVhd {
+    fn drop(&mut self) {
+        self.partition = None;
+        self.disk = None;
+
+        #[allow(unused_must_use)]
+        {
+            if let Some(virtual_disk) = self.virtual_disk.take() {
+                if self.mounted {
+                    dismount_vhd(&virtual_disk);
+                }
+            }
+
+            std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+impl TempVhd {
+    /// Creates a new uniquely named dynamic VHDX in the temp directory.
+    pub fn new(disk_size_gb: u64, block_size_mb: u32) -> WinResult<TempVhd> {
+        let path = TempVhd::unique_path();
+        let virtual_disk = create_vhd(&path, SizeSpec::gib(disk_size_gb), block_size_mb)?;
+
+        Ok(TempVhd {
+            path,
+            virtual_disk: Some(virtual_disk),
+            disk: None,
+            partition: None,
+            mounted: false,
+        })
+    }
+
+    /// Creates a new uniquely named VHDX in the temp directory and mounts it temporarily.
+    pub fn new_mounted(disk_size_gb: u64, block_size_mb: u32) -> WinResult<TempVhd> {
+        let mut temp_vhd = TempVhd::new(disk_size_gb, block_size_mb)?;
+        temp_vhd.mount()?;
+        Ok(temp_vhd)
+    }
+
+    /// Creates a new uniquely named VHDX in the temp directory, mounts it
+    /// and formats it into a single volume with the given file system.
+    pub fn new_formatted(
+        disk_size_gb: u64,
+        block_size_mb: u32,
+        file_system: &str,
+    ) -> WinResult<TempVhd> {
+        let mut temp_vhd = TempVhd::new_mounted(disk_size_gb, block_size_mb)?;
+        temp_vhd.format(file_system)?;
+        Ok(temp_vhd)
+    }
+
+    /// Mounts the temporary VHD if it's not mounted already.
+    pub fn mount(&mut self) -> WinResult<()> {
+        if !self.mounted {
+            mount_vhd_temporarily_for_setup(self.virtual_disk())?;
+            self.mounted = true;
+            self.disk = Some(open_vhd_backed_disk(self.virtual_disk())?);
+        }
+
+        Ok(())
+    }
+
+    /// Partitions and formats the mounted temporary VHD with the given file system.
+    pub fn format(&mut self, file_system: &str) -> WinResult<()> {
+        self.mount()?;
+        let partition = self.disk.as_ref().unwrap().format(file_system)?;
+        self.partition = Some(partition);
+        Ok(())
+    }
+
+    /// Dismounts the temporary VHD if it's mounted, keeping the file around until drop.
+    pub fn dismount(&mut self) -> WinResult<()> {
+        if self.mounted {
+            self.partition = None;
+            self.disk = None;
+            dismount_vhd(self.virtual_disk())?;
+            self.mounted = false;
+        }
+
+        Ok(())
+    }
+
+    /// Closes the handle to the temporary VHD, keeping the file around until drop.
+    /// This is useful when the test needs to reopen the file with different parameters.
+    pub fn close(&mut self) -> WinResult<()> {
+        self.dismount()?;
+        self.virtual_disk = None;
+        Ok(())
+    }
+
+    /// Returns the full path to the temporary VHD file.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Returns whether the temporary VHD is currently mounted.
+    pub fn is_mounted(&self) -> bool {
+        self.mounted
+    }
+
+    /// Returns the open virtual disk backing this fixture.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the virtual disk handle was closed through `TempVhd::close`.
+    pub fn virtual_disk(&self) -> &VirtualDisk {
+        self.virtual_disk
+            .as_ref()
+            .expect("Temporary VHD handle has been closed")
+    }
+
+    /// Returns the disk backed by the temporary VHD, if mounted.
+    pub fn disk(&self) -> Option<&Disk> {
+        self.disk.as_ref()
+    }
+
+    /// Returns the partition information of the temporary VHD, if formatted.
+    pub fn partition(&self) -> Option<&PartitionInfo> {
+        self.partition.as_ref()
+    }
+
+    fn unique_path() -> String {
+        let counter = TEMP_VHD_COUNTER.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|duration| duration.subsec_nanos())
+            .unwrap_or(0);
+
+        let mut path = std::env::temp_dir();
+        path.push(format!(
+            "virtdisk-rs-{}-{}-{}.vhdx",
+            std::process::id(),
+            counter,
+            nanos
+        ));
+        path.to_string_lossy().into_owned()
+    }
+}
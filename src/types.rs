@@ -0,0 +1,84 @@
+// Copyright (c) 2019 Rafael Alcaraz Mercado. All rights reserved.
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// All files in the project carrying such notice may not be copied, modified, or distributed
+// except according to those terms.
+// THE SOURCE CODE IS AVAILABLE UNDER THE ABOVE CHOSEN LICENSE "AS IS", WITH NO WARRANTIES.
+
+//! Windows types referenced by the public API of this crate, re-exported so that callers
+//! don't need to depend on winutils-rs or winapi to name them, along with constructors
+//! for the ones that are awkward to build by hand.
+//!
+//! - `Guid`: the Windows GUID structure, see `crate::guid::WinGuid` for a value type on top of it.
+//! - `Handle`: a raw Windows handle, as returned by the `get_handle` methods of this crate.
+//! - `Overlapped`: the OVERLAPPED structure taken by the asynchronous virtdisk operations,
+//!   see `overlapped` and `overlapped_at`.
+//! - `SecurityDescriptor`: the security descriptor taken by `VirtualDisk::attach` and
+//!   `VirtualDisk::create`, see `security_descriptor`.
+
+use winutils_rs::errorcodes::{error_code_to_winresult_code, WinResult};
+
+pub use winutils_rs::windefs::{
+    Bool, Byte, DWord, Guid, Handle, Overlapped, SecurityDescriptor, WChar, GUID_NULL,
+};
+
+/// Returns a zero initialized OVERLAPPED structure that signals the given event
+/// when the operation completes.
+/// The event must be a manual reset event that outlives the operation, e.g. `WinEvent::get_handle`.
+pub fn overlapped(event: Handle) -> Overlapped {
+    overlapped_at(0, event)
+}
+
+/// Returns a zero initialized OVERLAPPED structure for an operation at the given byte offset
+/// of a file or device, that signals the given event when the operation completes.
+/// Pass a null event to wait on the file handle instead.
+pub fn overlapped_at(offset: u64, event: Handle) -> Overlapped {
+    let mut overlapped = unsafe { std::mem::zeroed::<Overlapped>() };
+
+    unsafe {
+        overlapped.u.s_mut().Offset = offset as DWord;
+        overlapped.u.s_mut().OffsetHigh = (offset >> 32) as DWord;
+    }
+
+    overlapped.hEvent = event;
+    overlapped
+}
+
+/// Returns an absolute security descriptor with no owner, group, DACL or SACL.
+/// Windows grants access according to the default DACL of the caller's token
+/// to objects created with such a descriptor.
+pub fn security_descriptor() -> WinResult<SecurityDescriptor> {
+    let mut security_descriptor = unsafe { std::mem::zeroed::<SecurityDescriptor>() };
+
+    unsafe {
+        if winapi::um::securitybaseapi::InitializeSecurityDescriptor(
+            &mut security_descriptor as *mut SecurityDescriptor
+                as winapi::um::winnt::PSECURITY_DESCRIPTOR,
+            winapi::um::winnt::SECURITY_DESCRIPTOR_REVISION,
+        ) == 0
+        {
+            return Err(error_code_to_winresult_code(
+                winapi::um::errhandlingapi::GetLastError(),
+            ));
+        }
+    }
+
+    Ok(security_descriptor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overlapped_at_splits_offset() {
+        let overlapped = overlapped_at(0x1_0000_0200, std::ptr::null_mut());
+
+        unsafe {
+            assert_eq!(0x200, overlapped.u.s().Offset);
+            assert_eq!(0x1, overlapped.u.s().OffsetHigh);
+        }
+        assert!(overlapped.hEvent.is_null());
+    }
+}
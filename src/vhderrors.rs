@@ -0,0 +1,306 @@
+// Copyright (c) 2019 Rafael Alcaraz Mercado. All rights reserved.
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// All files in the project carrying such notice may not be copied, modified, or distributed
+// except according to those terms.
+// THE SOURCE CODE IS AVAILABLE UNDER THE ABOVE CHOSEN LICENSE "AS IS", WITH NO WARRANTIES.
+
+//! Typed view over the VHD specific error codes returned by virtdisk.
+//!
+//! Every function of this crate returns a generic `WinResultCode`.
+//! Use `VhdErrorExt::vhd_error_kind` on an error to branch on the actual VHD failure cause.
+
+use winapi::shared::winerror;
+use winutils_rs::errorcodes::{winresult_code_to_error_code, WinResult, WinResultCode};
+
+/// VHD specific failure cause, mapped from the ERROR_VHD_* and ERROR_VIRTDISK_* error codes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum VhdErrorKind {
+    /// ERROR_VHD_DRIVE_FOOTER_MISSING
+    DriveFooterMissing,
+
+    /// ERROR_VHD_DRIVE_FOOTER_CHECKSUM_MISMATCH
+    DriveFooterChecksumMismatch,
+
+    /// ERROR_VHD_DRIVE_FOOTER_CORRUPT
+    DriveFooterCorrupt,
+
+    /// ERROR_VHD_FORMAT_UNKNOWN
+    FormatUnknown,
+
+    /// ERROR_VHD_FORMAT_UNSUPPORTED_VERSION
+    FormatUnsupportedVersion,
+
+    /// ERROR_VHD_SPARSE_HEADER_CHECKSUM_MISMATCH
+    SparseHeaderChecksumMismatch,
+
+    /// ERROR_VHD_SPARSE_HEADER_UNSUPPORTED_VERSION
+    SparseHeaderUnsupportedVersion,
+
+    /// ERROR_VHD_SPARSE_HEADER_CORRUPT
+    SparseHeaderCorrupt,
+
+    /// ERROR_VHD_BLOCK_ALLOCATION_FAILURE
+    BlockAllocationFailure,
+
+    /// ERROR_VHD_BLOCK_ALLOCATION_TABLE_CORRUPT
+    BlockAllocationTableCorrupt,
+
+    /// ERROR_VHD_INVALID_BLOCK_SIZE
+    InvalidBlockSize,
+
+    /// ERROR_VHD_BITMAP_MISMATCH
+    BitmapMismatch,
+
+    /// ERROR_VHD_PARENT_VHD_NOT_FOUND
+    ParentVhdNotFound,
+
+    /// ERROR_VHD_CHILD_PARENT_ID_MISMATCH
+    ChildParentIdMismatch,
+
+    /// ERROR_VHD_CHILD_PARENT_TIMESTAMP_MISMATCH
+    ChildParentTimestampMismatch,
+
+    /// ERROR_VHD_METADATA_READ_FAILURE
+    MetadataReadFailure,
+
+    /// ERROR_VHD_METADATA_WRITE_FAILURE
+    MetadataWriteFailure,
+
+    /// ERROR_VHD_INVALID_SIZE
+    InvalidSize,
+
+    /// ERROR_VHD_INVALID_FILE_SIZE
+    InvalidFileSize,
+
+    /// ERROR_VIRTDISK_PROVIDER_NOT_FOUND
+    ProviderNotFound,
+
+    /// ERROR_VIRTDISK_NOT_VIRTUAL_DISK
+    NotVirtualDisk,
+
+    /// ERROR_VHD_PARENT_VHD_ACCESS_DENIED
+    ParentVhdAccessDenied,
+
+    /// ERROR_VHD_CHILD_PARENT_SIZE_MISMATCH
+    ChildParentSizeMismatch,
+
+    /// ERROR_VHD_DIFFERENCING_CHAIN_CYCLE_DETECTED
+    DifferencingChainCycleDetected,
+
+    /// ERROR_VHD_DIFFERENCING_CHAIN_ERROR_IN_PARENT
+    DifferencingChainErrorInParent,
+
+    /// ERROR_VIRTUAL_DISK_LIMITATION
+    VirtualDiskLimitation,
+
+    /// ERROR_VHD_INVALID_TYPE
+    InvalidType,
+
+    /// ERROR_VHD_INVALID_STATE
+    InvalidState,
+
+    /// ERROR_VIRTDISK_UNSUPPORTED_DISK_SECTOR_SIZE
+    UnsupportedDiskSectorSize,
+
+    /// ERROR_VIRTDISK_DISK_ALREADY_OWNED
+    DiskAlreadyOwned,
+
+    /// ERROR_VIRTDISK_DISK_ONLINE_AND_WRITABLE
+    DiskOnlineAndWritable,
+
+    /// ERROR_VHD_RESIZE_WOULD_TRUNCATE_DATA
+    ResizeWouldTruncateData,
+
+    /// ERROR_VHD_COULD_NOT_COMPUTE_MINIMUM_VIRTUAL_SIZE
+    CouldNotComputeMinimumVirtualSize,
+
+    /// ERROR_VHD_ALREADY_AT_OR_BELOW_MINIMUM_VIRTUAL_SIZE
+    AlreadyAtOrBelowMinimumVirtualSize,
+
+    /// ERROR_VHD_METADATA_FULL
+    MetadataFull,
+
+    /// ERROR_VHD_SHARED
+    Shared,
+}
+
+const VHD_ERROR_KINDS: &[(winerror::HRESULT, VhdErrorKind)] = &[
+    (
+        winerror::ERROR_VHD_DRIVE_FOOTER_MISSING,
+        VhdErrorKind::DriveFooterMissing,
+    ),
+    (
+        winerror::ERROR_VHD_DRIVE_FOOTER_CHECKSUM_MISMATCH,
+        VhdErrorKind::DriveFooterChecksumMismatch,
+    ),
+    (
+        winerror::ERROR_VHD_DRIVE_FOOTER_CORRUPT,
+        VhdErrorKind::DriveFooterCorrupt,
+    ),
+    (
+        winerror::ERROR_VHD_FORMAT_UNKNOWN,
+        VhdErrorKind::FormatUnknown,
+    ),
+    (
+        winerror::ERROR_VHD_FORMAT_UNSUPPORTED_VERSION,
+        VhdErrorKind::FormatUnsupportedVersion,
+    ),
+    (
+        winerror::ERROR_VHD_SPARSE_HEADER_CHECKSUM_MISMATCH,
+        VhdErrorKind::SparseHeaderChecksumMismatch,
+    ),
+    (
+        winerror::ERROR_VHD_SPARSE_HEADER_UNSUPPORTED_VERSION,
+        VhdErrorKind::SparseHeaderUnsupportedVersion,
+    ),
+    (
+        winerror::ERROR_VHD_SPARSE_HEADER_CORRUPT,
+        VhdErrorKind::SparseHeaderCorrupt,
+    ),
+    (
+        winerror::ERROR_VHD_BLOCK_ALLOCATION_FAILURE,
+        VhdErrorKind::BlockAllocationFailure,
+    ),
+    (
+        winerror::ERROR_VHD_BLOCK_ALLOCATION_TABLE_CORRUPT,
+        VhdErrorKind::BlockAllocationTableCorrupt,
+    ),
+    (
+        winerror::ERROR_VHD_INVALID_BLOCK_SIZE,
+        VhdErrorKind::InvalidBlockSize,
+    ),
+    (
+        winerror::ERROR_VHD_BITMAP_MISMATCH,
+        VhdErrorKind::BitmapMismatch,
+    ),
+    (
+        winerror::ERROR_VHD_PARENT_VHD_NOT_FOUND,
+        VhdErrorKind::ParentVhdNotFound,
+    ),
+    (
+        winerror::ERROR_VHD_CHILD_PARENT_ID_MISMATCH,
+        VhdErrorKind::ChildParentIdMismatch,
+    ),
+    (
+        winerror::ERROR_VHD_CHILD_PARENT_TIMESTAMP_MISMATCH,
+        VhdErrorKind::ChildParentTimestampMismatch,
+    ),
+    (
+        winerror::ERROR_VHD_METADATA_READ_FAILURE,
+        VhdErrorKind::MetadataReadFailure,
+    ),
+    (
+        winerror::ERROR_VHD_METADATA_WRITE_FAILURE,
+        VhdErrorKind::MetadataWriteFailure,
+    ),
+    (winerror::ERROR_VHD_INVALID_SIZE, VhdErrorKind::InvalidSize),
+    (
+        winerror::ERROR_VHD_INVALID_FILE_SIZE,
+        VhdErrorKind::InvalidFileSize,
+    ),
+    (
+        winerror::ERROR_VIRTDISK_PROVIDER_NOT_FOUND,
+        VhdErrorKind::ProviderNotFound,
+    ),
+    (
+        winerror::ERROR_VIRTDISK_NOT_VIRTUAL_DISK,
+        VhdErrorKind::NotVirtualDisk,
+    ),
+    (
+        winerror::ERROR_VHD_PARENT_VHD_ACCESS_DENIED,
+        VhdErrorKind::ParentVhdAccessDenied,
+    ),
+    (
+        winerror::ERROR_VHD_CHILD_PARENT_SIZE_MISMATCH,
+        VhdErrorKind::ChildParentSizeMismatch,
+    ),
+    (
+        winerror::ERROR_VHD_DIFFERENCING_CHAIN_CYCLE_DETECTED,
+        VhdErrorKind::DifferencingChainCycleDetected,
+    ),
+    (
+        winerror::ERROR_VHD_DIFFERENCING_CHAIN_ERROR_IN_PARENT,
+        VhdErrorKind::DifferencingChainErrorInParent,
+    ),
+    (
+        winerror::ERROR_VIRTUAL_DISK_LIMITATION,
+        VhdErrorKind::VirtualDiskLimitation,
+    ),
+    (winerror::ERROR_VHD_INVALID_TYPE, VhdErrorKind::InvalidType),
+    (
+        winerror::ERROR_VHD_INVALID_STATE,
+        VhdErrorKind::InvalidState,
+    ),
+    (
+        winerror::ERROR_VIRTDISK_UNSUPPORTED_DISK_SECTOR_SIZE,
+        VhdErrorKind::UnsupportedDiskSectorSize,
+    ),
+    (
+        winerror::ERROR_VIRTDISK_DISK_ALREADY_OWNED,
+        VhdErrorKind::DiskAlreadyOwned,
+    ),
+    (
+        winerror::ERROR_VIRTDISK_DISK_ONLINE_AND_WRITABLE,
+        VhdErrorKind::DiskOnlineAndWritable,
+    ),
+    (
+        winerror::ERROR_VHD_RESIZE_WOULD_TRUNCATE_DATA,
+        VhdErrorKind::ResizeWouldTruncateData,
+    ),
+    (
+        winerror::ERROR_VHD_COULD_NOT_COMPUTE_MINIMUM_VIRTUAL_SIZE,
+        VhdErrorKind::CouldNotComputeMinimumVirtualSize,
+    ),
+    (
+        winerror::ERROR_VHD_ALREADY_AT_OR_BELOW_MINIMUM_VIRTUAL_SIZE,
+        VhdErrorKind::AlreadyAtOrBelowMinimumVirtualSize,
+    ),
+    (
+        winerror::ERROR_VHD_METADATA_FULL,
+        VhdErrorKind::MetadataFull,
+    ),
+    (winerror::ERROR_VHD_SHARED, VhdErrorKind::Shared),
+];
+
+impl VhdErrorKind {
+    /// Maps an error code into its VHD specific failure cause.
+    /// Returns None if the error code is not VHD specific.
+    pub fn from_error_code(error_code: u32) -> Option<VhdErrorKind> {
+        VHD_ERROR_KINDS
+            .iter()
+            .find(|(code, _)| *code as u32 == error_code)
+            .map(|(_, kind)| *kind)
+    }
+
+    /// Returns the error code this failure cause maps from.
+    pub fn error_code(&self) -> u32 {
+        VHD_ERROR_KINDS
+            .iter()
+            .find(|(_, kind)| kind == self)
+            .map(|(code, _)| *code as u32)
+            .unwrap()
+    }
+}
+
+/// Extension to retrieve the VHD specific failure cause of an error.
+pub trait VhdErrorExt {
+    /// Returns the VHD specific failure cause of the error, if any.
+    fn vhd_error_kind(&self) -> Option<VhdErrorKind>;
+}
+
+impl VhdErrorExt for WinResultCode {
+    fn vhd_error_kind(&self) -> Option<VhdErrorKind> {
+        VhdErrorKind::from_error_code(winresult_code_to_error_code(*self))
+    }
+}
+
+impl<T> VhdErrorExt for WinResult<T> {
+    fn vhd_error_kind(&self) -> Option<VhdErrorKind> {
+        match self {
+            Ok(_) => None,
+            Err(error) => error.vhd_error_kind(),
+        }
+    }
+}
@@ -0,0 +1,710 @@
+// Copyright (c) 2019 Rafael Alcaraz Mercado. All rights reserved.
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// All files in the project carrying such notice may not be copied, modified, or distributed
+// except according to those terms.
+// THE SOURCE CODE IS AVAILABLE UNDER THE ABOVE CHOSEN LICENSE "AS IS", WITH NO WARRANTIES.
+
+//! Offline parsing of VHDX image files, reading the file directly instead of going through
+//! the virtual disk driver. Used to inspect images that might not open cleanly,
+//! e.g. after a crash of the host that had them attached.
+
+use crate::winutilities::*;
+use std::io::{Read, Seek, SeekFrom, Write};
+use winutils_rs::errorcodes::{error_code_to_winresult_code, WinResult, WinResultCode};
+use winutils_rs::utilities::guid_are_equal;
+use winutils_rs::windefs::*;
+
+const VHDX_SIGNATURE: &[u8] = b"vhdxfile";
+const HEADER_OFFSETS: [u64; 2] = [64 * 1024, 128 * 1024];
+const HEADER_SIZE: usize = 4 * 1024;
+const HEADER_SIGNATURE: u32 = 0x6461_6568; // "head"
+
+const LOG_SECTOR_SIZE: usize = 4 * 1024;
+const LOG_ENTRY_HEADER_SIZE: usize = 64;
+const LOG_DESCRIPTOR_SIZE: usize = 32;
+const LOG_ENTRY_SIGNATURE: u32 = 0x6567_6F6C; // "loge"
+const DATA_DESCRIPTOR_SIGNATURE: u32 = 0x6373_6564; // "desc"
+const ZERO_DESCRIPTOR_SIGNATURE: u32 = 0x6F72_657A; // "zero"
+const DATA_SECTOR_SIGNATURE: u32 = 0x6174_6164; // "data"
+
+/// Size of the buffer of zeros written when replaying zero descriptors.
+const ZERO_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Current header of a VHDX file.
+#[derive(Clone)]
+pub struct VhdxHeader {
+    /// Sequence number of the header, the valid header with the highest one is the current header.
+    pub sequence_number: u64,
+
+    /// Changes every time the file is opened for writing.
+    pub file_write_guid: Guid,
+
+    /// Changes every time the virtual disk data is modified.
+    pub data_write_guid: Guid,
+
+    /// Identifies the active log entries, GUID_NULL if the log is empty.
+    pub log_guid: Guid,
+
+    /// Version of the log format.
+    pub log_version: u16,
+
+    /// Version of the VHDX format.
+    pub version: u16,
+
+    /// Size of the log region, in bytes.
+    pub log_length: u32,
+
+    /// Offset of the log region in the file, in bytes.
+    pub log_offset: u64,
+}
+
+impl VhdxHeader {
+    /// Returns true if the header references log entries that have to be replayed
+    /// before the image is used.
+    pub fn has_log(&self) -> bool {
+        !guid_are_equal(&self.log_guid, &GUID_NULL)
+    }
+}
+
+/// Entry of the VHDX log, pending to be replayed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct LogEntry {
+    /// Offset of the entry in the log region, in bytes.
+    pub offset: u32,
+
+    /// Sequence number of the entry.
+    pub sequence_number: u64,
+
+    /// Offset in the log region of the oldest entry of the sequence this entry belongs to.
+    pub tail: u32,
+
+    /// Size of the entry, in bytes.
+    pub length: u32,
+
+    /// Number of data and zero descriptors of the entry.
+    pub descriptor_count: u32,
+
+    /// Size the file had when the entry was written, in bytes.
+    pub flushed_file_offset: u64,
+
+    /// Size the file must have once the entry is replayed, in bytes.
+    pub last_file_offset: u64,
+}
+
+/// State of the log of a VHDX file.
+#[derive(Clone)]
+pub struct LogReport {
+    /// Current header of the file.
+    pub header: VhdxHeader,
+
+    /// Entries of the active log sequence, from oldest to newest.
+    pub pending_entries: Vec<LogEntry>,
+}
+
+impl LogReport {
+    /// Returns true if the image wasn't shut down cleanly and has log entries pending to be replayed.
+    pub fn is_dirty(&self) -> bool {
+        self.header.has_log() && !self.pending_entries.is_empty()
+    }
+}
+
+/// What to do with the pending entries of a VHDX log.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LogRecovery {
+    /// Applies the pending entries to the file, as the driver would when opening the image.
+    Replay,
+
+    /// Drops the pending entries. Metadata updates recorded only in the log are lost,
+    /// which can leave the image inconsistent.
+    Discard,
+}
+
+/// Computes the CRC-32C (Castagnoli) checksum used by the VHDX format.
+fn crc32c(bytes: &[u8]) -> u32 {
+    let mut crc: u32 = !0;
+
+    for byte in bytes {
+        crc ^= *byte as u32;
+
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0x82F6_3B78 & 0u32.wrapping_sub(crc & 1));
+        }
+    }
+
+    !crc
+}
+
+/// Validates the checksum of a header or log entry, stored at offset 4 and computed
+/// with the checksum field set to zero.
+fn checksum_matches(buffer: &[u8]) -> bool {
+    let mut copy = buffer.to_vec();
+    copy[4..8].copy_from_slice(&[0; 4]);
+    crc32c(&copy) == read_u32(buffer, 4)
+}
+
+fn read_u32(buffer: &[u8], offset: usize) -> u32 {
+    let mut bytes: [u8; 4] = [0; 4];
+    bytes.copy_from_slice(&buffer[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(buffer: &[u8], offset: usize) -> u64 {
+    let mut bytes: [u8; 8] = [0; 8];
+    bytes.copy_from_slice(&buffer[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+fn io_error(error: std::io::Error) -> WinResultCode {
+    io_error_to_winresult_code(&error)
+}
+
+fn file_corrupt() -> WinResultCode {
+    error_code_to_winresult_code(winapi::shared::winerror::ERROR_FILE_CORRUPT)
+}
+
+fn read_at(file: &mut std::fs::File, offset: u64, length: usize) -> WinResult<Vec<u8>> {
+    let mut buffer = vec![0; length];
+    file.seek(SeekFrom::Start(offset)).map_err(io_error)?;
+    file.read_exact(&mut buffer).map_err(io_error)?;
+    Ok(buffer)
+}
+
+fn write_at(file: &mut std::fs::File, offset: u64, buffer: &[u8]) -> WinResult<()> {
+    file.seek(SeekFrom::Start(offset)).map_err(io_error)?;
+    file.write_all(buffer).map_err(io_error)
+}
+
+/// Current header along with the raw bytes and the slot it was read from,
+/// needed to write the next header.
+struct CurrentHeader {
+    header: VhdxHeader,
+    raw: Vec<u8>,
+    slot: usize,
+}
+
+/// Reads the current header of a VHDX file.
+/// Fails with ERROR_VHD_FORMAT_UNKNOWN if the file isn't a VHDX,
+/// or ERROR_FILE_CORRUPT if neither header is valid.
+fn read_current_header(file: &mut std::fs::File) -> WinResult<CurrentHeader> {
+    let signature = read_at(file, 0, VHDX_SIGNATURE.len()).map_err(|_| {
+        error_code_to_winresult_code(winapi::shared::winerror::ERROR_VHD_FORMAT_UNKNOWN as u32)
+    })?;
+
+    if signature != VHDX_SIGNATURE {
+        return Err(error_code_to_winresult_code(
+            winapi::shared::winerror::ERROR_VHD_FORMAT_UNKNOWN as u32,
+        ));
+    }
+
+    let mut current: Option<CurrentHeader> = None;
+
+    for (slot, offset) in HEADER_OFFSETS.iter().enumerate() {
+        let raw = read_at(file, *offset, HEADER_SIZE)?;
+
+        if read_u32(&raw, 0) != HEADER_SIGNATURE || !checksum_matches(&raw) {
+            continue;
+        }
+
+        let header = VhdxHeader {
+            sequence_number: read_u64(&raw, 8),
+            file_write_guid: guid_from_le_bytes(&raw[16..32]),
+            data_write_guid: guid_from_le_bytes(&raw[32..48]),
+            log_guid: guid_from_le_bytes(&raw[48..64]),
+            log_version: u16::from_le_bytes([raw[64], raw[65]]),
+            version: u16::from_le_bytes([raw[66], raw[67]]),
+            log_length: read_u32(&raw, 68),
+            log_offset: read_u64(&raw, 72),
+        };
+
+        let is_newer = match &current {
+            Some(current) => header.sequence_number > current.header.sequence_number,
+            None => true,
+        };
+
+        if is_newer {
+            current = Some(CurrentHeader { header, raw, slot });
+        }
+    }
+
+    current.ok_or_else(file_corrupt)
+}
+
+/// Log entry along with its raw bytes, needed to replay it.
+struct ParsedEntry {
+    entry: LogEntry,
+    bytes: Vec<u8>,
+}
+
+/// Reads bytes from the circular log region, wrapping around its end.
+fn read_wrapped(log: &[u8], offset: usize, length: usize) -> Vec<u8> {
+    (0..length)
+        .map(|index| log[(offset + index) % log.len()])
+        .collect()
+}
+
+/// Returns the size of the header and descriptors of a log entry, rounded up to whole sectors.
+fn descriptor_area_size(descriptor_count: usize) -> usize {
+    let size = LOG_ENTRY_HEADER_SIZE + descriptor_count * LOG_DESCRIPTOR_SIZE;
+    (size + LOG_SECTOR_SIZE - 1) / LOG_SECTOR_SIZE * LOG_SECTOR_SIZE
+}
+
+/// Parses and validates the log entry at the given offset of the log region.
+/// Returns None if there is no valid entry belonging to the given log there.
+fn parse_entry(log: &[u8], offset: usize, log_guid: &Guid) -> Option<ParsedEntry> {
+    let header = read_wrapped(log, offset, LOG_ENTRY_HEADER_SIZE);
+    let length = read_u32(&header, 8) as usize;
+    let tail = read_u32(&header, 12) as usize;
+
+    if read_u32(&header, 0) != LOG_ENTRY_SIGNATURE
+        || length == 0
+        || length % LOG_SECTOR_SIZE != 0
+        || length > log.len()
+        || tail % LOG_SECTOR_SIZE != 0
+        || tail >= log.len()
+        || !guid_are_equal(&guid_from_le_bytes(&header[32..48]), log_guid)
+    {
+        return None;
+    }
+
+    let bytes = read_wrapped(log, offset, length);
+
+    if !checksum_matches(&bytes) {
+        return None;
+    }
+
+    let sequence_number = read_u64(&bytes, 16);
+    let descriptor_count = read_u32(&bytes, 24) as usize;
+    let mut data_sector_offset = descriptor_area_size(descriptor_count);
+
+    if data_sector_offset > length {
+        return None;
+    }
+
+    for index in 0..descriptor_count {
+        let descriptor = LOG_ENTRY_HEADER_SIZE + index * LOG_DESCRIPTOR_SIZE;
+
+        if read_u64(&bytes, descriptor + 24) != sequence_number {
+            return None;
+        }
+
+        match read_u32(&bytes, descriptor) {
+            ZERO_DESCRIPTOR_SIGNATURE => {}
+            DATA_DESCRIPTOR_SIGNATURE => {
+                if data_sector_offset + LOG_SECTOR_SIZE > length {
+                    return None;
+                }
+
+                let sector = &bytes[data_sector_offset..data_sector_offset + LOG_SECTOR_SIZE];
+                let sector_sequence_number = (read_u32(sector, 4) as u64) << 32
+                    | read_u32(sector, LOG_SECTOR_SIZE - 4) as u64;
+
+                if read_u32(sector, 0) != DATA_SECTOR_SIGNATURE
+                    || sector_sequence_number != sequence_number
+                {
+                    return None;
+                }
+
+                data_sector_offset += LOG_SECTOR_SIZE;
+            }
+            _ => return None,
+        }
+    }
+
+    Some(ParsedEntry {
+        entry: LogEntry {
+            offset: offset as u32,
+            sequence_number,
+            tail: tail as u32,
+            length: length as u32,
+            descriptor_count: descriptor_count as u32,
+            flushed_file_offset: read_u64(&bytes, 48),
+            last_file_offset: read_u64(&bytes, 56),
+        },
+        bytes,
+    })
+}
+
+/// Finds the active sequence of the log: the run of valid entries with consecutive sequence
+/// numbers whose newest entry has the highest sequence number, trimmed to start at the tail
+/// recorded by that newest entry.
+fn active_sequence(log: &[u8], log_guid: &Guid) -> Vec<ParsedEntry> {
+    let mut active: Vec<ParsedEntry> = Vec::new();
+
+    for start in (0..log.len()).step_by(LOG_SECTOR_SIZE) {
+        let mut sequence: Vec<ParsedEntry> = Vec::new();
+        let mut offset = start;
+
+        while let Some(parsed) = parse_entry(log, offset, log_guid) {
+            if let Some(last) = sequence.last() {
+                if parsed.entry.sequence_number != last.entry.sequence_number + 1 {
+                    break;
+                }
+            }
+
+            offset = (offset + parsed.entry.length as usize) % log.len();
+            sequence.push(parsed);
+
+            if offset == start {
+                break;
+            }
+        }
+
+        let head_tail = match sequence.last() {
+            Some(head) => head.entry.tail,
+            None => continue,
+        };
+
+        let tail_index = match sequence
+            .iter()
+            .position(|parsed| parsed.entry.offset == head_tail)
+        {
+            Some(tail_index) => tail_index,
+            None => continue,
+        };
+
+        sequence.drain(..tail_index);
+
+        let is_newer = match (active.last(), sequence.last()) {
+            (Some(active_head), Some(head)) => {
+                head.entry.sequence_number > active_head.entry.sequence_number
+            }
+            (None, Some(_)) => true,
+            _ => false,
+        };
+
+        if is_newer {
+            active = sequence;
+        }
+    }
+
+    active
+}
+
+/// Reads the current header and the active log sequence of an open VHDX file.
+fn read_log(file: &mut std::fs::File) -> WinResult<(CurrentHeader, Vec<ParsedEntry>)> {
+    let current = read_current_header(file)?;
+
+    if !current.header.has_log() || current.header.log_length == 0 {
+        return Ok((current, Vec::new()));
+    }
+
+    let log = read_at(
+        file,
+        current.header.log_offset,
+        current.header.log_length as usize,
+    )?;
+    let sequence = active_sequence(&log, &current.header.log_guid);
+    Ok((current, sequence))
+}
+
+/// Reads the current header of the VHDX file at the given path.
+pub fn read_header(path: &str) -> WinResult<VhdxHeader> {
+    let mut file = std::fs::File::open(path).map_err(io_error)?;
+    Ok(read_current_header(&mut file)?.header)
+}
+
+/// Parses the log region of the VHDX file at the given path and reports the entries
+/// pending to be replayed, without modifying the file.
+pub fn inspect_log(path: &str) -> WinResult<LogReport> {
+    let mut file = std::fs::File::open(path).map_err(io_error)?;
+    let (current, sequence) = read_log(&mut file)?;
+
+    Ok(LogReport {
+        header: current.header,
+        pending_entries: sequence.iter().map(|parsed| parsed.entry).collect(),
+    })
+}
+
+/// Replays or discards the pending log entries of the VHDX file at the given path,
+/// and clears the log from its headers so that the driver opens the image without replaying it.
+/// The file must not be opened by the driver while this runs.
+/// Returns the state of the log before the recovery. Nothing is written if the log is empty.
+pub fn recover_log(path: &str, recovery: LogRecovery) -> WinResult<LogReport> {
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .map_err(io_error)?;
+    let (current, sequence) = read_log(&mut file)?;
+
+    let report = LogReport {
+        header: current.header.clone(),
+        pending_entries: sequence.iter().map(|parsed| parsed.entry).collect(),
+    };
+
+    if !current.header.has_log() {
+        return Ok(report);
+    }
+
+    if recovery == LogRecovery::Replay {
+        if let Some(head) = sequence.last() {
+            let file_size = file.metadata().map_err(io_error)?.len();
+
+            if file_size < head.entry.flushed_file_offset {
+                return Err(file_corrupt());
+            }
+
+            for parsed in &sequence {
+                replay_entry(&mut file, parsed)?;
+            }
+
+            if file_size < head.entry.last_file_offset {
+                file.set_len(head.entry.last_file_offset)
+                    .map_err(io_error)?;
+            }
+
+            file.sync_all().map_err(io_error)?;
+        }
+    }
+
+    clear_log_guid(&mut file, &current)?;
+    Ok(report)
+}
+
+/// Writes the data and zero descriptors of a log entry to their locations in the file.
+fn replay_entry(file: &mut std::fs::File, parsed: &ParsedEntry) -> WinResult<()> {
+    let bytes = &parsed.bytes;
+    let mut data_sector_offset = descriptor_area_size(parsed.entry.descriptor_count as usize);
+
+    for index in 0..parsed.entry.descriptor_count as usize {
+        let descriptor =
+            &bytes[LOG_ENTRY_HEADER_SIZE + index * LOG_DESCRIPTOR_SIZE..][..LOG_DESCRIPTOR_SIZE];
+        let file_offset = read_u64(descriptor, 16);
+
+        match read_u32(descriptor, 0) {
+            ZERO_DESCRIPTOR_SIGNATURE => {
+                let zeros = vec![0; ZERO_BUFFER_SIZE];
+                let mut remaining = read_u64(descriptor, 8);
+                let mut offset = file_offset;
+
+                while remaining > 0 {
+                    let chunk = std::cmp::min(remaining, ZERO_BUFFER_SIZE as u64) as usize;
+                    write_at(file, offset, &zeros[..chunk])?;
+                    offset += chunk as u64;
+                    remaining -= chunk as u64;
+                }
+            }
+            _ => {
+                // The data sector holds everything but the leading 8 and trailing 4 bytes
+                // of the sector, which are kept in the descriptor.
+                let sector = &bytes[data_sector_offset..data_sector_offset + LOG_SECTOR_SIZE];
+                let mut block: Vec<u8> = Vec::with_capacity(LOG_SECTOR_SIZE);
+                block.extend_from_slice(&descriptor[8..16]);
+                block.extend_from_slice(&sector[8..LOG_SECTOR_SIZE - 4]);
+                block.extend_from_slice(&descriptor[4..8]);
+                write_at(file, file_offset, &block)?;
+                data_sector_offset += LOG_SECTOR_SIZE;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes both headers with an empty log GUID, the non-current one first,
+/// flushing after each write so that one valid header always survives a crash.
+fn clear_log_guid(file: &mut std::fs::File, current: &CurrentHeader) -> WinResult<()> {
+    let mut raw = current.raw.clone();
+    raw[48..64].copy_from_slice(&[0; 16]);
+
+    for (index, slot) in [1 - current.slot, current.slot].iter().enumerate() {
+        let sequence_number = current.header.sequence_number + 1 + index as u64;
+        raw[8..16].copy_from_slice(&sequence_number.to_le_bytes());
+        raw[4..8].copy_from_slice(&[0; 4]);
+        let checksum = crc32c(&raw);
+        raw[4..8].copy_from_slice(&checksum.to_le_bytes());
+
+        write_at(file, HEADER_OFFSETS[*slot], &raw)?;
+        file.sync_all().map_err(io_error)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOG_GUID: [u8; 16] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16];
+    const LOG_OFFSET: usize = 192 * 1024;
+    const LOG_LENGTH: usize = 64 * 1024;
+    const DATA_OFFSET: usize = 320 * 1024;
+    const ZERO_OFFSET: usize = 324 * 1024;
+    const FILE_SIZE: usize = 328 * 1024;
+    const LAST_FILE_OFFSET: u64 = 384 * 1024;
+    const ENTRY_SEQUENCE_NUMBER: u64 = 0x1_0000_0005;
+
+    /// Crafted image removed at the end of the test.
+    struct CraftedImage {
+        path: std::path::PathBuf,
+    }
+
+    impl CraftedImage {
+        fn path(&self) -> &str {
+            self.path.to_str().unwrap()
+        }
+    }
+
+    impl std::ops::Drop for CraftedImage {
+        fn drop(&mut self) {
+            #[allow(unused_must_use)]
+            {
+                std::fs::remove_file(&self.path);
+            }
+        }
+    }
+
+    fn put_u32(buffer: &mut [u8], offset: usize, value: u32) {
+        buffer[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn put_u64(buffer: &mut [u8], offset: usize, value: u64) {
+        buffer[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn set_checksum(buffer: &mut [u8]) {
+        put_u32(buffer, 4, 0);
+        let checksum = crc32c(buffer);
+        put_u32(buffer, 4, checksum);
+    }
+
+    fn data_block() -> Vec<u8> {
+        (0..LOG_SECTOR_SIZE).map(|i| (i * 31 + 7) as u8).collect()
+    }
+
+    /// Writes an image whose only valid header references a log holding a single entry
+    /// with a data descriptor and a zero descriptor, both covering bytes set to 0xFF.
+    fn craft_image(name: &str, corrupt_entry: bool) -> CraftedImage {
+        let mut file = vec![0; FILE_SIZE];
+        file[..VHDX_SIGNATURE.len()].copy_from_slice(VHDX_SIGNATURE);
+
+        let header = &mut file[HEADER_OFFSETS[0] as usize..][..HEADER_SIZE];
+        put_u32(header, 0, HEADER_SIGNATURE);
+        put_u64(header, 8, 1);
+        header[48..64].copy_from_slice(&LOG_GUID);
+        header[66..68].copy_from_slice(&1u16.to_le_bytes());
+        put_u32(header, 68, LOG_LENGTH as u32);
+        put_u64(header, 72, LOG_OFFSET as u64);
+        set_checksum(header);
+
+        let block = data_block();
+        let entry = &mut file[LOG_OFFSET..][..2 * LOG_SECTOR_SIZE];
+        put_u32(entry, 0, LOG_ENTRY_SIGNATURE);
+        put_u32(entry, 8, (2 * LOG_SECTOR_SIZE) as u32);
+        put_u32(entry, 12, 0);
+        put_u64(entry, 16, ENTRY_SEQUENCE_NUMBER);
+        put_u32(entry, 24, 2);
+        entry[32..48].copy_from_slice(&LOG_GUID);
+        put_u64(entry, 48, FILE_SIZE as u64);
+        put_u64(entry, 56, LAST_FILE_OFFSET);
+
+        let data_descriptor = LOG_ENTRY_HEADER_SIZE;
+        put_u32(entry, data_descriptor, DATA_DESCRIPTOR_SIGNATURE);
+        entry[data_descriptor + 4..data_descriptor + 8]
+            .copy_from_slice(&block[LOG_SECTOR_SIZE - 4..]);
+        entry[data_descriptor + 8..data_descriptor + 16].copy_from_slice(&block[..8]);
+        put_u64(entry, data_descriptor + 16, DATA_OFFSET as u64);
+        put_u64(entry, data_descriptor + 24, ENTRY_SEQUENCE_NUMBER);
+
+        let zero_descriptor = LOG_ENTRY_HEADER_SIZE + LOG_DESCRIPTOR_SIZE;
+        put_u32(entry, zero_descriptor, ZERO_DESCRIPTOR_SIGNATURE);
+        put_u64(entry, zero_descriptor + 8, LOG_SECTOR_SIZE as u64);
+        put_u64(entry, zero_descriptor + 16, ZERO_OFFSET as u64);
+        put_u64(entry, zero_descriptor + 24, ENTRY_SEQUENCE_NUMBER);
+
+        let sector = LOG_SECTOR_SIZE;
+        put_u32(entry, sector, DATA_SECTOR_SIGNATURE);
+        put_u32(entry, sector + 4, (ENTRY_SEQUENCE_NUMBER >> 32) as u32);
+        entry[sector + 8..sector + LOG_SECTOR_SIZE - 4]
+            .copy_from_slice(&block[8..LOG_SECTOR_SIZE - 4]);
+        put_u32(
+            entry,
+            sector + LOG_SECTOR_SIZE - 4,
+            ENTRY_SEQUENCE_NUMBER as u32,
+        );
+        set_checksum(entry);
+
+        if corrupt_entry {
+            entry[sector + 100] ^= 0xFF;
+        }
+
+        for byte in &mut file[DATA_OFFSET..FILE_SIZE] {
+            *byte = 0xFF;
+        }
+
+        let path =
+            std::env::temp_dir().join(format!("vhdformat_{}_{}.vhdx", name, std::process::id()));
+        std::fs::write(&path, &file).unwrap();
+        CraftedImage { path }
+    }
+
+    #[test]
+    fn inspect_log_reports_pending_entry() {
+        let image = craft_image("inspect", false);
+        let report = inspect_log(image.path()).unwrap();
+
+        assert!(report.is_dirty());
+        assert_eq!(1, report.header.sequence_number);
+        assert_eq!(LOG_OFFSET as u64, report.header.log_offset);
+        assert_eq!(
+            vec![LogEntry {
+                offset: 0,
+                sequence_number: ENTRY_SEQUENCE_NUMBER,
+                tail: 0,
+                length: (2 * LOG_SECTOR_SIZE) as u32,
+                descriptor_count: 2,
+                flushed_file_offset: FILE_SIZE as u64,
+                last_file_offset: LAST_FILE_OFFSET,
+            }],
+            report.pending_entries
+        );
+    }
+
+    #[test]
+    fn inspect_log_skips_corrupt_entry() {
+        let image = craft_image("corrupt", true);
+        let report = inspect_log(image.path()).unwrap();
+
+        assert!(report.header.has_log());
+        assert!(report.pending_entries.is_empty());
+        assert!(!report.is_dirty());
+    }
+
+    #[test]
+    fn recover_log_replays_pending_entry() {
+        let image = craft_image("replay", false);
+        let report = recover_log(image.path(), LogRecovery::Replay).unwrap();
+        assert_eq!(1, report.pending_entries.len());
+
+        let file = std::fs::read(image.path()).unwrap();
+        assert_eq!(LAST_FILE_OFFSET as usize, file.len());
+        assert_eq!(data_block(), file[DATA_OFFSET..ZERO_OFFSET].to_vec());
+        assert!(file[ZERO_OFFSET..FILE_SIZE].iter().all(|byte| *byte == 0));
+
+        // Both headers are rewritten with an empty log, the current one last.
+        let report = inspect_log(image.path()).unwrap();
+        assert!(!report.header.has_log());
+        assert!(!report.is_dirty());
+        assert_eq!(3, report.header.sequence_number);
+        assert_eq!(LOG_OFFSET as u64, report.header.log_offset);
+    }
+
+    #[test]
+    fn recover_log_discards_pending_entry() {
+        let image = craft_image("discard", false);
+        let report = recover_log(image.path(), LogRecovery::Discard).unwrap();
+        assert_eq!(1, report.pending_entries.len());
+
+        let file = std::fs::read(image.path()).unwrap();
+        assert_eq!(FILE_SIZE, file.len());
+        assert!(file[DATA_OFFSET..FILE_SIZE]
+            .iter()
+            .all(|byte| *byte == 0xFF));
+
+        let report = inspect_log(image.path()).unwrap();
+        assert!(!report.header.has_log());
+        assert_eq!(3, report.header.sequence_number);
+    }
+}
@@ -0,0 +1,286 @@
+// Copyright (c) 2019 Rafael Alcaraz Mercado. All rights reserved.
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// All files in the project carrying such notice may not be copied, modified, or distributed
+// except according to those terms.
+// THE SOURCE CODE IS AVAILABLE UNDER THE ABOVE CHOSEN LICENSE "AS IS", WITH NO WARRANTIES.
+
+//! Wrappers around VHD Set (.vhds) functions used to work with point-in-time snapshots.
+
+use crate::virtdisk::*;
+use crate::virtdiskdefs::*;
+use crate::winutilities::guid_from_le_bytes;
+use winutils_rs::errorcodes::{WinResult, WinResultCode};
+use winutils_rs::utilities::guid_are_equal;
+use winutils_rs::windefs::*;
+
+/// Opens the virtual disk of a VHD Set as it was at the given snapshot,
+/// without applying the snapshot to the set.
+///
+/// Opening a snapshot requires the virtual disk of the set to be opened, so this can't
+/// be combined with `open_virtual_disk::Flag::VhdsetFileOnly`. Use `open_vhdset_file_only`
+/// for operations that only touch the set file itself.
+pub fn open_snapshot(
+    vhds_path: &str,
+    snapshot_id: impl Into<Guid>,
+    read_only: bool,
+) -> WinResult<VirtualDisk> {
+    let snapshot_id: Guid = snapshot_id.into();
+
+    if guid_are_equal(&snapshot_id, &GUID_NULL) {
+        return Err(WinResultCode::ErrorInvalidArgument);
+    }
+
+    let parameters = open_virtual_disk::Parameters {
+        version: open_virtual_disk::Version::Version3,
+        version_details: open_virtual_disk::VersionDetails {
+            version3: open_virtual_disk::Version3 {
+                get_info_only: 0,
+                read_only: read_only as Bool,
+                resiliency_guid: GUID_NULL,
+                snapshot_id,
+            },
+        },
+    };
+
+    VirtualDisk::open(
+        VirtualStorageType::vhdset(),
+        vhds_path,
+        VirtualDiskAccessMask::None,
+        open_virtual_disk::Flag::None as u32,
+        Some(&parameters),
+    )
+}
+
+/// Opens a VHD Set file without opening any of its virtual disks.
+/// The returned handle can be used to modify the set (e.g. `VirtualDisk::modify_vhdset`),
+/// but can't be used to read or attach the disk.
+pub fn open_vhdset_file_only(vhds_path: &str) -> WinResult<VirtualDisk> {
+    let parameters = open_virtual_disk::Parameters {
+        version: open_virtual_disk::Version::Version2,
+        version_details: open_virtual_disk::VersionDetails {
+            version2: open_virtual_disk::Version2 {
+                get_info_only: 0,
+                read_only: 0,
+                resiliency_guid: GUID_NULL,
+            },
+        },
+    };
+
+    VirtualDisk::open(
+        VirtualStorageType::vhdset(),
+        vhds_path,
+        VirtualDiskAccessMask::None,
+        open_virtual_disk::Flag::VhdsetFileOnly as u32,
+        Some(&parameters),
+    )
+}
+
+/// Takes a writable snapshot (reference point) of a VHD Set and makes the given leaf path
+/// the new default leaf of the set, mirroring the production checkpoint flow used by Hyper-V
+/// for guest backups.
+/// If updating the default leaf fails, the snapshot is deleted so that the set is left
+/// as it was before this call.
+pub fn take_writable_snapshot(
+    virtual_disk: &VirtualDisk,
+    snapshot_id: impl Into<Guid>,
+    leaf_path: &str,
+) -> WinResult<()> {
+    let snapshot_id: Guid = snapshot_id.into();
+
+    if guid_are_equal(&snapshot_id, &GUID_NULL) {
+        return Err(WinResultCode::ErrorInvalidArgument);
+    }
+
+    let take_parameters = take_snapshot_vhdset::Parameters {
+        version: take_snapshot_vhdset::Version::Version1,
+        version_details: take_snapshot_vhdset::VersionDetails {
+            version1: take_snapshot_vhdset::Version1 { snapshot_id },
+        },
+    };
+
+    virtual_disk.take_snapshot_vhdset(
+        &take_parameters,
+        take_snapshot_vhdset::Flag::Writable as u32,
+    )?;
+
+    let leaf_path_wstr = widestring::WideCString::from_str(leaf_path).unwrap();
+    let modify_parameters = modify_vhdset::Parameters {
+        version: modify_vhdset::Version::DefaultSnapshotPath,
+        version_details: modify_vhdset::VersionDetails {
+            default_file_path: leaf_path_wstr.as_ptr(),
+        },
+    };
+
+    if let Err(error) = virtual_disk.modify_vhdset(
+        &modify_parameters,
+        modify_vhdset::Flag::WritableSnapshot as u32,
+    ) {
+        let delete_parameters = delete_snapshot_vhdset::Parameters {
+            version: delete_snapshot_vhdset::Version::Version1,
+            version_details: delete_snapshot_vhdset::VersionDetails {
+                version1: delete_snapshot_vhdset::Version1 { snapshot_id },
+            },
+        };
+
+        // Roll back the snapshot, the original error is the one worth surfacing.
+        #[allow(unused_must_use)]
+        {
+            virtual_disk.delete_snapshot_vhdset(
+                &delete_parameters,
+                delete_snapshot_vhdset::Flag::None as u32,
+            );
+        }
+
+        return Err(error);
+    }
+
+    Ok(())
+}
+
+/// Snapshot stored in a VHD Set file.
+/// The IDs are plain `Guid`s for compatibility, wrap them in `WinGuid` to compare or print them.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone)]
+pub struct SnapshotEntry {
+    /// Identifier of the snapshot.
+    #[cfg_attr(feature = "serde", serde(with = "crate::winutilities::serde_guid"))]
+    pub snapshot_id: Guid,
+
+    /// Identifier of the snapshot this snapshot was taken from,
+    /// or `GUID_NULL` if this is the first snapshot in the set.
+    #[cfg_attr(feature = "serde", serde(with = "crate::winutilities::serde_guid"))]
+    pub parent_snapshot_id: Guid,
+
+    /// Creation time of the snapshot, as a FILETIME value
+    /// (100-nanosecond intervals since January 1, 1601 UTC).
+    pub creation_time: u64,
+
+    /// Path to the backing file that holds the snapshot.
+    pub file_path: String,
+}
+
+/// Lists the snapshots stored in a VHD Set file.
+///
+/// The set is opened without any of its virtual disks and every metadata item is decoded
+/// as a snapshot record. A snapshot record is laid out as the snapshot ID, the parent snapshot ID,
+/// the creation FILETIME, the byte length of the backing file path and the UTF-16 backing file path.
+/// Metadata items that don't decode as snapshot records, or whose snapshot ID doesn't match
+/// the metadata item ID, are skipped.
+pub fn list_snapshots(vhds_path: &str) -> WinResult<Vec<SnapshotEntry>> {
+    let vhdset = open_vhdset_file_only(vhds_path)?;
+    let mut snapshots: Vec<SnapshotEntry> = Vec::new();
+
+    for item in vhdset.enumerate_metadata()? {
+        let buffer = vhdset.get_metadata(&item)?;
+
+        if let Some(snapshot) = parse_snapshot_record(&buffer) {
+            if guid_are_equal(&snapshot.snapshot_id, &item) {
+                snapshots.push(snapshot);
+            }
+        }
+    }
+
+    snapshots.sort_by_key(|snapshot| snapshot.creation_time);
+    Ok(snapshots)
+}
+
+/// Decodes a snapshot record from a raw metadata buffer.
+/// Returns None if the buffer is not a well formed snapshot record.
+fn parse_snapshot_record(buffer: &[u8]) -> Option<SnapshotEntry> {
+    const HEADER_SIZE: usize = 16 + 16 + 8 + 4;
+
+    if buffer.len() < HEADER_SIZE {
+        return None;
+    }
+
+    let mut creation_time: [u8; 8] = [0; 8];
+    creation_time.copy_from_slice(&buffer[32..40]);
+    let path_length = u32::from_le_bytes([buffer[40], buffer[41], buffer[42], buffer[43]]) as usize;
+
+    if path_length % 2 != 0 || buffer.len() < HEADER_SIZE + path_length {
+        return None;
+    }
+
+    let path_wchars: Vec<WChar> = buffer[HEADER_SIZE..HEADER_SIZE + path_length]
+        .chunks(2)
+        .map(|wchar| u16::from_le_bytes([wchar[0], wchar[1]]))
+        .take_while(|wchar| *wchar != 0)
+        .collect();
+
+    let file_path = match String::from_utf16(&path_wchars) {
+        Ok(file_path) => file_path,
+        Err(_) => return None,
+    };
+
+    Some(SnapshotEntry {
+        snapshot_id: guid_from_le_bytes(&buffer[0..16]),
+        parent_snapshot_id: guid_from_le_bytes(&buffer[16..32]),
+        creation_time: u64::from_le_bytes(creation_time),
+        file_path,
+    })
+}
+
+/// Returns the byte ranges of the virtual disk that changed between two snapshots of a VHD Set,
+/// so replication tools can ship only the delta between checkpoints.
+///
+/// The most recent resilient change tracking ID of the `from` snapshot is queried against
+/// the `to` snapshot, which requires change tracking to have been enabled on the set
+/// before `from` was taken. Otherwise `QueryChangesVirtualDisk` fails and the error is returned.
+pub fn diff_snapshots(
+    vhds_path: &str,
+    from: impl Into<Guid>,
+    to: impl Into<Guid>,
+) -> WinResult<Vec<query_changes_virtual_disk::Range>> {
+    let (from, to): (Guid, Guid) = (from.into(), to.into());
+
+    if guid_are_equal(&from, &GUID_NULL) || guid_are_equal(&to, &GUID_NULL) {
+        return Err(WinResultCode::ErrorInvalidArgument);
+    }
+
+    let change_tracking_id = open_snapshot(vhds_path, from, true)?
+        .get_information(get_virtual_disk::InfoVersion::ChangeTrackingState)?
+        .most_recent_change_tracking_id()?;
+
+    open_snapshot(vhds_path, to, true)?.query_all_changes(&change_tracking_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(path: &str, path_length_delta: i32) -> Vec<u8> {
+        let path_bytes: Vec<u8> = path.encode_utf16().flat_map(u16::to_le_bytes).collect();
+        let mut buffer: Vec<u8> = (1..=16).chain(17..=32).collect();
+        buffer.extend_from_slice(&0x01D5_0000_0000_0000u64.to_le_bytes());
+        buffer.extend_from_slice(
+            &((path_bytes.len() as i32 + path_length_delta) as u32).to_le_bytes(),
+        );
+        buffer.extend_from_slice(&path_bytes);
+        buffer
+    }
+
+    #[test]
+    fn parses_snapshot_record() {
+        let snapshot = parse_snapshot_record(&record("C:\\vhds\\a.avhdx", 0)).unwrap();
+
+        assert!(guid_are_equal(
+            &crate::winutilities::guid_from_le_bytes(&(1..=16).collect::<Vec<u8>>()),
+            &snapshot.snapshot_id
+        ));
+        assert!(guid_are_equal(
+            &crate::winutilities::guid_from_le_bytes(&(17..=32).collect::<Vec<u8>>()),
+            &snapshot.parent_snapshot_id
+        ));
+        assert_eq!(0x01D5_0000_0000_0000, snapshot.creation_time);
+        assert_eq!("C:\\vhds\\a.avhdx", snapshot.file_path);
+    }
+
+    #[test]
+    fn skips_malformed_snapshot_records() {
+        assert!(parse_snapshot_record(&record("a.avhdx", 2)).is_none());
+        assert!(parse_snapshot_record(&record("a.avhdx", -1)).is_none());
+        assert!(parse_snapshot_record(&record("a.avhdx", 0)[..40]).is_none());
+    }
+}
@@ -11,6 +11,7 @@
 use crate::diskutilities::*;
 use crate::virtdisk::*;
 use crate::virtdiskdefs::*;
+use crate::winutilities::*;
 use winutils_rs::errorcodes::{
     error_code_to_winresult_code, winresult_code_to_error_code, WinResult, WinResultCode,
 };
@@ -23,17 +24,189 @@ pub struct MountedVolume {
     pub partition: PartitionInfo,
 }
 
+/// Workloads for which `recommended_geometry` knows a suitable disk geometry.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Workload {
+    /// General purpose data disks.
+    General,
+
+    /// Container scratch (sandbox) layers, which are small, short lived and mostly sparse.
+    ContainerScratch,
+
+    /// SQL Server data and log files.
+    SqlData,
+
+    /// Persistent memory (DAX) disks.
+    PersistentMemory,
+}
+
+/// Block size, sector sizes and partition alignment of a new VHD.
+/// A value of zero lets the provider choose its default.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct DiskGeometry {
+    /// Size of the blocks the VHD grows by, in bytes.
+    pub block_size_in_bytes: u32,
+
+    /// Logical sector size reported by the disk, in bytes.
+    pub logical_sector_size_in_bytes: u32,
+
+    /// Physical sector size reported by the disk, in bytes.
+    pub physical_sector_size_in_bytes: u32,
+
+    /// Alignment of the data partition `create_base_vhd` creates on the disk, in bytes.
+    /// Zero aligns it to 1 MB.
+    pub alignment_in_bytes: u64,
+}
+
+impl DiskGeometry {
+    /// Geometry with the given block size in MB and provider defaults for everything else.
+    pub fn from_block_size_mb(block_size_mb: u32) -> DiskGeometry {
+        DiskGeometry {
+            block_size_in_bytes: block_size_mb * 1024 * 1024,
+            ..Default::default()
+        }
+    }
+}
+
+/// Returns the suggested geometry for a new VHD holding the given workload.
+pub fn recommended_geometry(workload: Workload) -> DiskGeometry {
+    const KB: u32 = 1024;
+    const MB: u32 = 1024 * 1024;
+
+    match workload {
+        Workload::General => DiskGeometry {
+            block_size_in_bytes: 32 * MB,
+            logical_sector_size_in_bytes: 512,
+            physical_sector_size_in_bytes: 4 * KB,
+            alignment_in_bytes: MB as u64,
+        },
+        // Small blocks keep the layer from growing on scattered writes.
+        Workload::ContainerScratch => DiskGeometry {
+            block_size_in_bytes: MB,
+            logical_sector_size_in_bytes: 512,
+            physical_sector_size_in_bytes: 4 * KB,
+            alignment_in_bytes: MB as u64,
+        },
+        // Native 4K sectors avoid read-modify-write cycles on log writes.
+        Workload::SqlData => DiskGeometry {
+            block_size_in_bytes: 32 * MB,
+            logical_sector_size_in_bytes: 4 * KB,
+            physical_sector_size_in_bytes: 4 * KB,
+            alignment_in_bytes: MB as u64,
+        },
+        // DAX volumes map large pages, which need 2 MB alignment.
+        Workload::PersistentMemory => DiskGeometry {
+            block_size_in_bytes: 0,
+            logical_sector_size_in_bytes: 4 * KB,
+            physical_sector_size_in_bytes: 4 * KB,
+            alignment_in_bytes: (2 * MB) as u64,
+        },
+    }
+}
+
+/// Smallest virtual size accepted for VHDs and VHDXs, in bytes.
+pub const VIRTUAL_DISK_MIN_SIZE: u64 = 3 * 1024 * 1024;
+
+/// Largest virtual size of a VHD, in bytes.
+pub const VHD_MAX_SIZE: u64 = 2040 * 1024 * 1024 * 1024;
+
+/// Largest virtual size of a VHDX, in bytes.
+pub const VHDX_MAX_SIZE: u64 = 64 * 1024 * 1024 * 1024 * 1024;
+
+/// Virtual sizes must be a multiple of the sector size.
+const VIRTUAL_DISK_SIZE_ALIGNMENT: u64 = 512;
+
+/// Size of a virtual disk, built through unit-safe constructors.
+/// Constructors saturate on overflow instead of wrapping around, so that an overflowing size
+/// is rejected by `validate` rather than silently producing a tiny disk.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SizeSpec {
+    bytes: u64,
+}
+
+impl SizeSpec {
+    /// Size in bytes.
+    pub fn bytes(bytes: u64) -> SizeSpec {
+        SizeSpec { bytes }
+    }
+
+    /// Size in mebibytes.
+    pub fn mib(mib: u64) -> SizeSpec {
+        SizeSpec {
+            bytes: mib.saturating_mul(1024 * 1024),
+        }
+    }
+
+    /// Size in gibibytes.
+    pub fn gib(gib: u64) -> SizeSpec {
+        SizeSpec {
+            bytes: gib.saturating_mul(1024 * 1024 * 1024),
+        }
+    }
+
+    /// Returns the size in bytes, without validating it.
+    pub fn as_bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Validates the size against the limits of the given storage type and returns it in bytes.
+    /// Storage types detected by the provider are held to the VHDX limits.
+    /// Returns ErrorInvalidArgument if the size is out of the limits, isn't sector aligned,
+    /// or the storage type can't be sized (e.g. ISO).
+    pub fn validate(&self, storage_type: &VirtualStorageType) -> WinResult<u64> {
+        let max_size = match storage_type.device_id {
+            VIRTUAL_STORAGE_TYPE_DEVICE_VHD => VHD_MAX_SIZE,
+            VIRTUAL_STORAGE_TYPE_DEVICE_VHDX
+            | VIRTUAL_STORAGE_TYPE_DEVICE_VHDSET
+            | VIRTUAL_STORAGE_TYPE_DEVICE_UNKNOWN => VHDX_MAX_SIZE,
+            _ => return Err(WinResultCode::ErrorInvalidArgument),
+        };
+
+        if self.bytes < VIRTUAL_DISK_MIN_SIZE
+            || self.bytes > max_size
+            || self.bytes % VIRTUAL_DISK_SIZE_ALIGNMENT != 0
+        {
+            return Err(WinResultCode::ErrorInvalidArgument);
+        }
+
+        Ok(self.bytes)
+    }
+
+    /// Validates the size for a virtual disk whose storage type is detected from the file name.
+    fn validate_for_file(&self, filename: &str) -> WinResult<u64> {
+        self.validate(&detect_from_extension(filename).unwrap_or(VirtualStorageType::auto()))
+    }
+}
+
 /// Creates a new VHD specified by filename.
-pub fn create_vhd(filename: &str, disk_size_gb: u64, block_size_mb: u32) -> WinResult<VirtualDisk> {
+pub fn create_vhd(filename: &str, size: SizeSpec, block_size_mb: u32) -> WinResult<VirtualDisk> {
+    create_vhd_with_geometry(
+        filename,
+        size,
+        &DiskGeometry::from_block_size_mb(block_size_mb),
+    )
+}
+
+/// Creates a new VHD specified by filename with the given geometry.
+pub fn create_vhd_with_geometry(
+    filename: &str,
+    size: SizeSpec,
+    geometry: &DiskGeometry,
+) -> WinResult<VirtualDisk> {
     let mut parameters = unsafe { std::mem::zeroed::<create_virtual_disk::Parameters>() };
     parameters.version = create_virtual_disk::Version::Version2;
-    parameters.version_details.version2.maximum_size = disk_size_gb * 1024 * 1024 * 1024;
-    parameters.version_details.version2.block_size_in_bytes = block_size_mb * 1024 * 1024;
+    parameters.version_details.version2.maximum_size = size.validate_for_file(filename)?;
+    parameters.version_details.version2.block_size_in_bytes = geometry.block_size_in_bytes;
+    parameters.version_details.version2.sector_size_in_bytes =
+        geometry.logical_sector_size_in_bytes;
+    parameters
+        .version_details
+        .version2
+        .physical_sector_size_in_bytes = geometry.physical_sector_size_in_bytes;
 
-    let default_storage_type = VirtualStorageType {
-        device_id: 0,
-        vendor_id: GUID_NULL,
-    };
+    let default_storage_type = VirtualStorageType::auto();
 
     VirtualDisk::create(
         default_storage_type,
@@ -41,18 +214,246 @@ pub fn create_vhd(filename: &str, disk_size_gb: u64, block_size_mb: u32) -> WinR
         VirtualDiskAccessMask::None,
         None,
         create_virtual_disk::Flag::None as u32,
-        0,
+        provider_flags::ProviderFlags::None.bits(),
         &parameters,
         None,
     )
 }
 
+/// Logical and physical sector sizes of a disk, in bytes.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SectorSizes {
+    pub logical: u32,
+    pub physical: u32,
+}
+
+/// Sector sizes of a VHD compared with those of the physical disk hosting its file.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SectorSizeCheck {
+    pub vhd: SectorSizes,
+    pub host: SectorSizes,
+}
+
+impl SectorSizeCheck {
+    /// True if the logical sector sizes differ, e.g. a 4K native VHDX on a 512e host.
+    /// IO through the attached disk can then fail in subtle ways, so mounting is refused.
+    pub fn is_mismatched(&self) -> bool {
+        self.vhd.logical != self.host.logical
+    }
+
+    /// True if only the physical sector sizes differ.
+    /// This is safe but costs read-modify-write cycles, so it's only worth a warning.
+    pub fn is_suboptimal(&self) -> bool {
+        !self.is_mismatched() && self.vhd.physical != self.host.physical
+    }
+}
+
+/// Compares the sector sizes of a VHD with those of the physical disk hosting its file.
+pub fn check_sector_sizes(virtual_disk: &VirtualDisk) -> WinResult<SectorSizeCheck> {
+    let size_info = virtual_disk.get_information(get_virtual_disk::InfoVersion::Size)?;
+    let physical_sector_info =
+        virtual_disk.get_information(get_virtual_disk::InfoVersion::VhdPhysicalSectorSize)?;
+    let host_info = virtual_disk.get_information(get_virtual_disk::InfoVersion::PhysicalDisk)?;
+
+    unsafe {
+        let host = &host_info.info().version_details.physical_disk;
+
+        Ok(SectorSizeCheck {
+            vhd: SectorSizes {
+                logical: size_info.info().version_details.size.sector_size,
+                physical: physical_sector_info
+                    .info()
+                    .version_details
+                    .vhd_physical_sector_size,
+            },
+            host: SectorSizes {
+                logical: host.logical_sector_size,
+                physical: host.physical_sector_size,
+            },
+        })
+    }
+}
+
+/// Refuses to mount a VHD whose logical sector size differs from the one of its host,
+/// failing with ERROR_VIRTDISK_UNSUPPORTED_DISK_SECTOR_SIZE.
+/// VHDs whose sector sizes can't be queried (e.g. ISO images) aren't checked.
+fn ensure_sector_sizes_match(virtual_disk: &VirtualDisk) -> WinResult<()> {
+    match check_sector_sizes(virtual_disk) {
+        Ok(check) if check.is_mismatched() => Err(error_code_to_winresult_code(
+            winapi::shared::winerror::ERROR_VIRTDISK_UNSUPPORTED_DISK_SECTOR_SIZE as u32,
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// VHD_WRITE_CACHE_MODE values under which writes to the attached disk can be acknowledged
+/// before they reach the backing file: WRITE_INTERNALLY_CACHED, WRITE_CACHED and DISABLE_FLUSHING.
+const WRITE_CACHED_MODES: [u16; 3] = [1, 2, 4];
+
+/// Refuses to attach a VHD read-write with a write cached mode when its backing file lives on
+/// a remote share, failing with ERROR_NOT_SUPPORTED.
+/// Flushes issued to the disk don't reach the share in those modes, so a host or network failure
+/// loses acknowledged writes; use `VHD_WRITE_CACHE_MODE_CACHE_METADATA` (0) on remote shares.
+/// VHDs whose host can't be queried aren't checked.
+fn ensure_remote_cache_safe(
+    virtual_disk: &VirtualDisk,
+    flags: u32,
+    cache_mode: u16,
+) -> WinResult<()> {
+    if flags & attach_virtual_disk::Flag::ReadOnly as u32 != 0
+        || !WRITE_CACHED_MODES.contains(&cache_mode)
+    {
+        return Ok(());
+    }
+
+    match virtual_disk.is_remote_backed() {
+        Ok(true) => Err(error_code_to_winresult_code(
+            winapi::shared::winerror::ERROR_NOT_SUPPORTED,
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Refuses to attach a VHD with drive letters from session 0, failing with ERROR_NOT_SUPPORTED.
+/// See `MountOptions::refuse_service_drive_letters`.
+fn ensure_no_service_drive_letters(flags: u32) -> WinResult<()> {
+    if flags & attach_virtual_disk::Flag::NoDriveLetter as u32 != 0 {
+        return Ok(());
+    }
+
+    match current_session_id() {
+        Ok(0) => Err(error_code_to_winresult_code(
+            winapi::shared::winerror::ERROR_NOT_SUPPORTED,
+        )),
+        _ => Ok(()),
+    }
+}
+
 /// Mounts the given VHD into the host.
+/// Fails with ERROR_PRIVILEGE_NOT_HELD if the privilege to manage volumes can't be enabled.
 /// The flags are a u32 representation of any valid combination from `attach_virtual_disk::Flag` values.
 pub fn mount_vhd(virtual_disk: &VirtualDisk, flags: u32, cache_mode: u16) -> WinResult<()> {
-    use winapi::um::{errhandlingapi, ioapiset, winnt};
+    mount_vhd_with_profile(virtual_disk, flags, cache_mode, IoProfile::default())
+}
+
+/// Mounts the given VHD into the host, opening the disk it surfaces with the given IO profile.
+/// The flags are a u32 representation of any valid combination from `attach_virtual_disk::Flag` values.
+pub fn mount_vhd_with_profile(
+    virtual_disk: &VirtualDisk,
+    flags: u32,
+    cache_mode: u16,
+    io_profile: IoProfile,
+) -> WinResult<()> {
+    mount_vhd_until(virtual_disk, flags, cache_mode, io_profile, None)
+}
+
+/// Mounts the given VHD into the host, failing with ERROR_TIMEOUT if surfacing the disk
+/// and waiting for it to arrive takes longer than the given timeout.
+/// The virtual disk is detached on timeout, including when the surfacing request
+/// completes after the timeout expired.
+/// The flags are a u32 representation of any valid combination from `attach_virtual_disk::Flag` values.
+pub fn mount_vhd_with_timeout(
+    virtual_disk: &VirtualDisk,
+    flags: u32,
+    cache_mode: u16,
+    timeout: std::time::Duration,
+) -> WinResult<()> {
+    mount_vhd_until(
+        virtual_disk,
+        flags,
+        cache_mode,
+        IoProfile::default(),
+        Some(std::time::Instant::now() + timeout),
+    )
+}
+
+fn mount_vhd_until(
+    virtual_disk: &VirtualDisk,
+    flags: u32,
+    cache_mode: u16,
+    io_profile: IoProfile,
+    deadline: Option<std::time::Instant>,
+) -> WinResult<()> {
+    use std::os::windows::io::{AsRawHandle, BorrowedHandle, RawHandle};
+    use winapi::um::winnt;
+
+    let manage_volume = TemporaryPrivileges::acquire(&[winnt::SE_MANAGE_VOLUME_NAME])?;
+
+    match time_left(deadline)? {
+        Some(timeout) => {
+            // The surfacing request can't be cancelled once issued, run it on a duplicated
+            // handle so that it stays valid if the wait gives up on it.
+            let handle =
+                unsafe { BorrowedHandle::borrow_raw(virtual_disk.get_handle() as RawHandle) }
+                    .try_clone_to_owned()
+                    .map_err(|error| io_error_to_winresult_code(&error))?;
+            let abandoned = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+            let worker_abandoned = abandoned.clone();
+
+            let result = with_timeout(timeout, move || {
+                let handle = handle.as_raw_handle() as Handle;
+                surface_virtual_disk(handle, flags, cache_mode)?;
+
+                // The caller gave up while the disk was being surfaced, don't leave it attached.
+                if worker_abandoned.load(std::sync::atomic::Ordering::SeqCst) {
+                    unsafe {
+                        crate::virtdisk_bindings::DetachVirtualDisk(
+                            handle,
+                            detach_virtual_disk::Flag::None as u32,
+                            provider_flags::ProviderFlags::None.bits(),
+                        );
+                    }
+                }
+
+                Ok(())
+            });
+
+            if let Err(error) = result {
+                if winresult_code_to_error_code(error) == winapi::shared::winerror::ERROR_TIMEOUT {
+                    abandoned.store(true, std::sync::atomic::Ordering::SeqCst);
+
+                    // The request may have completed right after the wait gave up on it.
+                    #[allow(unused_must_use)]
+                    {
+                        virtual_disk.detach(
+                            detach_virtual_disk::Flag::None as u32,
+                            provider_flags::ProviderFlags::None.bits(),
+                        );
+                    }
+                }
+
+                return Err(error);
+            }
+        }
+        None => surface_virtual_disk(virtual_disk.get_handle(), flags, cache_mode)?,
+    }
+
+    // Make sure we revert the temporary privilege to manage volumes
+    drop(manage_volume);
+
+    let disk = time_left(deadline).and_then(|timeout| {
+        let disk_path = virtual_disk.get_physical_path()?;
+        wait_for_disk(&disk_path, timeout.unwrap_or(DISK_ARRIVAL_DEFAULT_TIMEOUT))?;
+        Disk::open(&disk_path, None, Some(io_profile.disk_file_flags()))
+    });
+
+    match disk.and_then(|disk| disk.force_online()) {
+        Err(error) => {
+            virtual_disk.detach(
+                detach_virtual_disk::Flag::None as u32,
+                provider_flags::ProviderFlags::None.bits(),
+            )?;
+            Err(error)
+        }
+        _ => Ok(()),
+    }
+}
 
-    let manage_volume = TemporaryPrivilege::new(winnt::SE_MANAGE_VOLUME_NAME);
+/// Surfaces the disk of the virtual disk whose handle is supplied, attaching it to the host.
+fn surface_virtual_disk(handle: Handle, flags: u32, cache_mode: u16) -> WinResult<()> {
+    use winapi::um::{errhandlingapi, ioapiset};
 
     #[repr(C)]
     pub struct StorageSurfaceVirtualDiskLev1Request {
@@ -75,7 +476,7 @@ pub fn mount_vhd(virtual_disk: &VirtualDisk, flags: u32, cache_mode: u16) -> Win
         request.cache_mode = cache_mode;
 
         if ioapiset::DeviceIoControl(
-            virtual_disk.get_handle(),
+            handle,
             2955548, // IOCTL_STORAGE_SURFACE_VIRTUAL_DISK
             &mut request as *mut _ as PVoid,
             std::mem::size_of::<StorageSurfaceVirtualDiskLev1Request>() as DWord,
@@ -89,17 +490,7 @@ pub fn mount_vhd(virtual_disk: &VirtualDisk, flags: u32, cache_mode: u16) -> Win
         }
     }
 
-    // Make sure we revert the temporary privilege to manage volumes
-    drop(manage_volume);
-
-    let disk = open_vhd_backed_disk(&virtual_disk)?;
-    match disk.force_online() {
-        Err(error) => {
-            virtual_disk.detach(detach_virtual_disk::Flag::None as u32, 0)?;
-            Err(error)
-        }
-        _ => Ok(()),
-    }
+    Ok(())
 }
 
 /// Mounts a VHD with temporary lifetime and without respecting flushes.
@@ -127,140 +518,1964 @@ pub fn mount_vhd_permanently_for_use(virtual_disk: &VirtualDisk) -> WinResult<()
     )
 }
 
-/// Dismounts the given VHD from the host.
-pub fn dismount_vhd(virtual_disk: &VirtualDisk) -> WinResult<()> {
-    virtual_disk.detach(detach_virtual_disk::Flag::None as u32, 0)
-}
+/// Name of a Windows privilege, e.g. `winapi::um::winnt::SE_MANAGE_VOLUME_NAME`.
+pub type PrivilegeName = &'static str;
 
-/// Opens a VHD for use as a container sandbox and returns a safe wrapper over the handle.
-pub fn open_vhd(filename: &str, read_only: bool) -> WinResult<VirtualDisk> {
-    let default_storage_type = VirtualStorageType {
-        device_id: 0,
-        vendor_id: VIRTUAL_STORAGE_TYPE_VENDOR_UNKNOWN,
-    };
+/// VHD operations whose privilege requirements can be checked up front.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Operation {
+    Create,
+    Open,
+    QueryInformation,
+    /// Attaches through `mount_vhd`, which surfaces the disk with a storage ioctl.
+    Mount,
+    Detach,
+    Compact,
+    Merge,
+    Resize,
+    /// Grows or shrinks the file system of a volume of an attached VHD.
+    ResizeVolume,
+    Format,
+}
 
-    let parameters = open_virtual_disk::Parameters {
-        version: open_virtual_disk::Version::Version2,
-        version_details: open_virtual_disk::VersionDetails {
-            version2: open_virtual_disk::Version2 {
-                get_info_only: 0,
-                read_only: read_only as Bool,
-                resiliency_guid: GUID_NULL,
-            },
-        },
-    };
+/// Returns the privileges the current process must hold for the given operation.
+/// Operations that only require access to the VHD file itself return an empty list.
+pub fn required_privileges(operation: Operation) -> Vec<PrivilegeName> {
+    use winapi::um::winnt;
 
-    VirtualDisk::open(
-        default_storage_type,
-        filename,
-        VirtualDiskAccessMask::None,
-        open_virtual_disk::Flag::ParentCachedIo as u32
-            | open_virtual_disk::Flag::IgnoreRelativeParentLocator as u32,
-        Some(&parameters),
-    )
+    match operation {
+        Operation::Create
+        | Operation::Open
+        | Operation::QueryInformation
+        | Operation::Compact
+        | Operation::Merge
+        | Operation::Resize => Vec::new(),
+        Operation::Mount | Operation::Detach | Operation::ResizeVolume | Operation::Format => {
+            vec![winnt::SE_MANAGE_VOLUME_NAME]
+        }
+    }
 }
 
-/// Creates a new base VHD specified by filename.
-pub fn create_base_vhd(
-    filename: &str,
-    disk_size_gb: u64,
-    block_size_mb: u32,
-    file_system: &str,
-) -> WinResult<MountedVolume> {
-    let virtual_disk = create_vhd(filename, disk_size_gb, block_size_mb)?;
-    mount_vhd_temporarily_for_setup(&virtual_disk)?;
-    let disk = open_vhd_backed_disk(&virtual_disk)?;
-    let partition_info = disk.format(file_system)?;
-    Ok(MountedVolume {
-        vhd: virtual_disk,
-        disk: disk,
-        partition: partition_info,
-    })
+/// Checks that the current process holds every privilege required by the given operation,
+/// so that a missing privilege is reported before any change is made instead of surfacing
+/// as an opaque ioctl failure halfway through.
+/// Returns ERROR_PRIVILEGE_NOT_HELD if a privilege is missing, typically because
+/// the process is not elevated. Held privileges are enabled by the operations themselves
+/// through `TemporaryPrivileges`.
+pub fn ensure_privileges(operation: Operation) -> WinResult<()> {
+    for privilege in required_privileges(operation) {
+        if !process_holds_privilege(privilege)? {
+            return Err(error_code_to_winresult_code(
+                winapi::shared::winerror::ERROR_PRIVILEGE_NOT_HELD,
+            ));
+        }
+    }
+
+    Ok(())
 }
 
-/// Creates a new diff VHD specified by filename based on the given parent disk.
-pub fn create_diff_vhd(filename: &str, parent_name: &str, block_size_mb: u32) -> WinResult<()> {
-    assert!(block_size_mb <= 256);
-    let mut block_size_in_bytes = block_size_mb * 1024 * 1024;
+/// Caching behavior of the handles used to access a VHD, its backing files
+/// and the disk it surfaces.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IoProfile {
+    /// Every backing file, including the leaf, is opened in cached mode,
+    /// and the disk is opened without FILE_FLAG_NO_BUFFERING.
+    Buffered,
 
-    if block_size_in_bytes == 0 {
-        let mut parameters = unsafe { std::mem::zeroed::<open_virtual_disk::Parameters>() };
-        parameters.version = open_virtual_disk::Version::Version2;
+    /// Only the parents of the leaf are opened in cached mode,
+    /// and the disk is opened with FILE_FLAG_NO_BUFFERING. This is the default.
+    Unbuffered,
 
-        let default_storage_type = VirtualStorageType {
-            device_id: 0,
-            vendor_id: GUID_NULL,
-        };
+    /// Same as `Unbuffered`, but writes through the disk handle bypass any intermediate cache
+    /// (FILE_FLAG_WRITE_THROUGH).
+    WriteThrough,
 
-        let parent = VirtualDisk::open(
-            default_storage_type,
-            parent_name,
-            VirtualDiskAccessMask::None,
-            open_virtual_disk::Flag::NoParents as u32,
-            Some(&parameters),
-        )?;
+    /// Same as `Unbuffered`, but flushes and FUA are disabled for the backing files,
+    /// trading crash consistency for throughput. Only meant for scratch disks.
+    NoWriteHardening,
+}
 
-        let vhd_info_wrapper = parent.get_information(get_virtual_disk::InfoVersion::Size)?;
-        block_size_in_bytes = unsafe { vhd_info_wrapper.info().version_details.size.block_size };
+impl IoProfile {
+    /// Returns the `open_virtual_disk::Flag` values used to open the VHD with this profile.
+    pub fn open_flags(&self) -> u32 {
+        match self {
+            IoProfile::Buffered => open_virtual_disk::Flag::CachedIo as u32,
+            IoProfile::Unbuffered | IoProfile::WriteThrough => {
+                open_virtual_disk::Flag::ParentCachedIo as u32
+            }
+            IoProfile::NoWriteHardening => {
+                open_virtual_disk::Flag::ParentCachedIo as u32
+                    | open_virtual_disk::Flag::NoWriteHardening as u32
+            }
+        }
     }
 
-    let parent_name_wstr = widestring::WideCString::from_str(parent_name).unwrap();
-    let mut parameters = unsafe { std::mem::zeroed::<create_virtual_disk::Parameters>() };
-    parameters.version = create_virtual_disk::Version::Version2;
-    parameters.version_details.version2.parent_path = parent_name_wstr.as_ptr();
-    parameters.version_details.version2.block_size_in_bytes = block_size_in_bytes;
-    parameters.version_details.version2.open_flags = open_virtual_disk::Flag::CachedIo as u32;
-
-    let default_storage_type = VirtualStorageType {
-        device_id: 0,
-        vendor_id: GUID_NULL,
-    };
+    /// Returns the file flags and attributes used to open the disk surfaced by the VHD.
+    pub fn disk_file_flags(&self) -> DWord {
+        use winapi::um::{winbase, winnt};
 
-    let _virtual_disk = VirtualDisk::create(
-        default_storage_type,
-        filename,
-        VirtualDiskAccessMask::None,
-        None,
-        create_virtual_disk::Flag::None as u32,
-        0,
-        &parameters,
-        None,
-    )?;
+        match self {
+            IoProfile::Buffered => winnt::FILE_ATTRIBUTE_NORMAL,
+            IoProfile::Unbuffered | IoProfile::NoWriteHardening => {
+                winnt::FILE_ATTRIBUTE_NORMAL | winbase::FILE_FLAG_NO_BUFFERING
+            }
+            IoProfile::WriteThrough => {
+                winnt::FILE_ATTRIBUTE_NORMAL
+                    | winbase::FILE_FLAG_NO_BUFFERING
+                    | winbase::FILE_FLAG_WRITE_THROUGH
+            }
+        }
+    }
+}
 
-    Ok(())
+impl Default for IoProfile {
+    fn default() -> IoProfile {
+        IoProfile::Unbuffered
+    }
 }
 
-/// Creates a VHD from the contents of another VHD. This is used to defragment VHDs
-/// after they are fully constructed.
-pub fn create_vhd_from_source(
-    filename: &str,
-    source_filename: &str,
-    block_size_mb: u32,
-) -> WinResult<()> {
-    let source_path_wstr = widestring::WideCString::from_str(source_filename).unwrap();
-    let mut parameters = unsafe { std::mem::zeroed::<create_virtual_disk::Parameters>() };
-    parameters.version = create_virtual_disk::Version::Version2;
-    parameters.version_details.version2.source_path = source_path_wstr.as_ptr();
-    parameters.version_details.version2.block_size_in_bytes = block_size_mb * 1024 * 1024;
-    parameters.version_details.version2.open_flags = open_virtual_disk::Flag::CachedIo as u32;
+/// Options used to open and attach a VHD by path.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MountOptions {
+    /// Opens and attaches the VHD read-only.
+    pub read_only: bool,
 
-    let default_storage_type = VirtualStorageType {
-        device_id: 0,
-        vendor_id: GUID_NULL,
-    };
+    /// Any valid combination from `attach_virtual_disk::Flag` values.
+    pub flags: u32,
 
-    let _virtual_disk = VirtualDisk::create(
-        default_storage_type,
-        filename,
-        VirtualDiskAccessMask::None,
-        None,
-        create_virtual_disk::Flag::None as u32,
-        0,
-        &parameters,
-        None,
-    )?;
+    /// Write cache mode of the attached disk (VHD_WRITE_CACHE_MODE).
+    pub cache_mode: u16,
 
-    Ok(())
+    /// Caching behavior of the handles to the VHD and the disk it surfaces.
+    pub io_profile: IoProfile,
+
+    /// Mounts the VHD even if its logical sector size differs from the one of its host.
+    pub allow_sector_size_mismatch: bool,
+
+    /// Refuses to mount the VHD read-write with a write cached mode if it lives on a remote share,
+    /// where flushes don't reach the backing file, failing with ERROR_NOT_SUPPORTED.
+    pub refuse_remote_write_caching: bool,
+
+    /// Refuses to attach the VHD with drive letters from session 0, failing with ERROR_NOT_SUPPORTED,
+    /// for services that must not take drive letters on the host, see `for_service`.
+    pub refuse_service_drive_letters: bool,
+}
+
+impl MountOptions {
+    /// Options matching `mount_vhd_temporarily_for_setup`.
+    pub fn temporary_for_setup() -> MountOptions {
+        MountOptions {
+            read_only: false,
+            flags: attach_virtual_disk::Flag::NoDriveLetter as u32
+                | attach_virtual_disk::Flag::BypassDefaultEncryptionPolicy as u32,
+            cache_mode: 4, // VHD_WRITE_CACHE_MODE_DISABLE_FLUSHING
+            io_profile: IoProfile::default(),
+            allow_sector_size_mismatch: false,
+            refuse_remote_write_caching: false,
+            refuse_service_drive_letters: false,
+        }
+    }
+
+    /// Options matching `mount_vhd_permanently_for_use`.
+    pub fn permanently_for_use() -> MountOptions {
+        MountOptions {
+            read_only: false,
+            flags: attach_virtual_disk::Flag::NoDriveLetter as u32
+                | attach_virtual_disk::Flag::PermanentLifetime as u32
+                | attach_virtual_disk::Flag::NoSecurityDescriptor as u32
+                | attach_virtual_disk::Flag::BypassDefaultEncryptionPolicy as u32,
+            cache_mode: 0, // VHD_WRITE_CACHE_MODE_CACHE_METADATA
+            io_profile: IoProfile::default(),
+            allow_sector_size_mismatch: false,
+            refuse_remote_write_caching: false,
+            refuse_service_drive_letters: false,
+        }
+    }
+
+    /// Options for services, which run in session 0: the VHD is attached without drive letters.
+    /// Drive letters assigned on attach come from the mount manager and live in the global
+    /// namespace, so every session sees them, but a service doesn't know which letters
+    /// the logged on users have taken for mapped drives or `subst`, which live in their logon
+    /// session and shadow global letters. Expose its volumes through
+    /// `diskutilities::add_mount_point` (global, persistent) or
+    /// `diskutilities::add_session_mount_point` (visible to the logon session of the caller) instead.
+    /// The attachment isn't permanent, so the VHD is detached when the service stops.
+    pub fn for_service() -> MountOptions {
+        MountOptions {
+            read_only: false,
+            flags: attach_virtual_disk::Flag::NoDriveLetter as u32
+                | attach_virtual_disk::Flag::BypassDefaultEncryptionPolicy as u32,
+            cache_mode: 0, // VHD_WRITE_CACHE_MODE_CACHE_METADATA
+            io_profile: IoProfile::default(),
+            allow_sector_size_mismatch: false,
+            refuse_remote_write_caching: false,
+            refuse_service_drive_letters: true,
+        }
+    }
+
+    /// Returns the attach flags, including the read-only flag if requested.
+    pub fn attach_flags(&self) -> u32 {
+        match self.read_only {
+            true => self.flags | attach_virtual_disk::Flag::ReadOnly as u32,
+            false => self.flags,
+        }
+    }
+}
+
+impl Default for MountOptions {
+    fn default() -> MountOptions {
+        MountOptions::temporary_for_setup()
+    }
+}
+
+/// Opens the VHD specified by filename and mounts it into the host with the given options.
+/// Fails with ERROR_VIRTDISK_UNSUPPORTED_DISK_SECTOR_SIZE if the logical sector size of the VHD
+/// differs from the one of its host, see `check_sector_sizes`, unless `allow_sector_size_mismatch`
+/// is set.
+/// With `refuse_remote_write_caching`, fails with ERROR_NOT_SUPPORTED if the VHD lives on a remote
+/// share and is attached read-write with a write cached mode, see `VirtualDisk::is_remote_backed`.
+/// With `refuse_service_drive_letters`, fails with ERROR_NOT_SUPPORTED if drive letters
+/// are requested from session 0.
+pub fn mount_vhd_with_options(filename: &str, options: &MountOptions) -> WinResult<VirtualDisk> {
+    let virtual_disk = open_vhd_with_profile(filename, options.read_only, options.io_profile)?;
+
+    check_mount_options(&virtual_disk, options)?;
+
+    mount_vhd_until(
+        &virtual_disk,
+        options.attach_flags(),
+        options.cache_mode,
+        options.io_profile,
+        None,
+    )?;
+    Ok(virtual_disk)
+}
+
+/// Enforces the refusals requested by the mount options before the VHD is attached,
+/// so that every entry point taking `MountOptions` behaves the same.
+fn check_mount_options(virtual_disk: &VirtualDisk, options: &MountOptions) -> WinResult<()> {
+    if !options.allow_sector_size_mismatch {
+        ensure_sector_sizes_match(virtual_disk)?;
+    }
+
+    if options.refuse_remote_write_caching {
+        ensure_remote_cache_safe(virtual_disk, options.attach_flags(), options.cache_mode)?;
+    }
+
+    if options.refuse_service_drive_letters {
+        ensure_no_service_drive_letters(options.attach_flags())?;
+    }
+
+    Ok(())
+}
+
+/// Result of `ensure_mounted`.
+pub struct MountState {
+    /// Open handle to the VHD.
+    pub virtual_disk: VirtualDisk,
+
+    /// True if the VHD was attached by this call, false if an existing attachment was found.
+    pub newly_attached: bool,
+
+    /// Paths of the volumes currently surfaced on the disk backed by the VHD.
+    pub volume_paths: Vec<String>,
+}
+
+/// Mounts the VHD specified by filename unless it's already attached to the host,
+/// in which case the existing attachment is reused.
+/// The options are checked as `mount_vhd_with_options` does, only when the VHD gets attached.
+pub fn ensure_mounted(filename: &str, options: &MountOptions) -> WinResult<MountState> {
+    let virtual_disk = open_vhd_with_profile(filename, options.read_only, options.io_profile)?;
+    let newly_attached = !is_vhd_attached(&virtual_disk, filename)?;
+
+    if newly_attached {
+        check_mount_options(&virtual_disk, options)?;
+
+        mount_vhd_with_profile(
+            &virtual_disk,
+            options.attach_flags(),
+            options.cache_mode,
+            options.io_profile,
+        )?;
+    }
+
+    let volume_paths =
+        open_vhd_backed_disk_with_profile(&virtual_disk, options.io_profile)?.volume_paths()?;
+
+    Ok(MountState {
+        virtual_disk,
+        newly_attached,
+        volume_paths,
+    })
+}
+
+/// Determines whether the given VHD is attached to the host, first by asking the provider if
+/// the disk is loaded and then by looking for its file in the list of attached virtual disks.
+fn is_vhd_attached(virtual_disk: &VirtualDisk, filename: &str) -> WinResult<bool> {
+    if let Ok(true) = virtual_disk.is_loaded() {
+        return Ok(true);
+    }
+
+    let filename = normalize_vhd_path(filename);
+
+    Ok(VirtualDisk::get_all_attached_physical_paths()?
+        .iter()
+        .any(|path| normalize_vhd_path(path) == filename))
+}
+
+/// Dismounts the given VHD from the host.
+pub fn dismount_vhd(virtual_disk: &VirtualDisk) -> WinResult<()> {
+    dismount_vhd_with_options(virtual_disk, &DismountOptions::default())
+}
+
+/// Options used to dismount a VHD.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct DismountOptions {
+    /// Removes the drive letters and mounted folders of every volume of the VHD before
+    /// detaching it, so that no orphaned mount points are left behind in NTFS folders.
+    pub remove_mount_points: bool,
+}
+
+/// Dismounts the given VHD from the host with the given options.
+pub fn dismount_vhd_with_options(
+    virtual_disk: &VirtualDisk,
+    options: &DismountOptions,
+) -> WinResult<()> {
+    if options.remove_mount_points {
+        let disk = open_vhd_backed_disk(virtual_disk)?;
+
+        for volume_path in disk.volume_paths()? {
+            for mount_point in list_mount_points(&volume_path)? {
+                remove_mount_point(&mount_point)?;
+            }
+        }
+    }
+
+    virtual_disk.detach(
+        detach_virtual_disk::Flag::None as u32,
+        provider_flags::ProviderFlags::None.bits(),
+    )
+}
+
+/// VHD attached with `attach_virtual_disk::Flag::PermanentLifetime`, which stays attached
+/// after every handle to it is closed. See `list_permanent_attachments`.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PermanentAttachment {
+    /// Path of the VHD file.
+    pub vhd_path: String,
+
+    /// Path of the disk surfaced by the VHD (e.g. \\.\PhysicalDrive3).
+    pub physical_path: String,
+}
+
+/// Returns the dependency flags of the VHD backing the given disk,
+/// a u32 representation of `storage_dependency::DependentDiskFlag` values.
+fn backing_vhd_dependency_flags(physical_path: &str) -> WinResult<u32> {
+    use winapi::um::{fileapi, winnt};
+
+    let disk = create_file(
+        physical_path,
+        0,
+        winnt::FILE_SHARE_READ | winnt::FILE_SHARE_WRITE,
+        None,
+        fileapi::OPEN_EXISTING,
+        winnt::FILE_ATTRIBUTE_NORMAL,
+        None,
+    )?;
+
+    let dependency_info_wrapper = VirtualDisk::wrap_handle(disk)?
+        .get_storage_dependency_information(
+            storage_dependency::GetFlag::HostVolumes as u32
+                | storage_dependency::GetFlag::DiskHandle as u32,
+            storage_dependency::InfoVersion::Version2,
+        )?;
+
+    Ok(dependency_info_wrapper
+        .entries_v2()
+        .iter()
+        .min_by_key(|entry| entry.ancestor_level)
+        .map(|entry| entry.dependency_type_flags)
+        .unwrap_or(0))
+}
+
+/// Lists the VHDs attached to the host with a permanent lifetime, which survive the process
+/// that attached them and are easily leaked. Attachments that detach while being listed are skipped.
+pub fn list_permanent_attachments() -> WinResult<Vec<PermanentAttachment>> {
+    let mut attachments: Vec<PermanentAttachment> = Vec::new();
+
+    for vhd_path in VirtualDisk::get_all_attached_physical_paths()? {
+        let physical_path = match VirtualDisk::open_info_only(&vhd_path)
+            .and_then(|virtual_disk| virtual_disk.get_physical_path())
+        {
+            Ok(physical_path) => physical_path,
+            Err(_) => continue,
+        };
+
+        let flags = match backing_vhd_dependency_flags(&physical_path) {
+            Ok(flags) => flags,
+            Err(_) => continue,
+        };
+
+        if flags & storage_dependency::DependentDiskFlag::PermanentLifetime as u32 != 0 {
+            attachments.push(PermanentAttachment {
+                vhd_path,
+                physical_path,
+            });
+        }
+    }
+
+    Ok(attachments)
+}
+
+/// Detaches the VHD specified by path from the host without needing the handle it was
+/// attached with, which is how permanent attachments left behind by other processes are cleaned up.
+/// Returns ERROR_FILE_NOT_FOUND if the VHD isn't attached.
+pub fn detach_by_path(vhd_path: &str) -> WinResult<()> {
+    let normalized_path = normalize_vhd_path(vhd_path);
+    let attached_path = VirtualDisk::get_all_attached_physical_paths()?
+        .into_iter()
+        .find(|path| normalize_vhd_path(path) == normalized_path)
+        .ok_or_else(|| {
+            error_code_to_winresult_code(winapi::shared::winerror::ERROR_FILE_NOT_FOUND)
+        })?;
+
+    VirtualDisk::open_options().open(&attached_path)?.detach(
+        detach_virtual_disk::Flag::None as u32,
+        provider_flags::ProviderFlags::None.bits(),
+    )
+}
+
+/// Number of attempts made to lock a volume before giving up, other processes might be
+/// closing their handles to it.
+const VOLUME_LOCK_ATTEMPTS: u32 = 5;
+
+/// Delay between attempts to lock a volume.
+const VOLUME_LOCK_RETRY_DELAY: std::time::Duration = std::time::Duration::from_millis(200);
+
+/// How hard `dismount_vhd_safely` tries to dismount volumes that are in use.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ForceLevel {
+    /// Fails without detaching if any volume can't be locked.
+    None,
+
+    /// Dismounts volumes that can't be locked anyway, invalidating stuck handles
+    /// to files on them.
+    DismountInUse,
+}
+
+/// Dismounts the given VHD from the host, making sure its file systems are clean first.
+/// Each volume of the VHD has its buffers flushed, is locked and has its file system dismounted,
+/// and only then the VHD is detached. The volumes stay locked until the VHD is detached.
+pub fn dismount_vhd_safely(virtual_disk: &VirtualDisk, force: ForceLevel) -> WinResult<()> {
+    let disk = open_vhd_backed_disk(virtual_disk)?;
+    let mut volumes: Vec<Volume> = Vec::new();
+
+    for volume_path in disk.volume_paths()? {
+        let volume = Volume::open(volume_path.trim_end_matches('\\'), None)?;
+        volume.flush()?;
+
+        let mut lock_result = volume.lock();
+
+        for _ in 1..VOLUME_LOCK_ATTEMPTS {
+            if lock_result.is_ok() {
+                break;
+            }
+
+            std::thread::sleep(VOLUME_LOCK_RETRY_DELAY);
+            lock_result = volume.lock();
+        }
+
+        match (lock_result, force) {
+            (Ok(()), _) | (Err(_), ForceLevel::DismountInUse) => volume.dismount()?,
+            (Err(error), ForceLevel::None) => return Err(error),
+        }
+
+        volumes.push(volume);
+    }
+
+    drop(disk);
+    virtual_disk.detach(
+        detach_virtual_disk::Flag::None as u32,
+        provider_flags::ProviderFlags::None.bits(),
+    )
+}
+
+/// Options used to attach a VHD Set or VHDX shared across the guests of a cluster.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SharedAttachOptions {
+    /// Attaches the disk read-only.
+    pub read_only: bool,
+
+    /// Keeps the disk from being surfaced to the host (`attach_virtual_disk::Flag::NoLocalHost`),
+    /// so that only the guests sharing it access its volumes.
+    pub no_local_host: bool,
+
+    /// Provider specific flags supplied to the attach call.
+    pub provider_specific_flags: provider_flags::ProviderFlags,
+}
+
+impl Default for SharedAttachOptions {
+    fn default() -> SharedAttachOptions {
+        SharedAttachOptions {
+            read_only: false,
+            no_local_host: true,
+            provider_specific_flags: provider_flags::ProviderFlags::None,
+        }
+    }
+}
+
+/// Attaches a VHD Set (or a legacy shared VHDX) so that it can be shared by the guests
+/// of a cluster, with SCSI persistent reservations issued through `raw_scsi_shared`.
+/// Returns ERROR_NOT_SUPPORTED for other storage types, since only VHD Sets and VHDX files
+/// support persistent reservations through the RSVD protocol. Shared VHDX files should be
+/// converted to VHD Sets, which is the format supported by current Hyper-V guest clusters.
+pub fn attach_shared(virtual_disk: &VirtualDisk, options: SharedAttachOptions) -> WinResult<()> {
+    match virtual_disk.storage_type()?.device_id {
+        VIRTUAL_STORAGE_TYPE_DEVICE_VHDSET | VIRTUAL_STORAGE_TYPE_DEVICE_VHDX => {}
+        _ => {
+            return Err(error_code_to_winresult_code(
+                winapi::shared::winerror::ERROR_NOT_SUPPORTED,
+            ))
+        }
+    }
+
+    let mut flags = attach_virtual_disk::Flag::NoDriveLetter as u32;
+
+    if options.read_only {
+        flags |= attach_virtual_disk::Flag::ReadOnly as u32;
+    }
+
+    if options.no_local_host {
+        flags |= attach_virtual_disk::Flag::NoLocalHost as u32;
+    }
+
+    let parameters = attach_virtual_disk::Parameters {
+        version: attach_virtual_disk::Version::Version1,
+        version_details: attach_virtual_disk::VersionDetails {
+            version1: attach_virtual_disk::Version1 { reserved: 0 },
+        },
+    };
+
+    virtual_disk.attach(
+        None,
+        flags,
+        options.provider_specific_flags.bits(),
+        &parameters,
+        None,
+    )
+}
+
+/// Issues a SCSI command to a disk attached through `attach_shared`, through the RSVD handle
+/// mode (`raw_scsi_virtual_disk::Version1::rsvd_handle`) so that persistent reservation
+/// commands are forwarded to the shared storage instead of being handled locally.
+pub fn raw_scsi_shared(
+    virtual_disk: &VirtualDisk,
+    mut command: raw_scsi_virtual_disk::Version1,
+) -> WinResult<raw_scsi_virtual_disk::Response> {
+    command.rsvd_handle = 1;
+
+    let parameters = raw_scsi_virtual_disk::Parameters {
+        version: raw_scsi_virtual_disk::Version::Version1,
+        version_details: raw_scsi_virtual_disk::VersionDetails { version1: command },
+    };
+
+    virtual_disk.raw_scsi_virtual_disk(&parameters, raw_scsi_virtual_disk::Flag::None as u32)
+}
+
+/// Determines the storage type of a virtual disk from the extension of its file name.
+/// Returns None if the extension isn't one of a known virtual disk format.
+pub fn detect_from_extension(filename: &str) -> Option<VirtualStorageType> {
+    let extension = std::path::Path::new(filename)
+        .extension()?
+        .to_string_lossy()
+        .to_lowercase();
+
+    match extension.as_str() {
+        "iso" => Some(VirtualStorageType::iso()),
+        "vhd" | "avhd" => Some(VirtualStorageType::vhd()),
+        "vhdx" | "avhdx" => Some(VirtualStorageType::vhdx()),
+        "vhds" => Some(VirtualStorageType::vhdset()),
+        _ => None,
+    }
+}
+
+/// Determines the storage type of a virtual disk from the signatures in its file,
+/// regardless of its extension.
+/// VHD Set files share the VHDX signature, so VHDX files with a .vhds extension are reported
+/// as VHD Sets.
+/// Returns None if the file doesn't carry the signature of a known virtual disk format.
+pub fn detect_from_header(filename: &str) -> WinResult<Option<VirtualStorageType>> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    const VHDX_SIGNATURE: &[u8] = b"vhdxfile";
+    const VHD_COOKIE: &[u8] = b"conectix";
+    const VHD_FOOTER_SIZE: u64 = 512;
+    const ISO_SIGNATURE: &[u8] = b"CD001";
+    const ISO_SIGNATURE_OFFSET: u64 = 16 * 2048 + 1;
+
+    let read_at = |offset: u64, length: usize| -> std::io::Result<Vec<u8>> {
+        let mut file = std::fs::File::open(filename)?;
+        let mut buffer = vec![0; length];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut buffer)?;
+        Ok(buffer)
+    };
+
+    let file_size = std::fs::metadata(filename)
+        .map_err(|error| io_error_to_winresult_code(&error))?
+        .len();
+
+    if let Ok(signature) = read_at(0, VHDX_SIGNATURE.len()) {
+        if signature == VHDX_SIGNATURE {
+            return Ok(match detect_from_extension(filename) {
+                Some(storage_type)
+                    if storage_type.device_id == VIRTUAL_STORAGE_TYPE_DEVICE_VHDSET =>
+                {
+                    Some(VirtualStorageType::vhdset())
+                }
+                _ => Some(VirtualStorageType::vhdx()),
+            });
+        }
+
+        // Dynamic and differencing VHDs keep a copy of the footer at the start of the file.
+        if signature == VHD_COOKIE {
+            return Ok(Some(VirtualStorageType::vhd()));
+        }
+    }
+
+    if file_size >= VHD_FOOTER_SIZE {
+        if let Ok(cookie) = read_at(file_size - VHD_FOOTER_SIZE, VHD_COOKIE.len()) {
+            if cookie == VHD_COOKIE {
+                return Ok(Some(VirtualStorageType::vhd()));
+            }
+        }
+    }
+
+    if let Ok(signature) = read_at(ISO_SIGNATURE_OFFSET, ISO_SIGNATURE.len()) {
+        if signature == ISO_SIGNATURE {
+            return Ok(Some(VirtualStorageType::iso()));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Opens a VHD for use as a container sandbox and returns a safe wrapper over the handle.
+pub fn open_vhd(filename: &str, read_only: bool) -> WinResult<VirtualDisk> {
+    open_vhd_with_profile(filename, read_only, IoProfile::default())
+}
+
+/// Opens a VHD for use as a container sandbox with the given IO profile,
+/// and returns a safe wrapper over the handle.
+pub fn open_vhd_with_profile(
+    filename: &str,
+    read_only: bool,
+    io_profile: IoProfile,
+) -> WinResult<VirtualDisk> {
+    let storage_type = detect_from_extension(filename).unwrap_or(VirtualStorageType::auto());
+
+    let parameters = open_virtual_disk::Parameters {
+        version: open_virtual_disk::Version::Version2,
+        version_details: open_virtual_disk::VersionDetails {
+            version2: open_virtual_disk::Version2 {
+                get_info_only: 0,
+                read_only: read_only as Bool,
+                resiliency_guid: GUID_NULL,
+            },
+        },
+    };
+
+    VirtualDisk::open(
+        storage_type,
+        filename,
+        VirtualDiskAccessMask::None,
+        io_profile.open_flags() | open_virtual_disk::Flag::IgnoreRelativeParentLocator as u32,
+        Some(&parameters),
+    )
+}
+
+/// How `mount_many` reacts to a VHD in the batch failing to mount.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BatchMode {
+    /// Keep every VHD that mounted successfully.
+    BestEffort,
+
+    /// Dismount every VHD that mounted successfully if any of them failed.
+    AllOrNothing,
+}
+
+/// Opens and mounts multiple VHDs in parallel, using up to `max_parallel` threads.
+/// Returns the result of every VHD in the same order as the supplied paths.
+///
+/// In `BatchMode::AllOrNothing` mode, if any VHD fails to mount the ones that succeeded are
+/// dismounted and closed, and their results are replaced by `ErrorOperationAborted`.
+pub fn mount_many(
+    paths: &[&str],
+    options: MountOptions,
+    max_parallel: usize,
+    mode: BatchMode,
+) -> Vec<WinResult<VirtualDisk>> {
+    let pending: std::sync::Arc<std::sync::Mutex<Vec<(usize, String)>>> =
+        std::sync::Arc::new(std::sync::Mutex::new(
+            paths
+                .iter()
+                .enumerate()
+                .rev()
+                .map(|(index, path)| (index, String::from(*path)))
+                .collect(),
+        ));
+
+    // Privileges are enabled on the token of the whole process, so a worker restoring them
+    // once its mount is done would disable them under the workers still mounting. Holding
+    // them for the whole batch turns the per-mount guards into no-ops. If they can't be
+    // enabled here every mount reports the failure on its own.
+    let _manage_volume = TemporaryPrivileges::acquire(&required_privileges(Operation::Mount)).ok();
+
+    let (sender, receiver) = std::sync::mpsc::channel::<(usize, WinResult<VirtualDisk>)>();
+    let worker_count = std::cmp::max(1, std::cmp::min(max_parallel, paths.len()));
+    let mut workers = Vec::with_capacity(worker_count);
+
+    for _ in 0..worker_count {
+        let pending = pending.clone();
+        let sender = sender.clone();
+
+        workers.push(std::thread::spawn(move || loop {
+            let next = pending
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner)
+                .pop();
+
+            let (index, path) = match next {
+                Some(next) => next,
+                None => break,
+            };
+
+            let result = mount_vhd_with_options(&path, &options);
+
+            if sender.send((index, result)).is_err() {
+                break;
+            }
+        }));
+    }
+
+    drop(sender);
+
+    let mut results: Vec<Option<WinResult<VirtualDisk>>> = paths.iter().map(|_| None).collect();
+
+    for (index, result) in receiver {
+        results[index] = Some(result);
+    }
+
+    for worker in workers {
+        #[allow(unused_must_use)]
+        {
+            worker.join();
+        }
+    }
+
+    let mut results: Vec<WinResult<VirtualDisk>> = results
+        .into_iter()
+        .map(|result| result.unwrap_or(Err(WinResultCode::ErrorGenFailure)))
+        .collect();
+
+    if mode == BatchMode::AllOrNothing && results.iter().any(|result| result.is_err()) {
+        for result in results.iter_mut() {
+            if let Ok(virtual_disk) = result {
+                #[allow(unused_must_use)]
+                {
+                    dismount_vhd(virtual_disk);
+                }
+
+                *result = Err(WinResultCode::ErrorOperationAborted);
+            }
+        }
+    }
+
+    results
+}
+
+/// Creates a file under a temporary name and only moves it to its final name once it's complete,
+/// so that failures midway don't leave partially initialized files behind.
+///
+/// The temporary file is deleted when the transaction is dropped without being committed,
+/// including when unwinding from a panic. Every handle to the temporary file must be closed
+/// before the transaction is committed or dropped, so declare the transaction before them.
+pub struct CreateTransaction {
+    final_path: String,
+    temp_path: String,
+    committed: bool,
+}
+
+impl CreateTransaction {
+    /// Starts a transaction creating the file at the given path.
+    /// The temporary name keeps the extension of the final name (`disk.vhdx` is created as
+    /// `disk.tmp.vhdx`), since the storage type of a VHD is detected from its extension.
+    /// Returns ERROR_FILE_EXISTS if the final file or the temporary file already exist.
+    /// A temporary file left behind by a process that died midway is never deleted,
+    /// since it might belong to a transaction still in progress.
+    pub fn new(final_path: &str) -> WinResult<CreateTransaction> {
+        let path = std::path::Path::new(final_path);
+
+        if path.exists() {
+            return Err(error_code_to_winresult_code(
+                winapi::shared::winerror::ERROR_FILE_EXISTS,
+            ));
+        }
+
+        let mut temp_name = path
+            .file_stem()
+            .ok_or(WinResultCode::ErrorInvalidArgument)?
+            .to_os_string();
+        temp_name.push(".tmp");
+
+        if let Some(extension) = path.extension() {
+            temp_name.push(".");
+            temp_name.push(extension);
+        }
+
+        let temp_path = path
+            .with_file_name(temp_name)
+            .to_string_lossy()
+            .into_owned();
+
+        if std::path::Path::new(&temp_path).exists() {
+            return Err(error_code_to_winresult_code(
+                winapi::shared::winerror::ERROR_FILE_EXISTS,
+            ));
+        }
+
+        Ok(CreateTransaction {
+            final_path: String::from(final_path),
+            temp_path,
+            committed: false,
+        })
+    }
+
+    /// Path the file must be created at.
+    pub fn temp_path(&self) -> &str {
+        &self.temp_path
+    }
+
+    /// Path the file ends up at once the transaction is committed.
+    pub fn final_path(&self) -> &str {
+        &self.final_path
+    }
+
+    /// Moves the temporary file to its final name, without replacing a file
+    /// created there in the meantime.
+    /// The move is retried according to `RetryPolicy::default` while it fails with
+    /// ERROR_SHARING_VIOLATION, since the virtual disk stack can keep the file open
+    /// for a short while after a detach.
+    pub fn commit(mut self) -> WinResult<()> {
+        let temp_path = widestring::WideCString::from_str(&self.temp_path)
+            .map_err(|_| WinResultCode::ErrorInvalidArgument)?;
+        let final_path = widestring::WideCString::from_str(&self.final_path)
+            .map_err(|_| WinResultCode::ErrorInvalidArgument)?;
+        let policy = RetryPolicy::default();
+        let mut attempt = 1;
+
+        loop {
+            let moved = unsafe {
+                winapi::um::winbase::MoveFileExW(
+                    temp_path.as_ptr(),
+                    final_path.as_ptr(),
+                    winapi::um::winbase::MOVEFILE_WRITE_THROUGH,
+                )
+            };
+
+            if moved != 0 {
+                break;
+            }
+
+            let error = unsafe { winapi::um::errhandlingapi::GetLastError() };
+
+            if error != winapi::shared::winerror::ERROR_SHARING_VIOLATION
+                || attempt >= policy.max_attempts
+            {
+                return Err(error_code_to_winresult_code(error));
+            }
+
+            attempt += 1;
+            std::thread::sleep(policy.delay_before(attempt));
+        }
+
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Drop for CreateTransaction {
+    fn drop(&mut self) {
+        if !self.committed {
+            #[allow(unused_must_use)]
+            {
+                std::fs::remove_file(&self.temp_path);
+            }
+        }
+    }
+}
+
+/// Creates a new base VHD specified by filename with the given geometry,
+/// and formats it into a single volume.
+/// See `recommended_geometry` for geometries suited to common workloads.
+///
+/// The VHD is created and formatted under a temporary name through a `CreateTransaction`,
+/// and is only moved to the given filename and mounted again once formatting succeeded.
+/// On failure nothing is left behind at either path.
+pub fn create_base_vhd(
+    filename: &str,
+    size: SizeSpec,
+    geometry: DiskGeometry,
+    file_system: &str,
+) -> WinResult<MountedVolume> {
+    let transaction = CreateTransaction::new(filename)?;
+
+    let partition_info = {
+        let virtual_disk = create_vhd_with_geometry(transaction.temp_path(), size, &geometry)?;
+        mount_vhd_temporarily_for_setup(&virtual_disk)?;
+        let partition_info = open_vhd_backed_disk(&virtual_disk)?
+            .format_aligned(file_system, geometry.alignment_in_bytes)?;
+        dismount_vhd(&virtual_disk)?;
+        partition_info
+    };
+
+    transaction.commit()?;
+
+    let virtual_disk = open_vhd(filename, false)?;
+    mount_vhd_temporarily_for_setup(&virtual_disk)?;
+    let disk = open_vhd_backed_disk(&virtual_disk)?;
+
+    // The volume can surface under another volume GUID path once the VHD is attached again.
+    let volume_path = disk.volume_path()?;
+    Ok(MountedVolume {
+        vhd: virtual_disk,
+        disk: disk,
+        partition: partition_info.with_volume_path(volume_path),
+    })
+}
+
+/// Describes the partitions of a generation 2 boot VHDX, see `create_gen2_boot_vhdx`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootLayout {
+    /// Geometry of the new VHDX.
+    pub geometry: DiskGeometry,
+
+    /// Size of the EFI system partition in bytes. FAT32 needs at least 260 MiB
+    /// on disks with 4 KB logical sectors.
+    pub esp_size: u64,
+
+    /// Size of the Microsoft reserved partition in bytes.
+    pub msr_size: u64,
+
+    /// File system of the Windows partition, which spans the rest of the disk.
+    pub windows_file_system: String,
+}
+
+impl Default for BootLayout {
+    fn default() -> BootLayout {
+        BootLayout {
+            geometry: DiskGeometry::default(),
+            esp_size: 100 * 1024 * 1024,
+            msr_size: 16 * 1024 * 1024,
+            windows_file_system: String::from("NTFS"),
+        }
+    }
+}
+
+/// Result of `create_gen2_boot_vhdx`, the VHDX stays attached while this is alive.
+pub struct Gen2BootVhdx {
+    pub vhd: VirtualDisk,
+    pub disk: Disk,
+
+    /// Volume path of the FAT32 EFI system partition, where the boot files go.
+    pub esp_volume_path: String,
+
+    /// The Microsoft reserved partition. It never gets a volume, so it has no path.
+    pub msr: Partition,
+
+    /// Volume path of the Windows partition, where the image is applied.
+    pub windows_volume_path: String,
+}
+
+/// Creates a new VHDX specified by filename laid out to boot a generation 2 (UEFI) virtual machine:
+/// a GPT disk with an EFI system partition formatted FAT32, a Microsoft reserved partition
+/// and a Windows partition spanning the rest of the disk, formatted as requested.
+/// The VHDX is left attached without drive letters so that boot files and an image
+/// can be laid down through the returned volume paths.
+///
+/// The ESP and Windows volumes are formatted while the VHDX still has a temporary name,
+/// which only becomes the given filename once both formats succeeded; the returned volume paths
+/// are then read from the VHDX attached again at its final path. If partitioning or formatting
+/// fails, the partially laid out VHDX is deleted and the given filename is never created.
+pub fn create_gen2_boot_vhdx(
+    filename: &str,
+    size: SizeSpec,
+    layout: &BootLayout,
+) -> WinResult<Gen2BootVhdx> {
+    use crate::guid::WinGuid;
+
+    const ESP: usize = 0;
+    const MSR: usize = 1;
+    const WINDOWS: usize = 2;
+
+    let specs = [
+        GptPartitionSpec {
+            partition_type: WinGuid::from(crate::gpt::PARTITION_SYSTEM_GUID),
+            length: Some(layout.esp_size),
+            attributes: 0,
+            name: String::from("EFI system partition"),
+        },
+        GptPartitionSpec {
+            partition_type: WinGuid::from(crate::gpt::PARTITION_MSFT_RESERVED_GUID),
+            length: Some(layout.msr_size),
+            attributes: 0,
+            name: String::from("Microsoft reserved partition"),
+        },
+        GptPartitionSpec {
+            partition_type: WinGuid::from(crate::gpt::PARTITION_BASIC_DATA_GUID),
+            length: None,
+            attributes: 0,
+            name: String::from("Basic data partition"),
+        },
+    ];
+
+    let transaction = CreateTransaction::new(filename)?;
+
+    {
+        let virtual_disk =
+            create_vhd_with_geometry(transaction.temp_path(), size, &layout.geometry)?;
+        mount_vhd_temporarily_for_setup(&virtual_disk)?;
+        let disk = open_vhd_backed_disk(&virtual_disk)?;
+        let partitions = disk.create_gpt_partitions(&specs)?;
+
+        format_volume(
+            &disk.partition_volume_path(&partitions[ESP])?,
+            "FAT32",
+            "System",
+        )?;
+        format_volume(
+            &disk.partition_volume_path(&partitions[WINDOWS])?,
+            &layout.windows_file_system,
+            "Windows",
+        )?;
+    }
+
+    transaction.commit()?;
+
+    let virtual_disk = open_vhd(filename, false)?;
+    mount_vhd_temporarily_for_setup(&virtual_disk)?;
+    let disk = open_vhd_backed_disk(&virtual_disk)?;
+    let mut partitions = disk.partitions()?;
+
+    if partitions.len() != specs.len() {
+        return Err(error_code_to_winresult_code(
+            winapi::shared::winerror::ERROR_INVALID_DATA,
+        ));
+    }
+
+    let esp_volume_path = disk.partition_volume_path(&partitions[ESP])?;
+    let windows_volume_path = disk.partition_volume_path(&partitions[WINDOWS])?;
+
+    Ok(Gen2BootVhdx {
+        vhd: virtual_disk,
+        disk,
+        esp_volume_path,
+        msr: partitions.remove(MSR),
+        windows_volume_path,
+    })
+}
+
+/// Describes how a new fixed VHD is laid out and formatted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FormatSpec {
+    /// Sector sizes of the new VHD. The block size is ignored, fixed VHDs aren't made of blocks.
+    pub geometry: DiskGeometry,
+
+    /// File system the single volume of the VHD is formatted with.
+    pub file_system: String,
+
+    /// Backs the VHD with a sparse file when the file system hosting it supports sparse VDLs,
+    /// so that the allocation doesn't have to zero the whole file.
+    pub sparse_file: bool,
+}
+
+impl Default for FormatSpec {
+    fn default() -> FormatSpec {
+        FormatSpec {
+            geometry: DiskGeometry::default(),
+            file_system: String::from("NTFS"),
+            sparse_file: true,
+        }
+    }
+}
+
+/// Creates a new fixed VHD specified by filename, with all of its physical space allocated upfront,
+/// and formats it into a single volume.
+/// Waits for the allocation to complete, reporting its progress through the optional callback.
+pub fn create_fixed_vhd(
+    filename: &str,
+    size: SizeSpec,
+    spec: &FormatSpec,
+    progress: Option<ProgressCallback>,
+) -> WinResult<MountedVolume> {
+    let mut parameters = unsafe { std::mem::zeroed::<create_virtual_disk::Parameters>() };
+    parameters.version = create_virtual_disk::Version::Version2;
+    parameters.version_details.version2.maximum_size = size.validate_for_file(filename)?;
+    parameters.version_details.version2.sector_size_in_bytes =
+        spec.geometry.logical_sector_size_in_bytes;
+    parameters
+        .version_details
+        .version2
+        .physical_sector_size_in_bytes = spec.geometry.physical_sector_size_in_bytes;
+
+    let mut flags = create_virtual_disk::Flag::FullPhysicalAllocation as u32;
+
+    if spec.sparse_file && supports_sparse_vdl(filename) {
+        flags |= create_virtual_disk::Flag::SparseFile as u32;
+    }
+
+    let default_storage_type = VirtualStorageType::auto();
+
+    let overlapped_event = OverlappedEvent::new()?;
+
+    let virtual_disk = VirtualDisk::create_overlapped(
+        default_storage_type,
+        filename,
+        VirtualDiskAccessMask::None,
+        None,
+        flags,
+        provider_flags::ProviderFlags::None.bits(),
+        &parameters,
+        overlapped_event.as_overlapped(),
+    )?;
+
+    wait_for_vhd_operation_with_progress(
+        &virtual_disk,
+        overlapped_event.as_overlapped(),
+        progress,
+    )?;
+
+    mount_vhd_temporarily_for_setup(&virtual_disk)?;
+    let disk = open_vhd_backed_disk(&virtual_disk)?;
+    let partition_info = disk.format(&spec.file_system)?;
+    Ok(MountedVolume {
+        vhd: virtual_disk,
+        disk: disk,
+        partition: partition_info,
+    })
+}
+
+/// Determines whether the file system that would host the given file supports sparse VDLs,
+/// which is required to create fixed VHDs backed by sparse files.
+/// Returns false if the file system can't be queried.
+fn supports_sparse_vdl(filename: &str) -> bool {
+    use winapi::um::fileapi;
+
+    let filename_wstr = widestring::WideCString::from_str(filename).unwrap();
+    let mut root_path: Vec<WChar> = vec![0; 261];
+    let mut file_system_flags: DWord = 0;
+
+    unsafe {
+        fileapi::GetVolumePathNameW(
+            filename_wstr.as_ptr(),
+            root_path.as_mut_ptr(),
+            root_path.len() as DWord,
+        ) != 0
+            && fileapi::GetVolumeInformationW(
+                root_path.as_ptr(),
+                std::ptr::null_mut(),
+                0,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                &mut file_system_flags,
+                std::ptr::null_mut(),
+                0,
+            ) != 0
+            && file_system_flags & winapi::um::winnt::FILE_SUPPORTS_SPARSE_VDL != 0
+    }
+}
+
+/// Creates a new diff VHD specified by filename based on the given parent disk.
+pub fn create_diff_vhd(filename: &str, parent_name: &str, block_size_mb: u32) -> WinResult<()> {
+    assert!(block_size_mb <= 256);
+    let mut block_size_in_bytes = block_size_mb * 1024 * 1024;
+
+    if block_size_in_bytes == 0 {
+        let mut parameters = unsafe { std::mem::zeroed::<open_virtual_disk::Parameters>() };
+        parameters.version = open_virtual_disk::Version::Version2;
+
+        let default_storage_type = VirtualStorageType::auto();
+
+        let parent = VirtualDisk::open(
+            default_storage_type,
+            parent_name,
+            VirtualDiskAccessMask::None,
+            open_virtual_disk::Flag::NoParents as u32,
+            Some(&parameters),
+        )?;
+
+        let vhd_info_wrapper = parent.get_information(get_virtual_disk::InfoVersion::Size)?;
+        block_size_in_bytes = unsafe { vhd_info_wrapper.info().version_details.size.block_size };
+    }
+
+    let parent_name_wstr = widestring::WideCString::from_str(parent_name).unwrap();
+    let mut parameters = unsafe { std::mem::zeroed::<create_virtual_disk::Parameters>() };
+    parameters.version = create_virtual_disk::Version::Version2;
+    parameters.version_details.version2.parent_path = parent_name_wstr.as_ptr();
+    parameters.version_details.version2.block_size_in_bytes = block_size_in_bytes;
+    parameters.version_details.version2.open_flags = open_virtual_disk::Flag::CachedIo as u32;
+
+    let default_storage_type = VirtualStorageType::auto();
+
+    let _virtual_disk = VirtualDisk::create(
+        default_storage_type,
+        filename,
+        VirtualDiskAccessMask::None,
+        None,
+        create_virtual_disk::Flag::None as u32,
+        provider_flags::ProviderFlags::None.bits(),
+        &parameters,
+        None,
+    )?;
+
+    Ok(())
+}
+
+/// Creates a new diff VHD specified by filename based on the given parent disk, bound to a
+/// resiliency GUID so that replica consumers can reconnect to it after a failure.
+/// The parent is opened to supply its storage type explicitly instead of letting the provider
+/// infer it from the file extension.
+///
+/// The resiliency GUID isn't reported by `get_information`, so the round trip is validated by
+/// reopening the new VHD with the GUID and checking that the same virtual disk is returned.
+/// Returns ERROR_INVALID_DATA if the reopened VHD has a different virtual disk ID.
+pub fn create_resilient_diff_vhd(
+    filename: &str,
+    parent_name: &str,
+    resiliency_guid: Guid,
+) -> WinResult<VirtualDisk> {
+    if guid_are_equal(&resiliency_guid, &GUID_NULL) {
+        return Err(WinResultCode::ErrorInvalidArgument);
+    }
+
+    let parent_storage_type = VirtualDisk::open_options()
+        .get_info_only(true)
+        .open(parent_name)?
+        .storage_type()?;
+
+    let virtual_disk_id = {
+        let virtual_disk = VirtualDisk::create_options()
+            .parent_path(parent_name)
+            .parent_virtual_storage_type(parent_storage_type)
+            .resiliency_guid(resiliency_guid)
+            .open_flags(open_virtual_disk::Flag::CachedIo as u32)
+            .create(filename)?;
+
+        let info_wrapper =
+            virtual_disk.get_information(get_virtual_disk::InfoVersion::VirtualDiskId)?;
+        unsafe { info_wrapper.info().version_details.virtual_disk_id }
+    };
+
+    let virtual_disk = VirtualDisk::open_options()
+        .resiliency_guid(resiliency_guid)
+        .open(filename)?;
+
+    let info_wrapper =
+        virtual_disk.get_information(get_virtual_disk::InfoVersion::VirtualDiskId)?;
+
+    if !guid_are_equal(
+        unsafe { &info_wrapper.info().version_details.virtual_disk_id },
+        &virtual_disk_id,
+    ) {
+        return Err(error_code_to_winresult_code(
+            winapi::shared::winerror::ERROR_INVALID_DATA,
+        ));
+    }
+
+    Ok(virtual_disk)
+}
+
+/// Creates a zero filled file of the given size and opens it as a blank virtual disk,
+/// skipping virtual disk verification. The returned virtual disk is ready to be populated with
+/// raw blocks, e.g. by copy tools writing a fixed VHD image.
+/// The virtual storage type is detected from the file extension.
+/// If the file can't be opened as a blank virtual disk, it is deleted.
+pub fn create_blank_backed_vhd(filename: &str, size: SizeSpec) -> WinResult<VirtualDisk> {
+    let size_bytes = size.validate_for_file(filename)?;
+    let file = match std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(filename)
+    {
+        Ok(file) => file,
+        Err(error) => {
+            return Err(match error.raw_os_error() {
+                Some(error) => error_code_to_winresult_code(error as u32),
+                None => WinResultCode::ErrorGenFailure,
+            })
+        }
+    };
+
+    let set_len_result = file.set_len(size_bytes);
+    drop(file);
+
+    if let Err(error) = set_len_result {
+        #[allow(unused_must_use)]
+        {
+            std::fs::remove_file(filename);
+        }
+
+        return Err(match error.raw_os_error() {
+            Some(error) => error_code_to_winresult_code(error as u32),
+            None => WinResultCode::ErrorGenFailure,
+        });
+    }
+
+    let default_storage_type = VirtualStorageType::auto();
+
+    let parameters = open_virtual_disk::Parameters {
+        version: open_virtual_disk::Version::Version2,
+        version_details: open_virtual_disk::VersionDetails {
+            version2: open_virtual_disk::Version2 {
+                get_info_only: 0,
+                read_only: 0,
+                resiliency_guid: GUID_NULL,
+            },
+        },
+    };
+
+    match VirtualDisk::open(
+        default_storage_type,
+        filename,
+        VirtualDiskAccessMask::None,
+        open_virtual_disk::Flag::BlankFile as u32,
+        Some(&parameters),
+    ) {
+        Ok(virtual_disk) => Ok(virtual_disk),
+        Err(error) => {
+            #[allow(unused_must_use)]
+            {
+                std::fs::remove_file(filename);
+            }
+
+            Err(error)
+        }
+    }
+}
+
+/// Callback invoked with the current and completion values of a long running VHD operation.
+pub type ProgressCallback<'callback> = &'callback mut dyn FnMut(u64, u64);
+
+/// Creates a VHD from the contents of another VHD. This is used to defragment VHDs
+/// after they are fully constructed.
+/// Waits for the copy to complete, reporting its progress through the optional callback.
+/// If `verify` is true, the virtual sizes and a sample of the contents of both VHDs
+/// are compared once the copy is done.
+pub fn create_vhd_from_source(
+    filename: &str,
+    source_filename: &str,
+    block_size_mb: u32,
+    progress: Option<ProgressCallback>,
+    verify: bool,
+) -> WinResult<()> {
+    let source_path_wstr = widestring::WideCString::from_str(source_filename).unwrap();
+    let mut parameters = unsafe { std::mem::zeroed::<create_virtual_disk::Parameters>() };
+    parameters.version = create_virtual_disk::Version::Version2;
+    parameters.version_details.version2.source_path = source_path_wstr.as_ptr();
+    parameters.version_details.version2.block_size_in_bytes = block_size_mb * 1024 * 1024;
+    parameters.version_details.version2.open_flags = open_virtual_disk::Flag::CachedIo as u32;
+
+    let default_storage_type = VirtualStorageType::auto();
+
+    let overlapped_event = OverlappedEvent::new()?;
+
+    {
+        let virtual_disk = VirtualDisk::create_overlapped(
+            default_storage_type,
+            filename,
+            VirtualDiskAccessMask::None,
+            None,
+            create_virtual_disk::Flag::None as u32,
+            provider_flags::ProviderFlags::None.bits(),
+            &parameters,
+            overlapped_event.as_overlapped(),
+        )?;
+
+        wait_for_vhd_operation_with_progress(
+            &virtual_disk,
+            overlapped_event.as_overlapped(),
+            progress,
+        )?;
+    }
+
+    if verify {
+        verify_vhd_copy(filename, source_filename)?;
+    }
+
+    Ok(())
+}
+
+/// Compares the virtual size and a sample of the contents of a VHD and the VHD it was copied from.
+/// Fails with ERROR_INVALID_DATA if the sizes differ, and with ERROR_CRC if the contents differ.
+fn verify_vhd_copy(filename: &str, source_filename: &str) -> WinResult<()> {
+    let virtual_size = |virtual_disk: &VirtualDisk| -> WinResult<u64> {
+        let info_wrapper = virtual_disk.get_information(get_virtual_disk::InfoVersion::Size)?;
+        Ok(unsafe { info_wrapper.info().version_details.size.virtual_size })
+    };
+
+    let copy = open_vhd(filename, true)?;
+    let source = open_vhd(source_filename, true)?;
+    let copy_size = virtual_size(&copy)?;
+
+    if copy_size != virtual_size(&source)? {
+        return Err(error_code_to_winresult_code(
+            winapi::shared::winerror::ERROR_INVALID_DATA,
+        ));
+    }
+
+    if sampled_content_hash(&copy, copy_size)? != sampled_content_hash(&source, copy_size)? {
+        return Err(error_code_to_winresult_code(
+            winapi::shared::winerror::ERROR_CRC,
+        ));
+    }
+
+    Ok(())
+}
+
+/// Attaches the VHD read-only and hashes evenly spaced samples of its contents.
+fn sampled_content_hash(virtual_disk: &VirtualDisk, virtual_size: u64) -> WinResult<u64> {
+    use std::hash::Hasher;
+    use winapi::um::{fileapi, winnt};
+
+    const SAMPLE_COUNT: u64 = 64;
+    const SAMPLE_SIZE: u64 = 64 * 1024;
+
+    let parameters = attach_virtual_disk::Parameters {
+        version: attach_virtual_disk::Version::Version1,
+        version_details: attach_virtual_disk::VersionDetails {
+            version1: attach_virtual_disk::Version1 { reserved: 0 },
+        },
+    };
+
+    virtual_disk.attach(
+        None,
+        attach_virtual_disk::Flag::ReadOnly as u32
+            | attach_virtual_disk::Flag::NoDriveLetter as u32,
+        provider_flags::ProviderFlags::None.bits(),
+        &parameters,
+        None,
+    )?;
+
+    let hash_result = (|| -> WinResult<u64> {
+        let disk_path = virtual_disk.get_physical_path()?;
+        wait_for_disk(&disk_path, DISK_ARRIVAL_DEFAULT_TIMEOUT)?;
+        let disk = Disk::open(&disk_path, Some(winnt::GENERIC_READ), None)?;
+
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        let mut buffer: Vec<Byte> = vec![0; SAMPLE_SIZE as usize];
+        let sample_count =
+            std::cmp::max(1, std::cmp::min(SAMPLE_COUNT, virtual_size / SAMPLE_SIZE));
+        let stride = (virtual_size / sample_count / SAMPLE_SIZE) * SAMPLE_SIZE;
+
+        for sample in 0..sample_count {
+            let offset = sample * stride;
+            let mut overlapped = unsafe { std::mem::zeroed::<Overlapped>() };
+            let mut bytes_read: DWord = 0;
+
+            unsafe {
+                overlapped.u.s_mut().Offset = offset as DWord;
+                overlapped.u.s_mut().OffsetHigh = (offset >> 32) as DWord;
+
+                if fileapi::ReadFile(
+                    disk.get_handle(),
+                    buffer.as_mut_ptr() as PVoid,
+                    SAMPLE_SIZE as DWord,
+                    &mut bytes_read,
+                    &mut overlapped,
+                ) == 0
+                {
+                    return Err(error_code_to_winresult_code(
+                        winapi::um::errhandlingapi::GetLastError(),
+                    ));
+                }
+            }
+
+            hasher.write(&buffer[..bytes_read as usize]);
+        }
+
+        Ok(hasher.finish())
+    })();
+
+    let detach_result = virtual_disk.detach(
+        detach_virtual_disk::Flag::None as u32,
+        provider_flags::ProviderFlags::None.bits(),
+    );
+    let hash = hash_result?;
+    detach_result?;
+    Ok(hash)
+}
+
+/// Digest algorithms supported by `hash_vhd_contents`.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HashAlgo {
+    /// SHA-256, computed through the CNG BCrypt provider.
+    Sha256,
+    /// 64-bit xxHash with a seed of 0, much faster than SHA-256 but not cryptographically secure.
+    XxHash64,
+}
+
+/// Contents of a VHD hashed by `hash_vhd_contents`.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HashScope {
+    /// The raw bytes of the file backing the VHD, metadata included.
+    /// Ranges of a sparse backing file that are not allocated are hashed as zeros without being read.
+    BackingFile,
+    /// The block device surfaced by the VHD, which must be attached.
+    /// Two VHDs with the same virtual contents hash the same regardless of their format.
+    AttachedDisk,
+}
+
+/// Digest produced by `hash_vhd_contents`.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct ContentDigest {
+    pub algorithm: HashAlgo,
+    pub digest: Vec<Byte>,
+    /// Number of bytes fed to the hash, including the unallocated ranges hashed as zeros.
+    pub bytes_hashed: u64,
+}
+
+impl ContentDigest {
+    /// Returns the digest as a lowercase hexadecimal string.
+    pub fn to_hex(&self) -> String {
+        self.digest
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+}
+
+/// Streaming 64-bit xxHash, seeded with 0.
+struct XxHash64 {
+    accumulators: [u64; 4],
+    buffer: [Byte; 32],
+    buffer_len: usize,
+    total_len: u64,
+}
+
+impl XxHash64 {
+    const PRIME_1: u64 = 0x9E37_79B1_85EB_CA87;
+    const PRIME_2: u64 = 0xC2B2_AE3D_27D4_EB4F;
+    const PRIME_3: u64 = 0x1656_67B1_9E37_79F9;
+    const PRIME_4: u64 = 0x85EB_CA77_C2B2_AE63;
+    const PRIME_5: u64 = 0x27D4_EB2F_1656_67C5;
+
+    fn new() -> XxHash64 {
+        XxHash64 {
+            accumulators: [
+                XxHash64::PRIME_1.wrapping_add(XxHash64::PRIME_2),
+                XxHash64::PRIME_2,
+                0,
+                0u64.wrapping_sub(XxHash64::PRIME_1),
+            ],
+            buffer: [0; 32],
+            buffer_len: 0,
+            total_len: 0,
+        }
+    }
+
+    fn round(accumulator: u64, input: u64) -> u64 {
+        accumulator
+            .wrapping_add(input.wrapping_mul(XxHash64::PRIME_2))
+            .rotate_left(31)
+            .wrapping_mul(XxHash64::PRIME_1)
+    }
+
+    fn merge_round(hash: u64, accumulator: u64) -> u64 {
+        (hash ^ XxHash64::round(0, accumulator))
+            .wrapping_mul(XxHash64::PRIME_1)
+            .wrapping_add(XxHash64::PRIME_4)
+    }
+
+    fn read_u64(bytes: &[Byte]) -> u64 {
+        let mut word = [0; 8];
+        word.copy_from_slice(&bytes[..8]);
+        u64::from_le_bytes(word)
+    }
+
+    fn process_stripe(&mut self, stripe: &[Byte]) {
+        for (lane, accumulator) in self.accumulators.iter_mut().enumerate() {
+            *accumulator = XxHash64::round(*accumulator, XxHash64::read_u64(&stripe[lane * 8..]));
+        }
+    }
+
+    fn update(&mut self, mut data: &[Byte]) {
+        self.total_len += data.len() as u64;
+
+        if self.buffer_len > 0 {
+            let needed = std::cmp::min(32 - self.buffer_len, data.len());
+            self.buffer[self.buffer_len..self.buffer_len + needed].copy_from_slice(&data[..needed]);
+            self.buffer_len += needed;
+            data = &data[needed..];
+
+            if self.buffer_len < 32 {
+                return;
+            }
+
+            let stripe = self.buffer;
+            self.process_stripe(&stripe);
+            self.buffer_len = 0;
+        }
+
+        while data.len() >= 32 {
+            self.process_stripe(&data[..32]);
+            data = &data[32..];
+        }
+
+        self.buffer[..data.len()].copy_from_slice(data);
+        self.buffer_len = data.len();
+    }
+
+    fn finish(&self) -> u64 {
+        let [v1, v2, v3, v4] = self.accumulators;
+
+        let mut hash = if self.total_len >= 32 {
+            let mut hash = v1
+                .rotate_left(1)
+                .wrapping_add(v2.rotate_left(7))
+                .wrapping_add(v3.rotate_left(12))
+                .wrapping_add(v4.rotate_left(18));
+            for accumulator in &self.accumulators {
+                hash = XxHash64::merge_round(hash, *accumulator);
+            }
+            hash
+        } else {
+            XxHash64::PRIME_5
+        };
+
+        hash = hash.wrapping_add(self.total_len);
+
+        let mut remaining = &self.buffer[..self.buffer_len];
+
+        while remaining.len() >= 8 {
+            hash ^= XxHash64::round(0, XxHash64::read_u64(remaining));
+            hash = hash
+                .rotate_left(27)
+                .wrapping_mul(XxHash64::PRIME_1)
+                .wrapping_add(XxHash64::PRIME_4);
+            remaining = &remaining[8..];
+        }
+
+        if remaining.len() >= 4 {
+            let mut word = [0; 4];
+            word.copy_from_slice(&remaining[..4]);
+            hash ^= (u32::from_le_bytes(word) as u64).wrapping_mul(XxHash64::PRIME_1);
+            hash = hash
+                .rotate_left(23)
+                .wrapping_mul(XxHash64::PRIME_2)
+                .wrapping_add(XxHash64::PRIME_3);
+            remaining = &remaining[4..];
+        }
+
+        for byte in remaining {
+            hash ^= (*byte as u64).wrapping_mul(XxHash64::PRIME_5);
+            hash = hash.rotate_left(11).wrapping_mul(XxHash64::PRIME_1);
+        }
+
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(XxHash64::PRIME_2);
+        hash ^= hash >> 29;
+        hash = hash.wrapping_mul(XxHash64::PRIME_3);
+        hash ^= hash >> 32;
+        hash
+    }
+}
+
+/// SHA-256 hash object of the CNG BCrypt provider, destroyed on drop.
+struct BCryptSha256 {
+    handle: winapi::shared::bcrypt::BCRYPT_HASH_HANDLE,
+}
+
+impl BCryptSha256 {
+    fn new() -> WinResult<BCryptSha256> {
+        use winapi::shared::bcrypt;
+
+        let mut handle: bcrypt::BCRYPT_HASH_HANDLE = std::ptr::null_mut();
+
+        match unsafe {
+            bcrypt::BCryptCreateHash(
+                bcrypt::BCRYPT_SHA256_ALG_HANDLE,
+                &mut handle,
+                std::ptr::null_mut(),
+                0,
+                std::ptr::null_mut(),
+                0,
+                0,
+            )
+        } {
+            0 => Ok(BCryptSha256 { handle }),
+            _ => Err(WinResultCode::ErrorGenFailure),
+        }
+    }
+
+    fn update(&mut self, data: &[Byte]) -> WinResult<()> {
+        for chunk in data.chunks(ULong::max_value() as usize) {
+            match unsafe {
+                winapi::shared::bcrypt::BCryptHashData(
+                    self.handle,
+                    chunk.as_ptr() as *mut Byte,
+                    chunk.len() as ULong,
+                    0,
+                )
+            } {
+                0 => {}
+                _ => return Err(WinResultCode::ErrorGenFailure),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> WinResult<Vec<Byte>> {
+        let mut digest: Vec<Byte> = vec![0; 32];
+
+        match unsafe {
+            winapi::shared::bcrypt::BCryptFinishHash(
+                self.handle,
+                digest.as_mut_ptr(),
+                digest.len() as ULong,
+                0,
+            )
+        } {
+            0 => Ok(digest),
+            _ => Err(WinResultCode::ErrorGenFailure),
+        }
+    }
+}
+
+impl Drop for BCryptSha256 {
+    fn drop(&mut self) {
+        unsafe {
+            winapi::shared::bcrypt::BCryptDestroyHash(self.handle);
+        }
+    }
+}
+
+enum ContentHasher {
+    Sha256(BCryptSha256),
+    XxHash64(XxHash64),
+}
+
+impl ContentHasher {
+    fn new(algorithm: HashAlgo) -> WinResult<ContentHasher> {
+        match algorithm {
+            HashAlgo::Sha256 => Ok(ContentHasher::Sha256(BCryptSha256::new()?)),
+            HashAlgo::XxHash64 => Ok(ContentHasher::XxHash64(XxHash64::new())),
+        }
+    }
+
+    fn update(&mut self, data: &[Byte]) -> WinResult<()> {
+        match self {
+            ContentHasher::Sha256(hasher) => hasher.update(data),
+            ContentHasher::XxHash64(hasher) => {
+                hasher.update(data);
+                Ok(())
+            }
+        }
+    }
+
+    fn finish(&mut self) -> WinResult<Vec<Byte>> {
+        match self {
+            ContentHasher::Sha256(hasher) => hasher.finish(),
+            ContentHasher::XxHash64(hasher) => Ok(hasher.finish().to_be_bytes().to_vec()),
+        }
+    }
+}
+
+/// Size of the reads issued while hashing the contents of a VHD.
+/// A multiple of every sector size so reads on the attached disk stay aligned.
+const HASH_CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// Queries the allocated ranges of a file as (offset, length) pairs sorted by offset.
+/// Files that are not sparse report a single range covering the whole file.
+fn allocated_ranges(file: &std::fs::File, file_size: u64) -> WinResult<Vec<(u64, u64)>> {
+    use std::os::windows::io::AsRawHandle;
+    use winapi::shared::winerror;
+    use winapi::um::{errhandlingapi, ioapiset, winioctl};
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct FileAllocatedRangeBuffer {
+        file_offset: i64,
+        length: i64,
+    }
+
+    const RANGES_PER_QUERY: usize = 512;
+
+    let mut ranges = Vec::new();
+    let mut query = FileAllocatedRangeBuffer {
+        file_offset: 0,
+        length: file_size as i64,
+    };
+    let mut output = [FileAllocatedRangeBuffer {
+        file_offset: 0,
+        length: 0,
+    }; RANGES_PER_QUERY];
+
+    while (query.length as u64) > 0 {
+        let mut bytes: DWord = 0;
+
+        let more_data = match unsafe {
+            ioapiset::DeviceIoControl(
+                file.as_raw_handle() as Handle,
+                winioctl::FSCTL_QUERY_ALLOCATED_RANGES,
+                &mut query as *mut FileAllocatedRangeBuffer as PVoid,
+                std::mem::size_of::<FileAllocatedRangeBuffer>() as DWord,
+                output.as_mut_ptr() as PVoid,
+                std::mem::size_of_val(&output) as DWord,
+                &mut bytes,
+                std::ptr::null_mut(),
+            )
+        } {
+            0 => match unsafe { errhandlingapi::GetLastError() } {
+                winerror::ERROR_MORE_DATA => true,
+                error => return Err(error_code_to_winresult_code(error)),
+            },
+            _ => false,
+        };
+
+        let count = bytes as usize / std::mem::size_of::<FileAllocatedRangeBuffer>();
+        ranges.extend(
+            output[..count]
+                .iter()
+                .map(|range| (range.file_offset as u64, range.length as u64)),
+        );
+
+        match (more_data, ranges.last()) {
+            (true, Some(&(offset, length))) => {
+                query.file_offset = (offset + length) as i64;
+                query.length = file_size.saturating_sub(offset + length) as i64;
+            }
+            _ => break,
+        }
+    }
+
+    Ok(ranges)
+}
+
+/// Hashes the contents of a VHD sequentially, reporting the bytes hashed so far and the total
+/// through the optional progress callback.
+/// This allows verifying an image after a conversion, fork or mirror.
+/// Hashing the `AttachedDisk` scope requires the VHD to be attached, otherwise the error
+/// of `get_physical_path` is returned.
+pub fn hash_vhd_contents(
+    virtual_disk: &VirtualDisk,
+    algorithm: HashAlgo,
+    scope: HashScope,
+    mut progress: Option<ProgressCallback>,
+) -> WinResult<ContentDigest> {
+    use winapi::um::winnt;
+
+    let mut hasher = ContentHasher::new(algorithm)?;
+    let mut buffer: Vec<Byte> = vec![0; HASH_CHUNK_SIZE as usize];
+    let mut bytes_hashed: u64 = 0;
+
+    let mut report = |bytes_hashed: u64, total: u64| {
+        if let Some(callback) = progress.as_mut() {
+            (*callback)(bytes_hashed, total);
+        }
+    };
+
+    match scope {
+        HashScope::BackingFile => {
+            use std::os::windows::fs::OpenOptionsExt;
+            use std::os::windows::io::AsRawHandle;
+
+            let file = std::fs::OpenOptions::new()
+                .read(true)
+                .share_mode(winnt::FILE_SHARE_READ | winnt::FILE_SHARE_WRITE)
+                .open(virtual_disk.backing_file_path()?)
+                .map_err(|error| io_error_to_winresult_code(&error))?;
+
+            let file_size = file
+                .metadata()
+                .map_err(|error| io_error_to_winresult_code(&error))?
+                .len();
+
+            let mut ranges = allocated_ranges(&file, file_size)?;
+            ranges.push((file_size, 0));
+
+            for (offset, length) in ranges {
+                // Unallocated ranges read back as zeros, feed them without touching the disk
+                if offset > bytes_hashed {
+                    for byte in buffer.iter_mut() {
+                        *byte = 0;
+                    }
+
+                    while bytes_hashed < offset {
+                        let chunk = std::cmp::min(HASH_CHUNK_SIZE, offset - bytes_hashed);
+                        hasher.update(&buffer[..chunk as usize])?;
+                        bytes_hashed += chunk;
+                        report(bytes_hashed, file_size);
+                    }
+                }
+
+                let end = std::cmp::min(offset + length, file_size);
+
+                while bytes_hashed < end {
+                    let chunk = std::cmp::min(HASH_CHUNK_SIZE, end - bytes_hashed) as usize;
+                    let bytes_read = read_handle_at(
+                        file.as_raw_handle() as Handle,
+                        bytes_hashed,
+                        &mut buffer[..chunk],
+                    )?;
+
+                    if bytes_read == 0 {
+                        return Err(error_code_to_winresult_code(
+                            winapi::shared::winerror::ERROR_HANDLE_EOF,
+                        ));
+                    }
+
+                    hasher.update(&buffer[..bytes_read])?;
+                    bytes_hashed += bytes_read as u64;
+                    report(bytes_hashed, file_size);
+                }
+            }
+        }
+        HashScope::AttachedDisk => {
+            let info_wrapper = virtual_disk.get_information(get_virtual_disk::InfoVersion::Size)?;
+            let virtual_size = unsafe { info_wrapper.info().version_details.size.virtual_size };
+
+            let disk_path = virtual_disk.get_physical_path()?;
+            wait_for_disk(&disk_path, DISK_ARRIVAL_DEFAULT_TIMEOUT)?;
+            let disk = Disk::open(&disk_path, Some(winnt::GENERIC_READ), None)?;
+
+            while bytes_hashed < virtual_size {
+                let chunk = std::cmp::min(HASH_CHUNK_SIZE, virtual_size - bytes_hashed) as usize;
+                let bytes_read =
+                    read_handle_at(disk.get_handle(), bytes_hashed, &mut buffer[..chunk])?;
+
+                if bytes_read == 0 {
+                    return Err(error_code_to_winresult_code(
+                        winapi::shared::winerror::ERROR_HANDLE_EOF,
+                    ));
+                }
+
+                hasher.update(&buffer[..bytes_read])?;
+                bytes_hashed += bytes_read as u64;
+                report(bytes_hashed, virtual_size);
+            }
+        }
+    }
+
+    Ok(ContentDigest {
+        algorithm,
+        digest: hasher.finish()?,
+        bytes_hashed,
+    })
 }
 
 /// Finds the given mounted VHD and returns the resulting volume path.
@@ -269,8 +2484,138 @@ pub fn get_vhd_volume_path(virtual_disk: &VirtualDisk) -> WinResult<String> {
     disk.volume_path()
 }
 
-/// Determines the VHD path of the VHD hosting a volume or file within the volume.
-pub fn get_vhd_from_filename(filename: &str) -> WinResult<String> {
+/// Finds the volumes of the disk backed by the VHD specified by filename,
+/// without requiring the caller to hold an open handle to the VHD.
+/// The VHD is opened to query information only, so this works while other processes use it.
+/// Returns an empty vector if the VHD is not attached.
+pub fn volume_path_for_vhd_file(filename: &str) -> WinResult<Vec<String>> {
+    let virtual_disk = VirtualDisk::open_options()
+        .get_info_only(true)
+        .parent_cached_io(true)
+        .open(filename)?;
+
+    if !is_vhd_attached(&virtual_disk, filename)? {
+        return Ok(Vec::new());
+    }
+
+    let disk_path = virtual_disk.get_physical_path()?;
+    Disk::open(&disk_path, Some(0), None)?.volume_paths()
+}
+
+/// Determines the VHD path of the VHD hosting a volume or file within the volume.
+pub fn get_vhd_from_filename(filename: &str) -> WinResult<String> {
+    use winapi::um::{fileapi, winnt};
+
+    let file = create_file(
+        filename,
+        0,
+        winnt::FILE_SHARE_READ | winnt::FILE_SHARE_WRITE,
+        None,
+        fileapi::OPEN_EXISTING,
+        winnt::FILE_ATTRIBUTE_NORMAL,
+        None,
+    )?;
+
+    let virtual_disk = VirtualDisk::wrap_handle(file)?;
+    let dependency_info_wrapper = match virtual_disk.get_storage_dependency_information(
+        storage_dependency::GetFlag::HostVolumes as u32,
+        storage_dependency::InfoVersion::Version2,
+    ) {
+        Err(WinResultCode::WindowsErrorCode(error))
+            if error == winapi::shared::winerror::ERROR_VIRTDISK_NOT_VIRTUAL_DISK as u32 =>
+        {
+            return Ok(String::from(""));
+        }
+        Err(error)
+            if winresult_code_to_error_code(error)
+                == winapi::shared::winerror::ERROR_VIRTDISK_NOT_VIRTUAL_DISK as u32 =>
+        {
+            return Ok(String::from(""));
+        }
+        Err(error) => {
+            return Err(error);
+        }
+        Ok(wrapper) => wrapper,
+    };
+
+    const MAX_PATH: usize = 256;
+    let mut filename: [WChar; MAX_PATH] = [0; MAX_PATH];
+
+    unsafe {
+        match PathCchCombine(
+            filename.as_mut_ptr(),
+            MAX_PATH,
+            dependency_info_wrapper.info().version_details.version2[0].host_volume_name,
+            dependency_info_wrapper.info().version_details.version2[0]
+                .dependent_volume_relative_path,
+        ) {
+            0 => wide_to_string(&filename),
+            _ => Err(WinResultCode::ErrorGenFailure),
+        }
+    }
+}
+
+/// Returns true if the given file or volume is hosted on an attached virtual disk.
+/// Files that can't be opened are reported as not being on a virtual disk.
+pub fn is_on_virtual_disk(path: &str) -> bool {
+    match get_vhd_from_filename(path) {
+        Ok(vhd_path) => !vhd_path.is_empty(),
+        Err(_) => false,
+    }
+}
+
+/// Returns the storage type of the given file if it is a virtual disk file,
+/// detected from the signatures in its contents. See `detect_from_header`.
+/// Files that can't be read are reported as not being virtual disk files.
+pub fn is_virtual_disk_file(path: &str) -> Option<VirtualStorageType> {
+    detect_from_header(path)
+        .ok()
+        .and_then(|storage_type| storage_type)
+}
+
+/// Storage a file or volume is hosted on, as reported by its storage dependency information.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone)]
+pub struct HostedOn {
+    /// Nesting level of this entry, 0 being the virtual disk closest to the queried file.
+    pub ancestor_level: u32,
+
+    /// A u32 representation of any valid combination from `storage_dependency::DependentDiskFlag` values.
+    pub dependency_type_flags: u32,
+
+    pub provider_specific_flags: u32,
+    pub virtual_storage_type: VirtualStorageType,
+
+    /// Path of the virtual disk device (e.g. \\.\PhysicalDrive3).
+    pub dependency_device_name: String,
+
+    /// Volume hosting the virtual disk backing file.
+    pub host_volume_name: String,
+
+    /// Volume exposed by the virtual disk.
+    pub dependent_volume_name: String,
+
+    /// Path of the virtual disk backing file, relative to the host volume.
+    pub dependent_volume_relative_path: String,
+}
+
+impl HostedOn {
+    /// Returns the full path of the virtual disk backing file.
+    pub fn backing_file_path(&self) -> String {
+        let mut path = String::from(self.host_volume_name.trim_end_matches('\\'));
+        path.push('\\');
+        path.push_str(self.dependent_volume_relative_path.trim_start_matches('\\'));
+        path
+    }
+}
+
+/// Entry of the storage dependency information of a file or volume, see `host_chain_for_file`.
+pub type StorageDependencyEntry = HostedOn;
+
+/// Returns the complete nesting of storage hosting the given file or volume
+/// (e.g. a VHD inside a VHD inside a host volume), ordered by ancestor level.
+/// Returns an empty vector if the file is not hosted on a virtual disk.
+pub fn host_chain_for_file(filename: &str) -> WinResult<Vec<HostedOn>> {
     use winapi::um::{fileapi, winnt};
 
     let file = create_file(
@@ -283,155 +2628,933 @@ pub fn get_vhd_from_filename(filename: &str) -> WinResult<String> {
         None,
     )?;
 
-    let virtual_disk = VirtualDisk::wrap_handle(file)?;
-    let dependency_info_wrapper = match virtual_disk.get_storage_dependency_information(
-        storage_dependency::GetFlag::HostVolumes as u32,
-        storage_dependency::InfoVersion::Version2,
-    ) {
-        Err(WinResultCode::WindowsErrorCode(error))
-            if error == winapi::shared::winerror::ERROR_VIRTDISK_NOT_VIRTUAL_DISK as u32 =>
-        {
-            return Ok(String::from(""));
+    let virtual_disk = VirtualDisk::wrap_handle(file)?;
+    let dependency_info_wrapper = match virtual_disk.get_storage_dependency_information(
+        storage_dependency::GetFlag::HostVolumes as u32,
+        storage_dependency::InfoVersion::Version2,
+    ) {
+        Err(error)
+            if winresult_code_to_error_code(error)
+                == winapi::shared::winerror::ERROR_VIRTDISK_NOT_VIRTUAL_DISK as u32 =>
+        {
+            return Ok(Vec::new());
+        }
+        Err(error) => {
+            return Err(error);
+        }
+        Ok(wrapper) => wrapper,
+    };
+
+    let wide_ptr_to_string = |ptr: PWStr| -> String {
+        if ptr.is_null() {
+            String::new()
+        } else {
+            let mut string =
+                unsafe { widestring::WideCString::from_ptr_str(ptr) }.to_string_lossy();
+            string.shrink_to_fit();
+            string
+        }
+    };
+
+    let info = dependency_info_wrapper.info();
+    let mut chain: Vec<HostedOn> = Vec::new();
+
+    unsafe {
+        let entries = info.version_details.version2.as_ptr();
+
+        for index in 0..info.number_entries as isize {
+            let entry = &*entries.offset(index);
+            chain.push(HostedOn {
+                ancestor_level: entry.ancestor_level,
+                dependency_type_flags: entry.dependency_type_flags,
+                provider_specific_flags: entry.provider_specific_flags,
+                virtual_storage_type: entry.virtual_storage_type,
+                dependency_device_name: wide_ptr_to_string(entry.dependency_device_name),
+                host_volume_name: wide_ptr_to_string(entry.host_volume_name),
+                dependent_volume_name: wide_ptr_to_string(entry.dependent_volume_name),
+                dependent_volume_relative_path: wide_ptr_to_string(
+                    entry.dependent_volume_relative_path,
+                ),
+            });
+        }
+    }
+
+    chain.sort_by_key(|hosted_on| hosted_on.ancestor_level);
+    Ok(chain)
+}
+
+/// Sets the caching mode on a mounted VHD.
+pub fn set_vhd_caching_mode(virtual_disk: &VirtualDisk, cache_mode: u16) -> WinResult<()> {
+    #[repr(C)]
+    struct CachePolicyRequest {
+        request_level: u32,
+        cache_mode: u16,
+    }
+
+    let mut request = CachePolicyRequest {
+        request_level: 1,
+        cache_mode: cache_mode,
+    };
+
+    let mut bytes: DWord = 0;
+
+    unsafe {
+        match winapi::um::ioapiset::DeviceIoControl(
+            virtual_disk.get_handle(),
+            2955792, // IOCTL_STORAGE_SET_SURFACE_CACHE_POLICY
+            &mut request as *mut _ as PVoid,
+            std::mem::size_of::<CachePolicyRequest>() as u32,
+            std::ptr::null_mut(),
+            0,
+            &mut bytes,
+            std::ptr::null_mut(),
+        ) {
+            0 => Err(error_code_to_winresult_code(
+                winapi::um::errhandlingapi::GetLastError(),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Returns the size of the VHD on the physical disk.
+pub fn get_physical_vhd_size_in_kb(virtual_disk: &VirtualDisk) -> WinResult<u64> {
+    let info_wrapper = virtual_disk.get_information(get_virtual_disk::InfoVersion::Size)?;
+    unsafe { Ok(info_wrapper.info().version_details.size.physical_size / 1024) }
+}
+
+/// Opens the disk backed by the secified VHD.
+/// Waits up to `DISK_ARRIVAL_DEFAULT_TIMEOUT` for the disk to arrive if it can't be opened yet.
+pub fn open_vhd_backed_disk(virtual_disk: &VirtualDisk) -> WinResult<Disk> {
+    open_vhd_backed_disk_with_profile(virtual_disk, IoProfile::default())
+}
+
+/// Opens the disk surfaced by an attached VHD with the given IO profile.
+pub fn open_vhd_backed_disk_with_profile(
+    virtual_disk: &VirtualDisk,
+    io_profile: IoProfile,
+) -> WinResult<Disk> {
+    let disk_path = virtual_disk.get_physical_path()?;
+    wait_for_disk(&disk_path, DISK_ARRIVAL_DEFAULT_TIMEOUT)?;
+    Disk::open(&disk_path, None, Some(io_profile.disk_file_flags()))
+}
+
+/// DISK_PERFORMANCE structure returned by IOCTL_DISK_PERFORMANCE, not defined by winapi.
+#[repr(C)]
+struct DiskPerformance {
+    bytes_read: i64,
+    bytes_written: i64,
+    read_time: i64,
+    write_time: i64,
+    idle_time: i64,
+    read_count: DWord,
+    write_count: DWord,
+    queue_depth: DWord,
+    split_count: DWord,
+    query_time: i64,
+    storage_device_number: DWord,
+    _storage_manager_name: [WChar; 8],
+}
+
+/// Cumulative IO counters of the disk surfaced by an attached VHD, see `io_statistics`.
+/// Counters start when the disk arrives; use `VhdIoStats::rates_since` on two samples
+/// to get IOPS, throughput and latencies over the interval between them.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct VhdIoStats {
+    /// Number of the disk surfaced by the VHD.
+    pub disk_number: u32,
+
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub read_count: u64,
+    pub write_count: u64,
+
+    /// Accumulated time spent servicing reads.
+    pub read_time: std::time::Duration,
+
+    /// Accumulated time spent servicing writes.
+    pub write_time: std::time::Duration,
+
+    /// Accumulated time the disk had no outstanding requests.
+    pub idle_time: std::time::Duration,
+
+    /// Number of requests outstanding on the disk when it was sampled.
+    pub queue_depth: u32,
+
+    /// Number of requests split into multiple requests by the disk stack.
+    pub split_count: u64,
+
+    /// System time the disk was sampled at, in 100 nanosecond units.
+    pub query_time: u64,
+}
+
+/// IO rates of the disk surfaced by an attached VHD over the interval between two samples,
+/// see `VhdIoStats::rates_since`.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct VhdIoRates {
+    /// Length of the interval between the samples.
+    pub interval: std::time::Duration,
+
+    pub read_iops: f64,
+    pub write_iops: f64,
+    pub read_bytes_per_second: f64,
+    pub write_bytes_per_second: f64,
+
+    /// Average time to service a read completed during the interval, zero if there were none.
+    pub average_read_latency: std::time::Duration,
+
+    /// Average time to service a write completed during the interval, zero if there were none.
+    pub average_write_latency: std::time::Duration,
+
+    /// Number of requests outstanding at the end of the interval.
+    pub queue_depth: u32,
+
+    /// Fraction of the interval the disk had outstanding requests, from 0.0 to 1.0.
+    pub utilization: f64,
+}
+
+impl VhdIoStats {
+    /// Returns the rates over the interval between an earlier sample of the same disk and this one.
+    /// Returns ErrorInvalidArgument if the samples are of different disks,
+    /// or the earlier sample wasn't taken before this one.
+    pub fn rates_since(&self, earlier: &VhdIoStats) -> WinResult<VhdIoRates> {
+        if self.disk_number != earlier.disk_number || self.query_time <= earlier.query_time {
+            return Err(WinResultCode::ErrorInvalidArgument);
+        }
+
+        let interval = hundred_nanoseconds(self.query_time - earlier.query_time);
+        let seconds = interval.as_secs_f64();
+        let reads = self.read_count.saturating_sub(earlier.read_count);
+        let writes = self.write_count.saturating_sub(earlier.write_count);
+        let average = |time: std::time::Duration, count: u64| match count {
+            0 => std::time::Duration::from_secs(0),
+            count => std::time::Duration::from_nanos((time.as_nanos() / count as u128) as u64),
+        };
+        let idle_time = self.idle_time.saturating_sub(earlier.idle_time);
+
+        Ok(VhdIoRates {
+            interval,
+            read_iops: reads as f64 / seconds,
+            write_iops: writes as f64 / seconds,
+            read_bytes_per_second: self.bytes_read.saturating_sub(earlier.bytes_read) as f64
+                / seconds,
+            write_bytes_per_second: self.bytes_written.saturating_sub(earlier.bytes_written) as f64
+                / seconds,
+            average_read_latency: average(self.read_time.saturating_sub(earlier.read_time), reads),
+            average_write_latency: average(
+                self.write_time.saturating_sub(earlier.write_time),
+                writes,
+            ),
+            queue_depth: self.queue_depth,
+            utilization: (1.0 - idle_time.as_secs_f64() / seconds).max(0.0).min(1.0),
+        })
+    }
+}
+
+fn hundred_nanoseconds(units: u64) -> std::time::Duration {
+    std::time::Duration::from_nanos(units.saturating_mul(100))
+}
+
+/// Samples the IO counters of the disk surfaced by an attached VHD, through IOCTL_DISK_PERFORMANCE
+/// on the disk itself, so that hosts can monitor the IO of individual container disks without WMI.
+/// The first sample of a disk enables its counters if no other performance consumer did,
+/// in which case it may report zeroes.
+pub fn io_statistics(virtual_disk: &VirtualDisk) -> WinResult<VhdIoStats> {
+    let disk = open_vhd_backed_disk(virtual_disk)?;
+    let mut performance = unsafe { std::mem::zeroed::<DiskPerformance>() };
+    let mut bytes: DWord = 0;
+
+    unsafe {
+        if winapi::um::ioapiset::DeviceIoControl(
+            disk.get_handle(),
+            winapi::um::winioctl::IOCTL_DISK_PERFORMANCE,
+            std::ptr::null_mut(),
+            0,
+            &mut performance as *mut _ as PVoid,
+            std::mem::size_of::<DiskPerformance>() as DWord,
+            &mut bytes,
+            std::ptr::null_mut(),
+        ) == 0
+        {
+            return Err(error_code_to_winresult_code(
+                winapi::um::errhandlingapi::GetLastError(),
+            ));
+        }
+    }
+
+    Ok(VhdIoStats {
+        disk_number: performance.storage_device_number,
+        bytes_read: performance.bytes_read as u64,
+        bytes_written: performance.bytes_written as u64,
+        read_count: performance.read_count as u64,
+        write_count: performance.write_count as u64,
+        read_time: hundred_nanoseconds(performance.read_time as u64),
+        write_time: hundred_nanoseconds(performance.write_time as u64),
+        idle_time: hundred_nanoseconds(performance.idle_time as u64),
+        queue_depth: performance.queue_depth,
+        split_count: performance.split_count as u64,
+        query_time: performance.query_time as u64,
+    })
+}
+
+/// Expands the virtual size of a VHD to the requested size, if the current size is smaller
+/// than the requested size.
+/// Returns true if the VHD was expanded, false if the current size of the VHD is already greater
+/// than or equal to the specified new size.
+/// Returns ErrorInvalidArgument if the new size is out of the limits of the storage type of the VHD.
+pub fn expand_vhd(virtual_disk: &VirtualDisk, new_size: SizeSpec) -> WinResult<bool> {
+    let new_size = new_size.validate(&virtual_disk.storage_type()?)?;
+    let info_wrapper = virtual_disk.get_information(get_virtual_disk::InfoVersion::Size)?;
+
+    if unsafe { info_wrapper.info().version_details.size.virtual_size } < new_size {
+        #[repr(C)]
+        struct VhdResizeRequest {
+            new_virtual_size: u64,
+            expand_only: Boolean,
+            allow_unsafe_virtual_size: Boolean,
+            shrink_to_minimum_safe_size: Boolean,
+        }
+
+        let mut request = VhdResizeRequest {
+            new_virtual_size: new_size,
+            expand_only: 1,
+            allow_unsafe_virtual_size: 0,
+            shrink_to_minimum_safe_size: 0,
+        };
+
+        let mut bytes: DWord = 0;
+
+        unsafe {
+            if winapi::um::ioapiset::DeviceIoControl(
+                virtual_disk.get_handle(),
+                2955600, // IOCTL_STORAGE_RESIZE_VIRTUAL_DISK
+                &mut request as *mut _ as PVoid,
+                std::mem::size_of::<VhdResizeRequest>() as u32,
+                std::ptr::null_mut(),
+                0,
+                &mut bytes,
+                std::ptr::null_mut(),
+            ) == 0
+            {
+                return Err(error_code_to_winresult_code(
+                    winapi::um::errhandlingapi::GetLastError(),
+                ));
+            }
+        }
+
+        // The resize bypasses the VirtualDisk methods that invalidate cached information.
+        virtual_disk.invalidate_info_cache();
+
+        // Let the disk of an attached VHD pick up its new size right away
+        if let Ok(disk_path) = virtual_disk.get_physical_path() {
+            Disk::open(&disk_path, Some(0), None)?.refresh()?;
+        }
+
+        Ok(true)
+    } else {
+        Ok(false)
+    }
+}
+
+/// Sizes realized by `grow_to` and `online_resize`.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct GrowResult {
+    /// True if the virtual size of the VHD was expanded.
+    pub vhd_expanded: bool,
+
+    /// True if the last basic partition and its file system were expanded.
+    pub volume_expanded: bool,
+
+    /// Virtual size of the VHD after the call, in bytes.
+    pub virtual_size: u64,
+
+    /// Size of the file system of the volume in the last basic partition after the call, in bytes.
+    pub volume_size: u64,
+}
+
+/// Grows a VHD and the file system of its last basic partition to the requested size.
+///
+/// The virtual size of the VHD is expanded first if it's smaller than `new_size`.
+/// The VHD is then attached if it isn't already, the disk is told to pick up its new size,
+/// and the partition and its file system are expanded to fill the disk.
+/// A VHD attached by this call is detached before returning.
+pub fn grow_to(virtual_disk: &VirtualDisk, new_size: SizeSpec) -> WinResult<GrowResult> {
+    let vhd_expanded = expand_vhd(virtual_disk, new_size)?;
+    let newly_attached = virtual_disk.get_physical_path().is_err();
+
+    if newly_attached {
+        let options = MountOptions::default();
+        mount_vhd_with_profile(
+            virtual_disk,
+            options.attach_flags(),
+            options.cache_mode,
+            options.io_profile,
+        )?;
+    }
+
+    let result = grow_attached_volume(virtual_disk, vhd_expanded);
+
+    if newly_attached {
+        let dismount_result = dismount_vhd(virtual_disk);
+
+        // A failure growing the volume is the one worth surfacing.
+        if result.is_ok() {
+            dismount_result?;
+        }
+    }
+
+    result
+}
+
+/// Resizes an attached VHD to the requested size while it stays in use,
+/// and grows its last basic partition and file system to fill the disk.
+///
+/// The VHD is expanded through `expand_vhd`, which supports VHDs that are attached
+/// and tells the disk to pick up its new size. The partition and its file system
+/// are then expanded, so the new space is usable as soon as this returns.
+/// Only growing is supported, a VHD that is already as large as `new_size` is left as is
+/// and only its volume is expanded to fill the disk.
+/// Returns the error of `get_physical_path` if the VHD isn't attached.
+pub fn online_resize(virtual_disk: &VirtualDisk, new_size: SizeSpec) -> WinResult<GrowResult> {
+    virtual_disk.get_physical_path()?;
+
+    let vhd_expanded = expand_vhd(virtual_disk, new_size)?;
+    grow_attached_volume(virtual_disk, vhd_expanded)
+}
+
+/// Expands the last basic partition of the disk backed by an attached VHD,
+/// once the disk has been told about any change in its size.
+fn grow_attached_volume(virtual_disk: &VirtualDisk, vhd_expanded: bool) -> WinResult<GrowResult> {
+    let disk = open_vhd_backed_disk(virtual_disk)?;
+
+    if vhd_expanded {
+        disk.refresh()?;
+    }
+
+    let volume_expanded = disk.expand_volume()?;
+    let info_wrapper = virtual_disk.get_information(get_virtual_disk::InfoVersion::Size)?;
+
+    Ok(GrowResult {
+        vhd_expanded,
+        volume_expanded,
+        virtual_size: unsafe { info_wrapper.info().version_details.size.virtual_size },
+        volume_size: volume_space(&disk.last_basic_volume_path()?)?.total,
+    })
+}
+
+/// Fragmentation percentage from which the VHD is worth defragmenting
+/// through `create_vhd_from_source`.
+const FRAGMENTATION_THRESHOLD_PERCENT: u32 = 30;
+
+/// Minimum amount of reclaimable space, in bytes, for a compaction to be worthwhile.
+const RECLAIMABLE_THRESHOLD_BYTES: u64 = 1024 * 1024 * 1024;
+
+/// Recommendation of whether compacting or defragmenting a VHD is worthwhile,
+/// along with the figures it was based on.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CompactionAdvice {
+    /// Fragmentation level of the VHD, as a percentage.
+    pub fragmentation_percent: u32,
+
+    /// Virtual size of the VHD, in bytes.
+    pub virtual_size: u64,
+
+    /// Size of the VHD file on the host, in bytes.
+    pub physical_size: u64,
+
+    /// Free space inside the guest file systems, in bytes.
+    /// None if the VHD is not attached and its volumes can't be inspected.
+    pub guest_free_bytes: Option<u64>,
+
+    /// Estimated amount of host space that compaction would give back, in bytes.
+    pub reclaimable_bytes: u64,
+
+    /// True if compacting the VHD is expected to reclaim a worthwhile amount of space.
+    pub compaction_recommended: bool,
+
+    /// True if the VHD is fragmented enough to be worth recreating with `create_vhd_from_source`.
+    pub defragmentation_recommended: bool,
+}
+
+/// Combines the fragmentation, the physical versus virtual size and the free space inside
+/// the guest file systems of a VHD to tell whether compaction is worthwhile.
+/// Guest free space can only be inspected while the VHD is attached.
+pub fn compaction_recommendation(virtual_disk: &VirtualDisk) -> WinResult<CompactionAdvice> {
+    let fragmentation_percent = virtual_disk.fragmentation_percent()?;
+    let info_wrapper = virtual_disk.get_information(get_virtual_disk::InfoVersion::Size)?;
+    let (virtual_size, physical_size) = unsafe {
+        let size = &info_wrapper.info().version_details.size;
+        (size.virtual_size, size.physical_size)
+    };
+
+    let guest_free_bytes = match virtual_disk.get_physical_path() {
+        Ok(disk_path) => {
+            let disk = Disk::open(&disk_path, Some(0), None)?;
+            let mut free_bytes: u64 = 0;
+
+            for volume_path in disk.volume_paths()? {
+                free_bytes += volume_space(&volume_path)?.free;
+            }
+
+            Some(free_bytes)
+        }
+        Err(_) => None,
+    };
+
+    // Space used by the guest can't be given back, everything else the file holds can.
+    let reclaimable_bytes = match guest_free_bytes {
+        Some(guest_free_bytes) => {
+            let guest_used_bytes = virtual_size.saturating_sub(guest_free_bytes);
+            physical_size.saturating_sub(guest_used_bytes)
+        }
+        None => 0,
+    };
+
+    Ok(CompactionAdvice {
+        fragmentation_percent,
+        virtual_size,
+        physical_size,
+        guest_free_bytes,
+        reclaimable_bytes,
+        compaction_recommended: reclaimable_bytes >= RECLAIMABLE_THRESHOLD_BYTES
+            && reclaimable_bytes >= physical_size / 4,
+        defragmentation_recommended: fragmentation_percent >= FRAGMENTATION_THRESHOLD_PERCENT,
+    })
+}
+
+/// Steps taken by `seal_image` to turn a VHD into a golden image.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SealStep {
+    /// A new virtual disk ID was assigned to the VHD.
+    ResetDiskId,
+    /// Resilient change tracking was disabled.
+    DisableChangeTracking,
+    /// The VHD was fully compacted.
+    Compact,
+    /// The backing file was marked read-only.
+    SetReadOnly,
+}
+
+/// Options of `seal_image`. By default every step is taken.
+#[derive(Copy, Clone)]
+pub struct SealOptions {
+    /// Assigns a new virtual disk ID, so VMs deployed from the image don't share it.
+    /// A random ID is generated if `disk_id` is None.
+    pub reset_disk_id: bool,
+    pub disk_id: Option<Guid>,
+
+    /// Disables resilient change tracking, dropping the tracking state of the image.
+    /// Only applies to VHDX files.
+    pub disable_change_tracking: bool,
+
+    /// Fully compacts the VHD, scanning for zeroed blocks and moving blocks to reclaim space.
+    pub compact: bool,
+
+    /// Sets the read-only attribute on the backing file once every other step is done.
+    pub set_read_only: bool,
+}
+
+impl Default for SealOptions {
+    fn default() -> SealOptions {
+        SealOptions {
+            reset_disk_id: true,
+            disk_id: None,
+            disable_change_tracking: true,
+            compact: true,
+            set_read_only: true,
+        }
+    }
+}
+
+/// Outcome of `seal_image`.
+#[derive(Clone)]
+pub struct SealReport {
+    /// Steps that were taken, in the order they completed.
+    pub completed_steps: Vec<SealStep>,
+
+    /// Virtual disk ID assigned to the image, if it was reset.
+    pub disk_id: Option<Guid>,
+
+    /// Size of the VHD file on the host before and after sealing, in bytes.
+    pub physical_size_before: u64,
+    pub physical_size_after: u64,
+}
+
+/// Seals the VHD at the given path as a golden image, taking the steps enabled in the options
+/// in order: resetting the disk ID, disabling change tracking, compacting and marking the
+/// backing file read-only.
+/// The VHD must not be attached. Sealing stops at the first step that fails.
+pub fn seal_image(path: &str, options: SealOptions) -> WinResult<SealReport> {
+    let physical_size = |virtual_disk: &VirtualDisk| -> WinResult<u64> {
+        let info_wrapper = virtual_disk.get_information(get_virtual_disk::InfoVersion::Size)?;
+        Ok(unsafe { info_wrapper.info().version_details.size.physical_size })
+    };
+
+    let mut completed_steps = Vec::new();
+    let mut disk_id = None;
+    let physical_size_before;
+    let physical_size_after;
+
+    {
+        let virtual_disk = open_vhd(path, false)?;
+        physical_size_before = physical_size(&virtual_disk)?;
+
+        if options.reset_disk_id {
+            let new_disk_id = match options.disk_id {
+                Some(new_disk_id) => new_disk_id,
+                None => new_guid()?,
+            };
+            virtual_disk.set_virtual_disk_id(new_disk_id)?;
+            disk_id = Some(new_disk_id);
+            completed_steps.push(SealStep::ResetDiskId);
         }
-        Err(error)
-            if winresult_code_to_error_code(error)
-                == winapi::shared::winerror::ERROR_VIRTDISK_NOT_VIRTUAL_DISK as u32 =>
+
+        if options.disable_change_tracking
+            && virtual_disk.storage_type()?.device_id == VIRTUAL_STORAGE_TYPE_DEVICE_VHDX
         {
-            return Ok(String::from(""));
-        }
-        Err(error) => {
-            return Err(error);
+            virtual_disk.set_change_tracking_enabled(false)?;
+            completed_steps.push(SealStep::DisableChangeTracking);
         }
-        Ok(wrapper) => wrapper,
-    };
 
-    const MAX_PATH: usize = 256;
-    let mut filename: [WChar; MAX_PATH] = [0; MAX_PATH];
+        if options.compact {
+            let parameters = compact_virtual_disk::Parameters {
+                version: compact_virtual_disk::Version::Version1,
+                version_details: compact_virtual_disk::VersionDetails {
+                    version1: compact_virtual_disk::Version1 { reserved: 0 },
+                },
+            };
 
-    unsafe {
-        match PathCchCombine(
-            filename.as_mut_ptr(),
-            MAX_PATH,
-            dependency_info_wrapper.info().version_details.version2[0].host_volume_name,
-            dependency_info_wrapper.info().version_details.version2[0]
-                .dependent_volume_relative_path,
-        ) {
-            0 => {
-                let mut string =
-                    widestring::WideCString::from_ptr_str(filename.as_ptr()).to_string_lossy();
-                string.shrink_to_fit();
-                Ok(string)
+            let overlapped_event = OverlappedEvent::new()?;
+
+            match virtual_disk.compact(
+                compact_virtual_disk::Flag::None as u32,
+                &parameters,
+                Some(overlapped_event.as_overlapped()),
+            ) {
+                Ok(()) | Err(WinResultCode::ErrorIoPending) => {}
+                Err(error) => return Err(error),
             }
-            _ => Err(WinResultCode::ErrorGenFailure),
+
+            wait_for_vhd_operation(&virtual_disk, overlapped_event.as_overlapped())?;
+            completed_steps.push(SealStep::Compact);
         }
+
+        physical_size_after = physical_size(&virtual_disk)?;
     }
-}
 
-/// Sets the caching mode on a mounted VHD.
-pub fn set_vhd_caching_mode(virtual_disk: &VirtualDisk, cache_mode: u16) -> WinResult<()> {
-    #[repr(C)]
-    struct CachePolicyRequest {
-        request_level: u32,
-        cache_mode: u16,
+    if options.set_read_only {
+        let mut permissions = std::fs::metadata(path)
+            .map_err(|error| io_error_to_winresult_code(&error))?
+            .permissions();
+        permissions.set_readonly(true);
+        std::fs::set_permissions(path, permissions)
+            .map_err(|error| io_error_to_winresult_code(&error))?;
+        completed_steps.push(SealStep::SetReadOnly);
     }
 
-    let mut request = CachePolicyRequest {
-        request_level: 1,
-        cache_mode: cache_mode,
-    };
+    Ok(SealReport {
+        completed_steps,
+        disk_id,
+        physical_size_before,
+        physical_size_after,
+    })
+}
 
-    let mut bytes: DWord = 0;
+/// Maximum depth of a differencing chain, used to stop walking chains that loop.
+const MAXIMUM_CHAIN_DEPTH: usize = 256;
 
-    unsafe {
-        match winapi::um::ioapiset::DeviceIoControl(
-            virtual_disk.get_handle(),
-            2955792, // IOCTL_STORAGE_SET_SURFACE_CACHE_POLICY
-            &mut request as *mut _ as PVoid,
-            std::mem::size_of::<CachePolicyRequest>() as u32,
-            std::ptr::null_mut(),
-            0,
-            &mut bytes,
-            std::ptr::null_mut(),
-        ) {
-            0 => Err(error_code_to_winresult_code(
-                winapi::um::errhandlingapi::GetLastError(),
-            )),
-            _ => Ok(()),
+/// Retrieves the paths of the VHDs of a differencing chain,
+/// starting with the given leaf and ending with the base VHD.
+pub fn differencing_chain(leaf_path: &str) -> WinResult<Vec<String>> {
+    let mut chain: Vec<String> = vec![String::from(leaf_path)];
+
+    loop {
+        let virtual_disk = VirtualDisk::open_options()
+            .get_info_only(true)
+            .open(chain.last().unwrap())?;
+
+        if virtual_disk.disk_kind()? != DiskKind::Differencing {
+            return Ok(chain);
+        }
+
+        let parent_location = virtual_disk.parent_location()?;
+
+        match parent_location.paths.into_iter().next() {
+            Some(parent_path) if parent_location.resolved => chain.push(parent_path),
+            _ => {
+                return Err(error_code_to_winresult_code(
+                    winapi::shared::winerror::ERROR_VHD_PARENT_VHD_NOT_FOUND as u32,
+                ))
+            }
+        }
+
+        if chain.len() > MAXIMUM_CHAIN_DEPTH {
+            return Err(error_code_to_winresult_code(
+                winapi::shared::winerror::ERROR_INVALID_DATA,
+            ));
         }
     }
 }
 
-/// Returns the size of the VHD on the physical disk.
-pub fn get_physical_vhd_size_in_kb(virtual_disk: &VirtualDisk) -> WinResult<u64> {
-    let info_wrapper = virtual_disk.get_information(get_virtual_disk::InfoVersion::Size)?;
-    unsafe { Ok(info_wrapper.info().version_details.size.physical_size / 1024) }
+/// State of the link between a differencing VHD and its parent, see `validate_chain`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LinkStatus {
+    /// The parent was found and matches what the child recorded.
+    Valid,
+
+    /// None of the parent locators recorded by the child point to an existing file.
+    MissingParent,
+
+    /// The parent was found but its identifier doesn't match the one recorded by the child.
+    IdMismatch,
+
+    /// The parent was found but its timestamp doesn't match the one recorded by the child.
+    TimestampMismatch,
+
+    /// The parent is a VHD that already appears closer to the leaf, so the chain links are reordered.
+    Cycle,
+
+    /// The link couldn't be verified, with the given error code.
+    Error(u32),
 }
 
-/// Opens the disk backed by the secified VHD.
-pub fn open_vhd_backed_disk(virtual_disk: &VirtualDisk) -> WinResult<Disk> {
-    let disk_path = virtual_disk.get_physical_path()?;
-    Disk::open(
-        &disk_path,
-        None,
-        Some(
-            winapi::um::winnt::FILE_ATTRIBUTE_NORMAL | winapi::um::winbase::FILE_FLAG_NO_BUFFERING,
-        ),
-    )
+/// Link between a differencing VHD and its parent, see `validate_chain`.
+#[derive(Clone)]
+pub struct ChainLink {
+    /// Path to the differencing VHD.
+    pub child_path: String,
+
+    /// Path to the parent VHD, if any of the parent locators recorded by the child points to a file.
+    pub parent_path: Option<String>,
+
+    /// Identifier of the parent as recorded by the child.
+    pub recorded_parent_id: Guid,
+
+    /// Timestamp of the parent as recorded by the child.
+    pub recorded_parent_timestamp: u32,
+
+    /// State of the link.
+    pub status: LinkStatus,
 }
 
-/// Expands the virtual size of a VHD to the requested size, if the current size is smaller
-/// than the requested size.
-/// Returns true if the VHD was expanded, false if the current size of the VHD is already greater
-/// than or equal to the specified new size.
-pub fn expand_vhd(virtual_disk: &VirtualDisk, new_size: u64) -> WinResult<bool> {
-    let info_wrapper = virtual_disk.get_information(get_virtual_disk::InfoVersion::Size)?;
+/// Result of verifying a differencing chain, see `validate_chain`.
+#[derive(Clone)]
+pub struct ChainReport {
+    /// Links of the chain, starting with the link between the leaf and its parent.
+    pub links: Vec<ChainLink>,
 
-    if unsafe { info_wrapper.info().version_details.size.virtual_size } < new_size {
-        #[repr(C)]
-        struct VhdResizeRequest {
-            new_virtual_size: u64,
-            expand_only: Boolean,
-            allow_unsafe_virtual_size: Boolean,
-            shrink_to_minimum_safe_size: Boolean,
+    /// Path to the base VHD of the chain, if the chain could be walked down to it.
+    pub base_path: Option<String>,
+}
+
+impl ChainReport {
+    /// Returns true if every link of the chain is valid and the base VHD was reached.
+    pub fn is_valid(&self) -> bool {
+        self.base_path.is_some()
+            && self
+                .links
+                .iter()
+                .all(|link| link.status == LinkStatus::Valid)
+    }
+}
+
+/// Walks the differencing chain of the given leaf VHD down to its base, verifying that
+/// every parent can be found and matches the identifier and timestamp recorded by its child.
+/// Each disk is opened on its own, so the values recorded by a child are compared against
+/// the ones of its parent instead of relying on the provider to resolve the chain.
+/// Broken links are reported instead of failing, so the whole chain can be inspected before
+/// attempting merges. The walk continues past mismatched parents, but stops at missing parents
+/// and reordered links.
+pub fn validate_chain(leaf_path: &str) -> WinResult<ChainReport> {
+    let mut links: Vec<ChainLink> = Vec::new();
+    let mut visited: Vec<String> = vec![normalize_vhd_path(leaf_path)];
+    let mut child_path = String::from(leaf_path);
+
+    loop {
+        let child = VirtualDisk::open_options()
+            .get_info_only(true)
+            .flags(open_virtual_disk::Flag::NoParents as u32)
+            .open(&child_path)?;
+
+        if child.disk_kind()? != DiskKind::Differencing {
+            return Ok(ChainReport {
+                links,
+                base_path: Some(child_path),
+            });
         }
 
-        let mut request = VhdResizeRequest {
-            new_virtual_size: new_size,
-            expand_only: 1,
-            allow_unsafe_virtual_size: 0,
-            shrink_to_minimum_safe_size: 0,
+        let recorded_parent_id = unsafe {
+            child
+                .get_information(get_virtual_disk::InfoVersion::ParentIdentifier)?
+                .info()
+                .version_details
+                .parent_identifier
         };
 
-        let mut bytes: DWord = 0;
+        let recorded_parent_timestamp = unsafe {
+            child
+                .get_information(get_virtual_disk::InfoVersion::ParentTimeStamp)?
+                .info()
+                .version_details
+                .parent_time_stamp
+        };
 
-        unsafe {
-            match winapi::um::ioapiset::DeviceIoControl(
-                virtual_disk.get_handle(),
-                2955600, // IOCTL_STORAGE_RESIZE_VIRTUAL_DISK
-                &mut request as *mut _ as PVoid,
-                std::mem::size_of::<VhdResizeRequest>() as u32,
-                std::ptr::null_mut(),
-                0,
-                &mut bytes,
-                std::ptr::null_mut(),
-            ) {
-                0 => Err(error_code_to_winresult_code(
-                    winapi::um::errhandlingapi::GetLastError(),
-                )),
-                _ => Ok(true),
+        let parent_path = child
+            .parent_location()?
+            .paths
+            .iter()
+            .map(|locator| resolve_parent_locator(&child_path, locator))
+            .find(|path| std::path::Path::new(path).is_file());
+
+        let mut status = match parent_path {
+            Some(ref parent_path) => {
+                verify_parent(parent_path, &recorded_parent_id, recorded_parent_timestamp)
+            }
+            None => LinkStatus::MissingParent,
+        };
+
+        if let Some(ref parent_path) = parent_path {
+            let normalized_parent_path = normalize_vhd_path(parent_path);
+
+            if visited.contains(&normalized_parent_path) {
+                status = LinkStatus::Cycle;
             }
+
+            visited.push(normalized_parent_path);
         }
-    } else {
-        Ok(false)
+
+        let next_path = match status {
+            LinkStatus::MissingParent | LinkStatus::Cycle => None,
+            _ => parent_path.clone(),
+        };
+
+        links.push(ChainLink {
+            child_path,
+            parent_path,
+            recorded_parent_id,
+            recorded_parent_timestamp,
+            status,
+        });
+
+        child_path = match next_path {
+            Some(next_path) if links.len() < MAXIMUM_CHAIN_DEPTH => next_path,
+            _ => {
+                return Ok(ChainReport {
+                    links,
+                    base_path: None,
+                })
+            }
+        };
+    }
+}
+
+/// Opens the given parent on its own and compares its identifier and timestamp
+/// with the ones recorded by its child.
+/// VHDX children record the data write GUID of their parent and no timestamp,
+/// VHD children record the unique identifier and the footer timestamp of their parent.
+fn verify_parent(parent_path: &str, recorded_id: &Guid, recorded_time_stamp: u32) -> LinkStatus {
+    use winapi::shared::winerror;
+
+    let actual_values = || -> WinResult<(Guid, Option<u32>)> {
+        let parent = VirtualDisk::open_options()
+            .get_info_only(true)
+            .flags(open_virtual_disk::Flag::NoParents as u32)
+            .open(parent_path)?;
+
+        if parent.storage_type()?.device_id == VIRTUAL_STORAGE_TYPE_DEVICE_VHDX {
+            return Ok((
+                crate::vhdformat::read_header(parent_path)?.data_write_guid,
+                None,
+            ));
+        }
+
+        let identifier = unsafe {
+            parent
+                .get_information(get_virtual_disk::InfoVersion::Identifier)?
+                .info()
+                .version_details
+                .identifier
+        };
+
+        Ok((identifier, Some(vhd_footer_time_stamp(parent_path)?)))
+    };
+
+    match actual_values() {
+        Ok((id, _)) if !guid_are_equal(&id, recorded_id) => LinkStatus::IdMismatch,
+        Ok((_, Some(time_stamp))) if time_stamp != recorded_time_stamp => {
+            LinkStatus::TimestampMismatch
+        }
+        Ok(_) => LinkStatus::Valid,
+        Err(error) => match winresult_code_to_error_code(error) {
+            error
+                if error == winerror::ERROR_FILE_NOT_FOUND
+                    || error == winerror::ERROR_PATH_NOT_FOUND =>
+            {
+                LinkStatus::MissingParent
+            }
+            error => LinkStatus::Error(error),
+        },
+    }
+}
+
+/// Reads the timestamp from the footer of a VHD, which differencing children record
+/// to detect a parent that was modified after they were created.
+fn vhd_footer_time_stamp(path: &str) -> WinResult<u32> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    const VHD_COOKIE: &[u8] = b"conectix";
+    const VHD_FOOTER_SIZE: i64 = 512;
+    const TIME_STAMP_OFFSET: usize = 24;
+
+    let mut footer = [0u8; VHD_FOOTER_SIZE as usize];
+    let mut file = std::fs::File::open(path).map_err(|error| io_error_to_winresult_code(&error))?;
+    file.seek(SeekFrom::End(-VHD_FOOTER_SIZE))
+        .and_then(|_| file.read_exact(&mut footer))
+        .map_err(|error| io_error_to_winresult_code(&error))?;
+
+    if &footer[..VHD_COOKIE.len()] != VHD_COOKIE {
+        return Err(error_code_to_winresult_code(
+            winapi::shared::winerror::ERROR_FILE_CORRUPT,
+        ));
+    }
+
+    let mut time_stamp = [0u8; 4];
+    time_stamp.copy_from_slice(&footer[TIME_STAMP_OFFSET..TIME_STAMP_OFFSET + 4]);
+    Ok(u32::from_be_bytes(time_stamp))
+}
+
+/// Resolves a parent locator recorded by a differencing VHD,
+/// relative locators being relative to the directory of the child.
+fn resolve_parent_locator(child_path: &str, locator: &str) -> String {
+    let locator_path = std::path::Path::new(locator);
+
+    if locator_path.is_absolute() {
+        return String::from(locator);
+    }
+
+    match std::path::Path::new(child_path).parent() {
+        Some(directory) => directory.join(locator_path).to_string_lossy().into_owned(),
+        None => String::from(locator),
     }
 }
 
+/// Normalizes a VHD path so that different spellings of the same file compare equal.
+fn normalize_vhd_path(path: &str) -> String {
+    let path = std::fs::canonicalize(path)
+        .map(|path| path.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| String::from(path));
+    path.trim_start_matches("\\\\?\\").to_lowercase()
+}
+
 /// Merges a differencing disk into its immediate parent. This function should be called with caution,
 /// there might be destructive side effects if the parent disk has other child disks.
 pub fn merge_diff_vhd(virtual_disk: &VirtualDisk) -> WinResult<()> {
-    let event = WinEvent::create(false, false, None, None)?;
-    let mut overlapped = unsafe { std::mem::zeroed::<Overlapped>() };
-    overlapped.hEvent = event.get_handle();
+    let overlapped_event = OverlappedEvent::new()?;
 
     let mut parameters = unsafe { std::mem::zeroed::<merge_virtual_disk::Parameters>() };
     parameters.version = merge_virtual_disk::Version::Version2;
@@ -441,9 +3564,11 @@ pub fn merge_diff_vhd(virtual_disk: &VirtualDisk) -> WinResult<()> {
     match virtual_disk.merge(
         merge_virtual_disk::Flag::None as u32,
         &parameters,
-        Some(&overlapped),
+        Some(overlapped_event.as_overlapped()),
     ) {
-        Err(WinResultCode::ErrorIoPending) => wait_for_vhd_operation(&virtual_disk, &overlapped),
+        Err(WinResultCode::ErrorIoPending) => {
+            wait_for_vhd_operation(&virtual_disk, overlapped_event.as_overlapped())
+        }
         Err(WinResultCode::ErrorSuccess) => {
             panic!("Success case on a merge call with overlapped struct is unexpected!")
         }
@@ -457,9 +3582,80 @@ pub fn wait_for_vhd_operation(
     virtual_disk: &VirtualDisk,
     overlapped: &Overlapped,
 ) -> WinResult<()> {
+    wait_for_vhd_operation_with_progress(virtual_disk, overlapped, None)
+}
+
+/// Default interval between progress reports while waiting for an operation.
+pub const DEFAULT_PROGRESS_INTERVAL: std::time::Duration = std::time::Duration::from_millis(500);
+
+/// Waits for the given operation, reporting its progress through the optional callback.
+pub fn wait_for_vhd_operation_with_progress(
+    virtual_disk: &VirtualDisk,
+    overlapped: &Overlapped,
+    progress_callback: Option<ProgressCallback>,
+) -> WinResult<()> {
+    wait_for_vhd_operation_with_interval(
+        virtual_disk,
+        overlapped,
+        progress_callback,
+        DEFAULT_PROGRESS_INTERVAL,
+    )
+}
+
+/// Waits for the given operation, reporting its progress through the optional callback
+/// at most every `progress_interval`.
+/// If the OVERLAPPED structure has an event, the wait returns as soon as the event is signaled
+/// on completion. Otherwise the operation progress is polled every `progress_interval`.
+pub fn wait_for_vhd_operation_with_interval(
+    virtual_disk: &VirtualDisk,
+    overlapped: &Overlapped,
+    progress_callback: Option<ProgressCallback>,
+    progress_interval: std::time::Duration,
+) -> WinResult<()> {
+    wait_for_vhd_operation_until(
+        virtual_disk,
+        overlapped,
+        progress_callback,
+        progress_interval,
+        None,
+    )
+}
+
+/// Waits for the given operation, cancelling it if it doesn't complete within the given timeout.
+/// Once cancelled, the wait goes on until the operation acknowledges the cancellation,
+/// so the OVERLAPPED structure can be safely released, and ERROR_TIMEOUT is returned.
+pub fn wait_for_vhd_operation_with_timeout(
+    virtual_disk: &VirtualDisk,
+    overlapped: &Overlapped,
+    timeout: std::time::Duration,
+) -> WinResult<()> {
+    wait_for_vhd_operation_until(
+        virtual_disk,
+        overlapped,
+        None,
+        DEFAULT_PROGRESS_INTERVAL,
+        Some(std::time::Instant::now() + timeout),
+    )
+}
+
+fn wait_for_vhd_operation_until(
+    virtual_disk: &VirtualDisk,
+    overlapped: &Overlapped,
+    mut progress_callback: Option<ProgressCallback>,
+    progress_interval: std::time::Duration,
+    deadline: Option<std::time::Instant>,
+) -> WinResult<()> {
+    use winapi::um::{errhandlingapi, ioapiset, synchapi, winbase};
+
+    let mut event = overlapped.hEvent;
+
     loop {
         let progress = virtual_disk.get_operation_progress(overlapped)?;
 
+        if let Some(callback) = progress_callback.as_mut() {
+            (*callback)(progress.current_value, progress.completion_value);
+        }
+
         match progress.operation_status {
             winapi::shared::winerror::ERROR_IO_PENDING => {
                 // Job is in progress
@@ -478,6 +3674,207 @@ pub fn wait_for_vhd_operation(
             }
         }
 
-        std::thread::sleep(std::time::Duration::from_millis(500));
+        let interval = match time_left(deadline) {
+            Ok(Some(time_left)) => std::cmp::min(progress_interval, time_left),
+            Ok(None) => progress_interval,
+            Err(error) => {
+                unsafe {
+                    ioapiset::CancelIoEx(
+                        virtual_disk.get_handle(),
+                        overlapped as *const Overlapped as *mut Overlapped,
+                    );
+                }
+
+                // The operation may complete before the cancellation is noticed,
+                // either way it has to be done before the OVERLAPPED can be released.
+                #[allow(unused_must_use)]
+                {
+                    wait_for_vhd_operation_until(
+                        virtual_disk,
+                        overlapped,
+                        None,
+                        progress_interval,
+                        None,
+                    );
+                }
+
+                return Err(error);
+            }
+        };
+
+        if event.is_null() {
+            std::thread::sleep(interval);
+            continue;
+        }
+
+        match unsafe { synchapi::WaitForSingleObject(event, wait_milliseconds(interval)) } {
+            winbase::WAIT_OBJECT_0 => {
+                // The event is signaled for good, if the operation still reports
+                // progress fall back to polling instead of spinning on the event.
+                event = std::ptr::null_mut();
+            }
+            winapi::shared::winerror::WAIT_TIMEOUT => {}
+            _ => {
+                return Err(error_code_to_winresult_code(unsafe {
+                    errhandlingapi::GetLastError()
+                }))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(query_time: u64) -> VhdIoStats {
+        VhdIoStats {
+            disk_number: 3,
+            bytes_read: 0,
+            bytes_written: 0,
+            read_count: 0,
+            write_count: 0,
+            read_time: std::time::Duration::from_secs(0),
+            write_time: std::time::Duration::from_secs(0),
+            idle_time: std::time::Duration::from_secs(0),
+            queue_depth: 0,
+            split_count: 0,
+            query_time,
+        }
+    }
+
+    #[test]
+    fn rates_since_computes_rates_over_interval() {
+        let earlier = sample(10_000_000);
+        let later = VhdIoStats {
+            bytes_read: 4 * 1024 * 1024,
+            bytes_written: 1024 * 1024,
+            read_count: 400,
+            write_count: 100,
+            read_time: std::time::Duration::from_millis(800),
+            write_time: std::time::Duration::from_millis(300),
+            idle_time: std::time::Duration::from_millis(1500),
+            queue_depth: 2,
+            ..sample(30_000_000)
+        };
+
+        let rates = later.rates_since(&earlier).unwrap();
+        assert_eq!(std::time::Duration::from_secs(2), rates.interval);
+        assert_eq!(200.0, rates.read_iops);
+        assert_eq!(50.0, rates.write_iops);
+        assert_eq!(2.0 * 1024.0 * 1024.0, rates.read_bytes_per_second);
+        assert_eq!(512.0 * 1024.0, rates.write_bytes_per_second);
+        assert_eq!(
+            std::time::Duration::from_millis(2),
+            rates.average_read_latency
+        );
+        assert_eq!(
+            std::time::Duration::from_millis(3),
+            rates.average_write_latency
+        );
+        assert_eq!(2, rates.queue_depth);
+        assert_eq!(0.25, rates.utilization);
+    }
+
+    #[test]
+    fn rates_since_handles_idle_interval_and_huge_counts() {
+        let earlier = sample(0);
+        let idle = VhdIoStats {
+            idle_time: std::time::Duration::from_secs(1),
+            ..sample(10_000_000)
+        };
+
+        let rates = idle.rates_since(&earlier).unwrap();
+        assert_eq!(
+            std::time::Duration::from_secs(0),
+            rates.average_read_latency
+        );
+        assert_eq!(
+            std::time::Duration::from_secs(0),
+            rates.average_write_latency
+        );
+        assert_eq!(0.0, rates.utilization);
+
+        // Counts beyond u32::MAX used to be truncated before dividing.
+        let busy = VhdIoStats {
+            read_count: 1 << 32,
+            read_time: std::time::Duration::from_secs(1 << 12),
+            ..sample(10_000_000)
+        };
+
+        let rates = busy.rates_since(&earlier).unwrap();
+        assert_eq!(
+            std::time::Duration::from_nanos(953),
+            rates.average_read_latency
+        );
+    }
+
+    fn xxhash64(data: &[Byte]) -> u64 {
+        let mut hasher = XxHash64::new();
+        hasher.update(data);
+        hasher.finish()
+    }
+
+    fn xxhash64_pattern(length: usize) -> Vec<Byte> {
+        (0..length).map(|index| (index * 7 + 3) as Byte).collect()
+    }
+
+    #[test]
+    fn xxhash64_matches_known_answers() {
+        assert_eq!(0xEF46_DB37_51D8_E999, xxhash64(b""));
+        assert_eq!(0xD24E_C4F1_A98C_6E5B, xxhash64(b"a"));
+        assert_eq!(0x44BC_2CF5_AD77_0999, xxhash64(b"abc"));
+        assert_eq!(
+            0xFBCE_A83C_8A37_8BF1,
+            xxhash64(b"Nobody inspects the spammish repetition")
+        );
+        assert_eq!(0x23C3_C17E_F790_FD97, xxhash64(&xxhash64_pattern(32)));
+        assert_eq!(0xA61F_8D4C_170F_E531, xxhash64(&xxhash64_pattern(100)));
+        assert_eq!(0x5F23_5FA0_33F1_A3FB, xxhash64(&xxhash64_pattern(1000)));
+    }
+
+    #[test]
+    fn xxhash64_is_independent_of_update_boundaries() {
+        let data = xxhash64_pattern(1000);
+
+        for chunk_size in &[1, 7, 31, 32, 33, 100] {
+            let mut hasher = XxHash64::new();
+
+            for chunk in data.chunks(*chunk_size) {
+                hasher.update(chunk);
+            }
+
+            assert_eq!(xxhash64(&data), hasher.finish());
+        }
+    }
+
+    #[test]
+    fn rates_since_rejects_mismatched_samples() {
+        let earlier = sample(10_000_000);
+
+        assert!(sample(10_000_000).rates_since(&earlier).is_err());
+        assert!(sample(5_000_000).rates_since(&earlier).is_err());
+        assert!(VhdIoStats {
+            disk_number: 4,
+            ..sample(20_000_000)
+        }
+        .rates_since(&earlier)
+        .is_err());
+    }
+
+    #[test]
+    fn service_mount_options_skip_drive_letters() {
+        let options = MountOptions::for_service();
+
+        assert_ne!(
+            0,
+            options.attach_flags() & attach_virtual_disk::Flag::NoDriveLetter as u32
+        );
+        assert_eq!(
+            0,
+            options.attach_flags() & attach_virtual_disk::Flag::PermanentLifetime as u32
+        );
+        assert!(options.refuse_service_drive_letters);
+        assert!(!MountOptions::default().refuse_service_drive_letters);
     }
 }
@@ -10,57 +10,574 @@
 
 use crate::virtdisk_bindings::*;
 use crate::virtdiskdefs::*;
-use widestring::{WideCString, WideStr, WideString};
+use crate::winutilities::*;
+use std::os::windows::io::{AsRawHandle, IntoRawHandle, RawHandle};
+use widestring::WideCString;
 use winutils_rs::errorcodes::{error_code_to_winresult_code, WinResult, WinResultCode};
 use winutils_rs::windefs::*;
 
+/// Allocates a zero initialized buffer of at least the given size in bytes,
+/// aligned for the variable length info structures filled by virtdisk APIs.
+fn zeroed_info_buffer(size: usize) -> Vec<u64> {
+    vec![0; (size + std::mem::size_of::<u64>() - 1) / std::mem::size_of::<u64>()]
+}
+
+/// Calls a virtdisk API that fills a variable length buffer, growing the buffer until the API
+/// stops returning ERROR_INSUFFICIENT_BUFFER.
+/// The call receives a pointer to a zero initialized buffer and the size of that buffer in bytes,
+/// which it must update with the size reported by the API. The buffer is reallocated before
+/// every retry, so the pointer supplied to the call is always valid for the supplied size.
+/// Returns the buffer and the size reported by the successful call.
+fn grow_and_retry<F>(initial_size: usize, mut call: F) -> WinResult<(Vec<u64>, usize)>
+where
+    F: FnMut(*mut u64, &mut u32) -> DWord,
+{
+    let mut size = initial_size;
+
+    loop {
+        let mut raw_buffer = zeroed_info_buffer(size);
+        let mut reported_size = (raw_buffer.len() * std::mem::size_of::<u64>()) as u32;
+
+        match error_code_to_winresult_code(call(raw_buffer.as_mut_ptr(), &mut reported_size)) {
+            WinResultCode::ErrorSuccess => return Ok((raw_buffer, reported_size as usize)),
+            WinResultCode::ErrorInsufficientBuffer => {
+                // Don't trust the reported size to always grow, the data might have changed
+                // between calls.
+                size = if reported_size as usize > size {
+                    reported_size as usize
+                } else {
+                    size * 2
+                };
+            }
+            error => return Err(error),
+        }
+    }
+}
+
 /// Wrapper of a get_virtual_disk::Info struct that can be of a variable heap allocated length.
+#[derive(Clone)]
 pub struct GetVirtualDiskInfoWrapper {
-    raw_buffer: Vec<Byte>,
+    raw_buffer: Vec<u64>,
+    size: usize,
 }
 
 impl GetVirtualDiskInfoWrapper {
     /// Gets a reference to a get_virtual_disk::Info struct,
     /// using the internal raw buffer.
     pub fn info(&self) -> &get_virtual_disk::Info {
-        unsafe { std::mem::transmute(self.raw_buffer.as_ptr()) }
+        unsafe { &*(self.raw_buffer.as_ptr() as *const get_virtual_disk::Info) }
     }
 
-    /// Gets a mut reference to a get_virtual_disk::Info struct,
-    /// using the internal raw buffer.
-    pub fn info_mut(&mut self) -> &mut get_virtual_disk::Info {
-        unsafe { std::mem::transmute(self.raw_buffer.as_mut_ptr()) }
+    /// Returns the version of the information held by this instance.
+    pub fn version(&self) -> get_virtual_disk::InfoVersion {
+        self.info().version
+    }
+
+    /// Overwrites the version details through the supplied setter, only if the held
+    /// information is of the expected version. Returns ErrorInvalidArgument otherwise.
+    /// Variable length details (parent location and change tracking state) have no setters,
+    /// since writing them could go past the region filled by the API.
+    fn set_details<F: FnOnce(&mut get_virtual_disk::InfoVersionDetails)>(
+        &mut self,
+        version: get_virtual_disk::InfoVersion,
+        setter: F,
+    ) -> WinResult<()> {
+        if self.version() != version {
+            return Err(WinResultCode::ErrorInvalidArgument);
+        }
+
+        let info = unsafe { &mut *(self.raw_buffer.as_mut_ptr() as *mut get_virtual_disk::Info) };
+        setter(&mut info.version_details);
+        Ok(())
+    }
+
+    /// Requires the information to be of version `Size`.
+    pub fn set_size(&mut self, size: get_virtual_disk::InfoSize) -> WinResult<()> {
+        self.set_details(get_virtual_disk::InfoVersion::Size, |details| {
+            details.size = size
+        })
+    }
+
+    /// Requires the information to be of version `ParentIdentifier`.
+    pub fn set_parent_identifier(&mut self, parent_identifier: Guid) -> WinResult<()> {
+        self.set_details(get_virtual_disk::InfoVersion::ParentIdentifier, |details| {
+            details.parent_identifier = parent_identifier
+        })
+    }
+
+    /// Requires the information to be of version `ParentTimeStamp`.
+    pub fn set_parent_time_stamp(&mut self, parent_time_stamp: u32) -> WinResult<()> {
+        self.set_details(get_virtual_disk::InfoVersion::ParentTimeStamp, |details| {
+            details.parent_time_stamp = parent_time_stamp
+        })
+    }
+
+    /// Requires the information to be of version `VirtualStorageType`.
+    pub fn set_virtual_storage_type(
+        &mut self,
+        virtual_storage_type: VirtualStorageType,
+    ) -> WinResult<()> {
+        self.set_details(
+            get_virtual_disk::InfoVersion::VirtualStorageType,
+            |details| details.virtual_storage_type = virtual_storage_type,
+        )
+    }
+
+    /// Requires the information to be of version `ProviderSubType`.
+    pub fn set_provider_sub_type(&mut self, provider_sub_type: u32) -> WinResult<()> {
+        self.set_details(get_virtual_disk::InfoVersion::ProviderSubType, |details| {
+            details.provider_sub_type = provider_sub_type
+        })
+    }
+
+    /// Requires the information to be of version `Is4KAligned`.
+    pub fn set_is_4k_aligned(&mut self, is_4k_aligned: bool) -> WinResult<()> {
+        self.set_details(get_virtual_disk::InfoVersion::Is4KAligned, |details| {
+            details.is_4k_aligned = is_4k_aligned as Bool
+        })
+    }
+
+    /// Requires the information to be of version `PhysicalDisk`.
+    pub fn set_physical_disk(
+        &mut self,
+        physical_disk: get_virtual_disk::InfoPhysicalDisk,
+    ) -> WinResult<()> {
+        self.set_details(get_virtual_disk::InfoVersion::PhysicalDisk, |details| {
+            details.physical_disk = physical_disk
+        })
+    }
+
+    /// Requires the information to be of version `VhdPhysicalSectorSize`.
+    pub fn set_vhd_physical_sector_size(&mut self, vhd_physical_sector_size: u32) -> WinResult<()> {
+        self.set_details(
+            get_virtual_disk::InfoVersion::VhdPhysicalSectorSize,
+            |details| details.vhd_physical_sector_size = vhd_physical_sector_size,
+        )
+    }
+
+    /// Requires the information to be of version `SmallestSafeVirtualSize`.
+    pub fn set_smallest_safe_virtual_size(
+        &mut self,
+        smallest_safe_virtual_size: u64,
+    ) -> WinResult<()> {
+        self.set_details(
+            get_virtual_disk::InfoVersion::SmallestSafeVirtualSize,
+            |details| details.smallest_safe_virtual_size = smallest_safe_virtual_size,
+        )
+    }
+
+    /// Requires the information to be of version `Fragmentation`.
+    pub fn set_fragmentation_percentage(&mut self, fragmentation_percentage: u32) -> WinResult<()> {
+        self.set_details(get_virtual_disk::InfoVersion::Fragmentation, |details| {
+            details.fragmentation_percentage = fragmentation_percentage
+        })
+    }
+
+    /// Requires the information to be of version `IsLoaded`.
+    pub fn set_is_loaded(&mut self, is_loaded: bool) -> WinResult<()> {
+        self.set_details(get_virtual_disk::InfoVersion::IsLoaded, |details| {
+            details.is_loaded = is_loaded as Bool
+        })
+    }
+
+    /// Requires the information to be of version `VirtualDiskId`.
+    pub fn set_virtual_disk_id(&mut self, virtual_disk_id: Guid) -> WinResult<()> {
+        self.set_details(get_virtual_disk::InfoVersion::VirtualDiskId, |details| {
+            details.virtual_disk_id = virtual_disk_id
+        })
+    }
+
+    /// Returns the most recent resilient change tracking ID, which is stored past the end
+    /// of the fixed size info struct.
+    /// Requires the information to be of version `ChangeTrackingState`.
+    pub fn most_recent_change_tracking_id(&self) -> WinResult<String> {
+        if self.version() != get_virtual_disk::InfoVersion::ChangeTrackingState {
+            return Err(WinResultCode::ErrorInvalidArgument);
+        }
+
+        let id_start = unsafe {
+            self.info()
+                .version_details
+                .change_tracking_state
+                .most_recent_id
+                .as_ptr()
+        };
+
+        wide_to_string(self.trailing_wide_chars(id_start))
+    }
+
+    /// Returns the parent paths, which are stored past the end of the fixed size info struct:
+    /// the path to the parent if it was resolved, the parent locators recorded by the child otherwise.
+    /// Requires the information to be of version `ParentLocation`.
+    pub fn parent_location_paths(&self) -> WinResult<Vec<String>> {
+        if self.version() != get_virtual_disk::InfoVersion::ParentLocation {
+            return Err(WinResultCode::ErrorInvalidArgument);
+        }
+
+        let parent_location = unsafe { &self.info().version_details.parent_location };
+        let buffer = self.trailing_wide_chars(parent_location.parent_location_buffer.as_ptr());
+
+        // A resolved parent is a single path, unresolved locators are a multi-string
+        if parent_location.parent_resolved != 0 {
+            return Ok(vec![wide_to_string(buffer)?]);
+        }
+
+        let mut paths: Vec<String> = Vec::new();
+
+        for path in buffer.split(|wchar| *wchar == 0) {
+            if path.is_empty() {
+                break;
+            }
+
+            paths.push(wide_to_string(path)?);
+        }
+
+        Ok(paths)
+    }
+
+    /// Returns the wide characters from the given position of the info struct
+    /// up to the end of the region filled by the API.
+    fn trailing_wide_chars(&self, start: *const WChar) -> &[WChar] {
+        let filled_end = self.raw_buffer.as_ptr() as usize + self.size;
+        let length = filled_end.saturating_sub(start as usize) / std::mem::size_of::<WChar>();
+
+        unsafe { std::slice::from_raw_parts(start, length) }
     }
 }
 
 /// Wrapper of a storage_dependency::Info struct that can be of a variable heap allocated length.
 pub struct GetStorageDependencyInformationWrapper {
-    raw_buffer: Vec<Byte>,
+    raw_buffer: Vec<u64>,
+    size: usize,
 }
 
 impl GetStorageDependencyInformationWrapper {
     /// Gets a reference to a storage_dependency::Info struct,
     /// using the internal raw buffer.
     pub fn info(&self) -> &storage_dependency::Info {
-        unsafe { std::mem::transmute(self.raw_buffer.as_ptr()) }
+        unsafe { &*(self.raw_buffer.as_ptr() as *const storage_dependency::Info) }
     }
 
-    /// Gets a mut reference to a storage_dependency::Info struct,
-    /// using the internal raw buffer.
-    pub fn info_mut(&mut self) -> &mut storage_dependency::Info {
-        unsafe { std::mem::transmute(self.raw_buffer.as_mut_ptr()) }
+    /// Returns the version of the information held by this instance.
+    pub fn version(&self) -> storage_dependency::InfoVersion {
+        self.info().version
+    }
+
+    /// Returns the number of entries of the given type that are both reported by the API
+    /// and fully contained in the valid region of the buffer.
+    fn valid_entries<T>(&self) -> usize {
+        let info = self.info();
+        let offset = &info.version_details as *const _ as usize - info as *const _ as usize;
+        let capacity = self.size.saturating_sub(offset) / std::mem::size_of::<T>();
+        std::cmp::min(info.number_entries as usize, capacity)
+    }
+
+    /// Returns a mutable slice over the valid entries of the given type.
+    fn entries_mut<T>(&mut self) -> &mut [T] {
+        let count = self.valid_entries::<T>();
+        unsafe {
+            let info = &mut *(self.raw_buffer.as_mut_ptr() as *mut storage_dependency::Info);
+            std::slice::from_raw_parts_mut(&mut info.version_details as *mut _ as *mut T, count)
+        }
+    }
+
+    /// Returns the version 1 entries, or an empty slice if the information is of another version.
+    pub fn entries_v1(&self) -> &[storage_dependency::InfoVersion1] {
+        if self.version() != storage_dependency::InfoVersion::Version1 {
+            return &[];
+        }
+
+        unsafe {
+            std::slice::from_raw_parts(
+                self.info().version_details.version1.as_ptr(),
+                self.valid_entries::<storage_dependency::InfoVersion1>(),
+            )
+        }
+    }
+
+    /// Returns the version 2 entries, or an empty slice if the information is of another version.
+    pub fn entries_v2(&self) -> &[storage_dependency::InfoVersion2] {
+        if self.version() != storage_dependency::InfoVersion::Version2 {
+            return &[];
+        }
+
+        unsafe {
+            std::slice::from_raw_parts(
+                self.info().version_details.version2.as_ptr(),
+                self.valid_entries::<storage_dependency::InfoVersion2>(),
+            )
+        }
+    }
+
+    /// Overwrites a version 1 entry. Returns ErrorInvalidArgument if the information
+    /// is of another version or the index is out of the valid entries.
+    pub fn set_entry_v1(
+        &mut self,
+        index: usize,
+        entry: storage_dependency::InfoVersion1,
+    ) -> WinResult<()> {
+        if self.version() != storage_dependency::InfoVersion::Version1 {
+            return Err(WinResultCode::ErrorInvalidArgument);
+        }
+
+        match self.entries_mut().get_mut(index) {
+            Some(slot) => {
+                *slot = entry;
+                Ok(())
+            }
+            None => Err(WinResultCode::ErrorInvalidArgument),
+        }
+    }
+
+    /// Overwrites a version 2 entry. Returns ErrorInvalidArgument if the information
+    /// is of another version or the index is out of the valid entries.
+    pub fn set_entry_v2(
+        &mut self,
+        index: usize,
+        entry: storage_dependency::InfoVersion2,
+    ) -> WinResult<()> {
+        if self.version() != storage_dependency::InfoVersion::Version2 {
+            return Err(WinResultCode::ErrorInvalidArgument);
+        }
+
+        match self.entries_mut().get_mut(index) {
+            Some(slot) => {
+                *slot = entry;
+                Ok(())
+            }
+            None => Err(WinResultCode::ErrorInvalidArgument),
+        }
+    }
+}
+
+/// Returns the open flags carried by the parameters used to create a virtual disk.
+fn create_open_flags(parameters: &create_virtual_disk::Parameters) -> u32 {
+    unsafe {
+        match parameters.version {
+            create_virtual_disk::Version::Version2 => {
+                parameters.version_details.version2.open_flags
+            }
+            create_virtual_disk::Version::Version3 => {
+                parameters.version_details.version3.open_flags
+            }
+            create_virtual_disk::Version::Version4 => {
+                parameters.version_details.version4.open_flags
+            }
+            _ => open_virtual_disk::Flag::None as u32,
+        }
+    }
+}
+
+/// Returns the rest of the string if it starts with the given prefix, ignoring ASCII case.
+fn strip_prefix_ignore_ascii_case<'a>(string: &'a str, prefix: &str) -> Option<&'a str> {
+    match string.get(..prefix.len()) {
+        Some(start) if start.eq_ignore_ascii_case(prefix) => Some(&string[prefix.len()..]),
+        _ => None,
+    }
+}
+
+/// Normalizes the path to a virtual disk file into a form accepted by the virtdisk APIs.
+/// `VirtualDisk::open` and `VirtualDisk::create` normalize the paths they are given,
+/// so this is only needed to compare paths or to pass them to other APIs.
+///
+/// - NT device paths, either raw (\Device\HarddiskVolume3\vhds\a.vhdx) or through the
+///   GLOBALROOT link (\\?\GLOBALROOT\Device\HarddiskVolume3\vhds\a.vhdx, also with \\.\),
+///   are rewritten on top of the volume GUID path (\\?\Volume{GUID}\vhds\a.vhdx).
+///   Device paths that don't belong to a volume are kept behind the \\?\GLOBALROOT link.
+/// - UNC paths to SMB shares (\\server\share\a.vhdx or \\?\UNC\server\share\a.vhdx),
+///   volume GUID paths and absolute paths are kept as they are.
+/// - Relative paths are made absolute against the current directory, drive relative paths
+///   (C:a.vhdx) against the current directory of their drive, as the file APIs do.
+///
+/// Forward slashes are replaced with backslashes in every form.
+pub fn normalize_virtual_disk_path(path: &str) -> WinResult<String> {
+    let path = path.replace('/', "\\");
+
+    let device_path = strip_prefix_ignore_ascii_case(&path, "\\\\?\\GLOBALROOT")
+        .or_else(|| strip_prefix_ignore_ascii_case(&path, "\\\\.\\GLOBALROOT"))
+        .or_else(|| strip_prefix_ignore_ascii_case(&path, "\\Device\\").map(|_| path.as_str()));
+
+    if let Some(device_path) = device_path {
+        let device_length = match strip_prefix_ignore_ascii_case(device_path, "\\Device\\") {
+            Some(rest) => device_path.len() - rest.len() + rest.find('\\').unwrap_or(rest.len()),
+            // Other objects of the NT namespace are left for the virtdisk APIs to resolve.
+            None => return Ok(path.clone()),
+        };
+        let (device, relative_path) = device_path.split_at(device_length);
+
+        return Ok(match crate::diskutilities::volume_name_for_device(device) {
+            Ok(volume_name) => format!("{}{}", volume_name, relative_path),
+            Err(_) => format!("\\\\?\\GLOBALROOT{}", device_path),
+        });
+    }
+
+    if std::path::Path::new(&path).is_absolute() {
+        return Ok(path);
+    }
+
+    full_path_name(&path)
+}
+
+/// Resolves a relative path the way the file APIs do, including drive relative
+/// paths (C:vhds\a.vhdx, against the current directory of that drive)
+/// and rooted paths (\vhds\a.vhdx, against the current drive).
+fn full_path_name(path: &str) -> WinResult<String> {
+    use winapi::um::{errhandlingapi, fileapi};
+
+    let path_wstr = WideCString::from_str(path).map_err(|_| WinResultCode::ErrorInvalidArgument)?;
+    let mut buffer: Vec<WChar> = vec![0; 260];
+
+    loop {
+        let length = unsafe {
+            fileapi::GetFullPathNameW(
+                path_wstr.as_ptr(),
+                buffer.len() as DWord,
+                buffer.as_mut_ptr(),
+                std::ptr::null_mut(),
+            )
+        } as usize;
+
+        match length {
+            0 => {
+                return Err(error_code_to_winresult_code(unsafe {
+                    errhandlingapi::GetLastError()
+                }))
+            }
+            // The buffer was too small, the length includes the null terminator.
+            length if length > buffer.len() => buffer.resize(length, 0),
+            length => return wide_to_string(&buffer[..length]),
+        }
+    }
+}
+
+/// Path and parameters a virtual disk was opened or created with.
+#[derive(Clone)]
+struct OpenedWith {
+    path: String,
+    virtual_storage_type: VirtualStorageType,
+    open_flags: u32,
+}
+
+/// Cache of `VirtualDisk::get_information` results, see `VirtualDisk::set_info_cache`.
+/// Each information version is served from the cache until its time to live expires.
+/// Mutating calls on the virtual disk (setting information, resizing, attaching, ...)
+/// invalidate the whole cache; changes made through other handles are only observed
+/// once the cached entries expire.
+pub struct InfoCache {
+    default_ttl: std::time::Duration,
+    ttls: std::collections::HashMap<get_virtual_disk::InfoVersion, std::time::Duration>,
+    entries: std::sync::Mutex<
+        std::collections::HashMap<
+            get_virtual_disk::InfoVersion,
+            (std::time::Instant, GetVirtualDiskInfoWrapper),
+        >,
+    >,
+}
+
+impl InfoCache {
+    /// Creates a cache keeping every information version for the given time to live.
+    pub fn new(default_ttl: std::time::Duration) -> InfoCache {
+        InfoCache {
+            default_ttl,
+            ttls: std::collections::HashMap::new(),
+            entries: std::sync::Mutex::new(std::collections::HashMap::new()),
+        }
+    }
+
+    /// Overrides the time to live of a single information version.
+    /// A zero time to live disables caching for that version.
+    pub fn with_ttl(
+        mut self,
+        version: get_virtual_disk::InfoVersion,
+        ttl: std::time::Duration,
+    ) -> InfoCache {
+        self.ttls.insert(version, ttl);
+        self
+    }
+
+    /// Returns the time to live of the given information version.
+    pub fn ttl(&self, version: get_virtual_disk::InfoVersion) -> std::time::Duration {
+        self.ttls.get(&version).cloned().unwrap_or(self.default_ttl)
+    }
+
+    /// Drops every cached entry, the next queries go to the virtual disk provider.
+    pub fn invalidate(&self) {
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.clear();
+        }
+    }
+
+    fn get(&self, version: get_virtual_disk::InfoVersion) -> Option<GetVirtualDiskInfoWrapper> {
+        let ttl = self.ttl(version);
+        let entries = self.entries.lock().ok()?;
+
+        match entries.get(&version) {
+            Some((queried_at, info_wrapper)) if queried_at.elapsed() < ttl => {
+                Some(info_wrapper.clone())
+            }
+            _ => None,
+        }
+    }
+
+    fn insert(
+        &self,
+        version: get_virtual_disk::InfoVersion,
+        info_wrapper: &GetVirtualDiskInfoWrapper,
+    ) {
+        if self.ttl(version) == std::time::Duration::from_secs(0) {
+            return;
+        }
+
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.insert(version, (std::time::Instant::now(), info_wrapper.clone()));
+        }
     }
 }
 
 /// Safe abstraction to a virtual hard disk handle.
 /// Additionally, provides the entry point to all safe wrappers to the virtdisk C bindings.
+/// Virtual disks are Send and Sync, virtdisk APIs can be called on the same handle from any thread.
 pub struct VirtualDisk {
-    handle: Handle,
+    handle: OwnedHandle,
+    opened_with: Option<OpenedWith>,
+    info_cache: Option<InfoCache>,
+}
+
+impl std::fmt::Debug for VirtualDisk {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let mut debug = f.debug_struct("VirtualDisk");
+
+        match self.opened_with {
+            Some(ref opened_with) => debug
+                .field("path", &opened_with.path)
+                .field("virtual_storage_type", &opened_with.virtual_storage_type)
+                .field("open_flags", &format_args!("{:#x}", opened_with.open_flags)),
+            None => debug.field("path", &"<wrapped handle>"),
+        };
+
+        debug.finish()
+    }
+}
+
+impl AsRawHandle for VirtualDisk {
+    fn as_raw_handle(&self) -> RawHandle {
+        self.handle.as_raw_handle()
+    }
+}
+
+impl IntoRawHandle for VirtualDisk {
+    fn into_raw_handle(self) -> RawHandle {
+        self.handle.into_raw_handle()
+    }
 }
 
-impl std::ops::Drop for VirtualDisk {
-    fn drop(&mut self) {
-        winutils_rs::utilities::close_handle(&mut self.handle);
+impl From<std::os::windows::io::OwnedHandle> for VirtualDisk {
+    fn from(handle: std::os::windows::io::OwnedHandle) -> VirtualDisk {
+        VirtualDisk {
+            handle: OwnedHandle::from(handle),
+            opened_with: None,
+            info_cache: None,
+        }
     }
 }
 
@@ -68,9 +585,72 @@ impl VirtualDisk {
     /// Wraps the supplied virtual hard disk handle, providing a safe drop implementation that will close the handle
     /// on the end of its lifetime.
     pub fn wrap_handle(handle: Handle) -> WinResult<VirtualDisk> {
-        match handle {
-            handle if handle == std::ptr::null_mut() => Err(WinResultCode::ErrorInvalidArgument),
-            handle => Ok(VirtualDisk { handle }),
+        Ok(VirtualDisk {
+            handle: OwnedHandle::wrap(handle)?,
+            opened_with: None,
+            info_cache: None,
+        })
+    }
+
+    /// Wraps a handle returned by opening or creating the virtual disk at the given path,
+    /// remembering the path and parameters so that the virtual disk can be reopened.
+    fn wrap_opened_handle(
+        handle: Handle,
+        path: &str,
+        virtual_storage_type: VirtualStorageType,
+        open_flags: u32,
+    ) -> WinResult<VirtualDisk> {
+        Ok(VirtualDisk {
+            handle: OwnedHandle::wrap(handle)?,
+            opened_with: Some(OpenedWith {
+                path: String::from(path),
+                virtual_storage_type,
+                open_flags,
+            }),
+            info_cache: None,
+        })
+    }
+
+    /// Returns the path the virtual disk was opened or created with.
+    /// None if this instance wraps a handle that wasn't opened through this crate.
+    pub fn path(&self) -> Option<&str> {
+        self.opened_with
+            .as_ref()
+            .map(|opened_with| opened_with.path.as_str())
+    }
+
+    /// Returns the virtual storage type requested when the virtual disk was opened or created.
+    /// This can be the unknown storage type if the provider was left to pick it,
+    /// see `VirtualDisk::storage_type` for the storage type in effect.
+    pub fn opened_storage_type(&self) -> Option<VirtualStorageType> {
+        self.opened_with
+            .as_ref()
+            .map(|opened_with| opened_with.virtual_storage_type)
+    }
+
+    /// Returns the `open_virtual_disk::Flag` values the virtual disk was opened with, as a u32.
+    /// Virtual disks created through this crate report the open flags of their creation parameters.
+    pub fn open_flags(&self) -> Option<u32> {
+        self.opened_with
+            .as_ref()
+            .map(|opened_with| opened_with.open_flags)
+    }
+
+    /// Enables caching of `VirtualDisk::get_information` results, or disables it if None.
+    /// Useful on monitoring loops sampling many virtual disks.
+    pub fn set_info_cache(&mut self, info_cache: Option<InfoCache>) {
+        self.info_cache = info_cache;
+    }
+
+    /// Returns the information cache of the virtual disk, if enabled.
+    pub fn info_cache(&self) -> Option<&InfoCache> {
+        self.info_cache.as_ref()
+    }
+
+    /// Drops every cached information result, if caching is enabled.
+    pub fn invalidate_info_cache(&self) {
+        if let Some(ref info_cache) = self.info_cache {
+            info_cache.invalidate();
         }
     }
 
@@ -80,10 +660,10 @@ impl VirtualDisk {
     /// # Unsafe
     ///
     /// Marked as unsafe because of the possibility of leaking a handle.
+    /// Prefer `IntoRawHandle::into_raw_handle` or converting into
+    /// `std::os::windows::io::OwnedHandle`, which hand over ownership of the handle.
     pub unsafe fn release_handle(&mut self) -> Handle {
-        let handle = self.handle;
-        self.handle = std::ptr::null_mut();
-        handle
+        self.handle.release()
     }
 
     /// Returns a cloned value of the internally stored handle to the virtual disk.
@@ -91,7 +671,7 @@ impl VirtualDisk {
     /// Be careful and do not close the handle returned here because the code will panic at the
     /// end of the lifetime of this VirtualDisk instance if CloseHandle fails.
     pub fn get_handle(&self) -> Handle {
-        self.handle.clone()
+        self.handle.get()
     }
 
     /// Opens a virtual hard disk (VHD) or CD or DVD image file (ISO) for use, and returns a safe wrapper to its handle.
@@ -105,6 +685,7 @@ impl VirtualDisk {
         flags: u32,
         parameters: Option<&open_virtual_disk::Parameters>,
     ) -> WinResult<VirtualDisk> {
+        let path = &normalize_virtual_disk_path(path)?;
         let mut handle: Handle = std::ptr::null_mut();
 
         let parameters_ptr = match parameters {
@@ -121,12 +702,103 @@ impl VirtualDisk {
                 parameters_ptr,
                 &mut handle,
             ) {
-                0 => Ok(VirtualDisk { handle }),
+                0 => VirtualDisk::wrap_opened_handle(handle, path, virtual_storage_type, flags),
                 result => Err(error_code_to_winresult_code(result)),
             }
         }
     }
 
+    /// Opens a virtual disk by path (VHD or VHDX), with only the first `rw_depth` backing files
+    /// of its differencing chain opened read/write, starting from the leaf.
+    /// The rest of the chain is opened read-only, which guarantees parents stay untouched
+    /// while the leaf is written to.
+    ///
+    /// - A depth of 1 opens only the leaf read/write, which is what most callers want.
+    /// - Merging N levels of a chain into their parent requires a depth of at least N + 1,
+    ///   since the parent receiving the data must be writable too.
+    /// - A depth greater than the length of the chain opens the whole chain read/write.
+    ///
+    /// Returns ErrorInvalidArgument for a depth of 0.
+    /// Version 1 open parameters are required to supply the depth, so the handle is opened with
+    /// `VirtualDiskAccessMask::All` and doesn't support the options of `OpenOptions`.
+    pub fn open_with_rw_depth(path: &str, rw_depth: u32) -> WinResult<VirtualDisk> {
+        if rw_depth == 0 {
+            return Err(WinResultCode::ErrorInvalidArgument);
+        }
+
+        let parameters = open_virtual_disk::Parameters {
+            version: open_virtual_disk::Version::Version1,
+            version_details: open_virtual_disk::VersionDetails {
+                version1: open_virtual_disk::Version1 { rw_depth },
+            },
+        };
+
+        VirtualDisk::open(
+            VirtualStorageType::auto(),
+            path,
+            VirtualDiskAccessMask::All,
+            open_virtual_disk::Flag::None as u32,
+            Some(&parameters),
+        )
+    }
+
+    /// Opens a second handle to the virtual disk, only to query information.
+    /// Monitoring threads can use it to query the virtual disk without contending
+    /// on the handle of the writer.
+    /// Returns ErrorInvalidArgument if this instance wraps a handle that wasn't opened
+    /// through this crate, since the path of the virtual disk is unknown.
+    pub fn reopen_read_only(&self) -> WinResult<VirtualDiskInfoHandle> {
+        match self.opened_with {
+            Some(ref opened_with) => OpenOptions::new()
+                .virtual_storage_type(opened_with.virtual_storage_type)
+                .get_info_only(true)
+                .parent_cached_io(true)
+                .open(&opened_with.path)
+                .map(|inner| VirtualDiskInfoHandle { inner }),
+            None => Err(WinResultCode::ErrorInvalidArgument),
+        }
+    }
+
+    /// Opens the virtual disk at the given path read-only.
+    /// Operations that modify the virtual disk aren't available on the returned handle.
+    pub fn open_read_only(path: &str) -> WinResult<ReadOnlyVirtualDisk> {
+        OpenOptions::new()
+            .read_only(true)
+            .open(path)
+            .map(|inner| ReadOnlyVirtualDisk { inner })
+    }
+
+    /// Opens the virtual disk at the given path read-write.
+    /// The returned handle guarantees every operation of `VirtualDisk` can be attempted on it.
+    pub fn open_writable(path: &str) -> WinResult<WritableVirtualDisk> {
+        OpenOptions::new()
+            .open(path)
+            .map(|inner| WritableVirtualDisk { inner })
+    }
+
+    /// Opens the virtual disk at the given path only to query and set information.
+    /// The returned handle doesn't contend with other handles to the virtual disk,
+    /// and only exposes the methods that work without full access to it.
+    pub fn open_info_only(path: &str) -> WinResult<VirtualDiskInfoHandle> {
+        OpenOptions::new()
+            .get_info_only(true)
+            .parent_cached_io(true)
+            .open(path)
+            .map(|inner| VirtualDiskInfoHandle { inner })
+    }
+
+    /// Returns a blank set of options to open a virtual disk with.
+    /// See `OpenOptions` for details.
+    pub fn open_options() -> OpenOptions {
+        OpenOptions::new()
+    }
+
+    /// Returns a blank set of options to create a virtual disk with.
+    /// See `CreateOptions` for details.
+    pub fn create_options() -> CreateOptions {
+        CreateOptions::new()
+    }
+
     /// Creates a virtual hard disk, either using default paramters or using an existing virtual disk
     /// or physical disk.
     /// The returned object can be used to call any virtdisk API that operates over an open
@@ -142,6 +814,7 @@ impl VirtualDisk {
         parameters: &create_virtual_disk::Parameters,
         overlapped: Option<&Overlapped>,
     ) -> WinResult<VirtualDisk> {
+        let path = &normalize_virtual_disk_path(path)?;
         let mut handle: Handle = std::ptr::null_mut();
 
         let security_descriptor_ptr = match security_descriptor {
@@ -166,7 +839,57 @@ impl VirtualDisk {
                 overlapped_ptr,
                 &mut handle,
             ) {
-                0 => Ok(VirtualDisk { handle }),
+                0 => VirtualDisk::wrap_opened_handle(
+                    handle,
+                    path,
+                    virtual_storage_type,
+                    create_open_flags(parameters),
+                ),
+                result => Err(error_code_to_winresult_code(result)),
+            }
+        }
+    }
+
+    /// Creates a virtual hard disk asynchronously, returning as soon as the handle to the new
+    /// virtual disk is available. Completion is signaled through the supplied OVERLAPPED structure
+    /// and can be tracked with `VirtualDisk::get_operation_progress`.
+    /// The flags are a u32 representation of any valid combination from `create_virtual_disk::Flag` values.
+    pub fn create_overlapped(
+        virtual_storage_type: VirtualStorageType,
+        path: &str,
+        virtual_disk_access_mask: VirtualDiskAccessMask,
+        security_descriptor: Option<SecurityDescriptor>,
+        flags: u32,
+        provider_specific_flags: u32,
+        parameters: &create_virtual_disk::Parameters,
+        overlapped: &Overlapped,
+    ) -> WinResult<VirtualDisk> {
+        let path = &normalize_virtual_disk_path(path)?;
+        let mut handle: Handle = std::ptr::null_mut();
+
+        let security_descriptor_ptr = match security_descriptor {
+            Some(security_descriptor) => &security_descriptor,
+            None => std::ptr::null(),
+        };
+
+        unsafe {
+            match CreateVirtualDisk(
+                &virtual_storage_type,
+                WideCString::from_str(path).unwrap().as_ptr(),
+                virtual_disk_access_mask,
+                security_descriptor_ptr,
+                flags,
+                provider_specific_flags,
+                parameters,
+                overlapped,
+                &mut handle,
+            ) {
+                0 | winapi::shared::winerror::ERROR_IO_PENDING => VirtualDisk::wrap_opened_handle(
+                    handle,
+                    path,
+                    virtual_storage_type,
+                    create_open_flags(parameters),
+                ),
                 result => Err(error_code_to_winresult_code(result)),
             }
         }
@@ -193,9 +916,11 @@ impl VirtualDisk {
             None => std::ptr::null(),
         };
 
+        self.invalidate_info_cache();
+
         unsafe {
             match AttachVirtualDisk(
-                self.handle,
+                self.handle.get(),
                 security_descriptor_ptr,
                 flags,
                 provider_specific_flags,
@@ -212,83 +937,166 @@ impl VirtualDisk {
     /// by locating an appropriate virtual disk provider to accomplish the operation.
     /// The flags are a u32 representation of any valid combination from `detach_virtual_disk::Flag` values.
     pub fn detach(&self, flags: u32, provider_specific_flags: u32) -> WinResult<()> {
+        self.invalidate_info_cache();
+
         unsafe {
-            match DetachVirtualDisk(self.handle, flags, provider_specific_flags) {
+            match DetachVirtualDisk(self.handle.get(), flags, provider_specific_flags) {
                 0 => Ok(()),
                 result => Err(error_code_to_winresult_code(result)),
             }
         }
     }
 
-    /// Retrieves the path to the physical device object that contains a virtual hard disk (VHD) or CD or DVD image file (ISO).
-    pub fn get_physical_path(&self) -> WinResult<String> {
-        const PATH_SIZE: u32 = 256; // MAX_PATH
-        let mut disk_path_wstr: [WChar; PATH_SIZE as usize] = [0; PATH_SIZE as usize];
+    /// Detaches the virtual disk, retrying according to the given policy while it fails with
+    /// ERROR_BUSY, which happens as long as any handle is open on one of its volumes.
+    /// Any other error is returned right away. If every attempt failed and the policy asks for it,
+    /// the error lists the processes holding the virtual disk or its volumes open.
+    pub fn detach_with_retry(
+        &self,
+        flags: u32,
+        provider_specific_flags: u32,
+        policy: RetryPolicy,
+    ) -> Result<(), DetachError> {
+        let mut attempt = 1;
+
+        loop {
+            let error = match self.detach(flags, provider_specific_flags) {
+                Ok(()) => return Ok(()),
+                Err(error) => error,
+            };
+
+            let busy = winutils_rs::errorcodes::winresult_code_to_error_code(error)
+                == winapi::shared::winerror::ERROR_BUSY;
+
+            if !busy || attempt >= policy.max_attempts {
+                return Err(DetachError {
+                    error,
+                    attempts: attempt,
+                    lockers: match busy && policy.diagnose {
+                        true => self.lockers().ok(),
+                        false => None,
+                    },
+                });
+            }
 
-        unsafe {
-            let wchar_size = std::mem::size_of::<WChar>() as u32;
-            let mut bytes = PATH_SIZE * wchar_size;
-            match GetVirtualDiskPhysicalPath(self.handle, &mut bytes, disk_path_wstr.as_mut_ptr()) {
-                0 => {
-                    let mut string = WideString::from_ptr(
-                        disk_path_wstr.as_ptr(),
-                        ((bytes / wchar_size) - 1) as usize,
-                    )
-                    .to_string_lossy();
-                    string.shrink_to_fit();
-                    Ok(string)
+            attempt += 1;
+            std::thread::sleep(policy.delay_before(attempt));
+        }
+    }
+
+    /// Returns the processes holding the backing file or files on the volumes of the virtual disk open.
+    /// Volumes that can't be walked are skipped, and processes holding only a handle to a volume
+    /// itself (e.g. \\.\X:) aren't reported, since the Restart Manager only tracks files.
+    fn lockers(&self) -> WinResult<Vec<ProcessInfo>> {
+        let mut lockers = processes_using_files(&[self.backing_file_path()?])?;
+
+        if let Ok(physical_path) = self.get_physical_path() {
+            if let Ok(disk) = crate::diskutilities::Disk::open(&physical_path, Some(0), None) {
+                for volume_path in disk.volume_paths().unwrap_or_default() {
+                    let volume_lockers =
+                        crate::diskutilities::find_volume_lockers(&volume_path).unwrap_or_default();
+
+                    for locker in volume_lockers {
+                        if !lockers.iter().any(|known| known.pid == locker.pid) {
+                            lockers.push(locker);
+                        }
+                    }
                 }
-                result => Err(error_code_to_winresult_code(result)),
             }
         }
+
+        Ok(lockers)
     }
 
-    /// Retrieves the physical paths to all attached virtual disks and returns it in a vector of strings.
-    pub fn get_all_attached_physical_paths() -> WinResult<Vec<String>> {
-        let mut paths_buffer: Vec<WChar> = Vec::new();
-        let mut buffer_size_bytes: u32 = 0;
+    /// Retrieves the normalized Win32 path of the file backing the virtual disk on the host
+    /// (e.g. C:\vhds\disk.vhdx or \\server\share\disk.vhdx), resolved from the handle
+    /// so it's available for wrapped handles too, see `VirtualDisk::wrap_handle`.
+    /// Paths too long for the Win32 namespace keep their \\?\ prefix.
+    pub fn backing_file_path(&self) -> WinResult<String> {
+        use winapi::um::{errhandlingapi, fileapi};
+
+        const MAX_PATH: usize = 260;
+
+        let mut buffer: Vec<WChar> = vec![0; MAX_PATH + 1];
+
+        let path = loop {
+            let length = unsafe {
+                fileapi::GetFinalPathNameByHandleW(
+                    self.handle.get(),
+                    buffer.as_mut_ptr(),
+                    buffer.len() as DWord,
+                    0, // FILE_NAME_NORMALIZED | VOLUME_NAME_DOS
+                )
+            } as usize;
+
+            if length == 0 {
+                return Err(error_code_to_winresult_code(unsafe {
+                    errhandlingapi::GetLastError()
+                }));
+            }
 
-        let mut paths: Vec<String> = Vec::new();
+            if length < buffer.len() {
+                buffer.truncate(length);
+                break wide_to_string(&buffer)?;
+            }
 
-        unsafe {
-            // First figure out the required size to fit all paths
-            let result = GetAllAttachedVirtualDiskPhysicalPaths(
-                &mut buffer_size_bytes,
-                paths_buffer.as_mut_ptr(),
-            );
-
-            match error_code_to_winresult_code(result) {
-                WinResultCode::ErrorInsufficientBuffer => {
-                    let buffer_size = buffer_size_bytes as usize / std::mem::size_of::<WChar>();
-                    paths_buffer.resize(buffer_size, 0);
+            // The returned length includes the terminating NUL when the buffer is too small
+            buffer.resize(length, 0);
+        };
 
-                    match GetAllAttachedVirtualDiskPhysicalPaths(
-                        &mut buffer_size_bytes,
-                        paths_buffer.as_mut_ptr(),
-                    ) {
-                        0 => {
-                            assert_eq!(
-                                buffer_size * std::mem::size_of::<WChar>(),
-                                buffer_size_bytes as usize
-                            );
-
-                            for string in paths_buffer.as_slice().split(|element| *element == 0) {
-                                if !string.is_empty() {
-                                    let mut string = WideStr::from_slice(string).to_string_lossy();
-                                    string.shrink_to_fit();
-                                    paths.push(string);
-                                }
-                            }
-
-                            Ok(paths)
-                        }
-                        result => Err(error_code_to_winresult_code(result)),
-                    }
-                }
-                WinResultCode::ErrorSuccess => Ok(paths),
-                error => Err(error),
-            }
+        if path.len() >= MAX_PATH {
+            return Ok(path);
         }
+
+        Ok(match path.strip_prefix("\\\\?\\UNC\\") {
+            Some(share_path) => format!("\\\\{}", share_path),
+            None => String::from(path.trim_start_matches("\\\\?\\")),
+        })
+    }
+
+    /// Retrieves the path to the physical device object that contains a virtual hard disk (VHD) or CD or DVD image file (ISO).
+    pub fn get_physical_path(&self) -> WinResult<String> {
+        const WCHAR_SIZE: usize = std::mem::size_of::<WChar>();
+
+        // Start with room for MAX_PATH characters, and grow the buffer for long
+        // (e.g. \\?\ prefixed) device paths as requested by the API.
+        let (raw_buffer, _) = grow_and_retry((260 + 1) * WCHAR_SIZE, |buffer, size| unsafe {
+            GetVirtualDiskPhysicalPath(self.handle.get(), size, buffer as *mut WChar)
+        })?;
+
+        // The reported size may include NUL padding past the end of the path.
+        let disk_path_wstr = unsafe {
+            std::slice::from_raw_parts(
+                raw_buffer.as_ptr() as *const WChar,
+                raw_buffer.len() * std::mem::size_of::<u64>() / WCHAR_SIZE,
+            )
+        };
+
+        wide_to_string(disk_path_wstr)
+    }
+
+    /// Retrieves the physical paths to all attached virtual disks and returns it in a vector of strings.
+    pub fn get_all_attached_physical_paths() -> WinResult<Vec<String>> {
+        const WCHAR_SIZE: usize = std::mem::size_of::<WChar>();
+
+        // The paths are returned as NUL separated strings, more disks can get attached
+        // between the size query and the retry so let the buffer grow as many times as needed.
+        let (raw_buffer, size) = grow_and_retry((260 + 1) * WCHAR_SIZE, |buffer, size| unsafe {
+            GetAllAttachedVirtualDiskPhysicalPaths(size, buffer as *mut WChar)
+        })?;
+
+        let paths_wstr = unsafe {
+            std::slice::from_raw_parts(
+                raw_buffer.as_ptr() as *const WChar,
+                std::cmp::min(size, raw_buffer.len() * std::mem::size_of::<u64>()) / WCHAR_SIZE,
+            )
+        };
+
+        paths_wstr
+            .split(|element| *element == 0)
+            .filter(|path| !path.is_empty())
+            .map(wide_to_string)
+            .collect()
     }
 
     /// Retrieves on the supplied info structure the storage dependency information of a virtual disk.
@@ -299,97 +1107,197 @@ impl VirtualDisk {
         flags: u32,
         version: storage_dependency::InfoVersion,
     ) -> WinResult<GetStorageDependencyInformationWrapper> {
-        let mut raw_buffer: Vec<Byte> = Vec::new();
-        let size: u32 = std::mem::size_of::<storage_dependency::Info>() as u32;
-        let mut buffer_size: u32 = size;
-        raw_buffer.reserve(buffer_size as usize);
-
-        let info_ptr = raw_buffer.as_mut_ptr() as *mut storage_dependency::Info;
-
-        unsafe {
-            (*info_ptr).version = version;
-
-            let result = GetStorageDependencyInformation(
-                self.handle,
-                flags,
-                size,
-                info_ptr,
-                &mut buffer_size,
-            );
-
-            match error_code_to_winresult_code(result) {
-                WinResultCode::ErrorInsufficientBuffer => {
-                    raw_buffer.reserve(buffer_size as usize);
-
-                    let result = GetStorageDependencyInformation(
-                        self.handle,
-                        flags,
-                        size,
-                        info_ptr,
-                        &mut buffer_size,
-                    );
+        let (raw_buffer, size) = grow_and_retry(
+            std::mem::size_of::<storage_dependency::Info>(),
+            |buffer, size| unsafe {
+                let info_ptr = buffer as *mut storage_dependency::Info;
+                (*info_ptr).version = version;
+                GetStorageDependencyInformation(self.handle.get(), flags, *size, info_ptr, size)
+            },
+        )?;
 
-                    match error_code_to_winresult_code(result) {
-                        WinResultCode::ErrorSuccess => {
-                            Ok(GetStorageDependencyInformationWrapper { raw_buffer })
-                        }
-                        error => Err(error),
-                    }
-                }
-                WinResultCode::ErrorSuccess => {
-                    Ok(GetStorageDependencyInformationWrapper { raw_buffer })
-                }
-                error => Err(error),
-            }
-        }
+        Ok(GetStorageDependencyInformationWrapper { raw_buffer, size })
     }
 
     /// Retrieves information of a virtual disk wrapped on a safe structure on top of a raw buffer.
+    /// Served from the information cache while fresh, see `VirtualDisk::set_info_cache`.
     pub fn get_information(
         &self,
         version: get_virtual_disk::InfoVersion,
     ) -> WinResult<GetVirtualDiskInfoWrapper> {
+        if let Some(info_wrapper) = self
+            .info_cache
+            .as_ref()
+            .and_then(|info_cache| info_cache.get(version))
+        {
+            return Ok(info_wrapper);
+        }
+
         let mut size_used: u32 = 0;
-        let mut raw_buffer: Vec<Byte> = Vec::new();
-        let mut size: u32 = std::mem::size_of::<get_virtual_disk::Info>() as u32;
-        raw_buffer.reserve(size as usize);
+        let (raw_buffer, _) = grow_and_retry(
+            std::mem::size_of::<get_virtual_disk::Info>(),
+            |buffer, size| unsafe {
+                let info_ptr = buffer as *mut get_virtual_disk::Info;
+                (*info_ptr).version = version;
+                GetVirtualDiskInformation(self.handle.get(), size, info_ptr, &mut size_used)
+            },
+        )?;
+
+        let buffer_size = raw_buffer.len() * std::mem::size_of::<u64>();
+        let info_wrapper = GetVirtualDiskInfoWrapper {
+            raw_buffer,
+            size: match size_used as usize {
+                0 => buffer_size,
+                size_used => std::cmp::min(size_used, buffer_size),
+            },
+        };
 
-        let info_ptr = raw_buffer.as_mut_ptr() as *mut get_virtual_disk::Info;
+        if let Some(ref info_cache) = self.info_cache {
+            info_cache.insert(version, &info_wrapper);
+        }
 
-        unsafe {
-            (*info_ptr).version = version;
+        Ok(info_wrapper)
+    }
 
-            let result =
-                GetVirtualDiskInformation(self.handle, &mut size, info_ptr, &mut size_used);
+    /// Retrieves whether the virtual disk is fixed, dynamic or differencing.
+    pub fn disk_kind(&self) -> WinResult<DiskKind> {
+        let info_wrapper = self.get_information(get_virtual_disk::InfoVersion::ProviderSubType)?;
+        Ok(DiskKind::from_provider_sub_type(unsafe {
+            info_wrapper.info().version_details.provider_sub_type
+        }))
+    }
 
-            match error_code_to_winresult_code(result) {
-                WinResultCode::ErrorInsufficientBuffer => {
-                    raw_buffer.reserve(size as usize);
+    /// Retrieves the storage type (device and vendor) of the virtual disk.
+    pub fn storage_type(&self) -> WinResult<VirtualStorageType> {
+        let info_wrapper =
+            self.get_information(get_virtual_disk::InfoVersion::VirtualStorageType)?;
+        Ok(unsafe { info_wrapper.info().version_details.virtual_storage_type })
+    }
 
-                    let result =
-                        GetVirtualDiskInformation(self.handle, &mut size, info_ptr, &mut size_used);
+    /// Retrieves whether the virtual disk is 4 KB aligned.
+    pub fn is_4k_aligned(&self) -> WinResult<bool> {
+        let info_wrapper = self.get_information(get_virtual_disk::InfoVersion::Is4KAligned)?;
+        Ok(unsafe { info_wrapper.info().version_details.is_4k_aligned } != 0)
+    }
 
-                    match error_code_to_winresult_code(result) {
-                        WinResultCode::ErrorSuccess => Ok(GetVirtualDiskInfoWrapper { raw_buffer }),
-                        error => Err(error),
-                    }
-                }
-                WinResultCode::ErrorSuccess => Ok(GetVirtualDiskInfoWrapper { raw_buffer }),
-                error => Err(error),
-            }
+    /// Retrieves whether the backing file of the virtual disk lives on a remote file system,
+    /// e.g. an SMB3 share.
+    pub fn is_remote_backed(&self) -> WinResult<bool> {
+        let info_wrapper = self.get_information(get_virtual_disk::InfoVersion::PhysicalDisk)?;
+        Ok(unsafe { info_wrapper.info().version_details.physical_disk.is_remote } != 0)
+    }
+
+    /// Retrieves whether the virtual disk is currently loaded by the virtual disk provider.
+    pub fn is_loaded(&self) -> WinResult<bool> {
+        let info_wrapper = self.get_information(get_virtual_disk::InfoVersion::IsLoaded)?;
+        Ok(unsafe { info_wrapper.info().version_details.is_loaded } != 0)
+    }
+
+    /// Retrieves whether the virtual disk is attached to the host and, if it is,
+    /// the number of the disk it surfaces and whether that disk is writable.
+    /// A virtual disk loaded by the provider without a disk surfaced for it is reported as detached.
+    pub fn attach_state(&self) -> WinResult<AttachState> {
+        if !self.is_loaded()? {
+            return Ok(AttachState::Detached);
         }
+
+        let physical_path = match self.get_physical_path() {
+            Ok(physical_path) => physical_path,
+            Err(_) => return Ok(AttachState::Detached),
+        };
+
+        let digits = physical_path
+            .trim_end_matches(|c: char| c.is_ascii_digit())
+            .len();
+        let disk_number = physical_path[digits..].parse::<u32>().map_err(|_| {
+            error_code_to_winresult_code(winapi::shared::winerror::ERROR_INVALID_DATA)
+        })?;
+
+        match crate::diskutilities::Disk::open(&physical_path, Some(0), None)?.is_writable()? {
+            true => Ok(AttachState::AttachedReadWrite { disk_number }),
+            false => Ok(AttachState::AttachedReadOnly { disk_number }),
+        }
+    }
+
+    /// Retrieves the location of the parent of a differencing virtual disk.
+    /// If the parent was resolved, the returned paths hold its path only.
+    /// Otherwise they hold every parent locator recorded by the virtual disk.
+    pub fn parent_location(&self) -> WinResult<ParentLocation> {
+        let info_wrapper = self.get_information(get_virtual_disk::InfoVersion::ParentLocation)?;
+
+        Ok(ParentLocation {
+            resolved: unsafe {
+                info_wrapper
+                    .info()
+                    .version_details
+                    .parent_location
+                    .parent_resolved
+            } != 0,
+            paths: info_wrapper.parent_location_paths()?,
+        })
+    }
+
+    /// Retrieves the fragmentation level of the virtual disk, as a percentage.
+    pub fn fragmentation_percent(&self) -> WinResult<u32> {
+        let info_wrapper = self.get_information(get_virtual_disk::InfoVersion::Fragmentation)?;
+        Ok(unsafe { info_wrapper.info().version_details.fragmentation_percentage })
+    }
+
+    /// Retrieves the commonly inspected information of the virtual disk at once,
+    /// e.g. to report it to monitoring tools.
+    pub fn summary(&self) -> WinResult<VirtualDiskSummary> {
+        let disk_kind = self.disk_kind()?;
+        let size_wrapper = self.get_information(get_virtual_disk::InfoVersion::Size)?;
+        let size = unsafe { size_wrapper.info().version_details.size };
+        let id_wrapper = self.get_information(get_virtual_disk::InfoVersion::VirtualDiskId)?;
+
+        Ok(VirtualDiskSummary {
+            path: self.path().map(String::from),
+            storage_type: self.storage_type()?,
+            disk_kind,
+            virtual_size: size.virtual_size,
+            physical_size: size.physical_size,
+            block_size: size.block_size,
+            sector_size: size.sector_size,
+            virtual_disk_id: unsafe { id_wrapper.info().version_details.virtual_disk_id },
+            attach_state: self.attach_state()?,
+            parent_location: match disk_kind {
+                DiskKind::Differencing => Some(self.parent_location()?),
+                _ => None,
+            },
+        })
     }
 
     /// Sets information about a virtual hard disk.
     pub fn set_information(&self, info: &set_virtual_disk::Info) -> WinResult<()> {
+        self.invalidate_info_cache();
+
         unsafe {
-            match SetVirtualDiskInformation(self.handle, info) {
+            match SetVirtualDiskInformation(self.handle.get(), info) {
                 0 => Ok(()),
                 result => Err(error_code_to_winresult_code(result)),
             }
         }
     }
 
+    /// Sets the unique identifier of the virtual disk, as returned by the `VirtualDiskId` information.
+    pub fn set_virtual_disk_id(&self, virtual_disk_id: Guid) -> WinResult<()> {
+        self.set_information(&set_virtual_disk::Info {
+            version: set_virtual_disk::InfoVersion::VirtualDiskId,
+            version_details: set_virtual_disk::InfoVersionDetails { virtual_disk_id },
+        })
+    }
+
+    /// Enables or disables resilient change tracking on the virtual disk.
+    pub fn set_change_tracking_enabled(&self, enabled: bool) -> WinResult<()> {
+        self.set_information(&set_virtual_disk::Info {
+            version: set_virtual_disk::InfoVersion::ChangeTrackingState,
+            version_details: set_virtual_disk::InfoVersionDetails {
+                change_tracking_enabled: enabled as Bool,
+            },
+        })
+    }
+
     /// Enumerates the metadata associated with a virtual disk.
     /// The returned vector of GUID refer to a set of metadata that can be retrieved
     /// using function `VirtualHardDisk::get_metadata`.
@@ -398,8 +1306,11 @@ impl VirtualDisk {
         let mut vector_size: u32 = 0;
 
         unsafe {
-            let result =
-                EnumerateVirtualDiskMetadata(self.handle, &mut vector_size, guids.as_mut_ptr());
+            let result = EnumerateVirtualDiskMetadata(
+                self.handle.get(),
+                &mut vector_size,
+                guids.as_mut_ptr(),
+            );
 
             match error_code_to_winresult_code(result) {
                 WinResultCode::ErrorInsufficientBuffer => {
@@ -414,7 +1325,7 @@ impl VirtualDisk {
                     );
 
                     match EnumerateVirtualDiskMetadata(
-                        self.handle,
+                        self.handle.get(),
                         &mut vector_size,
                         guids.as_mut_ptr(),
                     ) {
@@ -438,7 +1349,7 @@ impl VirtualDisk {
 
         unsafe {
             let result = GetVirtualDiskMetadata(
-                self.handle,
+                self.handle.get(),
                 item,
                 &mut buffer_size,
                 buffer.as_mut_ptr() as *mut Void,
@@ -449,7 +1360,7 @@ impl VirtualDisk {
                     buffer.resize(buffer_size as usize, 0);
 
                     match GetVirtualDiskMetadata(
-                        self.handle,
+                        self.handle.get(),
                         item,
                         &mut buffer_size,
                         buffer.as_mut_ptr() as *mut Void,
@@ -471,7 +1382,7 @@ impl VirtualDisk {
     pub fn set_metadata(&self, item: &Guid, buffer: &[u8]) -> WinResult<()> {
         unsafe {
             match SetVirtualDiskMetadata(
-                self.handle,
+                self.handle.get(),
                 item,
                 buffer.len() as u32,
                 buffer.as_ptr() as *const Void,
@@ -485,7 +1396,7 @@ impl VirtualDisk {
     /// Deletes metadata from a virtual disk.
     pub fn delete_metadata(&self, item: &Guid) -> WinResult<()> {
         unsafe {
-            match DeleteVirtualDiskMetadata(self.handle, item) {
+            match DeleteVirtualDiskMetadata(self.handle.get(), item) {
                 0 => Ok(()),
                 result => Err(error_code_to_winresult_code(result)),
             }
@@ -504,7 +1415,7 @@ impl VirtualDisk {
         };
 
         unsafe {
-            match GetVirtualDiskOperationProgress(self.handle, overlapped, &mut progress) {
+            match GetVirtualDiskOperationProgress(self.handle.get(), overlapped, &mut progress) {
                 0 => Ok(progress),
                 result => Err(error_code_to_winresult_code(result)),
             }
@@ -524,8 +1435,10 @@ impl VirtualDisk {
             None => std::ptr::null(),
         };
 
+        self.invalidate_info_cache();
+
         unsafe {
-            match CompactVirtualDisk(self.handle, flags, parameters, overlapped_ptr) {
+            match CompactVirtualDisk(self.handle.get(), flags, parameters, overlapped_ptr) {
                 0 => Ok(()),
                 result => Err(error_code_to_winresult_code(result)),
             }
@@ -545,8 +1458,10 @@ impl VirtualDisk {
             None => std::ptr::null(),
         };
 
+        self.invalidate_info_cache();
+
         unsafe {
-            match MergeVirtualDisk(self.handle, flags, parameters, overlapped_ptr) {
+            match MergeVirtualDisk(self.handle.get(), flags, parameters, overlapped_ptr) {
                 0 => Ok(()),
                 result => Err(error_code_to_winresult_code(result)),
             }
@@ -566,8 +1481,10 @@ impl VirtualDisk {
             None => std::ptr::null(),
         };
 
+        self.invalidate_info_cache();
+
         unsafe {
-            match ExpandVirtualDisk(self.handle, flags, parameters, overlapped_ptr) {
+            match ExpandVirtualDisk(self.handle.get(), flags, parameters, overlapped_ptr) {
                 0 => Ok(()),
                 result => Err(error_code_to_winresult_code(result)),
             }
@@ -587,8 +1504,10 @@ impl VirtualDisk {
             None => std::ptr::null(),
         };
 
+        self.invalidate_info_cache();
+
         unsafe {
-            match ResizeVirtualDisk(self.handle, flags, parameters, overlapped_ptr) {
+            match ResizeVirtualDisk(self.handle.get(), flags, parameters, overlapped_ptr) {
                 0 => Ok(()),
                 result => Err(error_code_to_winresult_code(result)),
             }
@@ -610,7 +1529,7 @@ impl VirtualDisk {
         overlapped: &Overlapped,
     ) -> WinResult<()> {
         unsafe {
-            match MirrorVirtualDisk(self.handle, flags, parameters, overlapped) {
+            match MirrorVirtualDisk(self.handle.get(), flags, parameters, overlapped) {
                 0 => Ok(()),
                 result => Err(error_code_to_winresult_code(result)),
             }
@@ -619,8 +1538,10 @@ impl VirtualDisk {
 
     /// Breaks a previously initiated mirror operation and sets the mirror to be the active virtual disk.
     pub fn break_mirror(&self) -> WinResult<()> {
+        self.invalidate_info_cache();
+
         unsafe {
-            match BreakMirrorVirtualDisk(self.handle) {
+            match BreakMirrorVirtualDisk(self.handle.get()) {
                 0 => Ok(()),
                 result => Err(error_code_to_winresult_code(result)),
             }
@@ -629,9 +1550,11 @@ impl VirtualDisk {
 
     /// Attaches a parent to a virtual disk opened with the `open_virtual_disk::Flag::CustomDiffChain` flag.
     pub fn add_parent(&self, parent_path: &str) -> WinResult<()> {
+        self.invalidate_info_cache();
+
         unsafe {
             match AddVirtualDiskParent(
-                self.handle,
+                self.handle.get(),
                 WideCString::from_str(parent_path).unwrap().as_ptr(),
             ) {
                 0 => Ok(()),
@@ -660,7 +1583,7 @@ impl VirtualDisk {
 
         unsafe {
             match QueryChangesVirtualDisk(
-                self.handle,
+                self.handle.get(),
                 WideCString::from_str(change_tracking_id).unwrap().as_ptr(),
                 byte_offset,
                 byte_length,
@@ -675,6 +1598,63 @@ impl VirtualDisk {
         }
     }
 
+    /// Retrieves every range of the virtual hard disk that changed since the given
+    /// resilient change tracking ID, issuing as many `query_changes` calls as needed
+    /// to cover the whole virtual size.
+    pub fn query_all_changes(
+        &self,
+        change_tracking_id: &str,
+    ) -> WinResult<Vec<query_changes_virtual_disk::Range>> {
+        const RANGE_BATCH: usize = 256;
+
+        let virtual_size = unsafe {
+            self.get_information(get_virtual_disk::InfoVersion::Size)?
+                .info()
+                .version_details
+                .size
+                .virtual_size
+        };
+
+        let mut changes: Vec<query_changes_virtual_disk::Range> = Vec::new();
+        let mut ranges: Vec<query_changes_virtual_disk::Range> =
+            vec![unsafe { std::mem::zeroed() }; RANGE_BATCH];
+        let mut byte_offset: u64 = 0;
+
+        while byte_offset < virtual_size {
+            let (range_count, processed_length) = self.query_changes(
+                change_tracking_id,
+                byte_offset,
+                virtual_size - byte_offset,
+                query_changes_virtual_disk::Flag::None as u32,
+                &mut ranges,
+            )?;
+
+            changes.extend_from_slice(&ranges[..range_count as usize]);
+
+            if processed_length == 0 {
+                break;
+            }
+
+            byte_offset += processed_length;
+        }
+
+        Ok(changes)
+    }
+
+    /// Trims the given byte ranges of the attached virtual disk, so container
+    /// garbage collection can return freed layer space to the host.
+    /// The ranges aren't guaranteed to read back as zeros, see `Disk::trim_ranges`
+    /// for the requirements on the ranges.
+    /// Returns the error of `get_physical_path` if the virtual disk isn't attached.
+    pub fn trim_ranges(&self, ranges: &[query_changes_virtual_disk::Range]) -> WinResult<()> {
+        crate::diskutilities::Disk::open(
+            &self.get_physical_path()?,
+            Some(winapi::um::winnt::GENERIC_READ | winapi::um::winnt::GENERIC_WRITE),
+            None,
+        )?
+        .trim_ranges(ranges)
+    }
+
     /// Creates a snapshot of the current virtual disk for VHD Set files.
     /// The flags are a u32 representation of any valid combination from `take_snapshot_vhdset::Flag` values.
     pub fn take_snapshot_vhdset(
@@ -683,7 +1663,7 @@ impl VirtualDisk {
         flags: u32,
     ) -> WinResult<()> {
         unsafe {
-            match TakeSnapshotVhdSet(self.handle, parameters, flags) {
+            match TakeSnapshotVhdSet(self.handle.get(), parameters, flags) {
                 0 => Ok(()),
                 result => Err(error_code_to_winresult_code(result)),
             }
@@ -698,7 +1678,7 @@ impl VirtualDisk {
         flags: u32,
     ) -> WinResult<()> {
         unsafe {
-            match DeleteSnapshotVhdSet(self.handle, parameters, flags) {
+            match DeleteSnapshotVhdSet(self.handle.get(), parameters, flags) {
                 0 => Ok(()),
                 result => Err(error_code_to_winresult_code(result)),
             }
@@ -714,7 +1694,7 @@ impl VirtualDisk {
         flags: u32,
     ) -> WinResult<()> {
         unsafe {
-            match ModifyVhdSet(self.handle, parameters, flags) {
+            match ModifyVhdSet(self.handle.get(), parameters, flags) {
                 0 => Ok(()),
                 result => Err(error_code_to_winresult_code(result)),
             }
@@ -729,7 +1709,7 @@ impl VirtualDisk {
         flags: u32,
     ) -> WinResult<()> {
         unsafe {
-            match ApplySnapshotVhdSet(self.handle, parameters, flags) {
+            match ApplySnapshotVhdSet(self.handle.get(), parameters, flags) {
                 0 => Ok(()),
                 result => Err(error_code_to_winresult_code(result)),
             }
@@ -755,13 +1735,75 @@ impl VirtualDisk {
         };
 
         unsafe {
-            match RawSCSIVirtualDisk(self.handle, parameters, flags, &mut response) {
+            match RawSCSIVirtualDisk(self.handle.get(), parameters, flags, &mut response) {
                 0 => Ok(response),
                 result => Err(error_code_to_winresult_code(result)),
             }
         }
     }
 
+    /// Retrieves the SCSI page 83 unique identifier the virtual disk is surfaced with,
+    /// which clustering and multipath software use to recognize the same disk across hosts.
+    /// The identifier is read from the Page 83 Data metadata item of VHDX files, falling back
+    /// to a SCSI INQUIRY of the Device Identification VPD page (0x83) through `raw_scsi_virtual_disk`,
+    /// which requires the virtual disk to be opened with read access.
+    pub fn page83_identity(&self) -> WinResult<Page83Identity> {
+        if let Ok(identifier) = self.get_metadata(&PAGE83_DATA_METADATA_ITEM) {
+            if identifier.len() == 16 {
+                return Ok(Page83Identity::binary(identifier));
+            }
+        }
+
+        const INQUIRY: u8 = 0x12;
+        const EVPD: u8 = 0x01;
+        const DEVICE_IDENTIFICATION_PAGE: u8 = 0x83;
+        const SCSI_IOCTL_DATA_IN: u8 = 1;
+        const SRB_FLAGS_DATA_IN: u32 = 0x00000040;
+        const SCSISTAT_GOOD: u8 = 0;
+
+        let mut data = [0u8; 255];
+        let mut sense_info = [0u8; 18];
+        let mut cdb: [u8; 6] = [
+            INQUIRY,
+            EVPD,
+            DEVICE_IDENTIFICATION_PAGE,
+            0,
+            data.len() as u8,
+            0,
+        ];
+
+        let parameters = raw_scsi_virtual_disk::Parameters {
+            version: raw_scsi_virtual_disk::Version::Version1,
+            version_details: raw_scsi_virtual_disk::VersionDetails {
+                version1: raw_scsi_virtual_disk::Version1 {
+                    rsvd_handle: 0,
+                    data_in: SCSI_IOCTL_DATA_IN,
+                    cdb_length: cdb.len() as u8,
+                    sense_info_length: sense_info.len() as u8,
+                    srb_flags: SRB_FLAGS_DATA_IN,
+                    data_transfer_length: data.len() as u32,
+                    data_buffer: data.as_mut_ptr() as *mut Void,
+                    sense_info: sense_info.as_mut_ptr(),
+                    cdb: cdb.as_mut_ptr(),
+                },
+            },
+        };
+
+        let response =
+            self.raw_scsi_virtual_disk(&parameters, raw_scsi_virtual_disk::Flag::None as u32)?;
+        let response = unsafe { response.version_details.version1 };
+
+        if response.scsi_status != SCSISTAT_GOOD {
+            return Err(error_code_to_winresult_code(
+                winapi::shared::winerror::ERROR_IO_DEVICE,
+            ));
+        }
+
+        let length = std::cmp::min(response.data_transfer_length as usize, data.len());
+        Page83Identity::from_vpd_page(&data[..length])
+            .ok_or_else(|| error_code_to_winresult_code(winapi::shared::winerror::ERROR_NOT_FOUND))
+    }
+
     /// Forks a virtual hard disk.
     /// `VirtualHardDisk::get_operation_progress` can be used to determine if the disk has been fully forked.
     /// The flags are a u32 representation of any valid combination from `fork_virtual_disk::Flag` values.
@@ -772,7 +1814,7 @@ impl VirtualDisk {
         overlapped: &mut Overlapped,
     ) -> WinResult<()> {
         unsafe {
-            match ForkVirtualDisk(self.handle, flags, parameters, overlapped) {
+            match ForkVirtualDisk(self.handle.get(), flags, parameters, overlapped) {
                 0 => Ok(()),
                 result => Err(error_code_to_winresult_code(result)),
             }
@@ -782,10 +1824,1035 @@ impl VirtualDisk {
     /// Completes a virtual hard disk fork initiated with `VirtualHardDisk::fork`.
     pub fn complete_fork(&self) -> WinResult<()> {
         unsafe {
-            match CompleteForkVirtualDisk(self.handle) {
+            match CompleteForkVirtualDisk(self.handle.get()) {
                 0 => Ok(()),
                 result => Err(error_code_to_winresult_code(result)),
             }
         }
     }
 }
+
+/// Virtual disk opened only to query and set information, see `VirtualDisk::open_info_only`.
+/// Operations that need full access to the virtual disk, like attaching it, aren't available.
+#[derive(Debug)]
+pub struct VirtualDiskInfoHandle {
+    inner: VirtualDisk,
+}
+
+impl AsRawHandle for VirtualDiskInfoHandle {
+    fn as_raw_handle(&self) -> RawHandle {
+        self.inner.as_raw_handle()
+    }
+}
+
+impl IntoRawHandle for VirtualDiskInfoHandle {
+    fn into_raw_handle(self) -> RawHandle {
+        self.inner.into_raw_handle()
+    }
+}
+
+impl VirtualDiskInfoHandle {
+    /// See `VirtualDisk::set_info_cache`.
+    pub fn set_info_cache(&mut self, info_cache: Option<InfoCache>) {
+        self.inner.set_info_cache(info_cache)
+    }
+
+    /// See `VirtualDisk::info_cache`.
+    pub fn info_cache(&self) -> Option<&InfoCache> {
+        self.inner.info_cache()
+    }
+
+    /// See `VirtualDisk::invalidate_info_cache`.
+    pub fn invalidate_info_cache(&self) {
+        self.inner.invalidate_info_cache()
+    }
+
+    /// See `VirtualDisk::get_handle`.
+    pub fn get_handle(&self) -> Handle {
+        self.inner.get_handle()
+    }
+
+    /// See `VirtualDisk::path`.
+    pub fn path(&self) -> Option<&str> {
+        self.inner.path()
+    }
+
+    /// See `VirtualDisk::backing_file_path`.
+    pub fn backing_file_path(&self) -> WinResult<String> {
+        self.inner.backing_file_path()
+    }
+
+    /// See `VirtualDisk::get_physical_path`.
+    pub fn get_physical_path(&self) -> WinResult<String> {
+        self.inner.get_physical_path()
+    }
+
+    /// See `VirtualDisk::get_storage_dependency_information`.
+    pub fn get_storage_dependency_information(
+        &self,
+        flags: u32,
+        version: storage_dependency::InfoVersion,
+    ) -> WinResult<GetStorageDependencyInformationWrapper> {
+        self.inner
+            .get_storage_dependency_information(flags, version)
+    }
+
+    /// See `VirtualDisk::get_information`.
+    pub fn get_information(
+        &self,
+        version: get_virtual_disk::InfoVersion,
+    ) -> WinResult<GetVirtualDiskInfoWrapper> {
+        self.inner.get_information(version)
+    }
+
+    /// See `VirtualDisk::disk_kind`.
+    pub fn disk_kind(&self) -> WinResult<DiskKind> {
+        self.inner.disk_kind()
+    }
+
+    /// See `VirtualDisk::storage_type`.
+    pub fn storage_type(&self) -> WinResult<VirtualStorageType> {
+        self.inner.storage_type()
+    }
+
+    /// See `VirtualDisk::is_4k_aligned`.
+    pub fn is_4k_aligned(&self) -> WinResult<bool> {
+        self.inner.is_4k_aligned()
+    }
+
+    /// See `VirtualDisk::is_remote_backed`.
+    pub fn is_remote_backed(&self) -> WinResult<bool> {
+        self.inner.is_remote_backed()
+    }
+
+    /// See `VirtualDisk::is_loaded`.
+    pub fn is_loaded(&self) -> WinResult<bool> {
+        self.inner.is_loaded()
+    }
+
+    /// See `VirtualDisk::attach_state`.
+    pub fn attach_state(&self) -> WinResult<AttachState> {
+        self.inner.attach_state()
+    }
+
+    /// See `VirtualDisk::parent_location`.
+    pub fn parent_location(&self) -> WinResult<ParentLocation> {
+        self.inner.parent_location()
+    }
+
+    /// See `VirtualDisk::fragmentation_percent`.
+    pub fn fragmentation_percent(&self) -> WinResult<u32> {
+        self.inner.fragmentation_percent()
+    }
+
+    /// See `VirtualDisk::summary`.
+    pub fn summary(&self) -> WinResult<VirtualDiskSummary> {
+        self.inner.summary()
+    }
+
+    /// See `VirtualDisk::set_information`.
+    pub fn set_information(&self, info: &set_virtual_disk::Info) -> WinResult<()> {
+        self.inner.set_information(info)
+    }
+
+    /// See `VirtualDisk::enumerate_metadata`.
+    pub fn enumerate_metadata(&self) -> WinResult<Vec<Guid>> {
+        self.inner.enumerate_metadata()
+    }
+
+    /// See `VirtualDisk::get_metadata`.
+    pub fn get_metadata(&self, item: &Guid) -> WinResult<Vec<u8>> {
+        self.inner.get_metadata(item)
+    }
+}
+
+/// Virtual disk opened read-only, see `VirtualDisk::open_read_only`.
+/// Operations that modify the virtual disk, like merging, resizing or setting information,
+/// aren't available, and the virtual disk can only be attached read-only.
+#[derive(Debug)]
+pub struct ReadOnlyVirtualDisk {
+    inner: VirtualDisk,
+}
+
+impl AsRawHandle for ReadOnlyVirtualDisk {
+    fn as_raw_handle(&self) -> RawHandle {
+        self.inner.as_raw_handle()
+    }
+}
+
+impl IntoRawHandle for ReadOnlyVirtualDisk {
+    fn into_raw_handle(self) -> RawHandle {
+        self.inner.into_raw_handle()
+    }
+}
+
+impl ReadOnlyVirtualDisk {
+    /// See `VirtualDisk::set_info_cache`.
+    pub fn set_info_cache(&mut self, info_cache: Option<InfoCache>) {
+        self.inner.set_info_cache(info_cache)
+    }
+
+    /// See `VirtualDisk::info_cache`.
+    pub fn info_cache(&self) -> Option<&InfoCache> {
+        self.inner.info_cache()
+    }
+
+    /// See `VirtualDisk::invalidate_info_cache`.
+    pub fn invalidate_info_cache(&self) {
+        self.inner.invalidate_info_cache()
+    }
+
+    /// See `VirtualDisk::get_handle`.
+    pub fn get_handle(&self) -> Handle {
+        self.inner.get_handle()
+    }
+
+    /// See `VirtualDisk::path`.
+    pub fn path(&self) -> Option<&str> {
+        self.inner.path()
+    }
+
+    /// Attaches the virtual disk read-only, see `VirtualDisk::attach`.
+    /// The read-only flag is always added to the supplied flags.
+    pub fn attach(
+        &self,
+        security_descriptor: Option<SecurityDescriptor>,
+        flags: u32,
+        provider_specific_flags: u32,
+        parameters: &attach_virtual_disk::Parameters,
+        overlapped: Option<&Overlapped>,
+    ) -> WinResult<()> {
+        self.inner.attach(
+            security_descriptor,
+            flags | attach_virtual_disk::Flag::ReadOnly as u32,
+            provider_specific_flags,
+            parameters,
+            overlapped,
+        )
+    }
+
+    /// See `VirtualDisk::detach`.
+    pub fn detach(&self, flags: u32, provider_specific_flags: u32) -> WinResult<()> {
+        self.inner.detach(flags, provider_specific_flags)
+    }
+
+    /// See `VirtualDisk::detach_with_retry`.
+    pub fn detach_with_retry(
+        &self,
+        flags: u32,
+        provider_specific_flags: u32,
+        policy: RetryPolicy,
+    ) -> Result<(), DetachError> {
+        self.inner
+            .detach_with_retry(flags, provider_specific_flags, policy)
+    }
+
+    /// See `VirtualDisk::backing_file_path`.
+    pub fn backing_file_path(&self) -> WinResult<String> {
+        self.inner.backing_file_path()
+    }
+
+    /// See `VirtualDisk::get_physical_path`.
+    pub fn get_physical_path(&self) -> WinResult<String> {
+        self.inner.get_physical_path()
+    }
+
+    /// See `VirtualDisk::get_storage_dependency_information`.
+    pub fn get_storage_dependency_information(
+        &self,
+        flags: u32,
+        version: storage_dependency::InfoVersion,
+    ) -> WinResult<GetStorageDependencyInformationWrapper> {
+        self.inner
+            .get_storage_dependency_information(flags, version)
+    }
+
+    /// See `VirtualDisk::get_information`.
+    pub fn get_information(
+        &self,
+        version: get_virtual_disk::InfoVersion,
+    ) -> WinResult<GetVirtualDiskInfoWrapper> {
+        self.inner.get_information(version)
+    }
+
+    /// See `VirtualDisk::storage_type`.
+    pub fn storage_type(&self) -> WinResult<VirtualStorageType> {
+        self.inner.storage_type()
+    }
+
+    /// See `VirtualDisk::attach_state`.
+    pub fn attach_state(&self) -> WinResult<AttachState> {
+        self.inner.attach_state()
+    }
+
+    /// See `VirtualDisk::parent_location`.
+    pub fn parent_location(&self) -> WinResult<ParentLocation> {
+        self.inner.parent_location()
+    }
+
+    /// See `VirtualDisk::enumerate_metadata`.
+    pub fn enumerate_metadata(&self) -> WinResult<Vec<Guid>> {
+        self.inner.enumerate_metadata()
+    }
+
+    /// See `VirtualDisk::get_metadata`.
+    pub fn get_metadata(&self, item: &Guid) -> WinResult<Vec<u8>> {
+        self.inner.get_metadata(item)
+    }
+
+    /// See `VirtualDisk::page83_identity`.
+    pub fn page83_identity(&self) -> WinResult<Page83Identity> {
+        self.inner.page83_identity()
+    }
+
+    /// See `VirtualDisk::get_operation_progress`.
+    pub fn get_operation_progress(
+        &self,
+        overlapped: &Overlapped,
+    ) -> WinResult<VirtualDiskProgress> {
+        self.inner.get_operation_progress(overlapped)
+    }
+
+    /// See `VirtualDisk::query_changes`.
+    pub fn query_changes(
+        &self,
+        change_tracking_id: &str,
+        byte_offset: u64,
+        byte_length: u64,
+        flags: u32,
+        ranges: &mut [query_changes_virtual_disk::Range],
+    ) -> WinResult<(u32, u64)> {
+        self.inner
+            .query_changes(change_tracking_id, byte_offset, byte_length, flags, ranges)
+    }
+
+    /// See `VirtualDisk::query_all_changes`.
+    pub fn query_all_changes(
+        &self,
+        change_tracking_id: &str,
+    ) -> WinResult<Vec<query_changes_virtual_disk::Range>> {
+        self.inner.query_all_changes(change_tracking_id)
+    }
+}
+
+/// Virtual disk opened read-write, see `VirtualDisk::open_writable`.
+/// Dereferences to `VirtualDisk`, every operation is available.
+#[derive(Debug)]
+pub struct WritableVirtualDisk {
+    inner: VirtualDisk,
+}
+
+impl std::ops::Deref for WritableVirtualDisk {
+    type Target = VirtualDisk;
+
+    fn deref(&self) -> &VirtualDisk {
+        &self.inner
+    }
+}
+
+impl AsRawHandle for WritableVirtualDisk {
+    fn as_raw_handle(&self) -> RawHandle {
+        self.inner.as_raw_handle()
+    }
+}
+
+impl IntoRawHandle for WritableVirtualDisk {
+    fn into_raw_handle(self) -> RawHandle {
+        self.inner.into_raw_handle()
+    }
+}
+
+impl WritableVirtualDisk {
+    /// See `VirtualDisk::set_info_cache`.
+    pub fn set_info_cache(&mut self, info_cache: Option<InfoCache>) {
+        self.inner.set_info_cache(info_cache)
+    }
+
+    /// Returns the wrapped virtual disk.
+    pub fn into_inner(self) -> VirtualDisk {
+        self.inner
+    }
+}
+
+/// Location of the parent of a differencing virtual disk, see `VirtualDisk::parent_location`.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParentLocation {
+    /// True if the parent was found and opened.
+    pub resolved: bool,
+
+    /// Path to the parent if resolved, parent locators recorded by the child otherwise.
+    pub paths: Vec<String>,
+}
+
+/// Commonly inspected information of a virtual disk, see `VirtualDisk::summary`.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone)]
+pub struct VirtualDiskSummary {
+    /// Path the virtual disk was opened or created with, None if it was adopted from a handle.
+    pub path: Option<String>,
+
+    pub storage_type: VirtualStorageType,
+    pub disk_kind: DiskKind,
+
+    /// Size of the virtual disk as seen by the guest, in bytes.
+    pub virtual_size: u64,
+
+    /// Size of the backing file on the host, in bytes.
+    pub physical_size: u64,
+
+    pub block_size: u32,
+    pub sector_size: u32,
+
+    #[cfg_attr(feature = "serde", serde(with = "crate::winutilities::serde_guid"))]
+    pub virtual_disk_id: Guid,
+
+    pub attach_state: AttachState,
+
+    /// Location of the parent, only for differencing virtual disks.
+    pub parent_location: Option<ParentLocation>,
+}
+
+/// Page 83 Data item of the VHDX metadata region, holding the 16 byte unique identifier
+/// the virtual disk is surfaced with.
+const PAGE83_DATA_METADATA_ITEM: Guid =
+    crate::guid::WinGuid::from_u128(0xBECA12AB_B2E6_4523_93EF_C309E000C746).into_guid();
+
+/// SCSI page 83 unique identifier of a virtual disk, see `VirtualDisk::page83_identity`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Page83Identity {
+    /// Raw identifier bytes, as reported in the identification descriptor.
+    pub identifier: Vec<u8>,
+
+    /// Identifier as text: uppercase hexadecimal for binary identifiers,
+    /// the identifier itself for ASCII and UTF-8 ones (e.g. SCSI name strings).
+    pub formatted: String,
+}
+
+impl Page83Identity {
+    fn binary(identifier: Vec<u8>) -> Page83Identity {
+        let formatted = identifier
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect();
+
+        Page83Identity {
+            identifier,
+            formatted,
+        }
+    }
+
+    /// Picks the identifier of the logical unit from a Device Identification VPD page,
+    /// preferring NAA identifiers, then SCSI name strings, EUI-64 and any other type.
+    fn from_vpd_page(page: &[u8]) -> Option<Page83Identity> {
+        const CODE_SET_ASCII: u8 = 2;
+        const CODE_SET_UTF8: u8 = 3;
+        const ASSOCIATION_LOGICAL_UNIT: u8 = 0;
+        const PREFERRED_TYPES: [u8; 3] = [3, 8, 2]; // NAA, SCSI name string, EUI-64
+
+        if page.len() < 4 || page[1] != 0x83 {
+            return None;
+        }
+
+        let page_length =
+            std::cmp::min(4 + ((page[2] as usize) << 8 | page[3] as usize), page.len());
+        let mut descriptors = Vec::new();
+        let mut offset = 4;
+
+        while offset + 4 <= page_length {
+            let code_set = page[offset] & 0x0F;
+            let association = (page[offset + 1] >> 4) & 0x03;
+            let identifier_type = page[offset + 1] & 0x0F;
+            let length = page[offset + 3] as usize;
+            let end = offset + 4 + length;
+
+            if end > page_length {
+                break;
+            }
+
+            if association == ASSOCIATION_LOGICAL_UNIT && length > 0 {
+                descriptors.push((identifier_type, code_set, &page[offset + 4..end]));
+            }
+
+            offset = end;
+        }
+
+        let rank = |identifier_type: u8| {
+            PREFERRED_TYPES
+                .iter()
+                .position(|preferred| *preferred == identifier_type)
+                .unwrap_or(PREFERRED_TYPES.len())
+        };
+
+        descriptors
+            .into_iter()
+            .min_by_key(|(identifier_type, _, _)| rank(*identifier_type))
+            .map(|(_, code_set, identifier)| match code_set {
+                CODE_SET_ASCII | CODE_SET_UTF8 => Page83Identity {
+                    identifier: identifier.to_vec(),
+                    formatted: String::from_utf8_lossy(identifier)
+                        .trim_end_matches('\0')
+                        .trim()
+                        .to_string(),
+                },
+                _ => Page83Identity::binary(identifier.to_vec()),
+            })
+    }
+}
+
+/// Mode a virtual disk ended up opened with through `OpenOptions::open_with_fallback`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum OpenMode {
+    ReadWrite,
+    ReadOnly,
+    GetInfoOnly,
+}
+
+/// Sharing conflict with another process that prevented a virtual disk from being opened.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum SharingConflict {
+    /// Another process holds the virtual disk open for writing, so it can't be opened read-write
+    /// or read-only, but it could still be opened to query information.
+    WriterPresent,
+
+    /// Another process holds the virtual disk open exclusively, so it can't be opened at all.
+    ExclusiveHolder,
+}
+
+/// Error returned by `OpenOptions::open_with_fallback` when every open mode failed.
+#[derive(Debug, Clone)]
+pub struct OpenFallbackError {
+    /// Sharing conflict that prevented the virtual disk from being opened, if that was the cause.
+    pub conflict: Option<SharingConflict>,
+
+    /// Every open mode attempted, in order, along with the error it failed with.
+    pub attempts: Vec<(OpenMode, WinResultCode)>,
+}
+
+impl OpenFallbackError {
+    /// Returns the error of the last attempted open mode.
+    pub fn last_error(&self) -> WinResultCode {
+        match self.attempts.last() {
+            Some((_, error)) => *error,
+            None => WinResultCode::ErrorGenFailure,
+        }
+    }
+}
+
+impl std::fmt::Display for OpenFallbackError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.conflict {
+            Some(SharingConflict::WriterPresent) => {
+                write!(f, "virtual disk is open for writing by another process")?
+            }
+            Some(SharingConflict::ExclusiveHolder) => {
+                write!(f, "virtual disk is open exclusively by another process")?
+            }
+            None => write!(f, "failed to open virtual disk")?,
+        }
+
+        for (mode, error) in &self.attempts {
+            write!(f, "; {:?}: {:?}", mode, error)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for OpenFallbackError {}
+
+/// Error returned by `VirtualDisk::detach_with_retry` when the virtual disk couldn't be detached.
+#[derive(Debug, Clone)]
+pub struct DetachError {
+    /// Error the last attempt failed with.
+    pub error: WinResultCode,
+
+    /// Number of detach attempts made.
+    pub attempts: u32,
+
+    /// Processes holding the virtual disk or its volumes open when the last attempt failed
+    /// with ERROR_BUSY, if they were looked up and could be determined.
+    pub lockers: Option<Vec<ProcessInfo>>,
+}
+
+impl From<DetachError> for WinResultCode {
+    fn from(error: DetachError) -> WinResultCode {
+        error.error
+    }
+}
+
+impl std::fmt::Display for DetachError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "failed to detach virtual disk after {} attempts: {:?}",
+            self.attempts, self.error
+        )?;
+
+        if let Some(lockers) = &self.lockers {
+            for locker in lockers {
+                write!(f, "; held open by {} (pid {})", locker.name, locker.pid)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for DetachError {}
+
+/// Options and flags used to open a virtual disk, in the style of `std::fs::OpenOptions`.
+#[derive(Copy, Clone)]
+pub struct OpenOptions {
+    virtual_storage_type: VirtualStorageType,
+    read_only: bool,
+    get_info_only: bool,
+    resiliency_guid: Guid,
+    snapshot_id: Guid,
+    flags: u32,
+}
+
+impl OpenOptions {
+    /// Creates a blank set of options that opens the virtual disk read-write,
+    /// detecting the virtual storage type from the file extension.
+    pub fn new() -> OpenOptions {
+        OpenOptions {
+            virtual_storage_type: VirtualStorageType::auto(),
+            read_only: false,
+            get_info_only: false,
+            resiliency_guid: GUID_NULL,
+            snapshot_id: GUID_NULL,
+            flags: open_virtual_disk::Flag::None as u32,
+        }
+    }
+
+    /// Sets the virtual storage type of the virtual disk.
+    pub fn virtual_storage_type(&mut self, virtual_storage_type: VirtualStorageType) -> &mut Self {
+        self.virtual_storage_type = virtual_storage_type;
+        self
+    }
+
+    /// Opens the virtual disk read-only.
+    pub fn read_only(&mut self, read_only: bool) -> &mut Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Opens the virtual disk only to query and set information.
+    pub fn get_info_only(&mut self, get_info_only: bool) -> &mut Self {
+        self.get_info_only = get_info_only;
+        self
+    }
+
+    /// Sets the resiliency GUID used to reconnect to a virtual disk after a failure.
+    pub fn resiliency_guid(&mut self, resiliency_guid: Guid) -> &mut Self {
+        self.resiliency_guid = resiliency_guid;
+        self
+    }
+
+    /// Opens a VHD Set at the given snapshot.
+    pub fn snapshot_id(&mut self, snapshot_id: Guid) -> &mut Self {
+        self.snapshot_id = snapshot_id;
+        self
+    }
+
+    /// Opens the backing file in cached mode.
+    pub fn cached_io(&mut self, cached_io: bool) -> &mut Self {
+        self.set_flag(open_virtual_disk::Flag::CachedIo, cached_io)
+    }
+
+    /// Opens all backing files except the leaf in cached mode.
+    pub fn parent_cached_io(&mut self, parent_cached_io: bool) -> &mut Self {
+        self.set_flag(open_virtual_disk::Flag::ParentCachedIo, parent_cached_io)
+    }
+
+    /// Sets any additional `open_virtual_disk::Flag` values, as a u32 combination.
+    pub fn flags(&mut self, flags: u32) -> &mut Self {
+        self.flags = flags;
+        self
+    }
+
+    fn set_flag(&mut self, flag: open_virtual_disk::Flag, enabled: bool) -> &mut Self {
+        match enabled {
+            true => self.flags |= flag as u32,
+            false => self.flags &= !(flag as u32),
+        }
+        self
+    }
+
+    /// Opens the virtual disk at the given path with these options.
+    pub fn open(&self, path: &str) -> WinResult<VirtualDisk> {
+        let parameters = match winutils_rs::utilities::guid_are_equal(&self.snapshot_id, &GUID_NULL)
+        {
+            true => open_virtual_disk::Parameters {
+                version: open_virtual_disk::Version::Version2,
+                version_details: open_virtual_disk::VersionDetails {
+                    version2: open_virtual_disk::Version2 {
+                        get_info_only: self.get_info_only as Bool,
+                        read_only: self.read_only as Bool,
+                        resiliency_guid: self.resiliency_guid,
+                    },
+                },
+            },
+            false => open_virtual_disk::Parameters {
+                version: open_virtual_disk::Version::Version3,
+                version_details: open_virtual_disk::VersionDetails {
+                    version3: open_virtual_disk::Version3 {
+                        get_info_only: self.get_info_only as Bool,
+                        read_only: self.read_only as Bool,
+                        resiliency_guid: self.resiliency_guid,
+                        snapshot_id: self.snapshot_id,
+                    },
+                },
+            },
+        };
+
+        VirtualDisk::open(
+            self.virtual_storage_type,
+            path,
+            VirtualDiskAccessMask::None,
+            self.flags,
+            Some(&parameters),
+        )
+    }
+
+    /// Opens the virtual disk at the given path with these options, falling back to
+    /// less demanding open modes (read-write, read-only, get-info-only) when another process
+    /// holds the file and the open fails with a sharing violation.
+    /// Open modes less demanding than the requested one are never upgraded.
+    /// Any error other than a sharing violation is returned right away.
+    pub fn open_with_fallback(
+        &self,
+        path: &str,
+    ) -> Result<(VirtualDisk, OpenMode), OpenFallbackError> {
+        let modes: &[OpenMode] = match (self.get_info_only, self.read_only) {
+            (true, _) => &[OpenMode::GetInfoOnly],
+            (false, true) => &[OpenMode::ReadOnly, OpenMode::GetInfoOnly],
+            (false, false) => &[
+                OpenMode::ReadWrite,
+                OpenMode::ReadOnly,
+                OpenMode::GetInfoOnly,
+            ],
+        };
+
+        let mut attempts: Vec<(OpenMode, WinResultCode)> = Vec::new();
+
+        for mode in modes {
+            let mut options = *self;
+            options.read_only(*mode == OpenMode::ReadOnly);
+            options.get_info_only(*mode == OpenMode::GetInfoOnly);
+
+            match options.open(path) {
+                Ok(virtual_disk) => return Ok((virtual_disk, *mode)),
+                Err(error) => {
+                    attempts.push((*mode, error));
+
+                    if winutils_rs::errorcodes::winresult_code_to_error_code(error)
+                        != winapi::shared::winerror::ERROR_SHARING_VIOLATION
+                    {
+                        // Earlier attempts failing with a sharing violation mean
+                        // another process holds the virtual disk open for writing.
+                        let conflict = match attempts.len() {
+                            1 => None,
+                            _ => Some(SharingConflict::WriterPresent),
+                        };
+
+                        return Err(OpenFallbackError { conflict, attempts });
+                    }
+                }
+            }
+        }
+
+        Err(OpenFallbackError {
+            conflict: Some(SharingConflict::ExclusiveHolder),
+            attempts,
+        })
+    }
+}
+
+impl Default for OpenOptions {
+    fn default() -> OpenOptions {
+        OpenOptions::new()
+    }
+}
+
+/// Options and parameters used to create a virtual disk, in the style of `OpenOptions`.
+#[derive(Clone)]
+pub struct CreateOptions {
+    virtual_storage_type: VirtualStorageType,
+    maximum_size: u64,
+    block_size_in_bytes: u32,
+    sector_size_in_bytes: u32,
+    physical_sector_size_in_bytes: u32,
+    parent_path: Option<String>,
+    source_path: Option<String>,
+    open_flags: u32,
+    parent_virtual_storage_type: VirtualStorageType,
+    source_virtual_storage_type: VirtualStorageType,
+    resiliency_guid: Guid,
+    backing_storage_type: Option<VirtualStorageType>,
+    flags: u32,
+}
+
+impl CreateOptions {
+    /// Creates a blank set of options that lets the provider choose every default,
+    /// detecting the virtual storage type from the file extension.
+    pub fn new() -> CreateOptions {
+        let unknown_storage_type = VirtualStorageType::auto();
+
+        CreateOptions {
+            virtual_storage_type: unknown_storage_type,
+            maximum_size: 0,
+            block_size_in_bytes: 0,
+            sector_size_in_bytes: 0,
+            physical_sector_size_in_bytes: 0,
+            parent_path: None,
+            source_path: None,
+            open_flags: open_virtual_disk::Flag::None as u32,
+            parent_virtual_storage_type: unknown_storage_type,
+            source_virtual_storage_type: unknown_storage_type,
+            resiliency_guid: GUID_NULL,
+            backing_storage_type: None,
+            flags: create_virtual_disk::Flag::None as u32,
+        }
+    }
+
+    /// Sets the virtual storage type of the new virtual disk.
+    pub fn virtual_storage_type(&mut self, virtual_storage_type: VirtualStorageType) -> &mut Self {
+        self.virtual_storage_type = virtual_storage_type;
+        self
+    }
+
+    /// Sets the virtual size of the new virtual disk, in bytes.
+    pub fn maximum_size(&mut self, maximum_size: u64) -> &mut Self {
+        self.maximum_size = maximum_size;
+        self
+    }
+
+    /// Sets the block size of the new virtual disk, in bytes.
+    pub fn block_size_in_bytes(&mut self, block_size_in_bytes: u32) -> &mut Self {
+        self.block_size_in_bytes = block_size_in_bytes;
+        self
+    }
+
+    /// Sets the logical and physical sector sizes of the new virtual disk, in bytes.
+    pub fn sector_sizes(
+        &mut self,
+        sector_size_in_bytes: u32,
+        physical_sector_size_in_bytes: u32,
+    ) -> &mut Self {
+        self.sector_size_in_bytes = sector_size_in_bytes;
+        self.physical_sector_size_in_bytes = physical_sector_size_in_bytes;
+        self
+    }
+
+    /// Creates a differencing virtual disk of the parent at the given path.
+    pub fn parent_path(&mut self, parent_path: &str) -> &mut Self {
+        self.parent_path = Some(String::from(parent_path));
+        self
+    }
+
+    /// Sets the virtual storage type of the parent virtual disk.
+    pub fn parent_virtual_storage_type(
+        &mut self,
+        parent_virtual_storage_type: VirtualStorageType,
+    ) -> &mut Self {
+        self.parent_virtual_storage_type = parent_virtual_storage_type;
+        self
+    }
+
+    /// Populates the new virtual disk with the contents of the virtual or physical disk
+    /// at the given path.
+    pub fn source_path(&mut self, source_path: &str) -> &mut Self {
+        self.source_path = Some(String::from(source_path));
+        self
+    }
+
+    /// Sets the virtual storage type of the source virtual disk.
+    pub fn source_virtual_storage_type(
+        &mut self,
+        source_virtual_storage_type: VirtualStorageType,
+    ) -> &mut Self {
+        self.source_virtual_storage_type = source_virtual_storage_type;
+        self
+    }
+
+    /// Sets the resiliency GUID used to reconnect to the virtual disk after a failure.
+    pub fn resiliency_guid(&mut self, resiliency_guid: Guid) -> &mut Self {
+        self.resiliency_guid = resiliency_guid;
+        self
+    }
+
+    /// Sets the virtual storage type of the backing store of the new virtual disk.
+    /// Requires version 3 of the create parameters, available since Windows 10.
+    pub fn backing_storage_type(&mut self, backing_storage_type: VirtualStorageType) -> &mut Self {
+        self.backing_storage_type = Some(backing_storage_type);
+        self
+    }
+
+    /// Sets the `open_virtual_disk::Flag` values used to open the parent or source,
+    /// as a u32 combination.
+    pub fn open_flags(&mut self, open_flags: u32) -> &mut Self {
+        self.open_flags = open_flags;
+        self
+    }
+
+    /// Sets the `create_virtual_disk::Flag` values, as a u32 combination.
+    pub fn flags(&mut self, flags: u32) -> &mut Self {
+        self.flags = flags;
+        self
+    }
+
+    /// Creates the virtual disk at the given path with these options.
+    /// Version 2 of the create parameters is used unless a backing storage type was supplied.
+    pub fn create(&self, path: &str) -> WinResult<VirtualDisk> {
+        let parent_path_wstr = self
+            .parent_path
+            .as_ref()
+            .map(|parent_path| WideCString::from_str(parent_path).unwrap());
+        let source_path_wstr = self
+            .source_path
+            .as_ref()
+            .map(|source_path| WideCString::from_str(source_path).unwrap());
+
+        let parent_path_ptr = match &parent_path_wstr {
+            Some(parent_path) => parent_path.as_ptr(),
+            None => std::ptr::null(),
+        };
+
+        let source_path_ptr = match &source_path_wstr {
+            Some(source_path) => source_path.as_ptr(),
+            None => std::ptr::null(),
+        };
+
+        let mut parameters = unsafe { std::mem::zeroed::<create_virtual_disk::Parameters>() };
+
+        match self.backing_storage_type {
+            None => {
+                parameters.version = create_virtual_disk::Version::Version2;
+                parameters.version_details.version2 = create_virtual_disk::Version2 {
+                    unique_id: GUID_NULL,
+                    maximum_size: self.maximum_size,
+                    block_size_in_bytes: self.block_size_in_bytes,
+                    sector_size_in_bytes: self.sector_size_in_bytes,
+                    physical_sector_size_in_bytes: self.physical_sector_size_in_bytes,
+                    parent_path: parent_path_ptr,
+                    source_path: source_path_ptr,
+                    open_flags: self.open_flags,
+                    parent_virtual_storage_type: self.parent_virtual_storage_type,
+                    source_virtual_storage_type: self.source_virtual_storage_type,
+                    resiliency_guid: self.resiliency_guid,
+                };
+            }
+            Some(backing_storage_type) => {
+                parameters.version = create_virtual_disk::Version::Version3;
+                parameters.version_details.version3 = create_virtual_disk::Version3 {
+                    unique_id: GUID_NULL,
+                    maximum_size: self.maximum_size,
+                    block_size_in_bytes: self.block_size_in_bytes,
+                    sector_size_in_bytes: self.sector_size_in_bytes,
+                    physical_sector_size_in_bytes: self.physical_sector_size_in_bytes,
+                    parent_path: parent_path_ptr,
+                    source_path: source_path_ptr,
+                    open_flags: self.open_flags,
+                    parent_virtual_storage_type: self.parent_virtual_storage_type,
+                    source_virtual_storage_type: self.source_virtual_storage_type,
+                    resiliency_guid: self.resiliency_guid,
+                    source_limit_path: std::ptr::null(),
+                    backing_storage_type,
+                };
+            }
+        }
+
+        VirtualDisk::create(
+            self.virtual_storage_type,
+            path,
+            VirtualDiskAccessMask::None,
+            None,
+            self.flags,
+            provider_flags::ProviderFlags::None.bits(),
+            &parameters,
+            None,
+        )
+    }
+}
+
+impl Default for CreateOptions {
+    fn default() -> CreateOptions {
+        CreateOptions::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a Device Identification VPD page out of (code set, association, type, identifier).
+    fn vpd_page(descriptors: &[(u8, u8, u8, &[u8])]) -> Vec<u8> {
+        let mut page = vec![0, 0x83, 0, 0];
+
+        for (code_set, association, identifier_type, identifier) in descriptors {
+            page.push(*code_set);
+            page.push(association << 4 | identifier_type);
+            page.push(0);
+            page.push(identifier.len() as u8);
+            page.extend_from_slice(identifier);
+        }
+
+        let page_length = (page.len() - 4) as u16;
+        page[2] = (page_length >> 8) as u8;
+        page[3] = page_length as u8;
+        page
+    }
+
+    #[test]
+    fn page83_prefers_naa_over_eui64() {
+        let eui64: &[u8] = &[0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77];
+        let naa: &[u8] = &[0x60, 0x02, 0x24, 0x80, 0xAB, 0xCD, 0xEF, 0x01];
+        let page = vpd_page(&[(1, 0, 2, eui64), (1, 0, 3, naa)]);
+
+        let identity = Page83Identity::from_vpd_page(&page).unwrap();
+        assert_eq!(naa.to_vec(), identity.identifier);
+        assert_eq!("60022480ABCDEF01", identity.formatted);
+    }
+
+    #[test]
+    fn page83_skips_target_port_descriptors() {
+        let port_naa: &[u8] = &[0x50, 0x01, 0x02, 0x03];
+        let eui64: &[u8] = &[0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77];
+        let page = vpd_page(&[(1, 1, 3, port_naa), (1, 0, 2, eui64)]);
+
+        let identity = Page83Identity::from_vpd_page(&page).unwrap();
+        assert_eq!(eui64.to_vec(), identity.identifier);
+    }
+
+    #[test]
+    fn page83_formats_ascii_scsi_name_string() {
+        let name: &[u8] = b"naa.600224801234\0\0\0\0";
+        let page = vpd_page(&[(3, 0, 8, name)]);
+
+        let identity = Page83Identity::from_vpd_page(&page).unwrap();
+        assert_eq!(name.to_vec(), identity.identifier);
+        assert_eq!("naa.600224801234", identity.formatted);
+    }
+
+    #[test]
+    fn page83_ignores_truncated_descriptor() {
+        let eui64: &[u8] = &[0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77];
+        let naa: &[u8] = &[0x60, 0x02, 0x24, 0x80, 0xAB, 0xCD, 0xEF, 0x01];
+        let mut page = vpd_page(&[(1, 0, 2, eui64), (1, 0, 3, naa)]);
+        page.truncate(page.len() - 3);
+
+        let identity = Page83Identity::from_vpd_page(&page).unwrap();
+        assert_eq!(eui64.to_vec(), identity.identifier);
+
+        page.truncate(4 + 4 + eui64.len() - 1);
+        assert_eq!(None, Page83Identity::from_vpd_page(&page));
+    }
+
+    #[test]
+    fn page83_rejects_other_pages() {
+        assert_eq!(None, Page83Identity::from_vpd_page(&[0, 0x80, 0, 0]));
+        assert_eq!(None, Page83Identity::from_vpd_page(&[0, 0x83]));
+    }
+}
@@ -10,10 +10,12 @@
 
 use winutils_rs::windefs::*;
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(C)]
 #[derive(Copy, Clone)]
 pub struct VirtualStorageType {
     pub device_id: u32,
+    #[cfg_attr(feature = "serde", serde(with = "crate::winutilities::serde_guid"))]
     pub vendor_id: Guid,
 }
 
@@ -39,6 +41,79 @@ pub const VIRTUAL_STORAGE_TYPE_DEVICE_VHD: u32 = 2;
 pub const VIRTUAL_STORAGE_TYPE_DEVICE_VHDX: u32 = 3;
 pub const VIRTUAL_STORAGE_TYPE_DEVICE_VHDSET: u32 = 4;
 
+impl VirtualStorageType {
+    /// Lets the provider pick the storage type, based on the file extension.
+    pub const fn auto() -> VirtualStorageType {
+        VirtualStorageType {
+            device_id: VIRTUAL_STORAGE_TYPE_DEVICE_UNKNOWN,
+            vendor_id: VIRTUAL_STORAGE_TYPE_VENDOR_UNKNOWN,
+        }
+    }
+
+    /// ISO image (.iso).
+    pub const fn iso() -> VirtualStorageType {
+        VirtualStorageType {
+            device_id: VIRTUAL_STORAGE_TYPE_DEVICE_ISO,
+            vendor_id: VIRTUAL_STORAGE_TYPE_VENDOR_MICROSOFT,
+        }
+    }
+
+    /// VHD (.vhd, .avhd).
+    pub const fn vhd() -> VirtualStorageType {
+        VirtualStorageType {
+            device_id: VIRTUAL_STORAGE_TYPE_DEVICE_VHD,
+            vendor_id: VIRTUAL_STORAGE_TYPE_VENDOR_MICROSOFT,
+        }
+    }
+
+    /// VHDX (.vhdx, .avhdx).
+    pub const fn vhdx() -> VirtualStorageType {
+        VirtualStorageType {
+            device_id: VIRTUAL_STORAGE_TYPE_DEVICE_VHDX,
+            vendor_id: VIRTUAL_STORAGE_TYPE_VENDOR_MICROSOFT,
+        }
+    }
+
+    /// VHD Set (.vhds).
+    pub const fn vhdset() -> VirtualStorageType {
+        VirtualStorageType {
+            device_id: VIRTUAL_STORAGE_TYPE_DEVICE_VHDSET,
+            vendor_id: VIRTUAL_STORAGE_TYPE_VENDOR_MICROSOFT,
+        }
+    }
+}
+
+impl std::fmt::Debug for VirtualStorageType {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let vendor = if winutils_rs::utilities::guid_are_equal(
+            &self.vendor_id,
+            &VIRTUAL_STORAGE_TYPE_VENDOR_MICROSOFT,
+        ) {
+            "Microsoft"
+        } else if winutils_rs::utilities::guid_are_equal(
+            &self.vendor_id,
+            &VIRTUAL_STORAGE_TYPE_VENDOR_UNKNOWN,
+        ) {
+            "Unknown"
+        } else {
+            "Other"
+        };
+
+        let mut debug = f.debug_struct("VirtualStorageType");
+
+        match self.device_id {
+            VIRTUAL_STORAGE_TYPE_DEVICE_UNKNOWN => debug.field("device", &"Unknown"),
+            VIRTUAL_STORAGE_TYPE_DEVICE_ISO => debug.field("device", &"ISO"),
+            VIRTUAL_STORAGE_TYPE_DEVICE_VHD => debug.field("device", &"VHD"),
+            VIRTUAL_STORAGE_TYPE_DEVICE_VHDX => debug.field("device", &"VHDX"),
+            VIRTUAL_STORAGE_TYPE_DEVICE_VHDSET => debug.field("device", &"VHDSet"),
+            device_id => debug.field("device_id", &device_id),
+        };
+
+        debug.field("vendor", &vendor).finish()
+    }
+}
+
 /// Access Mask for OpenVirtualDisk and CreateVirtualDisk. The virtual
 /// disk drivers expose file objects as handles therefore we map
 /// it into that AccessMask space.
@@ -545,6 +620,7 @@ pub mod get_virtual_disk {
     #[derive(Copy, Clone)]
     pub union InfoVersionDetails {
         pub size: InfoSize,
+        pub identifier: Guid,
         pub parent_location: InfoParentLocation,
         pub parent_identifier: Guid,
         pub parent_time_stamp: u32,
@@ -568,6 +644,47 @@ pub mod get_virtual_disk {
     }
 }
 
+/// Kind of virtual disk, as reported by `get_virtual_disk::InfoVersion::ProviderSubType`.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum DiskKind {
+    Fixed,
+    Dynamic,
+    Differencing,
+
+    /// Provider subtype not known to this crate.
+    Unknown(u32),
+}
+
+impl DiskKind {
+    /// Translates a provider subtype value into a disk kind.
+    pub fn from_provider_sub_type(provider_sub_type: u32) -> DiskKind {
+        match provider_sub_type {
+            2 => DiskKind::Fixed,
+            3 => DiskKind::Dynamic,
+            4 => DiskKind::Differencing,
+            other => DiskKind::Unknown(other),
+        }
+    }
+}
+
+/// Whether and how a virtual disk is attached to the host, see `VirtualDisk::attach_state`.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum AttachState {
+    Detached,
+
+    /// Attached and surfaced as the read-only disk with the given number.
+    AttachedReadOnly {
+        disk_number: u32,
+    },
+
+    /// Attached and surfaced as the writable disk with the given number.
+    AttachedReadWrite {
+        disk_number: u32,
+    },
+}
+
 pub const VIRTUAL_DISK_MAXIMUM_CHANGE_TRACKING_ID_LENGTH: u32 = 256;
 
 pub mod set_virtual_disk {
@@ -620,6 +737,7 @@ pub mod set_virtual_disk {
     }
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 pub struct VirtualDiskProgress {
@@ -1094,3 +1212,48 @@ pub mod fork_virtual_disk {
         ExistingFile = 0x00000001,
     }
 }
+
+/// Provider specific flags taken by `VirtualDisk::create`, `VirtualDisk::attach`
+/// and `VirtualDisk::detach`.
+pub mod provider_flags {
+    /// Typed provider specific flags.
+    /// The Microsoft VHD, VHDX, ISO and VHD Set providers don't define any provider specific
+    /// flag and expect zero, which is what `ProviderFlags::None` stands for.
+    /// Values defined by third party providers can be supplied through `ProviderFlags::Unknown`.
+    #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+    pub enum ProviderFlags {
+        None,
+        Unknown(u32),
+    }
+
+    impl ProviderFlags {
+        /// Returns the u32 representation supplied to the virtdisk APIs.
+        pub fn bits(&self) -> u32 {
+            match self {
+                ProviderFlags::None => 0,
+                ProviderFlags::Unknown(bits) => *bits,
+            }
+        }
+    }
+
+    impl Default for ProviderFlags {
+        fn default() -> ProviderFlags {
+            ProviderFlags::None
+        }
+    }
+
+    impl From<u32> for ProviderFlags {
+        fn from(bits: u32) -> ProviderFlags {
+            match bits {
+                0 => ProviderFlags::None,
+                bits => ProviderFlags::Unknown(bits),
+            }
+        }
+    }
+
+    impl From<ProviderFlags> for u32 {
+        fn from(flags: ProviderFlags) -> u32 {
+            flags.bits()
+        }
+    }
+}
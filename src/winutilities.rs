@@ -0,0 +1,1484 @@
+// Copyright (c) 2019 Rafael Alcaraz Mercado. All rights reserved.
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// All files in the project carrying such notice may not be copied, modified, or distributed
+// except according to those terms.
+// THE SOURCE CODE IS AVAILABLE UNDER THE ABOVE CHOSEN LICENSE "AS IS", WITH NO WARRANTIES.
+
+//! Windows utilities shared by the safe abstractions of this crate.
+
+use winutils_rs::diskformat::{FmIfsMediaType, FormatEx2Routine};
+use winutils_rs::errorcodes::{WinResult, WinResultCode};
+use winutils_rs::utilities::{create_file, WinEvent, WinEventResult, WinLibrary};
+use winutils_rs::windefs::*;
+
+/// Safe abstraction to an owned Windows handle, closing the handle at the end of its lifetime.
+/// This is the common building block of all handle based types of this crate.
+pub struct OwnedHandle {
+    handle: Handle,
+}
+
+impl std::ops::Drop for OwnedHandle {
+    fn drop(&mut self) {
+        winutils_rs::utilities::close_handle(&mut self.handle);
+    }
+}
+
+impl OwnedHandle {
+    /// Wraps the supplied handle, providing a safe drop implementation that will close the handle
+    /// on the end of its lifetime.
+    pub fn wrap(handle: Handle) -> WinResult<OwnedHandle> {
+        match handle {
+            handle if handle == std::ptr::null_mut() => Err(WinResultCode::ErrorInvalidArgument),
+            handle if handle == winapi::um::handleapi::INVALID_HANDLE_VALUE => {
+                Err(WinResultCode::ErrorInvalidArgument)
+            }
+            handle => Ok(OwnedHandle { handle }),
+        }
+    }
+
+    /// Releases the wrapped handle to ensure that at the end of the lifetime of this instance
+    /// the handle is not closed.
+    ///
+    /// # Unsafe
+    ///
+    /// Marked as unsafe because of the possibility of leaking a handle.
+    pub unsafe fn release(&mut self) -> Handle {
+        let handle = self.handle;
+        self.handle = std::ptr::null_mut();
+        handle
+    }
+
+    /// Returns a cloned value of the internally stored handle.
+    /// Be careful and do not close the handle returned here because the code will panic at the
+    /// end of the lifetime of this instance if CloseHandle fails.
+    pub fn get(&self) -> Handle {
+        self.handle.clone()
+    }
+
+    /// Returns a non-owning view of the handle that can't outlive this instance.
+    pub fn borrow(&self) -> BorrowedHandle {
+        BorrowedHandle {
+            handle: self.handle,
+            _owner: std::marker::PhantomData,
+        }
+    }
+}
+
+impl std::os::windows::io::AsRawHandle for OwnedHandle {
+    fn as_raw_handle(&self) -> std::os::windows::io::RawHandle {
+        self.handle as std::os::windows::io::RawHandle
+    }
+}
+
+impl std::os::windows::io::IntoRawHandle for OwnedHandle {
+    fn into_raw_handle(mut self) -> std::os::windows::io::RawHandle {
+        unsafe { self.release() as std::os::windows::io::RawHandle }
+    }
+}
+
+impl From<std::os::windows::io::OwnedHandle> for OwnedHandle {
+    fn from(handle: std::os::windows::io::OwnedHandle) -> OwnedHandle {
+        use std::os::windows::io::IntoRawHandle;
+
+        OwnedHandle {
+            handle: handle.into_raw_handle() as Handle,
+        }
+    }
+}
+
+impl From<OwnedHandle> for std::os::windows::io::OwnedHandle {
+    fn from(handle: OwnedHandle) -> std::os::windows::io::OwnedHandle {
+        use std::os::windows::io::{FromRawHandle, IntoRawHandle};
+
+        unsafe { std::os::windows::io::OwnedHandle::from_raw_handle(handle.into_raw_handle()) }
+    }
+}
+
+// A handle is a process wide reference to a kernel object, valid from any thread.
+// The files, disks, volumes and events wrapped by this crate can be used concurrently,
+// and closing the handle or giving it away requires ownership of the instance,
+// so it can be moved to and shared with other threads.
+unsafe impl Send for OwnedHandle {}
+unsafe impl Sync for OwnedHandle {}
+
+/// Non-owning view of a Windows handle. The handle is never closed by this type.
+#[derive(Copy, Clone)]
+pub struct BorrowedHandle<'owner> {
+    handle: Handle,
+    _owner: std::marker::PhantomData<&'owner OwnedHandle>,
+}
+
+// Same reasoning as for OwnedHandle, the view can't outlive its owner.
+unsafe impl<'owner> Send for BorrowedHandle<'owner> {}
+unsafe impl<'owner> Sync for BorrowedHandle<'owner> {}
+
+impl<'owner> BorrowedHandle<'owner> {
+    /// Creates a non-owning view of a raw handle.
+    ///
+    /// # Unsafe
+    ///
+    /// Marked as unsafe because the caller must guarantee the handle stays open
+    /// for the whole lifetime of the returned view.
+    pub unsafe fn from_raw(handle: Handle) -> BorrowedHandle<'owner> {
+        BorrowedHandle {
+            handle,
+            _owner: std::marker::PhantomData,
+        }
+    }
+
+    /// Returns the raw handle this instance is a view of.
+    pub fn get(&self) -> Handle {
+        self.handle
+    }
+}
+
+/// Checked signaling of `WinEvent`s.
+/// `WinEvent::set`, `reset` and `pulse` in winutils-rs 0.2 return an error when the underlying
+/// call succeeds and Ok when it fails, so code of this crate signals events through these instead.
+pub trait EventExt {
+    /// Sets the event to the signaled state.
+    fn set_event(&self) -> WinResult<()>;
+
+    /// Sets the event to the non-signaled state.
+    fn reset_event(&self) -> WinResult<()>;
+
+    /// Releases the threads currently waiting on the event and resets it.
+    fn pulse_event(&self) -> WinResult<()>;
+}
+
+fn event_call_result(result: winapi::shared::minwindef::BOOL) -> WinResult<()> {
+    match result {
+        0 => Err(winutils_rs::errorcodes::error_code_to_winresult_code(
+            unsafe { winapi::um::errhandlingapi::GetLastError() },
+        )),
+        _ => Ok(()),
+    }
+}
+
+impl EventExt for WinEvent {
+    fn set_event(&self) -> WinResult<()> {
+        event_call_result(unsafe { winapi::um::synchapi::SetEvent(self.get_handle()) })
+    }
+
+    fn reset_event(&self) -> WinResult<()> {
+        event_call_result(unsafe { winapi::um::synchapi::ResetEvent(self.get_handle()) })
+    }
+
+    fn pulse_event(&self) -> WinResult<()> {
+        event_call_result(unsafe { winapi::um::winbase::PulseEvent(self.get_handle()) })
+    }
+}
+
+/// Waits until any or all of the given events are signaled, up to the given timeout in milliseconds.
+/// Returns the index of the signaled event (0 when waiting for all of them), or None on timeout.
+/// At most `MAXIMUM_WAIT_OBJECTS` (64) events can be waited on at once.
+pub fn wait_multiple(
+    events: &[&WinEvent],
+    wait_all: bool,
+    timeout_ms: DWord,
+) -> WinResult<Option<usize>> {
+    use winapi::shared::winerror::WAIT_TIMEOUT;
+    use winapi::um::winbase::{WAIT_FAILED, WAIT_OBJECT_0};
+    use winapi::um::winnt::MAXIMUM_WAIT_OBJECTS;
+
+    if events.is_empty() || events.len() > MAXIMUM_WAIT_OBJECTS as usize {
+        return Err(WinResultCode::ErrorInvalidArgument);
+    }
+
+    let handles: Vec<Handle> = events.iter().map(|event| event.get_handle()).collect();
+    let result = unsafe {
+        winapi::um::synchapi::WaitForMultipleObjects(
+            handles.len() as DWord,
+            handles.as_ptr(),
+            wait_all as winapi::shared::minwindef::BOOL,
+            timeout_ms,
+        )
+    };
+
+    match result {
+        WAIT_TIMEOUT => Ok(None),
+        WAIT_FAILED => Err(winutils_rs::errorcodes::error_code_to_winresult_code(
+            unsafe { winapi::um::errhandlingapi::GetLastError() },
+        )),
+        _ if result.wrapping_sub(WAIT_OBJECT_0) < handles.len() as DWord => {
+            Ok(Some((result - WAIT_OBJECT_0) as usize))
+        }
+        // Only mutexes can be abandoned, never events
+        _ => Err(WinResultCode::ErrorGenFailure),
+    }
+}
+
+/// Maximum number of idle events kept by the event pool.
+const EVENT_POOL_CAPACITY: usize = 64;
+
+/// Manual reset event kept idle by the event pool.
+struct PooledEvent(WinEvent);
+
+// Events are kernel objects that can be signaled and waited on from any thread.
+unsafe impl Send for PooledEvent {}
+
+static EVENT_POOL: std::sync::Mutex<Vec<PooledEvent>> = std::sync::Mutex::new(Vec::new());
+static EVENTS_CREATED: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+static EVENTS_REUSED: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+static EVENTS_DISCARDED: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+/// Counters of the process wide event pool used by asynchronous operations of this crate.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct EventPoolMetrics {
+    /// Number of events created because the pool had no idle event to lease.
+    pub created: u64,
+    /// Number of leases served by an idle event of the pool.
+    pub reused: u64,
+    /// Number of events closed on release because the pool was full.
+    pub discarded: u64,
+    /// Number of events currently idle in the pool.
+    pub idle: usize,
+}
+
+/// Returns the current counters of the event pool.
+pub fn event_pool_metrics() -> EventPoolMetrics {
+    use std::sync::atomic::Ordering;
+
+    EventPoolMetrics {
+        created: EVENTS_CREATED.load(Ordering::Relaxed),
+        reused: EVENTS_REUSED.load(Ordering::Relaxed),
+        discarded: EVENTS_DISCARDED.load(Ordering::Relaxed),
+        idle: EVENT_POOL.lock().map(|pool| pool.len()).unwrap_or(0),
+    }
+}
+
+/// Non-signaled manual reset event leased from the event pool.
+/// The event is reset and handed back to the pool at the end of the lifetime of the lease,
+/// saving the creation of a kernel object per asynchronous operation.
+pub struct EventLease {
+    event: Option<WinEvent>,
+}
+
+impl EventLease {
+    /// Leases an idle event from the pool, creating a new one if the pool is empty.
+    pub fn acquire() -> WinResult<EventLease> {
+        use std::sync::atomic::Ordering;
+
+        let pooled = EVENT_POOL.lock().ok().and_then(|mut pool| pool.pop());
+
+        let event = match pooled {
+            Some(PooledEvent(event)) => {
+                EVENTS_REUSED.fetch_add(1, Ordering::Relaxed);
+                event
+            }
+            None => {
+                let event = WinEvent::create(true, false, None, None)?;
+                EVENTS_CREATED.fetch_add(1, Ordering::Relaxed);
+                event
+            }
+        };
+
+        Ok(EventLease { event: Some(event) })
+    }
+}
+
+// WinEvent is defined by winutils-rs and can't be marked here. Events can be signaled,
+// reset and waited on from any thread, so the lease can cross threads instead.
+unsafe impl Send for EventLease {}
+unsafe impl Sync for EventLease {}
+
+impl std::ops::Deref for EventLease {
+    type Target = WinEvent;
+
+    fn deref(&self) -> &WinEvent {
+        self.event.as_ref().unwrap()
+    }
+}
+
+impl std::ops::Drop for EventLease {
+    fn drop(&mut self) {
+        use std::sync::atomic::Ordering;
+
+        if let Some(event) = self.event.take() {
+            if event.reset_event().is_err() {
+                // An event that can't be reset would wake up the next waiter too early
+                EVENTS_DISCARDED.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+
+            match EVENT_POOL.lock() {
+                Ok(ref mut pool) if pool.len() < EVENT_POOL_CAPACITY => {
+                    pool.push(PooledEvent(event))
+                }
+                _ => {
+                    EVENTS_DISCARDED.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+}
+
+/// Token used to abort the cancellable waits of this crate from another thread.
+/// Clones share the same underlying manual reset event, leased from the event pool.
+#[derive(Clone)]
+pub struct CancellationToken {
+    event: std::sync::Arc<EventLease>,
+}
+
+impl CancellationToken {
+    /// Creates a token that is not cancelled.
+    pub fn new() -> WinResult<CancellationToken> {
+        Ok(CancellationToken {
+            event: std::sync::Arc::new(EventLease::acquire()?),
+        })
+    }
+
+    /// Cancels the token, waking up every wait using it. Cancelling twice has no further effect.
+    pub fn cancel(&self) -> WinResult<()> {
+        self.event.set_event()
+    }
+
+    /// Returns true if the token has been cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.event.wait(0) == WinEventResult::WaitObject0
+    }
+
+    /// Returns the event signaled when the token is cancelled, to be waited on along other events.
+    pub fn event(&self) -> &WinEvent {
+        &self.event
+    }
+}
+
+/// Safe abstraction to an OVERLAPPED structure that owns the event signaled on completion.
+/// The OVERLAPPED structure is heap allocated so that its address stays stable
+/// while an asynchronous operation is in flight, even if this instance is moved.
+/// The event is leased from the event pool.
+pub struct OverlappedEvent {
+    event: EventLease,
+    overlapped: Box<Overlapped>,
+}
+
+// The OVERLAPPED structure is heap allocated and only points to the leased event,
+// so it can be handed to the thread waiting for the operation.
+// It isn't Sync since the system writes to it while the operation is in flight.
+unsafe impl Send for OverlappedEvent {}
+
+impl OverlappedEvent {
+    /// Creates a new OVERLAPPED structure bound to a manual reset event leased from the event pool.
+    pub fn new() -> WinResult<OverlappedEvent> {
+        let event = EventLease::acquire()?;
+        let mut overlapped = Box::new(unsafe { std::mem::zeroed::<Overlapped>() });
+        overlapped.hEvent = event.get_handle();
+        Ok(OverlappedEvent { event, overlapped })
+    }
+
+    /// Returns a reference to the OVERLAPPED structure, to be supplied to asynchronous calls.
+    pub fn as_overlapped(&self) -> &Overlapped {
+        &self.overlapped
+    }
+
+    /// Returns a mutable reference to the OVERLAPPED structure, to be supplied to asynchronous calls.
+    pub fn as_overlapped_mut(&mut self) -> &mut Overlapped {
+        &mut self.overlapped
+    }
+
+    /// Returns the event signaled when the asynchronous operation completes.
+    pub fn event(&self) -> &WinEvent {
+        &self.event
+    }
+
+    /// Waits for the asynchronous operation to complete, up to the given timeout in milliseconds.
+    pub fn wait(&self, timeout_ms: DWord) -> WinEventResult {
+        self.event.wait(timeout_ms)
+    }
+
+    /// Returns true if the asynchronous operation has completed.
+    pub fn is_signaled(&self) -> bool {
+        self.event.wait(0) == WinEventResult::WaitObject0
+    }
+}
+
+/// Returns true if the access token of the current process holds the given privilege,
+/// whether or not it is currently enabled. Privileges that are not held can't be enabled,
+/// e.g. `SeManageVolumePrivilege` on the filtered token of a non-elevated process.
+pub fn process_holds_privilege(privilege_name: &str) -> WinResult<bool> {
+    use winapi::um::{errhandlingapi, processthreadsapi, securitybaseapi, winbase, winnt};
+    use winutils_rs::errorcodes::error_code_to_winresult_code;
+
+    let privilege_name_wstr = widestring::WideCString::from_str(privilege_name).unwrap();
+    let mut luid = unsafe { std::mem::zeroed::<winnt::LUID>() };
+
+    unsafe {
+        if winbase::LookupPrivilegeValueW(std::ptr::null(), privilege_name_wstr.as_ptr(), &mut luid)
+            == 0
+        {
+            return Err(error_code_to_winresult_code(errhandlingapi::GetLastError()));
+        }
+    }
+
+    let token = unsafe {
+        let mut token: Handle = std::ptr::null_mut();
+
+        if processthreadsapi::OpenProcessToken(
+            processthreadsapi::GetCurrentProcess(),
+            winnt::TOKEN_QUERY,
+            &mut token,
+        ) == 0
+        {
+            return Err(error_code_to_winresult_code(errhandlingapi::GetLastError()));
+        }
+
+        OwnedHandle::wrap(token)?
+    };
+
+    // The privileges array is variable length, so query the required size first.
+    // A Vec<u64> keeps the buffer aligned for the LUID_AND_ATTRIBUTES entries.
+    let mut required_size: DWord = 0;
+    let mut buffer: Vec<u64> = Vec::new();
+
+    loop {
+        let result = unsafe {
+            securitybaseapi::GetTokenInformation(
+                token.get(),
+                winnt::TokenPrivileges,
+                buffer.as_mut_ptr() as PVoid,
+                (buffer.len() * std::mem::size_of::<u64>()) as DWord,
+                &mut required_size,
+            )
+        };
+
+        if result != 0 {
+            break;
+        }
+
+        match unsafe { errhandlingapi::GetLastError() } {
+            winapi::shared::winerror::ERROR_INSUFFICIENT_BUFFER => {
+                let required_len = (required_size as usize + std::mem::size_of::<u64>() - 1)
+                    / std::mem::size_of::<u64>();
+                buffer.resize(required_len, 0);
+            }
+            error => return Err(error_code_to_winresult_code(error)),
+        }
+    }
+
+    unsafe {
+        let token_privileges = &*(buffer.as_ptr() as *const winnt::TOKEN_PRIVILEGES);
+        let privileges = std::slice::from_raw_parts(
+            token_privileges.Privileges.as_ptr(),
+            token_privileges.PrivilegeCount as usize,
+        );
+
+        Ok(privileges.iter().any(|privilege| {
+            privilege.Luid.LowPart == luid.LowPart && privilege.Luid.HighPart == luid.HighPart
+        }))
+    }
+}
+
+/// Enables a set of privileges on the access token of the current process for the lifetime
+/// of this instance, restoring their previous state on drop.
+/// Unlike acquiring privileges one at a time, the set is enabled atomically: either every
+/// privilege is enabled or the token is left untouched.
+///
+/// The token is shared by every thread of the process, so threads enabling the same
+/// privileges concurrently should do so under a single outer instance. Nested instances
+/// are cheap: privileges that were already enabled are left enabled on drop.
+pub struct TemporaryPrivileges {
+    token: OwnedHandle,
+    previous_state: Vec<u32>,
+}
+
+impl std::ops::Drop for TemporaryPrivileges {
+    fn drop(&mut self) {
+        unsafe {
+            winapi::um::securitybaseapi::AdjustTokenPrivileges(
+                self.token.get(),
+                0,
+                self.previous_state.as_mut_ptr() as *mut winapi::um::winnt::TOKEN_PRIVILEGES,
+                0,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+            );
+        }
+    }
+}
+
+impl TemporaryPrivileges {
+    /// Enables the given privileges, e.g. `winapi::um::winnt::SE_MANAGE_VOLUME_NAME`,
+    /// `SE_SECURITY_NAME`, `SE_BACKUP_NAME` or `SE_RESTORE_NAME`.
+    /// Returns ERROR_PRIVILEGE_NOT_HELD if the token doesn't hold any of them,
+    /// in which case none of the privileges are left enabled.
+    pub fn acquire(privilege_names: &[&str]) -> WinResult<TemporaryPrivileges> {
+        use winapi::shared::winerror;
+        use winapi::um::{errhandlingapi, processthreadsapi, securitybaseapi, winbase, winnt};
+        use winutils_rs::errorcodes::error_code_to_winresult_code;
+
+        let token = unsafe {
+            let mut token: Handle = std::ptr::null_mut();
+
+            if processthreadsapi::OpenProcessToken(
+                processthreadsapi::GetCurrentProcess(),
+                winnt::TOKEN_ADJUST_PRIVILEGES | winnt::TOKEN_QUERY,
+                &mut token,
+            ) == 0
+            {
+                return Err(error_code_to_winresult_code(errhandlingapi::GetLastError()));
+            }
+
+            OwnedHandle::wrap(token)?
+        };
+
+        // TOKEN_PRIVILEGES is a count followed by a variable length array of
+        // LUID_AND_ATTRIBUTES entries, three DWORDs each.
+        let buffer_len = 1 + 3 * privilege_names.len();
+        let mut new_state: Vec<u32> = vec![0; buffer_len];
+        let mut previous_state: Vec<u32> = vec![0; buffer_len];
+
+        unsafe {
+            let token_privileges = new_state.as_mut_ptr() as *mut winnt::TOKEN_PRIVILEGES;
+            (*token_privileges).PrivilegeCount = privilege_names.len() as DWord;
+            let privileges = std::slice::from_raw_parts_mut(
+                (*token_privileges).Privileges.as_mut_ptr(),
+                privilege_names.len(),
+            );
+
+            for (privilege, name) in privileges.iter_mut().zip(privilege_names.iter()) {
+                let name_wstr = widestring::WideCString::from_str(name).unwrap();
+
+                if winbase::LookupPrivilegeValueW(
+                    std::ptr::null(),
+                    name_wstr.as_ptr(),
+                    &mut privilege.Luid,
+                ) == 0
+                {
+                    return Err(error_code_to_winresult_code(errhandlingapi::GetLastError()));
+                }
+
+                privilege.Attributes = winnt::SE_PRIVILEGE_ENABLED;
+            }
+
+            let mut return_length: DWord = 0;
+
+            if securitybaseapi::AdjustTokenPrivileges(
+                token.get(),
+                0,
+                token_privileges,
+                (previous_state.len() * std::mem::size_of::<u32>()) as DWord,
+                previous_state.as_mut_ptr() as *mut winnt::TOKEN_PRIVILEGES,
+                &mut return_length,
+            ) == 0
+            {
+                return Err(error_code_to_winresult_code(errhandlingapi::GetLastError()));
+            }
+
+            // AdjustTokenPrivileges succeeds even if only some of the privileges were enabled.
+            let adjust_result = errhandlingapi::GetLastError();
+            let privileges = TemporaryPrivileges {
+                token,
+                previous_state,
+            };
+
+            if adjust_result == winerror::ERROR_NOT_ALL_ASSIGNED {
+                // Dropping restores the privileges that did get enabled.
+                drop(privileges);
+                return Err(error_code_to_winresult_code(
+                    winerror::ERROR_PRIVILEGE_NOT_HELD,
+                ));
+            }
+
+            Ok(privileges)
+        }
+    }
+}
+
+/// Converts an IO error returned by the standard library to a Windows result code.
+pub(crate) fn io_error_to_winresult_code(error: &std::io::Error) -> WinResultCode {
+    match error.raw_os_error() {
+        Some(error) => winutils_rs::errorcodes::error_code_to_winresult_code(error as u32),
+        None => WinResultCode::ErrorGenFailure,
+    }
+}
+
+/// Reads into the buffer from the given offset of a handle opened for synchronous IO.
+pub(crate) fn read_handle_at(handle: Handle, offset: u64, buffer: &mut [Byte]) -> WinResult<usize> {
+    use winapi::um::fileapi;
+
+    let mut overlapped = unsafe { std::mem::zeroed::<Overlapped>() };
+    let mut bytes_read: DWord = 0;
+
+    unsafe {
+        overlapped.u.s_mut().Offset = offset as DWord;
+        overlapped.u.s_mut().OffsetHigh = (offset >> 32) as DWord;
+
+        if fileapi::ReadFile(
+            handle,
+            buffer.as_mut_ptr() as PVoid,
+            buffer.len() as DWord,
+            &mut bytes_read,
+            &mut overlapped,
+        ) == 0
+        {
+            return Err(winutils_rs::errorcodes::error_code_to_winresult_code(
+                winapi::um::errhandlingapi::GetLastError(),
+            ));
+        }
+    }
+
+    Ok(bytes_read as usize)
+}
+
+/// Writes the buffer at the given offset of a handle opened for synchronous IO.
+pub(crate) fn write_handle_at(handle: Handle, offset: u64, buffer: &[Byte]) -> WinResult<()> {
+    use winapi::um::fileapi;
+
+    let mut overlapped = unsafe { std::mem::zeroed::<Overlapped>() };
+    let mut bytes_written: DWord = 0;
+
+    unsafe {
+        overlapped.u.s_mut().Offset = offset as DWord;
+        overlapped.u.s_mut().OffsetHigh = (offset >> 32) as DWord;
+
+        if fileapi::WriteFile(
+            handle,
+            buffer.as_ptr() as PVoid,
+            buffer.len() as DWord,
+            &mut bytes_written,
+            &mut overlapped,
+        ) == 0
+        {
+            return Err(winutils_rs::errorcodes::error_code_to_winresult_code(
+                winapi::um::errhandlingapi::GetLastError(),
+            ));
+        }
+    }
+
+    match bytes_written as usize == buffer.len() {
+        true => Ok(()),
+        false => Err(winutils_rs::errorcodes::error_code_to_winresult_code(
+            winapi::shared::winerror::ERROR_WRITE_FAULT,
+        )),
+    }
+}
+
+/// Result code returned by operations whose timeout expired.
+pub(crate) fn timeout_error() -> WinResultCode {
+    winutils_rs::errorcodes::error_code_to_winresult_code(winapi::shared::winerror::ERROR_TIMEOUT)
+}
+
+/// Converts a duration into a wait timeout in milliseconds, clamped below INFINITE
+/// so that a long wait never turns into one that doesn't time out.
+pub(crate) fn wait_milliseconds(duration: std::time::Duration) -> DWord {
+    duration
+        .as_millis()
+        .min((winapi::um::winbase::INFINITE - 1) as u128) as DWord
+}
+
+/// Returns the time left until the deadline, or ERROR_TIMEOUT if it already passed.
+/// No deadline means there is all the time in the world.
+pub(crate) fn time_left(
+    deadline: Option<std::time::Instant>,
+) -> WinResult<Option<std::time::Duration>> {
+    match deadline {
+        Some(deadline) => {
+            let now = std::time::Instant::now();
+
+            if now >= deadline {
+                return Err(timeout_error());
+            }
+
+            Ok(Some(deadline - now))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Returns the terminal services session the current process runs in.
+/// Services run in session 0, which has no interactive desktop.
+pub fn current_session_id() -> WinResult<DWord> {
+    use winapi::um::processthreadsapi;
+
+    let mut session_id: DWord = 0;
+
+    unsafe {
+        if processthreadsapi::ProcessIdToSessionId(
+            processthreadsapi::GetCurrentProcessId(),
+            &mut session_id,
+        ) == 0
+        {
+            return Err(winutils_rs::errorcodes::error_code_to_winresult_code(
+                winapi::um::errhandlingapi::GetLastError(),
+            ));
+        }
+    }
+
+    Ok(session_id)
+}
+
+/// Runs the operation on a worker thread, waiting up to the given timeout for it to complete.
+/// If the timeout expires, the synchronous IO the worker thread is blocked on is cancelled
+/// and ERROR_TIMEOUT is returned. An operation that can't be cancelled keeps running
+/// in the background and its result is discarded.
+pub fn with_timeout<T, F>(timeout: std::time::Duration, operation: F) -> WinResult<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> WinResult<T> + Send + 'static,
+{
+    use std::os::windows::io::AsRawHandle;
+
+    let (sender, receiver) = std::sync::mpsc::channel();
+
+    let worker = std::thread::spawn(move || {
+        #[allow(unused_must_use)]
+        {
+            sender.send(operation());
+        }
+    });
+
+    match receiver.recv_timeout(timeout) {
+        Ok(result) => {
+            #[allow(unused_must_use)]
+            {
+                worker.join();
+            }
+            result
+        }
+        Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {
+            unsafe {
+                winapi::um::ioapiset::CancelSynchronousIo(worker.as_raw_handle() as Handle);
+            }
+            Err(timeout_error())
+        }
+        // The operation panicked before producing a result
+        Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => Err(WinResultCode::ErrorGenFailure),
+    }
+}
+
+/// How an operation failing with a transient error is retried, e.g. `VirtualDisk::detach_with_retry`.
+/// The delay between attempts doubles after every attempt, up to `max_delay`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Number of times the operation is attempted, including the first one.
+    pub max_attempts: u32,
+
+    /// Delay before the second attempt.
+    pub delay: std::time::Duration,
+
+    /// Upper bound of the delay between attempts.
+    pub max_delay: std::time::Duration,
+
+    /// Looks up the processes holding the resource when the last attempt fails,
+    /// through the Restart Manager, so they can be reported along with the error.
+    pub diagnose: bool,
+}
+
+impl RetryPolicy {
+    /// Attempts the operation up to the given number of times, with a fixed delay between attempts.
+    pub fn new(max_attempts: u32, delay: std::time::Duration) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            delay,
+            max_delay: delay,
+            diagnose: true,
+        }
+    }
+
+    /// Returns the delay before the given attempt, numbered from 1.
+    pub(crate) fn delay_before(&self, attempt: u32) -> std::time::Duration {
+        let doublings = attempt.saturating_sub(2).min(16);
+        std::cmp::min(self.delay * (1 << doublings), self.max_delay)
+    }
+}
+
+impl Default for RetryPolicy {
+    /// 10 attempts, starting 250 ms apart and backing off up to 5 seconds.
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 10,
+            delay: std::time::Duration::from_millis(250),
+            max_delay: std::time::Duration::from_secs(5),
+            diagnose: true,
+        }
+    }
+}
+
+/// Process holding a file open, as reported by the Restart Manager.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessInfo {
+    pub pid: DWord,
+
+    /// Friendly name of the application, or the image name if it has none.
+    pub name: String,
+
+    /// Short name of the service, if the process hosts one.
+    pub service_name: Option<String>,
+
+    /// Terminal services session the process runs in.
+    pub session_id: DWord,
+}
+
+/// Restart Manager session that is ended at the end of its lifetime.
+struct RestartManagerSession {
+    handle: DWord,
+}
+
+impl std::ops::Drop for RestartManagerSession {
+    fn drop(&mut self) {
+        unsafe {
+            winapi::um::restartmanager::RmEndSession(self.handle);
+        }
+    }
+}
+
+/// Returns the processes that hold any of the given files open, through the Restart Manager.
+/// Only file paths are tracked; directories and volume paths never report any process.
+pub(crate) fn processes_using_files(paths: &[String]) -> WinResult<Vec<ProcessInfo>> {
+    use winapi::um::restartmanager;
+    use winutils_rs::errorcodes::error_code_to_winresult_code;
+
+    if paths.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut session = RestartManagerSession { handle: 0 };
+    let mut session_key: [WChar; restartmanager::CCH_RM_SESSION_KEY + 1] =
+        [0; restartmanager::CCH_RM_SESSION_KEY + 1];
+
+    unsafe {
+        match restartmanager::RmStartSession(&mut session.handle, 0, session_key.as_mut_ptr()) {
+            0 => {}
+            result => return Err(error_code_to_winresult_code(result)),
+        }
+    }
+
+    let paths_wstr: Vec<widestring::WideCString> = paths
+        .iter()
+        .map(|path| widestring::WideCString::from_str(path).unwrap())
+        .collect();
+    let mut paths_ptr: Vec<*const WChar> = paths_wstr.iter().map(|path| path.as_ptr()).collect();
+
+    unsafe {
+        match restartmanager::RmRegisterResources(
+            session.handle,
+            paths_ptr.len() as u32,
+            paths_ptr.as_mut_ptr(),
+            0,
+            std::ptr::null_mut(),
+            0,
+            std::ptr::null_mut(),
+        ) {
+            0 => {}
+            result => return Err(error_code_to_winresult_code(result)),
+        }
+    }
+
+    let mut process_infos: Vec<restartmanager::RM_PROCESS_INFO> = Vec::new();
+
+    // Processes can start using the files between calls, so grow the buffer until it fits
+    loop {
+        let mut needed: u32 = 0;
+        let mut count = process_infos.len() as u32;
+        let mut reboot_reasons: DWord = 0;
+
+        let result = unsafe {
+            restartmanager::RmGetList(
+                session.handle,
+                &mut needed,
+                &mut count,
+                process_infos.as_mut_ptr(),
+                &mut reboot_reasons,
+            )
+        };
+
+        match result {
+            0 => {
+                process_infos.truncate(count as usize);
+                break;
+            }
+            winapi::shared::winerror::ERROR_MORE_DATA => {
+                process_infos.resize(needed as usize, unsafe { std::mem::zeroed() });
+            }
+            result => return Err(error_code_to_winresult_code(result)),
+        }
+    }
+
+    Ok(process_infos
+        .iter()
+        .map(|process_info| {
+            let service_name =
+                wide_to_string(&process_info.strServiceShortName).unwrap_or_default();
+
+            ProcessInfo {
+                pid: process_info.Process.dwProcessId,
+                name: wide_to_string(&process_info.strAppName).unwrap_or_default(),
+                service_name: match service_name.is_empty() {
+                    true => None,
+                    false => Some(service_name),
+                },
+                session_id: process_info.TSSessionId,
+            }
+        })
+        .collect())
+}
+
+/// Opens a file or directory for reading with `FILE_FLAG_BACKUP_SEMANTICS`,
+/// enabling SeBackupPrivilege while the handle is opened so that the file ACLs are bypassed.
+/// The returned handle can be supplied to `BackupRead`.
+pub fn open_with_backup_semantics(path: &str) -> WinResult<OwnedHandle> {
+    use winapi::um::{fileapi, winbase, winnt};
+
+    let _backup = TemporaryPrivileges::acquire(&[winnt::SE_BACKUP_NAME])?;
+
+    OwnedHandle::wrap(create_file(
+        path,
+        winnt::GENERIC_READ,
+        winnt::FILE_SHARE_READ | winnt::FILE_SHARE_WRITE | winnt::FILE_SHARE_DELETE,
+        None,
+        fileapi::OPEN_EXISTING,
+        winbase::FILE_FLAG_BACKUP_SEMANTICS | winbase::FILE_FLAG_OPEN_REPARSE_POINT,
+        None,
+    )?)
+}
+
+/// Size of the buffer used to stream data between `BackupRead` and `BackupWrite`.
+const BACKUP_STREAM_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Copies a file or a directory tree into the target path, typically a directory in a volume
+/// of a mounted VHD, using `BackupRead` and `BackupWrite`.
+/// Besides the file data, this preserves the security descriptors, alternate data streams
+/// and extended attributes of every copied file and directory.
+/// Symbolic links and junctions are copied as reparse points without being followed.
+/// Requires SeBackupPrivilege, SeRestorePrivilege and SeSecurityPrivilege, which are
+/// enabled for the duration of the copy.
+/// Returns the number of bytes streamed, including metadata streams.
+pub fn backup_copy(source_path: &str, target_path: &str) -> WinResult<u64> {
+    use winapi::um::winnt;
+
+    let _privileges = TemporaryPrivileges::acquire(&[
+        winnt::SE_BACKUP_NAME,
+        winnt::SE_RESTORE_NAME,
+        winnt::SE_SECURITY_NAME,
+    ])?;
+
+    backup_copy_entry(
+        std::path::Path::new(source_path),
+        std::path::Path::new(target_path),
+    )
+}
+
+/// Copies a single file or directory, recursing into directories that are not reparse points.
+/// Directory symbolic links and junctions are recreated as empty directories,
+/// onto which `BackupWrite` restores the reparse point.
+fn backup_copy_entry(
+    source_path: &std::path::Path,
+    target_path: &std::path::Path,
+) -> WinResult<u64> {
+    use std::os::windows::fs::MetadataExt;
+    use winapi::um::{fileapi, winbase, winnt};
+
+    let metadata = std::fs::symlink_metadata(source_path)
+        .map_err(|error| io_error_to_winresult_code(&error))?;
+    let attributes = metadata.file_attributes();
+    let is_directory = attributes & winnt::FILE_ATTRIBUTE_DIRECTORY != 0;
+    let is_reparse_point = attributes & winnt::FILE_ATTRIBUTE_REPARSE_POINT != 0;
+    let source_path_str = source_path.to_string_lossy();
+    let target_path_str = target_path.to_string_lossy();
+
+    if is_directory {
+        std::fs::create_dir_all(target_path).map_err(|error| io_error_to_winresult_code(&error))?;
+    }
+
+    let source = OwnedHandle::wrap(create_file(
+        &source_path_str,
+        winnt::GENERIC_READ | winnt::ACCESS_SYSTEM_SECURITY,
+        winnt::FILE_SHARE_READ | winnt::FILE_SHARE_WRITE | winnt::FILE_SHARE_DELETE,
+        None,
+        fileapi::OPEN_EXISTING,
+        winbase::FILE_FLAG_BACKUP_SEMANTICS
+            | winbase::FILE_FLAG_OPEN_REPARSE_POINT
+            | winbase::FILE_FLAG_SEQUENTIAL_SCAN,
+        None,
+    )?)?;
+
+    let target = OwnedHandle::wrap(create_file(
+        &target_path_str,
+        winnt::GENERIC_WRITE
+            | winnt::WRITE_DAC
+            | winnt::WRITE_OWNER
+            | winnt::ACCESS_SYSTEM_SECURITY,
+        0,
+        None,
+        if is_directory {
+            fileapi::OPEN_EXISTING
+        } else {
+            fileapi::CREATE_ALWAYS
+        },
+        winbase::FILE_FLAG_BACKUP_SEMANTICS | winbase::FILE_FLAG_OPEN_REPARSE_POINT,
+        None,
+    )?)?;
+
+    let mut bytes_copied = backup_stream(&source, &target)?;
+
+    if is_directory && !is_reparse_point {
+        for entry in
+            std::fs::read_dir(source_path).map_err(|error| io_error_to_winresult_code(&error))?
+        {
+            let entry = entry.map_err(|error| io_error_to_winresult_code(&error))?;
+            bytes_copied += backup_copy_entry(&entry.path(), &target_path.join(entry.file_name()))?;
+        }
+    }
+
+    Ok(bytes_copied)
+}
+
+/// Streams all the backup data of the source handle into the target handle.
+fn backup_stream(source: &OwnedHandle, target: &OwnedHandle) -> WinResult<u64> {
+    use winapi::um::{errhandlingapi, winbase};
+    use winutils_rs::errorcodes::error_code_to_winresult_code;
+
+    let mut buffer: Vec<u8> = vec![0; BACKUP_STREAM_BUFFER_SIZE];
+    let mut read_context: PVoid = std::ptr::null_mut();
+    let mut write_context: PVoid = std::ptr::null_mut();
+    let mut bytes_copied: u64 = 0;
+
+    let result = loop {
+        let mut bytes_read: DWord = 0;
+        let mut bytes_written: DWord = 0;
+
+        unsafe {
+            if winbase::BackupRead(
+                source.get(),
+                buffer.as_mut_ptr(),
+                buffer.len() as DWord,
+                &mut bytes_read,
+                0,
+                1,
+                &mut read_context,
+            ) == 0
+            {
+                break Err(error_code_to_winresult_code(errhandlingapi::GetLastError()));
+            }
+
+            if bytes_read == 0 {
+                break Ok(bytes_copied);
+            }
+
+            if winbase::BackupWrite(
+                target.get(),
+                buffer.as_mut_ptr(),
+                bytes_read,
+                &mut bytes_written,
+                0,
+                1,
+                &mut write_context,
+            ) == 0
+            {
+                break Err(error_code_to_winresult_code(errhandlingapi::GetLastError()));
+            }
+        }
+
+        bytes_copied += bytes_written as u64;
+    };
+
+    // Calling with bAbort set releases the contexts allocated by the previous calls.
+    unsafe {
+        let mut ignored: DWord = 0;
+
+        if !read_context.is_null() {
+            winbase::BackupRead(
+                source.get(),
+                std::ptr::null_mut(),
+                0,
+                &mut ignored,
+                1,
+                1,
+                &mut read_context,
+            );
+        }
+
+        if !write_context.is_null() {
+            winbase::BackupWrite(
+                target.get(),
+                std::ptr::null_mut(),
+                0,
+                &mut ignored,
+                1,
+                1,
+                &mut write_context,
+            );
+        }
+    }
+
+    result
+}
+
+/// Callback invoked by fmifs.dll routines with progress and status packets (PFMIFSCALLBACK).
+pub type FmIfsCallback = unsafe extern "system" fn(u32, ULong, PVoid) -> Boolean;
+
+/// Chkdsk routine exported by fmifs.dll.
+pub type ChkdskRoutine = unsafe extern "system" fn(
+    *mut WChar,                      // DriveRoot
+    *mut WChar,                      // Format
+    winapi::shared::minwindef::BOOL, // CorrectErrors
+    winapi::shared::minwindef::BOOL, // Verbose
+    winapi::shared::minwindef::BOOL, // CheckOnlyIfDirty
+    winapi::shared::minwindef::BOOL, // ScanDrive
+    PVoid,
+    PVoid,
+    FmIfsCallback,
+);
+
+/// FormatEx routine exported by fmifs.dll, the predecessor of FormatEx2 still exported
+/// by builds that don't have FormatEx2.
+/// The callback is a `PFMIFSCALLBACK` such as `winutils_rs::diskformat::format_ex2_callback`.
+pub type FormatExRoutine = unsafe extern "system" fn(
+    *mut WChar,                      // DriveRoot
+    FmIfsMediaType,                  // MediaType
+    *mut WChar,                      // Format
+    *mut WChar,                      // Label
+    winapi::shared::minwindef::BOOL, // QuickFormat
+    ULong,                           // ClusterSize
+    PVoid,                           // Callback
+);
+
+/// EnableVolumeCompression routine exported by fmifs.dll.
+pub type EnableVolumeCompressionRoutine = unsafe extern "system" fn(*mut WChar, UShort) -> Boolean;
+
+/// Cached facade over fmifs.dll. The module is loaded and its routines resolved once
+/// per process, on first successful use.
+pub struct FmIfs {
+    _module: WinLibrary,
+    format_ex2: Option<FormatEx2Routine>,
+    format_ex: Option<FormatExRoutine>,
+    chkdsk: Option<ChkdskRoutine>,
+    enable_volume_compression: Option<EnableVolumeCompressionRoutine>,
+}
+
+// The module is never unloaded while the process is alive and the routines are free functions,
+// so the facade can be shared across threads.
+unsafe impl Send for FmIfs {}
+unsafe impl Sync for FmIfs {}
+
+static FMIFS: std::sync::OnceLock<FmIfs> = std::sync::OnceLock::new();
+
+impl FmIfs {
+    /// Returns the process wide facade, loading fmifs.dll on the first call.
+    /// Failed loads aren't cached, so a transient failure doesn't fail every later call.
+    pub fn get() -> WinResult<&'static FmIfs> {
+        if let Some(fmifs) = FMIFS.get() {
+            return Ok(fmifs);
+        }
+
+        // A thread racing this one might win, in which case the facade loaded here is dropped.
+        let fmifs = FmIfs::load()?;
+        Ok(FMIFS.get_or_init(|| fmifs))
+    }
+
+    fn load() -> WinResult<FmIfs> {
+        let module = WinLibrary::load(
+            "fmifs.dll",
+            winapi::um::libloaderapi::LOAD_LIBRARY_SEARCH_SYSTEM32,
+        )?;
+
+        // Not every SKU exports every routine, missing ones are reported when used.
+        unsafe {
+            Ok(FmIfs {
+                format_ex2: module
+                    .proc_address("FormatEx2")
+                    .ok()
+                    .map(|farproc| std::mem::transmute(farproc)),
+                format_ex: module
+                    .proc_address("FormatEx")
+                    .ok()
+                    .map(|farproc| std::mem::transmute(farproc)),
+                chkdsk: module
+                    .proc_address("Chkdsk")
+                    .ok()
+                    .map(|farproc| std::mem::transmute(farproc)),
+                enable_volume_compression: module
+                    .proc_address("EnableVolumeCompression")
+                    .ok()
+                    .map(|farproc| std::mem::transmute(farproc)),
+                _module: module,
+            })
+        }
+    }
+
+    /// Returns the FormatEx2 routine, or ERROR_PROC_NOT_FOUND if it isn't exported.
+    pub fn format(&self) -> WinResult<FormatEx2Routine> {
+        self.format_ex2.ok_or_else(proc_not_found)
+    }
+
+    /// Returns the FormatEx routine, or ERROR_PROC_NOT_FOUND if it isn't exported.
+    pub fn format_ex(&self) -> WinResult<FormatExRoutine> {
+        self.format_ex.ok_or_else(proc_not_found)
+    }
+
+    /// Returns the Chkdsk routine, or ERROR_PROC_NOT_FOUND if it isn't exported.
+    pub fn chkdsk(&self) -> WinResult<ChkdskRoutine> {
+        self.chkdsk.ok_or_else(proc_not_found)
+    }
+
+    /// Enables or disables the default NTFS compression on the root of a volume,
+    /// e.g. `\\?\Volume{4c1b02c1-d990-11dc-99ae-806e6f6e6963}\`.
+    pub fn enable_volume_compression(&self, root_path: &str, enable: bool) -> WinResult<()> {
+        const COMPRESSION_FORMAT_NONE: UShort = 0;
+        const COMPRESSION_FORMAT_DEFAULT: UShort = 1;
+
+        let enable_volume_compression =
+            self.enable_volume_compression.ok_or_else(proc_not_found)?;
+        let mut root_path_wstr = widestring::WideString::from_str(root_path).into_vec();
+        root_path_wstr.push(0);
+
+        let compression = if enable {
+            COMPRESSION_FORMAT_DEFAULT
+        } else {
+            COMPRESSION_FORMAT_NONE
+        };
+
+        match unsafe { enable_volume_compression(root_path_wstr.as_mut_ptr(), compression) } {
+            0 => Err(WinResultCode::ErrorGenFailure),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn proc_not_found() -> WinResultCode {
+    winutils_rs::errorcodes::error_code_to_winresult_code(
+        winapi::shared::winerror::ERROR_PROC_NOT_FOUND,
+    )
+}
+
+// Declared here instead of enabling the combaseapi feature of winapi,
+// which would link ole32 even when GUIDs are generated in Rust.
+#[cfg(not(feature = "guid-v4"))]
+#[link(name = "ole32")]
+extern "system" {
+    fn CoCreateGuid(guid: *mut Guid) -> i32;
+}
+
+/// Generates a new random GUID through CoCreateGuid.
+#[cfg(not(feature = "guid-v4"))]
+pub fn new_guid() -> WinResult<Guid> {
+    let mut guid = GUID_NULL;
+
+    match unsafe { CoCreateGuid(&mut guid) } {
+        winapi::shared::winerror::S_OK => Ok(guid),
+        result => Err(winutils_rs::errorcodes::error_code_to_winresult_code(
+            result as u32,
+        )),
+    }
+}
+
+/// Generates a new random (version 4) GUID without calling into the system,
+/// so that this crate doesn't have to link ole32.
+/// The random bits come from the randomly keyed hasher of the standard library,
+/// which is good enough for uniqueness but must not be relied on for secrecy.
+#[cfg(feature = "guid-v4")]
+pub fn new_guid() -> WinResult<Guid> {
+    use std::hash::{BuildHasher, Hasher};
+
+    static COUNTER: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+    let mut random = [0u64; 2];
+
+    for value in random.iter_mut() {
+        let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+        hasher.write_u64(COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed));
+        *value = hasher.finish();
+    }
+
+    let mut data4 = random[1].to_le_bytes();
+    data4[0] = (data4[0] & 0x3F) | 0x80; // RFC 4122 variant
+
+    Ok(Guid {
+        Data1: random[0] as u32,
+        Data2: (random[0] >> 32) as u16,
+        Data3: ((random[0] >> 48) as u16 & 0x0FFF) | 0x4000, // Version 4
+        Data4: data4,
+    })
+}
+
+/// Converts a UTF-16 buffer filled by a Windows API to a String, stopping at the first NUL.
+/// Returns ERROR_INVALID_NAME if the buffer isn't well formed UTF-16,
+/// instead of replacing the malformed characters.
+pub fn wide_to_string(wide: &[WChar]) -> WinResult<String> {
+    let length = wide
+        .iter()
+        .position(|wchar| *wchar == 0)
+        .unwrap_or(wide.len());
+
+    String::from_utf16(&wide[..length]).map_err(|_| {
+        winutils_rs::errorcodes::error_code_to_winresult_code(
+            winapi::shared::winerror::ERROR_INVALID_NAME,
+        )
+    })
+}
+
+/// Decodes a GUID stored in its little endian binary representation.
+/// The slice must hold at least 16 bytes.
+pub(crate) fn guid_from_le_bytes(bytes: &[u8]) -> Guid {
+    let mut data4: [u8; 8] = [0; 8];
+    data4.copy_from_slice(&bytes[8..16]);
+
+    Guid {
+        Data1: u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+        Data2: u16::from_le_bytes([bytes[4], bytes[5]]),
+        Data3: u16::from_le_bytes([bytes[6], bytes[7]]),
+        Data4: data4,
+    }
+}
+
+/// Formats a GUID in its canonical string representation,
+/// e.g. 4c1b02c1-d990-11dc-99ae-806e6f6e6963.
+pub fn guid_to_string(guid: &Guid) -> String {
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+        guid.Data1,
+        guid.Data2,
+        guid.Data3,
+        guid.Data4[0],
+        guid.Data4[1],
+        guid.Data4[2],
+        guid.Data4[3],
+        guid.Data4[4],
+        guid.Data4[5],
+        guid.Data4[6],
+        guid.Data4[7]
+    )
+}
+
+/// Parses a GUID from its canonical string representation, with or without surrounding braces.
+/// Returns None if the string is not a well formed GUID.
+pub fn guid_from_str(string: &str) -> Option<Guid> {
+    let string = string.trim_start_matches('{').trim_end_matches('}');
+    let groups: Vec<&str> = string.split('-').collect();
+
+    if groups.len() != 5
+        || groups
+            .iter()
+            .zip([8, 4, 4, 4, 12].iter())
+            .any(|(group, length)| {
+                group.len() != *length || !group.chars().all(|c| c.is_ascii_hexdigit())
+            })
+    {
+        return None;
+    }
+
+    let data4_hex = format!("{}{}", groups[3], groups[4]);
+    let mut data4: [u8; 8] = [0; 8];
+
+    for (index, byte) in data4.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&data4_hex[index * 2..index * 2 + 2], 16).ok()?;
+    }
+
+    Some(Guid {
+        Data1: u32::from_str_radix(groups[0], 16).ok()?,
+        Data2: u16::from_str_radix(groups[1], 16).ok()?,
+        Data3: u16::from_str_radix(groups[2], 16).ok()?,
+        Data4: data4,
+    })
+}
+
+/// Serializes GUIDs as strings in their canonical representation.
+/// Use it on GUID fields with `#[serde(with = "crate::winutilities::serde_guid")]`.
+#[cfg(feature = "serde")]
+pub mod serde_guid {
+    use super::*;
+
+    pub fn serialize<S: serde::Serializer>(guid: &Guid, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&guid_to_string(guid))
+    }
+
+    pub fn deserialize<'de, D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Guid, D::Error> {
+        let string = <String as serde::Deserialize>::deserialize(deserializer)?;
+        guid_from_str(&string)
+            .ok_or_else(|| serde::de::Error::custom(format!("invalid GUID {}", string)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_policy_delay_doubles_up_to_max_delay() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            delay: std::time::Duration::from_millis(100),
+            max_delay: std::time::Duration::from_millis(1000),
+            diagnose: false,
+        };
+
+        let delays: Vec<u128> = (2..=7)
+            .map(|attempt| policy.delay_before(attempt).as_millis())
+            .collect();
+        assert_eq!(vec![100, 200, 400, 800, 1000, 1000], delays);
+        assert_eq!(
+            std::time::Duration::from_millis(1000),
+            policy.delay_before(u32::max_value())
+        );
+    }
+
+    #[test]
+    fn retry_policy_new_uses_fixed_delay() {
+        let policy = RetryPolicy::new(3, std::time::Duration::from_millis(50));
+
+        for attempt in 1..=3 {
+            assert_eq!(
+                std::time::Duration::from_millis(50),
+                policy.delay_before(attempt)
+            );
+        }
+    }
+
+    #[test]
+    fn wait_milliseconds_never_reaches_infinite() {
+        use winapi::um::winbase::INFINITE;
+
+        assert_eq!(
+            1500,
+            wait_milliseconds(std::time::Duration::from_millis(1500))
+        );
+        assert_eq!(
+            INFINITE - 1,
+            wait_milliseconds(std::time::Duration::from_millis(INFINITE as u64))
+        );
+        assert_eq!(
+            INFINITE - 1,
+            wait_milliseconds(std::time::Duration::from_secs(u64::max_value()))
+        );
+    }
+
+    #[test]
+    fn can_signal_and_wait_multiple_events() {
+        let first = WinEvent::create(true, false, None, None).unwrap();
+        let second = WinEvent::create(true, false, None, None).unwrap();
+
+        assert_eq!(None, wait_multiple(&[&first, &second], false, 0).unwrap());
+
+        second.set_event().unwrap();
+        assert_eq!(
+            Some(1),
+            wait_multiple(&[&first, &second], false, 0).unwrap()
+        );
+        assert_eq!(None, wait_multiple(&[&first, &second], true, 0).unwrap());
+
+        first.set_event().unwrap();
+        assert_eq!(Some(0), wait_multiple(&[&first, &second], true, 0).unwrap());
+
+        first.reset_event().unwrap();
+        second.reset_event().unwrap();
+        assert_eq!(None, wait_multiple(&[&first, &second], false, 0).unwrap());
+    }
+
+    #[test]
+    fn cancellation_token_clones_share_state() {
+        let token = CancellationToken::new().unwrap();
+        let clone = token.clone();
+        assert!(!clone.is_cancelled());
+
+        std::thread::spawn(move || token.cancel().unwrap())
+            .join()
+            .unwrap();
+        assert!(clone.is_cancelled());
+    }
+}
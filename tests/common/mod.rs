@@ -0,0 +1,22 @@
+// Copyright (c) 2019 Rafael Alcaraz Mercado. All rights reserved.
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// All files in the project carrying such notice may not be copied, modified, or distributed
+// except according to those terms.
+// THE SOURCE CODE IS AVAILABLE UNDER THE ABOVE CHOSEN LICENSE "AS IS", WITH NO WARRANTIES.
+
+//! Helpers shared by the integration and compat test suites.
+
+/// Deletes the file at the given path at the end of its lifetime, even if the test panics.
+pub struct DeleteFileScopeExit<'a> {
+    pub filepath: &'a str,
+}
+
+impl<'a> std::ops::Drop for DeleteFileScopeExit<'a> {
+    fn drop(&mut self) {
+        if let Err(error) = std::fs::remove_file(self.filepath) {
+            println!("Failed to delete file {}: {}", self.filepath, error);
+        };
+    }
+}
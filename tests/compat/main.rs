@@ -0,0 +1,184 @@
+// Copyright (c) 2019 Rafael Alcaraz Mercado. All rights reserved.
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// All files in the project carrying such notice may not be copied, modified, or distributed
+// except according to those terms.
+// THE SOURCE CODE IS AVAILABLE UNDER THE ABOVE CHOSEN LICENSE "AS IS", WITH NO WARRANTIES.
+
+//! Differential tests that cross-check what this crate reports about a VHD against
+//! the Hyper-V PowerShell cmdlets (`Get-VHD`, `Mount-VHD`, `Dismount-VHD`).
+//!
+//! Only built with the `compat-tests` feature, and they require the Hyper-V PowerShell module
+//! and an elevated prompt: `cargo test --features compat-tests --test compat`.
+
+#[path = "../common/mod.rs"]
+mod common;
+
+use common::DeleteFileScopeExit;
+use virtdisk_rs::testutils::*;
+use virtdisk_rs::vhdutilities::*;
+use virtdisk_rs::virtdisk::*;
+use virtdisk_rs::virtdiskdefs::*;
+
+/// Runs the PowerShell script, returning its standard output.
+fn run_powershell(script: &str) -> Vec<u8> {
+    let output = std::process::Command::new("powershell.exe")
+        .args(&["-NoProfile", "-NonInteractive", "-Command", script])
+        .output()
+        .expect("Failed to run powershell.exe");
+
+    assert!(
+        output.status.success(),
+        "PowerShell failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    output.stdout
+}
+
+/// Runs the PowerShell script and parses its output, piped through ConvertTo-Json.
+fn powershell(script: &str) -> serde_json::Value {
+    serde_json::from_slice(&run_powershell(&format!(
+        "{} | ConvertTo-Json -Compress",
+        script
+    )))
+    .expect("PowerShell output is not valid JSON")
+}
+
+/// Runs `Get-VHD` on the given path, with enums converted to their names.
+fn get_vhd(path: &str) -> serde_json::Value {
+    powershell(&format!(
+        "Get-VHD -Path '{}' | Select-Object Size, FileSize, BlockSize, LogicalSectorSize, \
+         PhysicalSectorSize, ParentPath, Attached, DiskNumber, \
+         @{{n='VhdType';e={{\"$($_.VhdType)\"}}}}, @{{n='VhdFormat';e={{\"$($_.VhdFormat)\"}}}}",
+        path
+    ))
+}
+
+fn size_info(virtual_disk: &VirtualDisk) -> get_virtual_disk::InfoSize {
+    unsafe {
+        virtual_disk
+            .get_information(get_virtual_disk::InfoVersion::Size)
+            .unwrap()
+            .info()
+            .version_details
+            .size
+    }
+}
+
+fn normalized(path: &str) -> String {
+    std::fs::canonicalize(path)
+        .unwrap()
+        .to_string_lossy()
+        .trim_start_matches("\\\\?\\")
+        .to_lowercase()
+}
+
+#[test]
+fn size_and_type_match_get_vhd() {
+    let temp_vhd = TempVhd::new(1, 1).unwrap();
+    let virtual_disk = temp_vhd.virtual_disk();
+    let size = size_info(virtual_disk);
+    let physical_sector_size = unsafe {
+        virtual_disk
+            .get_information(get_virtual_disk::InfoVersion::VhdPhysicalSectorSize)
+            .unwrap()
+            .info()
+            .version_details
+            .vhd_physical_sector_size
+    };
+    let vhd = get_vhd(temp_vhd.path());
+
+    assert_eq!(vhd["VhdFormat"], "VHDX");
+    assert_eq!(vhd["VhdType"], "Dynamic");
+    assert_eq!(DiskKind::Dynamic, virtual_disk.disk_kind().unwrap());
+    assert_eq!(vhd["Size"], size.virtual_size);
+    assert_eq!(vhd["BlockSize"], size.block_size);
+    assert_eq!(vhd["LogicalSectorSize"], size.sector_size);
+    assert_eq!(vhd["PhysicalSectorSize"], physical_sector_size);
+}
+
+#[test]
+fn summary_matches_get_vhd() {
+    let temp_vhd = TempVhd::new(1, 1).unwrap();
+    let summary = temp_vhd.virtual_disk().summary().unwrap();
+    let vhd = get_vhd(temp_vhd.path());
+
+    assert_eq!(DiskKind::Dynamic, summary.disk_kind);
+    assert_eq!(vhd["Size"], summary.virtual_size);
+    assert_eq!(vhd["FileSize"], summary.physical_size);
+    assert_eq!(vhd["BlockSize"], summary.block_size);
+    assert_eq!(vhd["LogicalSectorSize"], summary.sector_size);
+    assert_eq!(AttachState::Detached, summary.attach_state);
+    assert!(summary.parent_location.is_none());
+}
+
+#[test]
+fn parent_chain_matches_get_vhd() {
+    let base = TempVhd::new(1, 1).unwrap();
+    let child_path = format!("{}.child.vhdx", base.path());
+    let _delete_child_scope_exit = DeleteFileScopeExit {
+        filepath: &child_path,
+    };
+
+    create_diff_vhd(&child_path, base.path(), 1).unwrap();
+
+    let vhd = get_vhd(&child_path);
+    let chain = differencing_chain(&child_path).unwrap();
+    let child = VirtualDisk::open_info_only(&child_path).unwrap();
+
+    assert_eq!(vhd["VhdType"], "Differencing");
+    assert_eq!(DiskKind::Differencing, child.disk_kind().unwrap());
+    assert_eq!(2, chain.len());
+    assert_eq!(
+        normalized(vhd["ParentPath"].as_str().unwrap()),
+        normalized(&chain[1])
+    );
+    assert_eq!(
+        normalized(vhd["ParentPath"].as_str().unwrap()),
+        normalized(&child.parent_location().unwrap().paths[0])
+    );
+}
+
+#[test]
+fn attach_state_matches_get_vhd() {
+    let mut temp_vhd = TempVhd::new(1, 1).unwrap();
+    let vhd = get_vhd(temp_vhd.path());
+
+    assert_eq!(vhd["Attached"], false);
+    assert_eq!(
+        AttachState::Detached,
+        temp_vhd.virtual_disk().attach_state().unwrap()
+    );
+
+    temp_vhd.mount().unwrap();
+    let vhd = get_vhd(temp_vhd.path());
+
+    assert_eq!(vhd["Attached"], true);
+    assert_eq!(
+        AttachState::AttachedReadWrite {
+            disk_number: vhd["DiskNumber"].as_u64().unwrap() as u32
+        },
+        temp_vhd.virtual_disk().attach_state().unwrap()
+    );
+}
+
+#[test]
+fn attach_state_reports_mount_vhd_attachments() {
+    let temp_vhd = TempVhd::new(1, 1).unwrap();
+    let vhd = powershell(&format!(
+        "Mount-VHD -Path '{}' -ReadOnly -NoDriveLetter -Passthru | Select-Object DiskNumber",
+        temp_vhd.path()
+    ));
+
+    let state = temp_vhd.virtual_disk().attach_state();
+    run_powershell(&format!("Dismount-VHD -Path '{}'", temp_vhd.path()));
+
+    assert_eq!(
+        AttachState::AttachedReadOnly {
+            disk_number: vhd["DiskNumber"].as_u64().unwrap() as u32
+        },
+        state.unwrap()
+    );
+}
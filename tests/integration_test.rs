@@ -8,49 +8,43 @@
 
 //! These tests verify basic workflows of the vhdutilities module, and not the entire crate.
 
-use virtdisk_rs::vhdutilities::*;
-
-struct DeleteDiskScopeExit<'a> {
-    filepath: &'a str,
-}
+mod common;
 
-impl<'a> std::ops::Drop for DeleteDiskScopeExit<'a> {
-    fn drop(&mut self) {
-        if let Err(error) = std::fs::remove_file(self.filepath) {
-            println!("Failed to delete file {}: {}", self.filepath, error);
-        };
-    }
-}
+use common::DeleteFileScopeExit;
+#[cfg(feature = "testutils")]
+use virtdisk_rs::testutils::*;
+use virtdisk_rs::vhdutilities::*;
+use virtdisk_rs::virtdiskdefs::*;
 
 #[test]
 fn can_create_plain_vhd() {
     let disk_path = String::from("can_create_plain_vhd.vhdx");
-    let _delete_file_scope_exit = DeleteDiskScopeExit {
+    let _delete_file_scope_exit = DeleteFileScopeExit {
         filepath: &disk_path,
     };
 
-    let _virtual_disk = create_vhd(&disk_path, 1, 1).unwrap();
+    let _virtual_disk = create_vhd(&disk_path, SizeSpec::gib(1), 1).unwrap();
 }
 
 #[test]
 fn can_temporarily_mount_plain_vhd() {
     let disk_path = String::from("can_temporarily_mount_plain_vhd.vhdx");
-    let _delete_file_scope_exit = DeleteDiskScopeExit {
+    let _delete_file_scope_exit = DeleteFileScopeExit {
         filepath: &disk_path,
     };
 
-    let virtual_disk = create_vhd(&disk_path, 1, 1).unwrap();
+    let virtual_disk = create_vhd(&disk_path, SizeSpec::gib(1), 1).unwrap();
     mount_vhd_temporarily_for_setup(&virtual_disk).unwrap();
 }
 
 #[test]
 fn can_open_temporarily_mounted_plain_vhd() {
     let disk_path = String::from("can_open_temporarily_mounted_plain_vhd.vhdx");
-    let _delete_file_scope_exit = DeleteDiskScopeExit {
+    let _delete_file_scope_exit = DeleteFileScopeExit {
         filepath: &disk_path,
     };
 
-    let virtual_disk = create_vhd(&disk_path, 1, 1).unwrap();
+    let virtual_disk = create_vhd(&disk_path, SizeSpec::gib(1), 1).unwrap();
     mount_vhd_temporarily_for_setup(&virtual_disk).unwrap();
     let _disk = open_vhd_backed_disk(&virtual_disk).unwrap();
 }
@@ -58,21 +52,33 @@ fn can_open_temporarily_mounted_plain_vhd() {
 #[test]
 fn can_create_base_vhd() {
     let disk_path = String::from("can_create_base_vhd.vhdx");
-    let _delete_file_scope_exit = DeleteDiskScopeExit {
+    let _delete_file_scope_exit = DeleteFileScopeExit {
         filepath: &disk_path,
     };
 
-    let _mounted_volume = create_base_vhd(&disk_path, 1, 1, "NTFS").unwrap();
+    let _mounted_volume = create_base_vhd(
+        &disk_path,
+        SizeSpec::gib(1),
+        DiskGeometry::from_block_size_mb(1),
+        "NTFS",
+    )
+    .unwrap();
 }
 
 #[test]
 fn can_open_vhd() {
     let disk_path = String::from("can_open_vhd.vhdx");
-    let _delete_file_scope_exit = DeleteDiskScopeExit {
+    let _delete_file_scope_exit = DeleteFileScopeExit {
         filepath: &disk_path,
     };
 
-    let mounted_volume = create_base_vhd(&disk_path, 1, 1, "NTFS").unwrap();
+    let mounted_volume = create_base_vhd(
+        &disk_path,
+        SizeSpec::gib(1),
+        DiskGeometry::from_block_size_mb(1),
+        "NTFS",
+    )
+    .unwrap();
     drop(mounted_volume);
 
     let _vhd = open_vhd(&disk_path, true).unwrap();
@@ -81,11 +87,17 @@ fn can_open_vhd() {
 #[test]
 fn can_mount_dismount_vhd() {
     let disk_path = String::from("can_mount_dismount_vhd.vhdx");
-    let _delete_file_scope_exit = DeleteDiskScopeExit {
+    let _delete_file_scope_exit = DeleteFileScopeExit {
         filepath: &disk_path,
     };
 
-    let mounted_volume = create_base_vhd(&disk_path, 1, 1, "NTFS").unwrap();
+    let mounted_volume = create_base_vhd(
+        &disk_path,
+        SizeSpec::gib(1),
+        DiskGeometry::from_block_size_mb(1),
+        "NTFS",
+    )
+    .unwrap();
     drop(mounted_volume);
 
     let vhd = open_vhd(&disk_path, false).unwrap();
@@ -93,75 +105,434 @@ fn can_mount_dismount_vhd() {
     assert_eq!((), dismount_vhd(&vhd).unwrap());
 }
 
+#[test]
+fn rejects_invalid_sizes() {
+    assert!(SizeSpec::gib(u64::MAX)
+        .validate(&VirtualStorageType::vhdx())
+        .is_err());
+    assert!(SizeSpec::mib(1)
+        .validate(&VirtualStorageType::vhdx())
+        .is_err());
+    assert!(SizeSpec::bytes(4 * 1024 * 1024 + 1)
+        .validate(&VirtualStorageType::vhdx())
+        .is_err());
+    assert!(SizeSpec::gib(4096)
+        .validate(&VirtualStorageType::vhd())
+        .is_err());
+    assert_eq!(
+        4096 * 1024 * 1024 * 1024,
+        SizeSpec::gib(4096)
+            .validate(&VirtualStorageType::vhdx())
+            .unwrap()
+    );
+}
+
 #[test]
 fn can_expand_vhd() {
     let disk_path = String::from("can_expand_vhd.vhdx");
-    let _delete_file_scope_exit = DeleteDiskScopeExit {
+    let _delete_file_scope_exit = DeleteFileScopeExit {
         filepath: &disk_path,
     };
 
-    let mounted_volume = create_base_vhd(&disk_path, 20, 32, "NTFS").unwrap();
+    let mounted_volume = create_base_vhd(
+        &disk_path,
+        SizeSpec::gib(20),
+        DiskGeometry::from_block_size_mb(32),
+        "NTFS",
+    )
+    .unwrap();
     drop(mounted_volume);
 
     let vhd = open_vhd(&disk_path, false).unwrap();
-    assert!(expand_vhd(&vhd, 50 * 1024 * 1024 * 1024).unwrap());
+    assert!(expand_vhd(&vhd, SizeSpec::gib(50)).unwrap());
 }
 
 #[test]
 fn can_expand_volume() {
     let disk_path = String::from("can_expand_volume.vhdx");
-    let _delete_file_scope_exit = DeleteDiskScopeExit {
+    let _delete_file_scope_exit = DeleteFileScopeExit {
         filepath: &disk_path,
     };
 
-    let mounted_volume = create_base_vhd(&disk_path, 20, 32, "NTFS").unwrap();
+    let mounted_volume = create_base_vhd(
+        &disk_path,
+        SizeSpec::gib(20),
+        DiskGeometry::from_block_size_mb(32),
+        "NTFS",
+    )
+    .unwrap();
     drop(mounted_volume);
 
     let vhd = open_vhd(&disk_path, false).unwrap();
-    assert!(expand_vhd(&vhd, 50 * 1024 * 1024 * 1024).unwrap());
+    assert!(expand_vhd(&vhd, SizeSpec::gib(50)).unwrap());
     assert_eq!((), mount_vhd_temporarily_for_setup(&vhd).unwrap());
 
     let disk = open_vhd_backed_disk(&vhd).unwrap();
     assert!(disk.expand_volume().unwrap());
 }
 
+/// Splits the disk of a mounted VHD into two NTFS formatted basic data partitions,
+/// the first one of 1 GiB and the second one spanning the rest of the disk.
+/// Returns the volume paths of both partitions.
+#[cfg(feature = "testutils")]
+fn split_into_two_volumes(disk: &virtdisk_rs::diskutilities::Disk) -> (String, String) {
+    use virtdisk_rs::diskutilities::{format_volume, GptPartitionSpec};
+    use virtdisk_rs::gpt::PARTITION_BASIC_DATA_GUID;
+    use virtdisk_rs::guid::WinGuid;
+
+    let spec = |length: Option<u64>, name: &str| GptPartitionSpec {
+        partition_type: WinGuid::from(PARTITION_BASIC_DATA_GUID),
+        length,
+        attributes: 0,
+        name: String::from(name),
+    };
+
+    let partitions = disk
+        .create_gpt_partitions(&[
+            spec(Some(1024 * 1024 * 1024), "first"),
+            spec(None, "second"),
+        ])
+        .unwrap();
+    let first = disk.partition_volume_path(&partitions[0]).unwrap();
+    let second = disk.partition_volume_path(&partitions[1]).unwrap();
+    format_volume(&first, "NTFS", "first").unwrap();
+    format_volume(&second, "NTFS", "second").unwrap();
+    (first, second)
+}
+
+#[cfg(feature = "testutils")]
+#[test]
+fn shrinks_last_volume_of_two_partition_disk() {
+    use virtdisk_rs::diskutilities::volume_space;
+
+    let temp_vhd = TempVhd::new_mounted(4, 1).unwrap();
+    let disk = temp_vhd.disk().unwrap();
+    let (first, second) = split_into_two_volumes(disk);
+    let first_size = volume_space(&first).unwrap().total;
+
+    assert!(disk.shrink_volume(2 * 1024 * 1024 * 1024).unwrap());
+
+    // Only the last partition and its file system are shrunk.
+    assert_eq!(first_size, volume_space(&first).unwrap().total);
+    assert!(volume_space(&second).unwrap().total <= 2 * 1024 * 1024 * 1024);
+    assert_eq!(2 * 1024 * 1024 * 1024, disk.partitions().unwrap()[1].length);
+}
+
+#[cfg(feature = "testutils")]
+#[test]
+fn online_resize_reports_last_volume_of_two_partition_disk() {
+    use virtdisk_rs::diskutilities::volume_space;
+
+    let temp_vhd = TempVhd::new_mounted(4, 1).unwrap();
+    let (first, second) = split_into_two_volumes(temp_vhd.disk().unwrap());
+    let first_size = volume_space(&first).unwrap().total;
+    let second_size = volume_space(&second).unwrap().total;
+
+    let result = online_resize(temp_vhd.virtual_disk(), SizeSpec::gib(8)).unwrap();
+    assert!(result.vhd_expanded);
+    assert!(result.volume_expanded);
+
+    // The reported size is the one of the expanded last volume, the first one is left as is.
+    assert_eq!(volume_space(&second).unwrap().total, result.volume_size);
+    assert!(result.volume_size > second_size);
+    assert_eq!(first_size, volume_space(&first).unwrap().total);
+}
+
+#[test]
+fn can_grow_vhd_and_volume() {
+    let disk_path = String::from("can_grow_vhd_and_volume.vhdx");
+    let _delete_file_scope_exit = DeleteFileScopeExit {
+        filepath: &disk_path,
+    };
+
+    let mounted_volume = create_base_vhd(
+        &disk_path,
+        SizeSpec::gib(20),
+        DiskGeometry::from_block_size_mb(32),
+        "NTFS",
+    )
+    .unwrap();
+    drop(mounted_volume);
+
+    let vhd = open_vhd(&disk_path, false).unwrap();
+    let result = grow_to(&vhd, SizeSpec::gib(50)).unwrap();
+    assert!(result.vhd_expanded);
+    assert!(result.volume_expanded);
+    assert_eq!(50 * 1024 * 1024 * 1024, result.virtual_size);
+}
+
 #[test]
 fn can_create_vhd_from_source() {
     let disk_path = String::from("can_create_vhd_from_source.vhdx");
-    let _delete_file_scope_exit = DeleteDiskScopeExit {
+    let _delete_file_scope_exit = DeleteFileScopeExit {
         filepath: &disk_path,
     };
 
     let copied_disk_path = String::from("can_create_vhd_from_source_copied.vhdx");
-    let _delete_copied_file_scope_exit = DeleteDiskScopeExit {
+    let _delete_copied_file_scope_exit = DeleteFileScopeExit {
         filepath: &copied_disk_path,
     };
 
-    let mounted_volume = create_base_vhd(&disk_path, 20, 32, "NTFS").unwrap();
+    let mounted_volume = create_base_vhd(
+        &disk_path,
+        SizeSpec::gib(20),
+        DiskGeometry::from_block_size_mb(32),
+        "NTFS",
+    )
+    .unwrap();
     drop(mounted_volume);
 
     assert_eq!(
         (),
-        create_vhd_from_source(&copied_disk_path, &disk_path, 1).unwrap()
+        create_vhd_from_source(&copied_disk_path, &disk_path, 1, None, true).unwrap()
     );
 }
 
 #[test]
 fn can_create_diff_and_merge_vhd() {
     let disk_path = String::from("parent.vhdx");
-    let _delete_file_scope_exit = DeleteDiskScopeExit {
+    let _delete_file_scope_exit = DeleteFileScopeExit {
         filepath: &disk_path,
     };
 
     let diff_disk_path = String::from("diff.vhdx");
-    let _delete_diff_file_scope_exit = DeleteDiskScopeExit {
+    let _delete_diff_file_scope_exit = DeleteFileScopeExit {
         filepath: &diff_disk_path,
     };
 
-    let mounted_volume = create_base_vhd(&disk_path, 20, 32, "NTFS").unwrap();
+    let mounted_volume = create_base_vhd(
+        &disk_path,
+        SizeSpec::gib(20),
+        DiskGeometry::from_block_size_mb(32),
+        "NTFS",
+    )
+    .unwrap();
     drop(mounted_volume);
 
     assert_eq!((), create_diff_vhd(&diff_disk_path, &disk_path, 1).unwrap());
     let diff_vhd = open_vhd(&diff_disk_path, false).unwrap();
     assert_eq!((), merge_diff_vhd(&diff_vhd).unwrap());
 }
+
+#[cfg(feature = "testutils")]
+#[test]
+fn can_create_formatted_temp_vhd() {
+    let temp_vhd = TempVhd::new_formatted(1, 1, "NTFS").unwrap();
+    assert!(temp_vhd.is_mounted());
+    assert!(temp_vhd.partition().is_some());
+    assert!(std::path::Path::new(temp_vhd.path()).exists());
+}
+
+#[cfg(feature = "testutils")]
+#[test]
+fn can_roll_back_mount_many() {
+    let mut first = TempVhd::new(1, 1).unwrap();
+    first.close().unwrap();
+    let mut second = TempVhd::new(1, 1).unwrap();
+    second.close().unwrap();
+
+    let paths = [first.path(), second.path(), "does_not_exist.vhdx"];
+    let results = mount_many(
+        &paths,
+        MountOptions::temporary_for_setup(),
+        2,
+        BatchMode::AllOrNothing,
+    );
+
+    assert_eq!(3, results.len());
+    assert!(results.iter().all(|result| result.is_err()));
+}
+
+#[cfg(feature = "testutils")]
+#[test]
+fn mount_many_keeps_how_disks_were_opened() {
+    let mut first = TempVhd::new(1, 1).unwrap();
+    first.close().unwrap();
+    let mut second = TempVhd::new(1, 1).unwrap();
+    second.close().unwrap();
+
+    let paths = [first.path(), second.path()];
+    let results = mount_many(
+        &paths,
+        MountOptions::temporary_for_setup(),
+        2,
+        BatchMode::BestEffort,
+    );
+
+    for (path, result) in paths.iter().zip(&results) {
+        let virtual_disk = result.as_ref().unwrap();
+        assert!(virtual_disk.path().unwrap().ends_with(*path));
+        virtual_disk.reopen_read_only().unwrap();
+        dismount_vhd(virtual_disk).unwrap();
+    }
+}
+
+#[cfg(feature = "testutils")]
+#[test]
+fn can_inspect_clean_vhdx_log() {
+    let mut temp_vhd = TempVhd::new(1, 1).unwrap();
+    temp_vhd.close().unwrap();
+
+    let report = virtdisk_rs::vhdformat::inspect_log(temp_vhd.path()).unwrap();
+    assert!(!report.is_dirty());
+    assert!(report.pending_entries.is_empty());
+}
+
+#[test]
+fn handle_types_are_send_and_sync() {
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    assert_send_sync::<virtdisk_rs::virtdisk::VirtualDisk>();
+    assert_send_sync::<virtdisk_rs::diskutilities::Disk>();
+    assert_send_sync::<virtdisk_rs::diskutilities::Volume>();
+    assert_send_sync::<virtdisk_rs::winutilities::EventLease>();
+}
+
+#[test]
+fn create_transaction_cleans_up_uncommitted_file() {
+    let transaction = CreateTransaction::new("create_transaction.vhdx").unwrap();
+    let temp_path = String::from(transaction.temp_path());
+    assert_eq!("create_transaction.tmp.vhdx", temp_path);
+
+    std::fs::write(&temp_path, b"partial").unwrap();
+    drop(transaction);
+
+    assert!(!std::path::Path::new(&temp_path).exists());
+    assert!(!std::path::Path::new("create_transaction.vhdx").exists());
+}
+
+#[test]
+fn create_transaction_keeps_existing_temp_file() {
+    let temp_path = "create_transaction_in_progress.tmp.vhdx";
+    let _delete_file_scope_exit = DeleteFileScopeExit {
+        filepath: temp_path,
+    };
+    std::fs::write(temp_path, b"in progress").unwrap();
+
+    assert!(CreateTransaction::new("create_transaction_in_progress.vhdx").is_err());
+    assert_eq!(b"in progress".to_vec(), std::fs::read(temp_path).unwrap());
+}
+
+#[test]
+fn normalizes_virtual_disk_paths() {
+    use virtdisk_rs::virtdisk::normalize_virtual_disk_path;
+
+    // Absolute paths, volume GUID paths and UNC paths are kept.
+    assert_eq!(
+        "C:\\vhds\\disk.vhdx",
+        normalize_virtual_disk_path("C:/vhds/disk.vhdx").unwrap()
+    );
+    assert_eq!(
+        "\\\\server\\share\\disk.vhdx",
+        normalize_virtual_disk_path("//server/share/disk.vhdx").unwrap()
+    );
+    assert_eq!(
+        "\\\\?\\UNC\\server\\share\\disk.vhdx",
+        normalize_virtual_disk_path("\\\\?\\UNC\\server\\share\\disk.vhdx").unwrap()
+    );
+
+    // Relative paths are made absolute.
+    assert_eq!(
+        std::env::current_dir()
+            .unwrap()
+            .join("disk.vhdx")
+            .to_string_lossy(),
+        normalize_virtual_disk_path("disk.vhdx").unwrap()
+    );
+
+    // Drive relative paths resolve against the current directory of their drive,
+    // and rooted paths against the current drive.
+    let current_dir = std::env::current_dir().unwrap();
+    let drive = current_dir.to_string_lossy()[..2].to_string();
+    assert_eq!(
+        current_dir.join("disk.vhdx").to_string_lossy(),
+        normalize_virtual_disk_path(&format!("{}disk.vhdx", drive)).unwrap()
+    );
+    assert_eq!(
+        format!("{}\\vhds\\disk.vhdx", drive),
+        normalize_virtual_disk_path("/vhds/disk.vhdx").unwrap()
+    );
+
+    // Device paths of a volume are rewritten on top of its volume GUID path.
+    let system_volume = virtdisk_rs::diskutilities::resolve_volume("C:\\").unwrap();
+    let expected = format!("{}\\vhds\\disk.vhdx", system_volume.volume_name);
+
+    for prefix in &["\\\\?\\GLOBALROOT", "\\\\.\\GLOBALROOT", ""] {
+        let device_path = format!("{}{}\\vhds\\disk.vhdx", prefix, system_volume.dos_device);
+        assert_eq!(expected, normalize_virtual_disk_path(&device_path).unwrap());
+    }
+
+    // Devices that aren't volumes are kept behind the GLOBALROOT link.
+    assert_eq!(
+        "\\\\?\\GLOBALROOT\\Device\\NotAVolume\\disk.vhdx",
+        normalize_virtual_disk_path("\\Device\\NotAVolume\\disk.vhdx").unwrap()
+    );
+}
+
+#[test]
+fn can_create_gen2_boot_vhdx() {
+    let disk_path = String::from("can_create_gen2_boot_vhdx.vhdx");
+    let _delete_file_scope_exit = DeleteFileScopeExit {
+        filepath: &disk_path,
+    };
+
+    let boot_vhdx =
+        create_gen2_boot_vhdx(&disk_path, SizeSpec::gib(1), &BootLayout::default()).unwrap();
+
+    assert_eq!(3, boot_vhdx.disk.partitions().unwrap().len());
+    assert!(boot_vhdx.esp_volume_path.starts_with("\\\\?\\Volume"));
+    assert!(boot_vhdx.windows_volume_path.starts_with("\\\\?\\Volume"));
+    assert_eq!(16 * 1024 * 1024, boot_vhdx.msr.length);
+}
+
+#[cfg(feature = "testutils")]
+#[test]
+fn can_get_page83_identity() {
+    let temp_vhd = TempVhd::new(1, 1).unwrap();
+    let identity = temp_vhd.virtual_disk().page83_identity().unwrap();
+
+    assert!(!identity.identifier.is_empty());
+    assert!(!identity.formatted.is_empty());
+}
+
+#[cfg(feature = "testutils")]
+#[test]
+fn can_rerandomize_gpt_disk_id() {
+    use virtdisk_rs::diskutilities::DiskIdentity;
+    use virtdisk_rs::guid::WinGuid;
+
+    let temp_vhd = TempVhd::new_formatted(1, 1, "NTFS").unwrap();
+    let disk = temp_vhd.disk().unwrap();
+    let original_disk_id = temp_vhd.partition().unwrap().disk_id();
+
+    assert_eq!(
+        DiskIdentity::Gpt(original_disk_id),
+        disk.identity().unwrap()
+    );
+
+    let new_disk_id = WinGuid::from(virtdisk_rs::winutilities::new_guid().unwrap());
+    disk.set_gpt_disk_id(new_disk_id.into()).unwrap();
+
+    assert_ne!(original_disk_id, new_disk_id);
+    assert_eq!(DiskIdentity::Gpt(new_disk_id), disk.identity().unwrap());
+}
+
+#[cfg(feature = "testutils")]
+#[test]
+fn reports_process_holding_file_on_mounted_volume() {
+    use std::io::Write;
+
+    let temp_vhd = TempVhd::new_formatted(1, 1, "NTFS").unwrap();
+    let volume_path = temp_vhd.partition().unwrap().volume_path();
+
+    let mut held_file =
+        std::fs::File::create(format!("{}\\held_by_test.txt", volume_path)).unwrap();
+    held_file.write_all(b"locked").unwrap();
+
+    let lockers = virtdisk_rs::diskutilities::find_volume_lockers(volume_path).unwrap();
+    assert!(lockers
+        .iter()
+        .any(|locker| locker.pid == std::process::id()));
+
+    drop(held_file);
+}